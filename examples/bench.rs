@@ -0,0 +1,55 @@
+//! Transcribes ten seconds of a generated tone with a tiny randomly initialized model and the
+//! miniature tokenizer of the tests, printing the time spent on each chunk. The text is
+//! meaningless, but the model runs the same pipeline as a real one, so the numbers track the
+//! overhead of the mel, encoder and decoder stages between changes.
+//!
+//! cargo run --release --no-default-features --features ndarray --example bench
+
+use burn::backend::ndarray::NdArray;
+use whisper_stream::model::load::random_whisper;
+use whisper_stream::model::{AudioEncoderConfig, TextDecoderConfig, WhisperConfig};
+use whisper_stream::token::{Gpt2Tokenizer, Language, VocabKind};
+use whisper_stream::transcribe::{transcribe, TranscribeOptions};
+
+type Backend = NdArray;
+
+const SAMPLE_RATE: usize = 16000;
+const SECONDS: usize = 10;
+/// 256 byte tokens, 4 merges and the 1608 special tokens of the multilingual layout.
+const N_VOCAB: usize = 1868;
+
+fn main() {
+    // 200 encoder positions hold 4 seconds of audio
+    let config = WhisperConfig::new(
+        AudioEncoderConfig::new(80, 200, 64, 2, 2),
+        TextDecoderConfig::new(N_VOCAB, 64, 64, 2, 2),
+    );
+    let whisper = random_whisper::<Backend>(&config, 0, &Default::default());
+    let bpe = Gpt2Tokenizer::from_bytes(
+        include_bytes!("../tests/fixtures/tokenizer.json"),
+        VocabKind::from_n_vocab(N_VOCAB),
+    )
+    .expect("the fixture tokenizer is valid");
+
+    // a 440 Hz tone with a slow vibrato, so that no two chunks are the same
+    let waveform: Vec<f32> = (0..SECONDS * SAMPLE_RATE)
+        .map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            let phase = 440.0 * t + 20.0 * (std::f32::consts::TAU * 0.5 * t).sin();
+            0.5 * (std::f32::consts::TAU * phase).sin()
+        })
+        .collect();
+
+    // windows that, with their padding, fit the 4 seconds of the encoder
+    let options = TranscribeOptions::recommended()
+        .language(Language::English)
+        .seed(0)
+        .vad(None)
+        .chunk_length(2.5)
+        .chunk_overlap(1.0)
+        .end_padding(50);
+
+    let transcript = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options)
+        .expect("the tiny model transcribes the tone");
+    println!("{}", transcript.metadata.metrics);
+}
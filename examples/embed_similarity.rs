@@ -0,0 +1,59 @@
+//! Embeds two audio files with the encoder of a converted model and prints the cosine
+//! similarity of their mean-pooled embeddings. The decoder is never loaded.
+//!
+//! cargo run --release --features ndarray --example embed_similarity -- models tiny a.wav b.wav
+
+use burn::backend::ndarray::NdArray;
+use burn::tensor::Tensor;
+use std::{env, path::Path, process};
+use whisper_stream::audio::SAMPLE_RATE;
+use whisper_stream::input;
+use whisper_stream::model::{load::load_named_encoder, Pooling};
+
+type Backend = NdArray;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 5 {
+        eprintln!(
+            "Usage: {} <model dir> <model name> <first audio file> <second audio file>",
+            args[0]
+        );
+        process::exit(1);
+    }
+
+    let device = Default::default();
+    let (_config, encoder) = load_named_encoder::<Backend>(Path::new(&args[1]), &args[2], &device)
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to load the encoder: {e}");
+            process::exit(1);
+        });
+
+    let embeddings: Vec<Tensor<Backend, 2>> = args[3..]
+        .iter()
+        .map(|path| {
+            let audio = input::load_audio_file(path).unwrap_or_else(|e| {
+                eprintln!("Failed to load {path}: {e}");
+                process::exit(1);
+            });
+            encoder
+                .embed_audio(&audio.waveform, SAMPLE_RATE, Pooling::Mean)
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to embed {path}: {e}");
+                    process::exit(1);
+                })
+        })
+        .collect();
+
+    let [a, b] = [&embeddings[0], &embeddings[1]].map(|embedding| {
+        embedding
+            .clone()
+            .flatten::<1>(0, 1)
+            .into_data()
+            .to_vec::<f32>()
+            .unwrap()
+    });
+    let dot: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    println!("cosine similarity: {:.4}", dot / (norm(&a) * norm(&b)));
+}
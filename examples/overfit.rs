@@ -0,0 +1,52 @@
+//! Overfits a randomly initialized model with the dimensions of whisper tiny on two synthetic
+//! samples, printing the training loss as it decreases.
+//!
+//! cargo run --release --features ndarray --example overfit
+
+use burn::backend::{ndarray::NdArray, Autodiff};
+use burn::optim::{AdamConfig, GradientsParams, Optimizer};
+use burn::tensor::{Distribution, Tensor};
+use whisper_stream::model::{AudioEncoderConfig, TextDecoderConfig, WhisperConfig};
+use whisper_stream::train::{collate, compute_loss, WhisperItem};
+
+type Backend = Autodiff<NdArray>;
+
+const N_MELS: usize = 80;
+const END_OF_TEXT: usize = 50257;
+/// Never a label, so that padding stays out of the loss.
+const PAD: usize = 50256;
+
+fn main() {
+    let device = Default::default();
+
+    let config = WhisperConfig::new(
+        AudioEncoderConfig::new(N_MELS, 1500, 384, 6, 4),
+        TextDecoderConfig::new(51865, 448, 384, 6, 4),
+    );
+    let mut whisper = config.init::<Backend>(&device);
+
+    // start of transcript, english, transcribe, no timestamps
+    let prefix = [50258, 50259, 50359, 50363];
+    let items: Vec<WhisperItem<Backend>> = [(200, vec![1000, 2000, 3000]), (160, vec![42, 4242])]
+        .into_iter()
+        .map(|(n_frames, text)| WhisperItem {
+            mels: Tensor::random([N_MELS, n_frames], Distribution::Normal(0.0, 1.0), &device),
+            tokens: prefix
+                .into_iter()
+                .chain(text)
+                .chain([END_OF_TEXT])
+                .collect(),
+        })
+        .collect();
+
+    let mut optimizer = AdamConfig::new().init();
+    for step in 0..30 {
+        let batch = collate(items.clone(), PAD, &device);
+        let loss = compute_loss(&whisper, batch.mels, batch.tokens, batch.pad_token)
+            .expect("the samples fit the model");
+        println!("step {step:2}: loss {:.4}", loss.clone().into_scalar());
+
+        let grads = GradientsParams::from_grads(loss.backward(), &whisper);
+        whisper = optimizer.step(1e-4, whisper, grads);
+    }
+}
@@ -0,0 +1,86 @@
+//! Transcribes two audio files concurrently from async code with a `WhisperService`, which
+//! decodes one of them at a time and streams the partial text of the first one. In an axum
+//! server, the service would be part of the router's state:
+//!
+//! ```ignore
+//! async fn transcribe(State(service): State<WhisperService<Wgpu>>, body: Bytes) -> String {
+//!     let waveform = decode_wav(&body);
+//!     let options = TranscribeOptions::new().language(Language::English);
+//!     match service.transcribe(waveform, SAMPLE_RATE, options).await {
+//!         Ok(transcript) => transcript.text,
+//!         Err(e) => e.to_string(),
+//!     }
+//! }
+//! ```
+//!
+//! cargo run --release --features ndarray,tokio --example service -- models tiny_en a.wav b.wav
+
+use burn::backend::ndarray::NdArray;
+use futures_core::Stream;
+use std::{env, future, path::Path, pin::Pin, process};
+use whisper_stream::audio::SAMPLE_RATE;
+use whisper_stream::input;
+use whisper_stream::model::load::load_named_model;
+use whisper_stream::service::{TranscriptUpdate, WhisperService};
+use whisper_stream::token::Language;
+use whisper_stream::transcribe::{DecodeEvent, TranscribeOptions};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 5 {
+        eprintln!(
+            "Usage: {} <model dir> <model name> <first audio file> <second audio file>",
+            args[0]
+        );
+        process::exit(1);
+    }
+
+    let device = Default::default();
+    let (bpe, _config, whisper) =
+        load_named_model::<NdArray>(Path::new(&args[1]), &args[2], &device).unwrap_or_else(|e| {
+            eprintln!("Failed to load the model: {e}");
+            process::exit(1);
+        });
+    let service = WhisperService::new(whisper, bpe, 1);
+
+    let [first, second] = [&args[3], &args[4]].map(|path| {
+        input::load_audio_file(path)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to load {path}: {e}");
+                process::exit(1);
+            })
+            .waveform
+    });
+    let options = TranscribeOptions::new().language(Language::English);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        // waits for the first one to finish, as the service decodes one file at a time
+        let second = tokio::spawn({
+            let service = service.clone();
+            let options = options.clone();
+            async move { service.transcribe(second, SAMPLE_RATE, options).await }
+        });
+
+        let mut updates = service.transcribe_stream(first, SAMPLE_RATE, options).await;
+        while let Some(update) = future::poll_fn(|cx| Pin::new(&mut updates).poll_next(cx)).await {
+            match update {
+                TranscriptUpdate::Event(DecodeEvent::ChunkFinished { chunk, text }) => {
+                    println!("first, chunk {chunk}: {text}")
+                }
+                TranscriptUpdate::Event(_) => {}
+                TranscriptUpdate::Done(result) => match result {
+                    Ok(transcript) => println!("first: {}", transcript.text),
+                    Err(e) => eprintln!("Failed to transcribe the first file: {e}"),
+                },
+            }
+        }
+
+        match second.await.expect("the task does not panic") {
+            Ok(transcript) => println!("second: {}", transcript.text),
+            Err(e) => eprintln!("Failed to transcribe the second file: {e}"),
+        }
+    });
+}
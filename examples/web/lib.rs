@@ -0,0 +1,58 @@
+//! Transcription in the browser, on the CPU backend since it never blocks on the GPU.
+//!
+//! Build with
+//! `cargo build --release --example web --target wasm32-unknown-unknown --no-default-features --features wasm32`,
+//! then generate the bindings next to `index.html` with
+//! `wasm-bindgen --target web --out-dir examples/web/pkg target/wasm32-unknown-unknown/release/examples/web.wasm`.
+
+use burn::backend::ndarray::{NdArray, NdArrayDevice};
+use wasm_bindgen::prelude::*;
+use whisper_stream::{
+    model::{load, Whisper},
+    token::{Gpt2Tokenizer, Language},
+    transcribe::{waveform_to_transcript, DecodeOptions},
+};
+
+#[wasm_bindgen]
+pub struct Transcriber {
+    whisper: Whisper<NdArray>,
+    bpe: Gpt2Tokenizer,
+}
+
+#[wasm_bindgen]
+impl Transcriber {
+    /// Loads a converted model from the contents of its `tokenizer.json`, `.cfg` and `.mpk`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(tokenizer: &[u8], config: &[u8], weights: Vec<u8>) -> Result<Transcriber, JsError> {
+        let (bpe, _config, whisper) =
+            load::load_model_bytes::<NdArray>(tokenizer, config, weights, &NdArrayDevice::Cpu)?;
+
+        Ok(Self { whisper, bpe })
+    }
+
+    /// Transcribes mono samples in `[-1, 1]` spoken in the language with the code `language`.
+    pub fn transcribe(
+        &self,
+        samples: &[f32],
+        sample_rate: usize,
+        language: &str,
+    ) -> Result<String, JsError> {
+        let lang = Language::from_code(language)
+            .ok_or_else(|| JsError::new(&format!("unknown language {language}")))?;
+        let options = DecodeOptions {
+            // without a seed one would be drawn from the system's entropy source
+            seed: Some(0),
+            ..DecodeOptions::recommended()
+        };
+        let transcript = waveform_to_transcript(
+            &self.whisper,
+            &self.bpe,
+            lang,
+            samples,
+            sample_rate,
+            &options,
+        )?;
+
+        Ok(transcript.text)
+    }
+}
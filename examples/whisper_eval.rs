@@ -0,0 +1,109 @@
+//! Transcribes every file of a manifest and prints the word and character error rates against
+//! the reference texts, with the alignment of each file that has errors. The manifest holds
+//! JSON lines with `audio` and `reference` fields, or CSV lines `audio,reference` unless its
+//! name ends in `.jsonl`. `--report` writes the report to a file instead, as JSON if the name
+//! ends in `.json`.
+//!
+//! cargo run --release --features ndarray --example whisper-eval -- models tiny_en test.csv
+
+use burn::backend::ndarray::NdArray;
+use std::{env, fs, path::Path, process};
+use whisper_stream::eval::{evaluate, read_manifest, NormalizeOptions};
+use whisper_stream::model::load::load_named_model;
+use whisper_stream::token::Language;
+use whisper_stream::transcribe::TranscribeOptions;
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let lang = take_flag_value(&mut args, "--lang");
+    let beam_size = take_flag_value(&mut args, "--beam-size");
+    let report_path = take_flag_value(&mut args, "--report");
+    let normalize = NormalizeOptions {
+        lowercase: !take_flag(&mut args, "--keep-case"),
+        strip_punctuation: !take_flag(&mut args, "--keep-punctuation"),
+        ..Default::default()
+    };
+    if args.len() != 4 {
+        eprintln!(
+            "Usage: {} <model dir> <model name> <manifest> [--lang <code>] [--beam-size <n>] [--report <file>] [--keep-case] [--keep-punctuation]",
+            args[0]
+        );
+        process::exit(1);
+    }
+
+    let mut options = TranscribeOptions::recommended();
+    if let Some(lang) = lang {
+        match Language::from_code(&lang) {
+            Some(lang) => options = options.language(lang),
+            None => {
+                eprintln!("Invalid language abbreviation: {lang}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(beam_size) = beam_size {
+        match beam_size.parse() {
+            Ok(beam_size) => options = options.beam_size(beam_size),
+            Err(_) => {
+                eprintln!("Invalid value for --beam-size: {beam_size}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let entries = read_manifest(&args[3]).unwrap_or_else(|e| {
+        eprintln!("Failed to read the manifest {}: {e}", args[3]);
+        process::exit(1);
+    });
+    let device = Default::default();
+    let (bpe, _config, whisper) =
+        load_named_model::<NdArray>(Path::new(&args[1]), &args[2], &device).unwrap_or_else(|e| {
+            eprintln!("Failed to load the model: {e}");
+            process::exit(1);
+        });
+
+    let report = evaluate(&whisper, &bpe, &entries, &options, &normalize).unwrap_or_else(|e| {
+        eprintln!("Evaluation failed: {e}");
+        process::exit(1);
+    });
+
+    match report_path {
+        Some(path) => {
+            let contents = if path.ends_with(".json") {
+                report.to_json()
+            } else {
+                report.to_string()
+            };
+            if let Err(e) = fs::write(&path, contents) {
+                eprintln!("Failed to write {path}: {e}");
+                process::exit(1);
+            }
+            println!(
+                "WER {:.2}%, CER {:.2}%",
+                report.wer() * 100.0,
+                report.cer() * 100.0
+            );
+        }
+        None => print!("{report}"),
+    }
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|arg| arg == flag) {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    if index + 1 >= args.len() {
+        eprintln!("Missing value for {flag}");
+        process::exit(1);
+    }
+    args.remove(index);
+    Some(args.remove(index))
+}
@@ -1,11 +1,17 @@
 #![allow(clippy::single_range_in_vec_init)]
-use burn::tensor::{activation::relu, backend::Backend, ElementConversion, Tensor};
+use burn::tensor::{activation::relu, backend::Backend, ElementConversion, Tensor, TensorData};
 
+use crate::error::{Result, WhisperError};
 use crate::helper::*;
+use std::collections::{hash_map::Entry, HashMap};
+use std::ops::Range;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+/// Sample rate in Hz expected by Whisper models.
+pub const SAMPLE_RATE: usize = 16000;
 
 const N_FFT: usize = 400;
 const HOP_LENGTH: usize = 160;
-// const N_MELS: usize = 128;
 const WINDOW_LENGTH: usize = N_FFT;
 
 /// Returns the maximum number of waveform samples that can be submitted to `prep_audio`
@@ -17,13 +23,340 @@ pub fn max_waveform_samples(n_frame_max: usize) -> usize {
     n_samples_max - 1
 }
 
+/// Fewest waveform samples accepted by `prep_audio`.
+pub const MIN_SAMPLES: usize = N_FFT;
+
+/// Number of frames `prep_audio` returns for a waveform of `n_samples` samples. The frame
+/// centered past the last sample is dropped, so no frame is made of padding alone.
+pub fn n_frames(n_samples: usize) -> usize {
+    n_samples / HOP_LENGTH
+}
+
+/// Root mean square level of `samples` in dBFS. Empty or all-zero input is `-200` dBFS.
+pub fn rms_db(samples: &[f32]) -> f32 {
+    let rms = (samples.iter().map(|x| x * x).sum::<f32>() / samples.len().max(1) as f32).sqrt();
+    20.0 * rms.max(1e-10).log10()
+}
+
 fn is_odd(x: usize) -> bool {
-    x % 2 != 0
+    !x.is_multiple_of(2)
+}
+
+/// Number of sinc zero crossings on each side of the resampling kernel.
+const RESAMPLE_ZERO_CROSSINGS: f64 = 16.0;
+
+/// Resample a mono waveform from `from_hz` to `to_hz` using a Hann-windowed sinc kernel.
+/// When downsampling the kernel cutoff is lowered to the target Nyquist frequency to avoid aliasing.
+pub fn resample(waveform: &[f32], from_hz: usize, to_hz: usize) -> Vec<f32> {
+    if from_hz == to_hz || waveform.is_empty() {
+        return waveform.to_vec();
+    }
+
+    let step = from_hz as f64 / to_hz as f64;
+    let cutoff = (to_hz as f64 / from_hz as f64).min(1.0);
+    let half_width = RESAMPLE_ZERO_CROSSINGS / cutoff;
+    let n_out = (waveform.len() as f64 / step).ceil() as usize;
+
+    (0..n_out)
+        .map(|i| {
+            let t = i as f64 * step;
+            let first = (t - half_width).ceil().max(0.0) as usize;
+            let last = ((t + half_width).floor() as usize).min(waveform.len() - 1);
+
+            (first..=last)
+                .map(|j| {
+                    let x = t - j as f64;
+                    let window = 0.5 * (1.0 + (std::f64::consts::PI * x / half_width).cos());
+                    waveform[j] as f64 * cutoff * sinc(cutoff * x) * window
+                })
+                .sum::<f64>() as f32
+        })
+        .collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        let px = std::f64::consts::PI * x;
+        px.sin() / px
+    }
+}
+
+/// Average interleaved multi-channel samples into a single channel.
+pub fn downmix(samples: &[f32], n_channels: usize) -> Vec<f32> {
+    if n_channels <= 1 {
+        return samples.to_vec();
+    }
+
+    samples
+        .chunks(n_channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect()
+}
+
+/// Clean-up applied to the waveform before it is split into chunks. Every step is off by
+/// default and they run in the order of the fields.
+#[derive(Clone, Debug, Default)]
+pub struct PreprocessOptions {
+    /// Subtract the mean so the waveform is centered on zero.
+    pub remove_dc: bool,
+    /// Cutoff in Hz of a high-pass filter removing rumble below the voice range.
+    pub highpass_hz: Option<f32>,
+    /// Scale the waveform to this RMS level in dBFS, as far as the peak allows without clipping.
+    pub normalize_db: Option<f32>,
+}
+
+/// Cutoff of the high-pass filter recommended for speech, in Hz.
+pub const DEFAULT_HIGHPASS_HZ: f32 = 80.0;
+
+/// Loudness target recommended for speech, in dBFS.
+pub const DEFAULT_NORMALIZE_DB: f32 = -20.0;
+
+/// Largest gain applied by [`normalize_loudness`], so that near silence is not boosted into noise.
+const MAX_NORMALIZE_GAIN_DB: f32 = 40.0;
+
+/// Applies the steps enabled in `opts` to `waveform`.
+pub fn preprocess(waveform: &mut [f32], sample_rate: usize, opts: &PreprocessOptions) {
+    if opts.remove_dc {
+        remove_dc_offset(waveform);
+    }
+    if let Some(cutoff_hz) = opts.highpass_hz {
+        highpass(waveform, sample_rate, cutoff_hz);
+    }
+    if let Some(target_db) = opts.normalize_db {
+        normalize_loudness(waveform, target_db);
+    }
+}
+
+/// Subtracts the mean of `waveform` from every sample.
+pub fn remove_dc_offset(waveform: &mut [f32]) {
+    if waveform.is_empty() {
+        return;
+    }
+
+    let mean = waveform.iter().map(|&x| x as f64).sum::<f64>() / waveform.len() as f64;
+    for x in waveform {
+        *x = (*x as f64 - mean) as f32;
+    }
+}
+
+/// Second-order Butterworth high-pass filter, from the RBJ audio EQ cookbook. Cutoffs outside
+/// `(0, sample_rate / 2)` leave the waveform unchanged.
+pub fn highpass(waveform: &mut [f32], sample_rate: usize, cutoff_hz: f32) {
+    let nyquist = sample_rate as f64 / 2.0;
+    let cutoff_hz = cutoff_hz as f64;
+    if !(cutoff_hz > 0.0 && cutoff_hz < nyquist) {
+        return;
+    }
+
+    let w0 = std::f64::consts::PI * cutoff_hz / nyquist;
+    let alpha = w0.sin() / std::f64::consts::SQRT_2;
+    let cos_w0 = w0.cos();
+    let a0 = 1.0 + alpha;
+    let b0 = (1.0 + cos_w0) / 2.0 / a0;
+    let b1 = -(1.0 + cos_w0) / a0;
+    let b2 = b0;
+    let a1 = -2.0 * cos_w0 / a0;
+    let a2 = (1.0 - alpha) / a0;
+
+    // transposed direct form II, which keeps the state small and the rounding error low
+    let (mut z1, mut z2) = (0.0, 0.0);
+    for x in waveform {
+        let input = *x as f64;
+        let output = b0 * input + z1;
+        z1 = b1 * input - a1 * output + z2;
+        z2 = b2 * input - a2 * output;
+        *x = output as f32;
+    }
+}
+
+/// Scales `waveform` to an RMS level of `target_db` dBFS. The gain is limited so that no sample
+/// exceeds full scale, and silent input is left unchanged.
+pub fn normalize_loudness(waveform: &mut [f32], target_db: f32) {
+    let peak = waveform.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+    if peak == 0.0 || !peak.is_finite() {
+        return;
+    }
+
+    let gain_db = (target_db - rms_db(waveform)).min(MAX_NORMALIZE_GAIN_DB);
+    let gain = 10f32.powf(gain_db / 20.0).min(1.0 / peak);
+    for x in waveform {
+        *x *= gain;
+    }
+}
+
+/// Settings for the energy-based voice activity detector.
+#[derive(Clone, Debug)]
+pub struct VadOptions {
+    /// Length of the frames whose energy is measured.
+    pub frame_ms: usize,
+    /// Frames with an RMS level at or above this many dBFS are considered speech.
+    pub energy_threshold_db: f32,
+    /// Speech separated by less silence than this is merged into one segment.
+    pub min_silence_ms: usize,
+    /// Silence kept around each segment so word onsets and tails are not clipped.
+    pub speech_pad_ms: usize,
+}
+
+impl Default for VadOptions {
+    fn default() -> Self {
+        Self {
+            frame_ms: 30,
+            energy_threshold_db: -50.0,
+            min_silence_ms: 500,
+            speech_pad_ms: 200,
+        }
+    }
+}
+
+/// Returns the sample ranges of `waveform` that contain speech, in order and non-overlapping.
+pub fn detect_speech_segments(
+    waveform: &[f32],
+    sample_rate: usize,
+    opts: &VadOptions,
+) -> Vec<Range<usize>> {
+    let ms_to_samples = |ms: usize| ms * sample_rate / 1000;
+    let frame_len = ms_to_samples(opts.frame_ms).max(1);
+    let min_silence = ms_to_samples(opts.min_silence_ms);
+    let pad = ms_to_samples(opts.speech_pad_ms);
+
+    let mut segments: Vec<Range<usize>> = Vec::new();
+    for (i, frame) in waveform.chunks(frame_len).enumerate() {
+        if rms_db(frame) < opts.energy_threshold_db {
+            continue;
+        }
+
+        let start = (i * frame_len).saturating_sub(pad);
+        let end = (i * frame_len + frame.len() + pad).min(waveform.len());
+        match segments.last_mut() {
+            Some(last) if start <= last.end + min_silence => last.end = end,
+            _ => segments.push(start..end),
+        }
+    }
+
+    segments
+}
+
+/// Settings for the detector of probable speaker turns, which compares the mean spectra of
+/// the audio before and after each frame. It marks acoustic changes rather than identifying
+/// speakers, so a turn may also be a change of microphone or background.
+#[derive(Clone, Debug)]
+pub struct TurnOptions {
+    /// Length of the windows on either side of a frame whose mean spectra are compared.
+    pub window_ms: usize,
+    /// Frames where the RMS difference between the two mean log-mel spectra is at or above
+    /// this, and highest within a window, are turns. Log-mel values are scaled by 1/4 per
+    /// decade, so 0.15 is a mean difference of about 6 dB.
+    pub threshold: f32,
+    /// A turn closer than this to the previous one is dropped, or replaces it if its change
+    /// is larger.
+    pub min_turn_ms: usize,
+    /// Each turn moves to the quietest frame within this distance, so that it falls at a
+    /// silence when there is one nearby.
+    pub snap_ms: usize,
+}
+
+impl Default for TurnOptions {
+    fn default() -> Self {
+        Self {
+            window_ms: 1000,
+            threshold: 0.15,
+            min_turn_ms: 2000,
+            snap_ms: 500,
+        }
+    }
+}
+
+/// How much quieter than the audio around a turn a frame must be to count as the pause the
+/// turn snaps to, in scaled log-mel units. 0.25 is 10 dB.
+const TURN_SILENCE_DROP: f32 = 0.25;
+
+/// Returns the sample offsets at which the speaker probably changes, in order. `mels` is the
+/// log-mel spectrogram of the waveform as returned by [`prep_audio`], laid out frame by frame.
+pub fn detect_speaker_turns(mels: &[f32], n_mels: usize, opts: &TurnOptions) -> Vec<usize> {
+    let ms_to_frames = |ms: usize| ms * SAMPLE_RATE / HOP_LENGTH / 1000;
+    let window = ms_to_frames(opts.window_ms).max(1);
+    let min_turn = ms_to_frames(opts.min_turn_ms);
+    let snap = ms_to_frames(opts.snap_ms);
+    let n_frames = mels.len() / n_mels.max(1);
+    if n_frames < 2 * window {
+        return Vec::new();
+    }
+    let frame = |t: usize| &mels[t * n_mels..(t + 1) * n_mels];
+
+    // running sums of the windows before and after frame t
+    let mut before = vec![0.0f64; n_mels];
+    let mut after = vec![0.0f64; n_mels];
+    for t in 0..window {
+        for m in 0..n_mels {
+            before[m] += frame(t)[m] as f64;
+            after[m] += frame(t + window)[m] as f64;
+        }
+    }
+    let mut scores = vec![0.0f64; n_frames];
+    let scored = scores.iter_mut().enumerate();
+    for (t, score) in scored.take(n_frames - window + 1).skip(window) {
+        if t > window {
+            for m in 0..n_mels {
+                before[m] += (frame(t - 1)[m] - frame(t - 1 - window)[m]) as f64;
+                after[m] += (frame(t - 1 + window)[m] - frame(t - 1)[m]) as f64;
+            }
+        }
+        let mean_sq = before
+            .iter()
+            .zip(&after)
+            .map(|(b, a)| ((a - b) / window as f64).powi(2))
+            .sum::<f64>()
+            / n_mels as f64;
+        *score = mean_sq.sqrt();
+    }
+
+    // peaks above the threshold, keeping the larger of two that are too close together
+    let mut peaks: Vec<usize> = Vec::new();
+    for t in window..=n_frames - window {
+        // of equal scores, the first one is the peak
+        let is_peak = scores[t] >= opts.threshold as f64
+            && (t - window..(t + window + 1).min(n_frames))
+                .all(|u| scores[u] < scores[t] || (scores[u] == scores[t] && u >= t));
+        if !is_peak {
+            continue;
+        }
+        match peaks.last_mut() {
+            Some(last) if t - *last < min_turn => {
+                if scores[t] > scores[*last] {
+                    *last = t;
+                }
+            }
+            _ => peaks.push(t),
+        }
+    }
+
+    let level = |t: usize| frame(t).iter().sum::<f32>() / n_mels as f32;
+    let mut turns: Vec<usize> = Vec::new();
+    for peak in peaks {
+        let nearby = peak.saturating_sub(snap).max(1)..(peak + snap + 1).min(n_frames);
+        let mean_level = nearby.clone().map(level).sum::<f32>() / nearby.len() as f32;
+        let quietest = nearby
+            .min_by(|&a, &b| level(a).total_cmp(&level(b)))
+            .unwrap_or(peak);
+        // without a pause nearby the change itself is the best estimate
+        let turn = if level(quietest) <= mean_level - TURN_SILENCE_DROP {
+            quietest
+        } else {
+            peak
+        };
+        if turns.last().is_none_or(|&last| turn > last) {
+            turns.push(turn);
+        }
+    }
+
+    turns.into_iter().map(|t| t * HOP_LENGTH).collect()
 }
 
 /// Transform an input waveform into a format interpretable by Whisper.
 /// With a waveform size of (n_batch, n_samples) the output will be of size (n_batch, n_mels, n_frame)
-/// where n_mels = 80,
+/// where n_mels = 80 (128 for large-v3 models),
 /// n_frame = int( ( n_samples_padded - n_fft ) / hop_length ),
 /// n_samples_padded = if n_fft is even: n_samples + n_fft else: n_samples + n_fft - 1,
 /// n_fft = 400,
@@ -32,7 +365,15 @@ pub fn prep_audio<B: Backend>(
     waveform: Tensor<B, 2>,
     sample_rate: f64,
     n_mels: usize,
-) -> Tensor<B, 3> {
+) -> Result<Tensor<B, 3>> {
+    let [_, n_samples] = waveform.dims();
+    if n_samples < N_FFT {
+        return Err(WhisperError::AudioTooShort {
+            n_samples,
+            min_samples: N_FFT,
+        });
+    }
+
     let device = waveform.device();
 
     let window = hann_window_device(WINDOW_LENGTH, &device);
@@ -42,7 +383,7 @@ pub fn prep_audio<B: Backend>(
     let [n_batch, n_row, n_col] = magnitudes.dims();
     let magnitudes = magnitudes.slice([0..n_batch, 0..n_row, 0..(n_col - 1)]);
 
-    let mel_spec = get_mel_filters_device(sample_rate, N_FFT, n_mels, false, &device)
+    let mel_spec = mel_filters(sample_rate, n_mels, &device)?
         .unsqueeze()
         .matmul(magnitudes);
 
@@ -51,9 +392,32 @@ pub fn prep_audio<B: Backend>(
     let max: f64 = log_spec.clone().max().into_scalar().elem();
 
     let log_spec = tensor_max_scalar(log_spec, max - 8.0);
-    
 
-    (log_spec + 4.0) / 4.0
+    Ok((log_spec + 4.0) / 4.0)
+}
+
+/// The filterbank of `n_mels` mels for [`N_FFT`], built and checked once per sample rate and
+/// mel count.
+fn mel_filters<B: Backend>(
+    sample_rate: f64,
+    n_mels: usize,
+    device: &B::Device,
+) -> Result<Tensor<B, 2>> {
+    static FILTERS: OnceLock<Mutex<HashMap<(u64, usize), TensorData>>> = OnceLock::new();
+
+    let mut filters = FILTERS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let data = match filters.entry((sample_rate.to_bits(), n_mels)) {
+        Entry::Occupied(entry) => entry.get().clone(),
+        Entry::Vacant(entry) => {
+            let weights = get_mel_filters_device::<B>(sample_rate, N_FFT, n_mels, false, device)?;
+            entry.insert(weights.into_data()).clone()
+        }
+    };
+
+    Ok(Tensor::from_data(data, device))
 }
 
 fn get_mel_filters_device<B: Backend>(
@@ -62,7 +426,7 @@ fn get_mel_filters_device<B: Backend>(
     n_mels: usize,
     htk: bool,
     device: &B::Device,
-) -> Tensor<B, 2> {
+) -> Result<Tensor<B, 2>> {
     let fmin = 0.0;
     let fmax = sample_rate * 0.5;
 
@@ -126,12 +490,22 @@ fn get_mel_filters_device<B: Backend>(
         "reducing n_mels.",
         stacklevel=2,
     )*/
-    if !(all_zeros(mel_f.slice([0..(n_mels - 2)])) || all_zeros(relu(-weights.clone().max_dim(1))))
-    {
-        println!("Empty filters detected in mel frequency basis. \nSome channels will produce empty responses. \nTry increasing your sampling rate (and fmax) or reducing n_mels.");
+    let nonzero_f: Tensor<B, 1> = mel_f.slice([0..n_mels]).not_equal_elem(0.0).float();
+    let empty_channel = weights
+        .clone()
+        .max_dim(1)
+        .squeeze::<1>(1)
+        .lower_equal_elem(0.0)
+        .float();
+    let n_empty: f32 = (nonzero_f * empty_channel).sum().into_scalar().elem();
+    if n_empty > 0.0 {
+        return Err(WhisperError::EmptyMelFilters {
+            n_mels,
+            n_empty: n_empty as usize,
+        });
     }
 
-    weights
+    Ok(weights)
 }
 
 fn fft_frequencies_device<B: Backend>(
@@ -191,8 +565,6 @@ fn hz_to_mel(freq: f64, htk: bool) -> f64 {
         # If we have scalar data, heck directly
         mels = min_log_mel + np.log(frequencies / min_log_hz) / logstep*/
 
-    
-
     if freq >= min_log_hz {
         min_log_mel + (freq / min_log_hz).ln() / logstep
     } else {
@@ -224,7 +596,6 @@ fn mel_to_hz_tensor<B: Backend>(mel: Tensor<B, 1>, htk: bool) -> Tensor<B, 1> {
         freqs = min_log_hz * np.exp(logstep * (mels - min_log_mel))*/
 
     let log_t = mel.clone().greater_equal_elem(min_log_mel).float();
-    
 
     /*let freq = if mel >= min_log_mel {
         min_log_hz * (logstep * (mel - min_log_mel)).exp()
@@ -341,3 +712,244 @@ pub fn stfft<B: Backend>(
 fn div_roundup(a: usize, b: usize) -> usize {
     a.div_ceil(b)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestBackend = burn::backend::NdArray;
+
+    fn sine(frequency: f64, sample_rate: usize) -> Vec<f32> {
+        let step = std::f64::consts::TAU * frequency / sample_rate as f64;
+        (0..sample_rate)
+            .map(|i| (step * i as f64).sin() as f32)
+            .collect()
+    }
+
+    /// Ignores the edges, where the filter runs out of samples.
+    fn max_error(actual: &[f32], expected: &[f32]) -> f32 {
+        actual[100..actual.len() - 100]
+            .iter()
+            .zip(&expected[100..])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn downsampling_keeps_the_frequency_of_a_sine() {
+        let resampled = resample(&sine(440.0, 48000), 48000, 16000);
+
+        assert_eq!(resampled.len(), 16000);
+        let error = max_error(&resampled, &sine(440.0, 16000));
+        assert!(error < 0.01, "max error {error}");
+    }
+
+    #[test]
+    fn upsampling_keeps_the_frequency_of_a_sine() {
+        let resampled = resample(&sine(1000.0, 8000), 8000, 16000);
+
+        assert_eq!(resampled.len(), 16000);
+        let error = max_error(&resampled, &sine(1000.0, 16000));
+        assert!(error < 0.01, "max error {error}");
+    }
+
+    #[test]
+    fn downsampling_removes_frequencies_above_the_new_nyquist() {
+        let resampled = resample(&sine(12000.0, 48000), 48000, 16000);
+
+        let error = max_error(&resampled, &[0.0; 16000]);
+        assert!(error < 0.01, "max error {error}");
+    }
+
+    #[test]
+    fn downmix_averages_interleaved_channels() {
+        assert_eq!(
+            downmix(&[1.0, 0.0, 0.5, 0.5, -1.0, 1.0], 2),
+            [0.5, 0.5, 0.0]
+        );
+        assert_eq!(downmix(&[0.25, 0.5], 1), [0.25, 0.5]);
+    }
+
+    /// Gain of the high-pass filter at `frequency`, once it has settled.
+    fn highpass_gain(frequency: f64, cutoff_hz: f32) -> f32 {
+        let mut waveform = sine(frequency, 16000);
+        highpass(&mut waveform, 16000, cutoff_hz);
+
+        let settled = &waveform[8000..];
+        let rms = (settled.iter().map(|x| x * x).sum::<f32>() / settled.len() as f32).sqrt();
+        rms * std::f32::consts::SQRT_2
+    }
+
+    #[test]
+    fn highpass_passes_speech_and_removes_rumble() {
+        // a second-order Butterworth filter is 3 dB down at the cutoff and falls 12 dB per
+        // octave below it
+        assert!((highpass_gain(80.0, 80.0) - 0.5f32.sqrt()).abs() < 0.01);
+        assert!((highpass_gain(20.0, 80.0) - 1.0 / 16.0).abs() < 0.005);
+        assert!((highpass_gain(1000.0, 80.0) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn preprocessing_handles_short_and_silent_input() {
+        let options = PreprocessOptions {
+            remove_dc: true,
+            highpass_hz: Some(DEFAULT_HIGHPASS_HZ),
+            normalize_db: Some(DEFAULT_NORMALIZE_DB),
+        };
+
+        for len in [0, 1, 2, 100] {
+            let mut silence = vec![0.0; len];
+            preprocess(&mut silence, 16000, &options);
+            assert!(silence.iter().all(|&x| x == 0.0));
+        }
+
+        let mut short = vec![0.5];
+        preprocess(&mut short, 16000, &options);
+        assert!(short.iter().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn dc_offset_is_removed_and_loudness_normalized() {
+        let mut waveform: Vec<f32> = sine(440.0, 16000).iter().map(|x| 0.01 * x + 0.2).collect();
+
+        remove_dc_offset(&mut waveform);
+        let mean = waveform.iter().sum::<f32>() / waveform.len() as f32;
+        assert!(mean.abs() < 1e-4, "mean {mean}");
+
+        normalize_loudness(&mut waveform, -20.0);
+        assert!((rms_db(&waveform) + 20.0).abs() < 0.01);
+
+        // the gain stops short of clipping
+        normalize_loudness(&mut waveform, 0.0);
+        let peak = waveform.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        assert!((peak - 1.0).abs() < 1e-4, "peak {peak}");
+    }
+
+    /// A mel, the bin of its peak and the weight there.
+    type Peak = (usize, usize, f32);
+
+    /// Sum of all weights, and the bin and weight of the peak of some filters, of librosa's
+    /// Slaney-normalized filterbank that the reference implementation uses.
+    const REFERENCE_FILTERS: [(usize, f32, [Peak; 4]); 2] = [
+        (
+            80,
+            1.999024,
+            [
+                (0, 1, 0.0248626),
+                (1, 2, 0.0228718),
+                (40, 43, 0.0147356),
+                (79, 192, 0.00316471),
+            ],
+        ),
+        (
+            128,
+            3.190986,
+            [
+                (0, 1, 0.0123740),
+                (1, 1, 0.0303926),
+                (64, 43, 0.0180915),
+                (127, 195, 0.00504160),
+            ],
+        ),
+    ];
+
+    #[test]
+    fn mel_filters_match_the_reference() {
+        for (n_mels, sum, peaks) in REFERENCE_FILTERS {
+            let filters = mel_filters::<TestBackend>(16000.0, n_mels, &Default::default()).unwrap();
+            assert_eq!(filters.dims(), [n_mels, N_FFT / 2 + 1]);
+
+            let weights = filters.into_data().to_vec::<f32>().unwrap();
+            let total: f32 = weights.iter().sum();
+            assert!((total - sum).abs() < 1e-4, "{n_mels} mels sum to {total}");
+            for (mel, bin, weight) in peaks {
+                let row = &weights[mel * (N_FFT / 2 + 1)..(mel + 1) * (N_FFT / 2 + 1)];
+                let peak = (0..row.len()).max_by(|&a, &b| row[a].total_cmp(&row[b]));
+                assert_eq!(peak, Some(bin), "peak of mel {mel} of {n_mels}");
+                assert!((row[bin] - weight).abs() < 1e-6, "mel {mel} of {n_mels}");
+            }
+        }
+    }
+
+    /// A mel and its value.
+    type Mel = (usize, f32);
+
+    /// Mels of frame 12 of a quarter second of 440 Hz and 1 kHz tones, computed with the
+    /// reference `log_mel_spectrogram`. The other mels of the frame are at the floor.
+    const REFERENCE_MELS: [(usize, f32, &[Mel]); 2] = [
+        (
+            80,
+            -0.5618,
+            &[
+                (9, 1.15939),
+                (10, 1.34874),
+                (11, 1.4382),
+                (12, 1.29352),
+                (24, 0.99167),
+                (25, 1.19061),
+                (26, 1.28914),
+                (27, 1.13275),
+            ],
+        ),
+        (
+            128,
+            -0.51465,
+            &[
+                (16, 1.34451),
+                (17, 1.33749),
+                (18, 1.48535),
+                (19, 1.27524),
+                (20, 1.28739),
+                (40, 1.19999),
+                (41, 1.2073),
+                (42, 1.32393),
+                (43, 1.14595),
+                (44, 1.09483),
+            ],
+        ),
+    ];
+
+    #[test]
+    fn log_mel_spectrogram_matches_the_reference() {
+        let step = std::f64::consts::TAU / 16000.0;
+        let waveform: Vec<f32> = (0..4000)
+            .map(|i| {
+                0.5 * (step * 440.0 * i as f64).sin() + 0.25 * (step * 1000.0 * i as f64).sin()
+            })
+            .map(|sample| sample as f32)
+            .collect();
+        let waveform =
+            Tensor::<TestBackend, 1>::from_floats(waveform.as_slice(), &Default::default());
+
+        for (n_mels, floor, expected) in REFERENCE_MELS {
+            let mels = prep_audio(waveform.clone().unsqueeze(), 16000.0, n_mels).unwrap();
+            assert_eq!(mels.dims(), [1, n_mels, 25]);
+
+            let frame = mels
+                .slice([0..1, 0..n_mels, 12..13])
+                .into_data()
+                .to_vec::<f32>()
+                .unwrap();
+            for (mel, &actual) in frame.iter().enumerate() {
+                let expected = expected
+                    .iter()
+                    .find(|&&(m, _)| m == mel)
+                    .map_or(floor, |&(_, value)| value);
+                assert!(
+                    (actual - expected).abs() < 1e-3,
+                    "mel {mel} of {n_mels} is {actual}, expected {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn too_many_mels_for_the_fft_are_an_error() {
+        let waveform = Tensor::<TestBackend, 2>::zeros([1, 4000], &Default::default());
+
+        assert!(matches!(
+            prep_audio(waveform, 16000.0, 512),
+            Err(WhisperError::EmptyMelFilters { n_mels: 512, .. })
+        ));
+    }
+}
@@ -4,6 +4,119 @@ pub struct BeamNode<T: Clone> {
     pub log_prob: f64,
 }
 
+/// How the scores of finished hypotheses of different lengths are compared.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LengthPenalty {
+    /// Rank by total log probability, which favors short hypotheses.
+    None,
+    /// Rank by mean log probability per token.
+    Mean,
+    /// Rank by `log_prob / ((5 + len) / 6)^alpha` as in Wu et al. (2016).
+    Google(f64),
+}
+
+impl LengthPenalty {
+    pub fn score(&self, log_prob: f64, len: usize) -> f64 {
+        match *self {
+            LengthPenalty::None => log_prob,
+            LengthPenalty::Mean => log_prob / len.max(1) as f64,
+            LengthPenalty::Google(alpha) => log_prob / ((5.0 + len as f64) / 6.0).powf(alpha),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BeamSearchConfig {
+    pub beam_size: usize,
+    pub max_depth: usize,
+    /// Search stops once `round(beam_size * patience)` hypotheses have finished.
+    pub patience: f64,
+    pub length_penalty: LengthPenalty,
+}
+
+/// Runs a beam search and returns up to `n` hypotheses with their length-penalized scores,
+/// best first. Finished hypotheses are preferred; if none finish before `max_depth` the
+/// unfinished beams are returned instead.
+///
+/// `next` returns the continuations of each beam with their cumulative log probabilities. Only
+/// the best `beam_size + 1` of each beam are considered, so it may omit the rest.
+pub fn beam_search_nbest<T, F, G>(
+    initial_beams: Vec<BeamNode<T>>,
+    next: F,
+    is_finished: G,
+    config: &BeamSearchConfig,
+    n: usize,
+) -> Vec<(Vec<T>, f64)>
+where
+    T: Clone,
+    F: Fn(&[BeamNode<T>]) -> Vec<Vec<(T, f64)>>,
+    G: Fn(&[T]) -> bool,
+{
+    let prefix_len = initial_beams
+        .iter()
+        .map(|beam| beam.seq.len())
+        .min()
+        .unwrap_or(0);
+    let max_candidates = ((config.beam_size as f64 * config.patience).round() as usize).max(1);
+
+    let (mut finished, mut beams): (Vec<_>, Vec<_>) = initial_beams
+        .into_iter()
+        .partition(|beam| is_finished(&beam.seq));
+
+    for _ in 0..config.max_depth {
+        if beams.is_empty() || finished.len() >= max_candidates {
+            break;
+        }
+
+        let continuations = next(&beams);
+
+        let mut candidates: Vec<BeamNode<T>> = beams
+            .iter()
+            .zip(continuations)
+            .flat_map(|(beam_node, continuations)| {
+                get_top_elements(
+                    &continuations,
+                    |(_, log_prob)| *log_prob,
+                    config.beam_size + 1,
+                )
+                .into_iter()
+                .map(|(tok, log_prob)| BeamNode {
+                    seq: [beam_node.seq.clone(), vec![tok.clone()]].concat(),
+                    log_prob: *log_prob,
+                })
+                .collect::<Vec<_>>()
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.log_prob.total_cmp(&a.log_prob));
+
+        beams = Vec::with_capacity(config.beam_size);
+        for candidate in candidates {
+            if is_finished(&candidate.seq) {
+                if finished.len() < max_candidates {
+                    finished.push(candidate);
+                }
+            } else if beams.len() < config.beam_size {
+                beams.push(candidate);
+            }
+        }
+    }
+
+    let hypotheses = if finished.is_empty() { beams } else { finished };
+
+    let mut scored: Vec<_> = hypotheses
+        .into_iter()
+        .map(|beam| {
+            let len = beam.seq.len().saturating_sub(prefix_len);
+            let score = config.length_penalty.score(beam.log_prob, len);
+            (beam.seq, score)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.truncate(n);
+
+    scored
+}
+
 pub fn beam_search<T, F, G>(
     initial_beams: Vec<BeamNode<T>>,
     next: F,
@@ -80,32 +193,207 @@ where
         .collect()
 }
 
+/// Returns the `num` elements with the highest scores, best first. Of elements with equal
+/// scores the earlier one ranks higher, so ties go to the lower token when `elems` is in token
+/// order.
 fn get_top_elements<T>(elems: &[T], score: impl Fn(&T) -> f64, num: usize) -> Vec<&T> {
-    let mut top_elems = Vec::with_capacity(num);
-    let mut scores = Vec::with_capacity(num);
+    let mut top_elems = Vec::with_capacity(num + 1);
+    let mut scores: Vec<f64> = Vec::with_capacity(num + 1);
 
     for elem in elems {
         let score = score(elem);
 
         // most common scenario
-        if top_elems.len() == num
-            && score < scores[0] {
-                continue;
-            }
-
-        if let Some((idx, _)) = scores.iter().enumerate().find(|(_, &s)| s >= score) {
-            top_elems.insert(idx, elem);
-            scores.insert(idx, score);
-        } else {
-            top_elems.push(elem);
-            scores.push(score);
+        if top_elems.len() == num && scores.last().is_none_or(|&last| score <= last) {
+            continue;
         }
 
+        let idx = scores.partition_point(|&s| s >= score);
+        top_elems.insert(idx, elem);
+        scores.insert(idx, score);
+
         if top_elems.len() > num {
-            top_elems.remove(0);
-            scores.remove(0);
+            top_elems.pop();
+            scores.pop();
         }
     }
 
     top_elems
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: usize = 9;
+    const END: usize = 0;
+
+    /// Ending right after the start token costs -1.25, while the only other path, `1 2`, ends
+    /// with a total of -1.5 three tokens later.
+    fn next(beams: &[BeamNode<usize>]) -> Vec<Vec<(usize, f64)>> {
+        beams
+            .iter()
+            .map(|beam| {
+                let steps: &[(usize, f64)] = match beam.seq.last() {
+                    Some(&START) => &[(END, -1.25), (1, -0.5)],
+                    Some(1) => &[(2, -0.5)],
+                    Some(2) => &[(END, -0.5)],
+                    _ => &[],
+                };
+                steps
+                    .iter()
+                    .map(|&(token, log_prob)| (token, beam.log_prob + log_prob))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn search(length_penalty: LengthPenalty, patience: f64, n: usize) -> Vec<(Vec<usize>, f64)> {
+        let config = BeamSearchConfig {
+            beam_size: 2,
+            max_depth: 10,
+            patience,
+            length_penalty,
+        };
+        let initial_beam = BeamNode {
+            seq: vec![START],
+            log_prob: 0.0,
+        };
+
+        beam_search_nbest(
+            vec![initial_beam],
+            next,
+            |seq: &[usize]| seq.last() == Some(&END),
+            &config,
+            n,
+        )
+    }
+
+    #[test]
+    fn total_log_prob_favors_the_short_hypothesis() {
+        let best = search(LengthPenalty::None, 1.0, 1);
+
+        assert_eq!(best, [(vec![START, END], -1.25)]);
+    }
+
+    #[test]
+    fn length_penalty_changes_the_winner() {
+        for length_penalty in [LengthPenalty::Mean, LengthPenalty::Google(1.0)] {
+            let best = search(length_penalty, 1.0, 1);
+
+            assert_eq!(best[0].0, [START, 1, 2, END], "{length_penalty:?}");
+        }
+    }
+
+    #[test]
+    fn nbest_returns_hypotheses_best_first() {
+        let hypotheses = search(LengthPenalty::Mean, 1.0, usize::MAX);
+
+        assert_eq!(
+            hypotheses,
+            [(vec![START, 1, 2, END], -0.5), (vec![START, END], -1.25)]
+        );
+    }
+
+    #[test]
+    fn low_patience_stops_at_the_first_finished_hypothesis() {
+        let hypotheses = search(LengthPenalty::Mean, 0.5, usize::MAX);
+
+        assert_eq!(hypotheses, [(vec![START, END], -1.25)]);
+    }
+
+    #[test]
+    fn ties_go_to_the_lower_token() {
+        let config = BeamSearchConfig {
+            beam_size: 1,
+            max_depth: 10,
+            patience: 1.0,
+            length_penalty: LengthPenalty::None,
+        };
+        let initial_beam = BeamNode {
+            seq: vec![START],
+            log_prob: 0.0,
+        };
+        // tokens 1 and 3 are equally likely after the start token, and either is then followed
+        // by the end token
+        let next = |beams: &[BeamNode<usize>]| {
+            beams
+                .iter()
+                .map(|beam| {
+                    let steps: &[(usize, f64)] = match beam.seq.last() {
+                        Some(&START) => &[(1, -0.5), (2, -2.0), (3, -0.5)],
+                        _ => &[(END, -0.1)],
+                    };
+                    steps
+                        .iter()
+                        .map(|&(token, log_prob)| (token, beam.log_prob + log_prob))
+                        .collect()
+                })
+                .collect()
+        };
+
+        let hypotheses = beam_search_nbest(
+            vec![initial_beam.clone()],
+            next,
+            |seq: &[usize]| seq.last() == Some(&END),
+            &config,
+            usize::MAX,
+        );
+        assert_eq!(hypotheses[0].0, [START, 1, END]);
+
+        let best = beam_search(
+            vec![initial_beam],
+            next,
+            |seq: &[usize]| seq.last() == Some(&END),
+            1,
+            10,
+        );
+        assert_eq!(best, [START, 1, END]);
+    }
+
+    #[test]
+    fn top_elements_keep_the_earlier_of_equal_scores() {
+        let elems = [(0, 1.0), (1, 2.0), (2, 1.0), (3, 2.0), (4, 0.0)];
+
+        let top = get_top_elements(&elems, |(_, score)| *score, 3);
+
+        assert_eq!(top, [&(1, 2.0), &(3, 2.0), &(0, 1.0)]);
+    }
+
+    #[test]
+    fn live_beams_always_share_a_length() {
+        let config = BeamSearchConfig {
+            beam_size: 3,
+            max_depth: 6,
+            patience: 2.0,
+            length_penalty: LengthPenalty::None,
+        };
+        let initial_beam = BeamNode {
+            seq: vec![START],
+            log_prob: 0.0,
+        };
+        // beams finish at every depth, so the live ones would diverge if any lagged behind
+        let next = |beams: &[BeamNode<usize>]| {
+            let len = beams[0].seq.len();
+            assert!(beams.iter().all(|beam| beam.seq.len() == len));
+            beams
+                .iter()
+                .map(|beam| {
+                    (0..4)
+                        .map(|token| (token, beam.log_prob - 0.1 * (token + len) as f64))
+                        .collect()
+                })
+                .collect()
+        };
+
+        let hypotheses = beam_search_nbest(
+            vec![initial_beam],
+            next,
+            |seq: &[usize]| seq.last() == Some(&END),
+            &config,
+            usize::MAX,
+        );
+
+        assert!(hypotheses.len() > 1);
+    }
+}
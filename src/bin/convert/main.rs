@@ -6,19 +6,30 @@ use burn::{
     backend::wgpu::Wgpu,
     config::Config,
     module::Module,
-    record::{self, DefaultRecorder, Recorder},
+    record::{self, NamedMpkFileRecorder, Recorder},
     tensor::backend::Backend,
 };
 
 fn save_whisper<B: Backend>(whisper: Whisper<B>, name: &str) -> Result<(), record::RecorderError> {
-    DefaultRecorder::new().record(whisper.into_record(), name.into())
+    // quantized weights are saved as i8, everything else at full precision
+    NamedMpkFileRecorder::<QuantizedPrecisionSettings>::new()
+        .record(whisper.into_record(), name.into())
 }
 
 use std::env;
 
 fn main() {
-    let model_name = match env::args().nth(1) {
-        Some(name) => name,
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let quantize = match args.iter().position(|arg| arg == "--quantize") {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+
+    let model_name = match args.first() {
+        Some(name) => name.clone(),
         None => {
             eprintln!("Model dump folder not provided");
             return;
@@ -34,6 +45,13 @@ fn main() {
         }
     };
 
+    let whisper = if quantize {
+        println!("Quantizing decoder weights to int8...");
+        whisper.quantize()
+    } else {
+        whisper
+    };
+
     println!("Saving model...");
     if let Err(e) = save_whisper(whisper, &model_name) {
         eprintln!("Error saving model {model_name}: {e}");
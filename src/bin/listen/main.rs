@@ -0,0 +1,333 @@
+#![recursion_limit = "256"]
+
+use burn::backend::wgpu::{Wgpu, WgpuDevice};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SampleFormat, SizedSample,
+};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::{
+    env,
+    io::{self, Write},
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use strum::IntoEnumIterator;
+use whisper_stream::{
+    audio::{self, SAMPLE_RATE},
+    model::{load, Whisper},
+    token::{Gpt2Tokenizer, Language},
+    transcribe::{waveform_to_transcript, DecodeOptions},
+};
+
+/// Seconds of device audio the ring buffer holds before samples are dropped.
+const RING_SECONDS: usize = 30;
+/// New audio required before the pending audio is transcribed again.
+const STEP_SECONDS: f64 = 1.0;
+/// Pending audio is committed as a line once it is this long.
+const MAX_LINE_SECONDS: f64 = 10.0;
+/// Pending audio is committed as a line once it ends with this much silence.
+const SILENCE_SECONDS: f64 = 0.6;
+/// Backlog in the ring buffer, in seconds, above which the inference is reported as lagging.
+const MAX_LAG_SECONDS: f64 = 2.0;
+
+struct CliOptions {
+    model_name: String,
+    lang: Language,
+    model_dir: String,
+    /// Stop after this many seconds instead of waiting for Enter.
+    duration: Option<f64>,
+}
+
+fn main() {
+    let options = parse_args();
+
+    let tensor_device = WgpuDevice::default();
+    println!("Loading model...");
+    let (bpe, _whisper_config, whisper) = match load::load_named_model::<Wgpu>(
+        Path::new(&options.model_dir),
+        &options.model_name,
+        &tensor_device,
+    ) {
+        Ok(model) => model,
+        Err(e) => {
+            eprintln!("Failed to load model: {e}");
+            process::exit(1);
+        }
+    };
+
+    let device = cpal::default_host()
+        .default_input_device()
+        .unwrap_or_else(|| {
+            eprintln!("No input device available");
+            process::exit(1);
+        });
+    let config = device.default_input_config().unwrap_or_else(|e| {
+        eprintln!("Failed to get the input config: {e}");
+        process::exit(1);
+    });
+    let sample_rate = config.sample_rate().0 as usize;
+    let channels = config.channels() as usize;
+
+    let (producer, consumer) = RingBuffer::<f32>::new(sample_rate * channels * RING_SECONDS);
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config.config(), producer, &dropped),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config.config(), producer, &dropped),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config.config(), producer, &dropped),
+        SampleFormat::I32 => build_stream::<i32>(&device, &config.config(), producer, &dropped),
+        other => {
+            eprintln!("Unsupported sample format {other}");
+            process::exit(1);
+        }
+    };
+    if let Err(e) = stream.play() {
+        eprintln!("Failed to start recording: {e}");
+        process::exit(1);
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    match options.duration {
+        Some(seconds) => {
+            println!("Listening for {seconds} s on {sample_rate} Hz, {channels} channel(s)")
+        }
+        None => {
+            println!("Listening on {sample_rate} Hz, {channels} channel(s), press Enter to stop");
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let _ = io::stdin().read_line(&mut String::new());
+                stop.store(true, Ordering::Relaxed);
+            });
+        }
+    }
+
+    let mut listener = Listener {
+        whisper,
+        bpe,
+        lang: options.lang,
+        sample_rate,
+        channels,
+        pending: Vec::new(),
+        n_transcribed: 0,
+        lines: Vec::new(),
+    };
+
+    let started = Instant::now();
+    let mut consumer = consumer;
+    let mut reported_drops = 0;
+    loop {
+        let finished = stop.load(Ordering::Relaxed)
+            || options
+                .duration
+                .is_some_and(|seconds| started.elapsed().as_secs_f64() >= seconds);
+        if finished {
+            break;
+        }
+
+        let n_dropped = dropped.load(Ordering::Relaxed);
+        if n_dropped > reported_drops {
+            eprintln!(
+                "\r\x1b[2KDropped {:.1} s of audio, inference cannot keep up with real time",
+                (n_dropped - reported_drops) as f64 / (sample_rate * channels) as f64
+            );
+            reported_drops = n_dropped;
+        }
+
+        listener.receive(&mut consumer);
+        if listener.new_seconds() < STEP_SECONDS {
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+
+        listener.step(false);
+
+        let backlog = consumer.slots() as f64 / (sample_rate * channels) as f64;
+        if backlog > MAX_LAG_SECONDS {
+            eprintln!("\r\x1b[2KInference is {backlog:.1} s behind real time");
+        }
+    }
+
+    drop(stream);
+    listener.receive(&mut consumer);
+    listener.step(true);
+
+    println!("\nTranscript:");
+    for line in &listener.lines {
+        println!("{line}");
+    }
+}
+
+fn parse_args() -> CliOptions {
+    let mut args: Vec<String> = env::args().collect();
+    let duration = take_flag_value(&mut args, "--duration").map(|value| {
+        value.parse::<f64>().unwrap_or_else(|_| {
+            eprintln!("Invalid value for --duration: {value}");
+            process::exit(1);
+        })
+    });
+    let model_dir =
+        take_flag_value(&mut args, "--model-dir").unwrap_or_else(|| "models".to_string());
+
+    if args.len() < 3 {
+        eprintln!(
+            "Usage: {} <model name> <lang> [--duration <seconds>] [--model-dir <dir>]",
+            args[0]
+        );
+        process::exit(1);
+    }
+
+    let lang_str = &args[2];
+    let lang = match Language::iter().find(|lang| lang.as_str() == lang_str) {
+        Some(lang) => lang,
+        None => {
+            eprintln!("Invalid language abbreviation: {lang_str}");
+            process::exit(1);
+        }
+    };
+
+    CliOptions {
+        model_name: args[1].clone(),
+        lang,
+        model_dir,
+        duration,
+    }
+}
+
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    if index + 1 >= args.len() {
+        eprintln!("Missing value for {flag}");
+        process::exit(1);
+    }
+
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Some(value)
+}
+
+/// Opens an input stream that pushes interleaved samples into `producer`. The callback never
+/// blocks: samples that do not fit are counted in `dropped` instead.
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut producer: Producer<f32>,
+    dropped: &Arc<AtomicUsize>,
+) -> cpal::Stream
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let dropped = dropped.clone();
+    let channels = config.channels as usize;
+    device
+        .build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                // only whole frames are pushed so the channels stay interleaved
+                let n_fit = data.len().min(producer.slots()) / channels * channels;
+                let n_pushed = match producer.write_chunk_uninit(n_fit) {
+                    Ok(chunk) => chunk.fill_from_iter(data.iter().map(|&s| s.to_sample::<f32>())),
+                    Err(_) => 0,
+                };
+                if n_pushed < data.len() {
+                    dropped.fetch_add(data.len() - n_pushed, Ordering::Relaxed);
+                }
+            },
+            |e| eprintln!("Input stream error: {e}"),
+            None,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to open the input stream: {e}");
+            process::exit(1);
+        })
+}
+
+/// Transcribes the audio received since the last committed line.
+struct Listener {
+    whisper: Whisper<Wgpu>,
+    bpe: Gpt2Tokenizer,
+    lang: Language,
+    sample_rate: usize,
+    channels: usize,
+    /// Mono samples at the device rate that are not part of a committed line yet.
+    pending: Vec<f32>,
+    /// Length of `pending` when it was last transcribed.
+    n_transcribed: usize,
+    lines: Vec<String>,
+}
+
+impl Listener {
+    fn receive(&mut self, consumer: &mut Consumer<f32>) {
+        let n_frames = consumer.slots() / self.channels;
+        let Ok(chunk) = consumer.read_chunk(n_frames * self.channels) else {
+            return;
+        };
+        let (first, second) = chunk.as_slices();
+        let interleaved = [first, second].concat();
+        chunk.commit_all();
+
+        self.pending
+            .extend(audio::downmix(&interleaved, self.channels));
+    }
+
+    fn new_seconds(&self) -> f64 {
+        (self.pending.len() - self.n_transcribed) as f64 / self.sample_rate as f64
+    }
+
+    /// Transcribes the pending audio and prints it as a partial line, or as a confirmed line if
+    /// it is long enough, ends in silence or `last` is set.
+    fn step(&mut self, last: bool) {
+        self.n_transcribed = self.pending.len();
+        let waveform = audio::resample(&self.pending, self.sample_rate, SAMPLE_RATE);
+        if waveform.len() < audio::MIN_SAMPLES {
+            return;
+        }
+
+        let options = DecodeOptions {
+            prompt: self.lines.last().cloned(),
+            vad: None,
+            ..DecodeOptions::recommended()
+        };
+        let text = match waveform_to_transcript(
+            &self.whisper,
+            &self.bpe,
+            self.lang,
+            &waveform,
+            SAMPLE_RATE,
+            &options,
+        ) {
+            Ok(transcript) => transcript.text.trim().to_string(),
+            Err(e) => {
+                eprintln!("\r\x1b[2KError during transcription: {e}");
+                process::exit(1);
+            }
+        };
+
+        let seconds = waveform.len() as f64 / SAMPLE_RATE as f64;
+        let n_silence = (SILENCE_SECONDS * SAMPLE_RATE as f64) as usize;
+        let ends_in_silence = waveform.len() > n_silence
+            && audio::rms_db(&waveform[waveform.len() - n_silence..])
+                < audio::VadOptions::default().energy_threshold_db;
+
+        let mut stdout = io::stdout().lock();
+        if last || seconds >= MAX_LINE_SECONDS || ends_in_silence {
+            if text.is_empty() {
+                let _ = write!(stdout, "\r\x1b[2K");
+            } else {
+                let _ = writeln!(stdout, "\r\x1b[2K{text}");
+                self.lines.push(text);
+            }
+            self.pending.clear();
+            self.n_transcribed = 0;
+        } else {
+            let _ = write!(stdout, "\r\x1b[2K{text}");
+        }
+        let _ = stdout.flush();
+    }
+}
@@ -1,140 +1,903 @@
 #![recursion_limit = "256"]
 
-use whisper_stream::model::*;
-use whisper_stream::token::Language;
-use whisper_stream::transcribe::waveform_to_text;
+mod serve;
 
-use strum::IntoEnumIterator;
+use whisper_stream::audio::{self, PreprocessOptions};
+use whisper_stream::cache::{EncoderCache, DEFAULT_ENCODER_CACHE_BYTES};
+use whisper_stream::checkpoint::{self, CheckpointFile};
+use whisper_stream::error::WhisperError;
+use whisper_stream::input::{self, PcmFormat};
+use whisper_stream::model::{load::WhisperSource, *};
+use whisper_stream::postprocess::{PostprocessOptions, Replacements};
+use whisper_stream::token::Language;
+use whisper_stream::transcribe::{
+    waveform_to_text_checkpointed, waveform_to_text_streaming, waveform_to_transcript, DecodeEvent,
+    LanguageMode, MergeStrategy, Progress, Task, TranscribeOptions, Transcript,
+    DEFAULT_MIN_LANGUAGE_SWITCH_PROB, DEFAULT_MIN_OVERLAP_AGREEMENT,
+};
 
+#[cfg(feature = "cuda")]
+use burn::backend::cuda::{Cuda, CudaDevice};
+#[cfg(feature = "ndarray")]
+use burn::backend::ndarray::{NdArray, NdArrayDevice};
 use burn::{
     backend::wgpu::{Wgpu, WgpuDevice},
-    config::Config,
-    module::Module,
-    record::{FullPrecisionSettings, NamedMpkFileRecorder, Recorder},
-    tensor::backend::Backend,
+    tensor::{backend::Backend, Tensor},
+};
+use std::{
+    env, fs,
+    io::{self, BufWriter, Write},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{mpsc, Arc},
+    thread,
+    time::Instant,
 };
-use hound::{self, SampleFormat};
-use std::{env, fs, process};
 use whisper_stream::token::Gpt2Tokenizer;
 
-fn load_audio_waveform(filename: &str) -> hound::Result<(Vec<f32>, usize)> {
-    let reader = hound::WavReader::open(filename)?;
-    let spec = reader.spec();
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let cli_options = CliOptions {
+        transcribe: transcribe_options(&mut args),
+        language_switch_prob: parse_flag_value(&mut args, "--language-switch-prob")
+            .unwrap_or(DEFAULT_MIN_LANGUAGE_SWITCH_PROB),
+        raw_input: take_flag(&mut args, "--raw").then(|| raw_input_options(&mut args)),
+        live: take_flag(&mut args, "--live"),
+        progress: !take_flag(&mut args, "--no-progress"),
+        stats: take_flag(&mut args, "--stats"),
+        checkpoint: take_flag_value(&mut args, "--checkpoint"),
+        encoder_cache: take_flag_value(&mut args, "--encoder-cache").map(PathBuf::from),
+        model_dir: take_flag_value(&mut args, "--model-dir")
+            .unwrap_or_else(|| "models".to_string())
+            .into(),
+        output_format: take_flag_value(&mut args, "--output-format").map(|value| {
+            OutputFormat::parse(&value).unwrap_or_else(|| {
+                eprintln!("Invalid output format: {value}, expected txt, json or verbose_json");
+                process::exit(1);
+            })
+        }),
+        mode: if take_flag(&mut args, "--serve") {
+            Mode::Serve
+        } else if let Some(dir) = take_flag_value(&mut args, "--watch") {
+            Mode::Watch(dir.into())
+        } else if let Some(dir) = take_flag_value(&mut args, "--export-bundle") {
+            Mode::ExportBundle {
+                dir: dir.into(),
+                quantize: take_flag(&mut args, "--quantize"),
+            }
+        } else {
+            Mode::Single
+        },
+    };
+    let backend = take_flag_value(&mut args, "--backend").unwrap_or_else(|| "wgpu".to_string());
+    let device_index = take_device_index(&mut args, "--device");
+    let encoder_index = take_device_index(&mut args, "--encoder-device").or(device_index);
+    let decoder_index = take_device_index(&mut args, "--decoder-device").or(device_index);
 
-    let channels = spec.channels as usize;
-    let sample_rate = spec.sample_rate as usize;
-    let bits_per_sample = spec.bits_per_sample;
-    let sample_format = spec.sample_format;
+    let n_positional = match cli_options.mode {
+        Mode::Single => 5,
+        Mode::Serve | Mode::Watch(_) | Mode::ExportBundle { .. } => 2,
+    };
+    if args.len() < n_positional {
+        eprintln!(
+            "Usage: {0} <model name> --serve|--watch <dir> [options]\n       {0} <model name> <audio file> <lang|auto|auto-per-chunk> <transcription file> [--prompt <text>] [--task transcribe|translate] [--beam-size <n>] [--nbest <n>] [--temperatures <t,...>] [--timestamps] [--word-timestamps] [--compression-ratio-threshold <x|none>] [--logprob-threshold <x|none>] [--no-speech-threshold <x|none>] [--max-ngram-repeats <n|none>] [--no-condition-on-previous-text] [--language-switch-prob <p>] [--turn-detection] [--chunk-length <s>] [--overlap <s>] [--merge-by-confidence] [--reseam] [--encoder-attention-window <frames>] [--postprocess] [--replace <rules.toml>] [--highpass] [--normalize] [--backend wgpu|ndarray|cuda] [--device <index>] [--encoder-device <index>] [--decoder-device <index>] [--raw --rate <hz> [--channels <n>] [--pcm-format s16|f32]] [--live] [--no-progress] [--stats] [--checkpoint <file>] [--encoder-cache <dir>] [--model-dir <dir>] [--seed <n>] [--output-format txt|json|verbose_json]",
+            args[0]
+        );
+        process::exit(1);
+    }
 
-    assert_eq!(sample_rate, 16000, "The audio sample rate must be 16k.");
-    assert_eq!(channels, 1, "The audio must be single-channel.");
+    match backend.as_str() {
+        "wgpu" => {
+            let device = |index: Option<usize>| match index {
+                Some(index) => WgpuDevice::DiscreteGpu(index),
+                None => WgpuDevice::DefaultDevice,
+            };
+            run::<Wgpu>(
+                &args,
+                cli_options,
+                device(encoder_index),
+                device(decoder_index),
+            );
+        }
+        #[cfg(feature = "ndarray")]
+        "ndarray" => {
+            if [encoder_index, decoder_index]
+                .iter()
+                .any(|index| index.is_some_and(|index| index != 0))
+            {
+                eprintln!("The ndarray backend only has a single CPU device");
+                process::exit(1);
+            }
+            run::<NdArray>(&args, cli_options, NdArrayDevice::Cpu, NdArrayDevice::Cpu);
+        }
+        #[cfg(feature = "cuda")]
+        "cuda" => run::<Cuda>(
+            &args,
+            cli_options,
+            CudaDevice::new(encoder_index.unwrap_or(0)),
+            CudaDevice::new(decoder_index.unwrap_or(0)),
+        ),
+        #[cfg(not(feature = "ndarray"))]
+        "ndarray" => {
+            eprintln!("The ndarray backend is not enabled, rebuild with `--features ndarray`");
+            process::exit(1);
+        }
+        #[cfg(not(feature = "cuda"))]
+        "cuda" => {
+            eprintln!("The cuda backend is not enabled, rebuild with `--features cuda`");
+            process::exit(1);
+        }
+        other => {
+            eprintln!("Unknown backend: {other}, expected one of wgpu, ndarray, cuda");
+            process::exit(1);
+        }
+    }
+}
 
-    let max_int_val = 2_u32.pow(bits_per_sample as u32 - 1) - 1;
+struct CliOptions {
+    /// Decoding options from the flags. The language is set from the positional argument.
+    transcribe: TranscribeOptions,
+    /// Probability a chunk's language must be detected with to switch languages, with
+    /// `auto-per-chunk`.
+    language_switch_prob: f64,
+    raw_input: Option<RawInput>,
+    /// Print partial text to stderr while decoding.
+    live: bool,
+    /// Draw a progress bar on stderr while decoding, unless the partial text is printed.
+    progress: bool,
+    /// Print the time spent in each stage once done.
+    stats: bool,
+    /// Save progress to this file after every chunk and resume from it on restart.
+    checkpoint: Option<String>,
+    /// Directory keeping the encoder output of every chunk, reused by later runs on the same audio.
+    encoder_cache: Option<PathBuf>,
+    /// Directory holding local models and the cache of downloaded ones.
+    model_dir: PathBuf,
+    /// Layout of the transcription file, picked from its extension if not given.
+    output_format: Option<OutputFormat>,
+    mode: Mode,
+}
 
-    let floats = match sample_format {
-        SampleFormat::Float => reader.into_samples::<f32>().collect::<hound::Result<_>>()?,
-        SampleFormat::Int => reader
-            .into_samples::<i32>()
-            .map(|s| s.map(|s| s as f32 / max_int_val as f32))
-            .collect::<hound::Result<_>>()?,
+/// Whether the binary transcribes a single file or keeps the model loaded for many.
+enum Mode {
+    Single,
+    /// Transcribe the files named by JSON commands read from stdin, see [`serve::serve`].
+    Serve,
+    /// Transcribe the audio files appearing in a directory, see [`serve::watch`].
+    Watch(PathBuf),
+    /// Save the loaded model under a directory in the layout it is loaded from, e.g. to keep a
+    /// downloaded model or an int8 copy of one. Not listed in the usage.
+    ExportBundle {
+        dir: PathBuf,
+        quantize: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    /// The transcript with its metadata, see [`Transcript::to_json`].
+    Json,
+    /// The `verbose_json` response of OpenAI's transcription API.
+    VerboseJson,
+}
+
+impl OutputFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "txt" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            "verbose_json" => Some(Self::VerboseJson),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Text => "txt",
+            Self::Json | Self::VerboseJson => "json",
+        }
+    }
+
+    /// JSON for paths ending in `.json` and plain text otherwise.
+    fn from_path(path: &Path) -> Self {
+        let is_json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        if is_json {
+            Self::Json
+        } else {
+            Self::Text
+        }
+    }
+}
+
+fn transcribe_options(args: &mut Vec<String>) -> TranscribeOptions {
+    let mut options = TranscribeOptions::recommended()
+        .timestamps(take_flag(args, "--timestamps"))
+        .word_timestamps(take_flag(args, "--word-timestamps"))
+        .condition_on_previous_text(!take_flag(args, "--no-condition-on-previous-text"));
+    if let Some(prompt) = take_flag_value(args, "--prompt") {
+        options = options.prompt(prompt);
+    }
+    match take_flag_value(args, "--task").as_deref() {
+        None | Some("transcribe") => {}
+        Some("translate") => options = options.task(Task::Translate),
+        Some(other) => {
+            eprintln!("Invalid task: {other}, expected transcribe or translate");
+            process::exit(1);
+        }
+    }
+    if let Some(beam_size) = parse_flag_value(args, "--beam-size") {
+        options = options.beam_size(beam_size);
+    }
+    if let Some(nbest) = parse_flag_value(args, "--nbest") {
+        options = options.nbest(nbest);
+    }
+    if let Some(temperatures) = take_flag_value(args, "--temperatures") {
+        let temperatures = temperatures
+            .split(',')
+            .map(|t| parse_value("--temperatures", t))
+            .collect();
+        options = options.temperatures(temperatures);
+    }
+    if let Some(threshold) = parse_threshold(args, "--compression-ratio-threshold") {
+        options = options.compression_ratio_threshold(threshold);
+    }
+    if let Some(threshold) = parse_threshold(args, "--logprob-threshold") {
+        options = options.log_prob_threshold(threshold);
+    }
+    if let Some(threshold) = parse_threshold(args, "--no-speech-threshold") {
+        options = options.no_speech_threshold(threshold);
+    }
+    if let Some(max_repeats) = parse_threshold(args, "--max-ngram-repeats") {
+        options = options.max_ngram_repeats(max_repeats);
+    }
+    if take_flag(args, "--turn-detection") {
+        options = options.turn_detection(Some(audio::TurnOptions::default()));
+    }
+    if let Some(seconds) = parse_flag_value(args, "--chunk-length") {
+        options = options.chunk_length(seconds);
+    }
+    if let Some(seconds) = parse_flag_value(args, "--overlap") {
+        options = options.chunk_overlap(seconds);
+    }
+    let reseam = take_flag(args, "--reseam");
+    if take_flag(args, "--merge-by-confidence") || reseam {
+        options = options
+            .merge_strategy(MergeStrategy::Confidence {
+                min_agreement: DEFAULT_MIN_OVERLAP_AGREEMENT,
+            })
+            .reseam(reseam);
+    }
+    if let Some(frames) = parse_flag_value(args, "--encoder-attention-window") {
+        options = options.encoder_attention_window(Some(frames));
+    }
+    if let Some(seed) = parse_flag_value(args, "--seed") {
+        options = options.seed(seed);
+    }
+    let mut postprocess = if take_flag(args, "--postprocess") {
+        PostprocessOptions::all()
+    } else {
+        PostprocessOptions::default()
     };
+    if let Some(rules) = take_flag_value(args, "--replace") {
+        postprocess.replacements = Replacements::from_file(&rules).unwrap_or_else(|e| {
+            eprintln!("Failed to load the replacement rules {rules}: {e}");
+            process::exit(1);
+        });
+    }
+    options = options.postprocess(postprocess);
+    let highpass = take_flag(args, "--highpass");
+    let normalize = take_flag(args, "--normalize");
+    options = options.preprocess(PreprocessOptions {
+        // an offset would also skew the level measured for normalization
+        remove_dc: highpass || normalize,
+        highpass_hz: highpass.then_some(audio::DEFAULT_HIGHPASS_HZ),
+        normalize_db: normalize.then_some(audio::DEFAULT_NORMALIZE_DB),
+    });
 
-    Ok((floats, sample_rate))
+    options
 }
 
-fn main() {
-    let tensor_device = WgpuDevice::default();
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid value for {flag}: {value}");
+        process::exit(1);
+    })
+}
 
-    let args: Vec<String> = env::args().collect();
+fn parse_flag_value<T: FromStr>(args: &mut Vec<String>, flag: &str) -> Option<T> {
+    take_flag_value(args, flag).map(|value| parse_value(flag, &value))
+}
 
-    if args.len() < 5 {
-        eprintln!(
-            "Usage: {} <model name> <audio file> <lang> <transcription file>",
-            args[0]
-        );
+/// Parses a threshold flag, where `none` disables the threshold.
+fn parse_threshold<T: FromStr>(args: &mut Vec<String>, flag: &str) -> Option<Option<T>> {
+    take_flag_value(args, flag).map(|value| match value.as_str() {
+        "none" => None,
+        value => Some(parse_value(flag, value)),
+    })
+}
+
+/// Format of headerless PCM read from stdin.
+struct RawInput {
+    format: PcmFormat,
+    sample_rate: usize,
+    channels: usize,
+}
+
+fn raw_input_options(args: &mut Vec<String>) -> RawInput {
+    let parse_number = |flag: &str, value: Option<String>| {
+        value.map(|value| {
+            value.parse::<usize>().unwrap_or_else(|_| {
+                eprintln!("Invalid value for {flag}: {value}");
+                process::exit(1);
+            })
+        })
+    };
+
+    let Some(sample_rate) = parse_number("--rate", take_flag_value(args, "--rate")) else {
+        eprintln!("--raw requires the input sample rate, e.g. --rate 16000");
         process::exit(1);
+    };
+    let channels = parse_number("--channels", take_flag_value(args, "--channels")).unwrap_or(1);
+    let format = match take_flag_value(args, "--pcm-format").as_deref() {
+        None | Some("s16") => PcmFormat::S16,
+        Some("f32") => PcmFormat::F32,
+        Some(other) => {
+            eprintln!("Invalid PCM format: {other}, expected s16 or f32");
+            process::exit(1);
+        }
+    };
+
+    RawInput {
+        format,
+        sample_rate,
+        channels,
+    }
+}
+
+fn run<B: Backend>(
+    args: &[String],
+    cli_options: CliOptions,
+    encoder_device: B::Device,
+    decoder_device: B::Device,
+) {
+    let model_name = &args[1];
+    let source = model_source(model_name, &cli_options.model_dir);
+    check_devices::<B>(&encoder_device, &decoder_device);
+
+    match &cli_options.mode {
+        Mode::Single => {}
+        Mode::ExportBundle { dir, quantize } => {
+            export_bundle::<B>(&source, dir, *quantize, &decoder_device);
+            return;
+        }
+        Mode::Serve | Mode::Watch(_) => {
+            // stdout carries the replies, so everything else goes to stderr
+            if encoder_device == decoder_device {
+                eprintln!("Using device {decoder_device:?}");
+            } else {
+                eprintln!("Using device {encoder_device:?} for the encoder and {decoder_device:?} for the decoder");
+            }
+            eprintln!("Loading model...");
+            let (bpe, whisper) = match source.load::<B>(&decoder_device) {
+                Ok((bpe, _config, whisper)) => (bpe, whisper),
+                Err(e) => {
+                    eprintln!("Failed to load model: {e}");
+                    process::exit(1);
+                }
+            };
+            let whisper = whisper.to_devices(&encoder_device, &decoder_device);
+            let options = with_encoder_cache(
+                cli_options.transcribe.clone(),
+                cli_options.encoder_cache.as_deref(),
+                model_name,
+                &source,
+            );
+            let server = serve::Server {
+                whisper: &whisper,
+                bpe: &bpe,
+                options,
+                language_switch_prob: cli_options.language_switch_prob,
+                output_format: cli_options.output_format,
+                model_name,
+            };
+            match &cli_options.mode {
+                Mode::Watch(dir) => serve::watch(server, dir),
+                _ => serve::serve(server),
+            }
+            return;
+        }
     }
 
     let wav_file = &args[2];
     let text_file = &args[4];
+    let output_format = cli_options
+        .output_format
+        .unwrap_or_else(|| OutputFormat::from_path(Path::new(text_file)));
 
     let lang_str = &args[3];
-    let lang = match Language::iter().find(|lang| lang.as_str() == lang_str) {
-        Some(lang) => lang,
+    let per_chunk = lang_str == "auto-per-chunk";
+    let lang = match Language::from_code(lang_str) {
+        Some(lang) => Some(lang),
+        None if lang_str == "auto" || per_chunk => None,
         None => {
             eprintln!("Invalid language abbreviation: {lang_str}");
             process::exit(1);
         }
     };
 
-    let model_name = &args[1];
+    if encoder_device == decoder_device {
+        println!("Using device {decoder_device:?}");
+    } else {
+        println!("Using device {encoder_device:?} for the encoder and {decoder_device:?} for the decoder");
+    }
 
     println!("Loading waveform...");
-    let (waveform, sample_rate) = match load_audio_waveform(wav_file) {
-        Ok((w, sr)) => (w, sr),
+    let loaded = match &cli_options.raw_input {
+        Some(raw) => input::read_raw_pcm(
+            io::stdin().lock(),
+            raw.format,
+            raw.sample_rate,
+            raw.channels,
+        ),
+        None => input::load_audio_file(wav_file),
+    };
+    let audio = match loaded {
+        Ok(audio) => audio,
         Err(e) => {
             eprintln!("Failed to load audio file: {e}");
             process::exit(1);
         }
     };
+    println!(
+        "Decoded {} ({}, {} Hz, {} channel(s)), {:.1} s",
+        audio.container,
+        audio.codec,
+        audio.source_sample_rate,
+        audio.source_channels,
+        audio.duration()
+    );
+    let waveform = audio.waveform;
+    let sample_rate = audio::SAMPLE_RATE;
 
-    let (bpe, _whisper_config, whisper) = load_model::<Wgpu>(model_name, &tensor_device);
+    let (bpe, _whisper_config, whisper) = load_model::<B>(&source, &decoder_device);
+    let whisper = whisper.to_devices(&encoder_device, &decoder_device);
 
-    let text = match waveform_to_text(&whisper, &bpe, lang, waveform, sample_rate, false) {
-        Ok((text, _tokens)) => text,
+    let (events, event_printer) = if cli_options.live || cli_options.progress {
+        let (sender, receiver) = mpsc::channel();
+        let printer = if cli_options.live {
+            thread::spawn(move || print_live(receiver))
+        } else {
+            thread::spawn(move || print_progress(receiver))
+        };
+        (Some(sender), Some(printer))
+    } else {
+        (None, None)
+    };
+
+    let mut options = cli_options.transcribe;
+    if let Some(events) = events {
+        options = options.events(events);
+    }
+    let options = with_encoder_cache(
+        options,
+        cli_options.encoder_cache.as_deref(),
+        model_name,
+        &source,
+    );
+    // the checkpointed and streaming paths need the language up front
+    let lang = lang.unwrap_or_else(|| {
+        if !bpe.kind().is_multilingual() {
+            return Language::English;
+        }
+        match whisper.detect_language(&bpe, &waveform, sample_rate, &options.decode) {
+            Ok((lang, prob)) => {
+                println!("Detected language {} ({:.0}%)", lang.as_str(), prob * 100.0);
+                lang
+            }
+            Err(e) => {
+                eprintln!("Failed to detect the language: {e}");
+                process::exit(1);
+            }
+        }
+    });
+    let options = if per_chunk {
+        // the detected language only holds until a chunk is confidently in another one
+        options.language_mode(LanguageMode::DetectPerChunk {
+            min_switch_prob: cli_options.language_switch_prob,
+        })
+    } else {
+        options.language(lang)
+    };
+
+    let result = match &cli_options.checkpoint {
+        Some(checkpoint_path) => checkpoint::resume_from(checkpoint_path).and_then(|resume| {
+            if let Some(checkpoint) = &resume {
+                println!(
+                    "Resuming from {checkpoint_path} after {} chunk(s), {:.1} s",
+                    checkpoint.n_chunks(),
+                    checkpoint.duration()
+                );
+            }
+
+            let mut sink = CheckpointFile::new(checkpoint_path);
+            let transcript = waveform_to_text_checkpointed(
+                &whisper,
+                &bpe,
+                lang,
+                &waveform,
+                sample_rate,
+                &options.decode,
+                resume,
+                &mut sink,
+            )?;
+            let metrics = transcript.metadata.metrics.clone();
+            write_transcript(
+                Path::new(text_file),
+                output_format,
+                transcript,
+                &bpe,
+                model_name,
+            )?;
+
+            // running the same command again starts a new transcription
+            sink.remove()?;
+            Ok(metrics)
+        }),
+        // the cleaned up text only exists once the whole transcript does
+        None if output_format != OutputFormat::Text || options.decode.postprocess.is_enabled() => {
+            waveform_to_transcript(
+                &whisper,
+                &bpe,
+                lang,
+                &waveform,
+                sample_rate,
+                &options.decode,
+            )
+            .and_then(|transcript| {
+                let metrics = transcript.metadata.metrics.clone();
+                write_transcript(
+                    Path::new(text_file),
+                    output_format,
+                    transcript,
+                    &bpe,
+                    model_name,
+                )?;
+                Ok(metrics)
+            })
+        }
+        None => {
+            let file = fs::File::create(text_file).unwrap_or_else(|e| {
+                eprintln!("Error creating transcription file: {e}");
+                process::exit(1);
+            });
+            let mut writer = BufWriter::new(file);
+
+            // the text is written as it is confirmed, so long recordings never sit in memory
+            waveform_to_text_streaming(
+                &whisper,
+                &bpe,
+                lang,
+                &waveform,
+                sample_rate,
+                &options.decode,
+                &mut writer,
+            )
+            .and_then(|metrics| {
+                writer.flush()?;
+                Ok(metrics)
+            })
+        }
+    };
+
+    // dropping the options closes the event channel, letting the printer finish
+    drop(options);
+    if let Some(event_printer) = event_printer {
+        let _ = event_printer.join();
+    }
+
+    match result {
+        Ok(metrics) => {
+            if cli_options.stats {
+                println!("{metrics}");
+            }
+        }
+        Err(WhisperError::AudioTooShort { n_samples, .. }) => {
+            eprintln!("Audio file {wav_file} is too short to transcribe ({n_samples} samples)");
+            process::exit(1);
+        }
+        Err(WhisperError::Tokenizer(e)) => {
+            eprintln!("Tokenizer error during transcription: {e}");
+            process::exit(1);
+        }
+        Err(WhisperError::Io(e)) => {
+            eprintln!("Error writing output file: {e}");
+            process::exit(1);
+        }
         Err(e) => {
             eprintln!("Error during transcription: {e}");
             process::exit(1);
         }
-    };
-
-    fs::write(text_file, text).unwrap_or_else(|e| {
-        eprintln!("Error writing transcription file: {e}");
-        process::exit(1);
-    });
+    }
 
     println!("Transcription finished.");
 }
 
-fn load_model<B: Backend>(
+fn write_transcript(
+    path: &Path,
+    format: OutputFormat,
+    mut transcript: Transcript,
+    bpe: &Gpt2Tokenizer,
     model_name: &str,
-    tensor_device_ref: &B::Device,
-) -> (Gpt2Tokenizer, WhisperConfig, Whisper<B>) {
-    let bpe = match Gpt2Tokenizer::new(model_name) {
-        Ok(bpe) => bpe,
-        Err(e) => {
-            eprintln!("Failed to load tokenizer: {e}, {model_name}");
-            process::exit(1);
+) -> Result<(), WhisperError> {
+    match format {
+        OutputFormat::Text => fs::write(path, transcript.text)?,
+        OutputFormat::Json => {
+            transcript.metadata.model = Some(model_name.to_string());
+            fs::write(path, transcript.to_json())?;
         }
-    };
+        OutputFormat::VerboseJson => fs::write(path, transcript.to_verbose_json(bpe))?,
+    }
 
-    let whisper_config =
-        match WhisperConfig::load(format!("models/{model_name}/{model_name}.cfg")) {
-            Ok(config) => config,
-            Err(e) => {
-                eprintln!("Failed to load whisper config: {e}");
-                process::exit(1);
+    Ok(())
+}
+
+/// Prints the partial text of the chunk being decoded to stderr, rewriting the current line.
+fn print_live(receiver: mpsc::Receiver<DecodeEvent>) {
+    let mut stderr = io::stderr();
+    for event in receiver {
+        let _ = match event {
+            DecodeEvent::Token { text, .. } | DecodeEvent::Revised { text, .. } => {
+                write!(stderr, "\r\x1b[2K{}", text.trim())
             }
+            DecodeEvent::ChunkFinished { text, .. } => writeln!(stderr, "\r\x1b[2K{}", text.trim()),
+            DecodeEvent::Progress(_) => Ok(()),
         };
+        let _ = stderr.flush();
+    }
+}
 
-    println!("Loading model...");
-    let whisper: Whisper<B> = {
-        match NamedMpkFileRecorder::<FullPrecisionSettings>::new()
-            .load(
-                format!("models/{model_name}/{model_name}").into(),
-                tensor_device_ref,
+/// Decoding steps between redraws of the progress bar, which keep the elapsed time ticking
+/// within long chunks.
+const PROGRESS_REDRAW_STEPS: usize = 16;
+
+/// Width of the progress bar in characters.
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+/// Draws a progress bar on stderr, rewriting the line after every chunk and every few
+/// decoding steps.
+fn print_progress(receiver: mpsc::Receiver<DecodeEvent>) {
+    let start = Instant::now();
+    let mut stderr = io::stderr();
+    let mut last: Option<(Progress, Instant)> = None;
+    let mut steps = 0;
+    for event in receiver {
+        match event {
+            DecodeEvent::Progress(progress) => last = Some((progress, Instant::now())),
+            DecodeEvent::Token { .. } | DecodeEvent::Revised { .. } => {
+                steps += 1;
+                if steps % PROGRESS_REDRAW_STEPS != 0 {
+                    continue;
+                }
+            }
+            DecodeEvent::ChunkFinished { .. } => continue,
+        }
+
+        let line = match &last {
+            Some((progress, received)) => progress_line(progress, received.elapsed().as_secs_f64()),
+            None => format!(
+                "decoding, {} elapsed",
+                format_duration(start.elapsed().as_secs_f64())
+            ),
+        };
+        let _ = write!(stderr, "\r\x1b[2K{line}");
+        let _ = stderr.flush();
+    }
+
+    if last.is_some() {
+        let _ = writeln!(stderr);
+    }
+}
+
+/// Describes `progress` as it stands `since` seconds after it was reported.
+fn progress_line(progress: &Progress, since: f64) -> String {
+    let elapsed = format_duration(progress.elapsed_seconds + since);
+    let remaining = progress
+        .remaining_seconds
+        .map(|remaining| format!(", {} left", format_duration((remaining - since).max(0.0))))
+        .unwrap_or_default();
+
+    match (
+        progress.fraction(),
+        progress.total_chunks,
+        progress.total_audio_seconds,
+    ) {
+        (Some(fraction), Some(total_chunks), Some(total_seconds)) => {
+            let filled = (fraction * PROGRESS_BAR_WIDTH as f64).round() as usize;
+            format!(
+                "[{}{}] {:3.0}% {}/{total_chunks} chunks, {:.1}/{total_seconds:.1} s, {elapsed} elapsed{remaining}",
+                "#".repeat(filled),
+                "-".repeat(PROGRESS_BAR_WIDTH - filled),
+                fraction * 100.0,
+                progress.chunks_done,
+                progress.audio_seconds,
             )
-            .map(|record| whisper_config.init(tensor_device_ref).load_record(record))
-        {
-            Ok(whisper_model) => whisper_model,
-            Err(e) => {
-                eprintln!("Failed to load whisper model file: {e}");
-                process::exit(1);
+        }
+        // streamed audio has no end to measure against
+        _ => format!(
+            "{} chunks, {:.1} s, {elapsed} elapsed{remaining}",
+            progress.chunks_done, progress.audio_seconds,
+        ),
+    }
+}
+
+/// Formats seconds as `m:ss`, or `h:mm:ss` from an hour on.
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+/// Reuses the encoder output kept in `cache_dir`, if given.
+fn with_encoder_cache(
+    options: TranscribeOptions,
+    cache_dir: Option<&Path>,
+    model_name: &str,
+    source: &WhisperSource,
+) -> TranscribeOptions {
+    let Some(cache_dir) = cache_dir else {
+        return options;
+    };
+
+    let cache = EncoderCache::open(
+        cache_dir,
+        model_name,
+        &source.weights_path(),
+        DEFAULT_ENCODER_CACHE_BYTES,
+    );
+    match cache {
+        Ok(cache) => options.encoder_cache(Arc::new(cache)),
+        Err(e) => {
+            eprintln!(
+                "Failed to open the encoder cache {}: {e}",
+                cache_dir.display()
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Exits if either device fails to run a computation.
+fn check_devices<B: Backend>(encoder_device: &B::Device, decoder_device: &B::Device) {
+    for device in [encoder_device, decoder_device] {
+        if let Err(e) = check_device::<B>(device) {
+            eprintln!("Failed to initialize device {device:?}: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs a tiny computation on `device` so a missing or broken adapter is reported up front
+/// instead of panicking deep inside the backend during model loading.
+fn check_device<B: Backend>(device: &B::Device) -> Result<(), String> {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        Tensor::<B, 1>::zeros([1], device).into_data();
+    }));
+    panic::set_hook(default_hook);
+
+    result.map_err(|e| {
+        e.downcast_ref::<String>()
+            .cloned()
+            .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "backend panicked during initialization".to_string())
+    })
+}
+
+/// Removes `flag` and its device index from `args`.
+fn take_device_index(args: &mut Vec<String>, flag: &str) -> Option<usize> {
+    take_flag_value(args, flag).map(|index| {
+        index.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!("Invalid device index for {flag}: {index}");
+            process::exit(1);
+        })
+    })
+}
+
+/// Removes `flag` from `args`, returning whether it was present.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let index = args.iter().position(|arg| arg == flag);
+    if let Some(index) = index {
+        args.remove(index);
+    }
+    index.is_some()
+}
+
+/// Removes `flag` and the value following it from `args`, returning the value if present.
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    if index + 1 >= args.len() {
+        eprintln!("Missing value for {flag}");
+        process::exit(1);
+    }
+
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Some(value)
+}
+
+/// Parses the model argument, either the name of a model in `model_dir` or
+/// `hf:owner/repo/<model name>[@revision]`, which is downloaded into `model_dir/hf`.
+fn model_source(model: &str, model_dir: &Path) -> WhisperSource {
+    let Some(spec) = model.strip_prefix("hf:") else {
+        return WhisperSource::Local {
+            dir: model_dir.to_path_buf(),
+            name: model.to_string(),
+        };
+    };
+
+    let (path, revision) = spec.split_once('@').unwrap_or((spec, "main"));
+    match path.rsplit_once('/') {
+        Some((repo_id, name)) if repo_id.contains('/') && !name.is_empty() => {
+            WhisperSource::HuggingFaceRepo {
+                repo_id: repo_id.to_string(),
+                revision: revision.to_string(),
+                name: name.to_string(),
+                cache_dir: model_dir.join("hf"),
             }
         }
+        _ => {
+            eprintln!("Invalid model {model}, expected hf:owner/repo/<model name>[@revision]");
+            process::exit(1);
+        }
+    }
+}
+
+/// Saves the model of `source` under `dir` and loads it back to check it, exiting on failure.
+fn export_bundle<B: Backend>(
+    source: &WhisperSource,
+    dir: &Path,
+    quantize: bool,
+    tensor_device_ref: &B::Device,
+) {
+    let (bpe, config, whisper) = load_model::<B>(source, tensor_device_ref);
+    let whisper = if quantize {
+        whisper.quantize()
+    } else {
+        whisper
     };
 
-    let whisper = whisper.to_device(tensor_device_ref);
+    let name = source.name();
+    let saved = save::save_whisper_bundle(&whisper, &config, &bpe, dir, name)
+        .and_then(|_| save::verify_bundle::<B>(dir, name, tensor_device_ref));
+    match saved {
+        Ok(()) => println!("Saved {}", dir.join(name).display()),
+        Err(e) => {
+            eprintln!("Failed to export the model to {}: {e}", dir.display());
+            process::exit(1);
+        }
+    }
+}
 
-    (bpe, whisper_config, whisper)
+fn load_model<B: Backend>(
+    source: &WhisperSource,
+    tensor_device_ref: &B::Device,
+) -> (Gpt2Tokenizer, WhisperConfig, Whisper<B>) {
+    println!("Loading model...");
+    match source.load(tensor_device_ref) {
+        Ok(model) => model,
+        Err(e) => {
+            eprintln!("Failed to load model: {e}");
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,314 @@
+//! Long-running modes that keep the model loaded between transcriptions, since loading it can
+//! take far longer than transcribing a short clip.
+
+use crate::{write_transcript, OutputFormat};
+
+use burn::tensor::backend::Backend;
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, BufRead, Write},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use whisper_stream::audio::SAMPLE_RATE;
+use whisper_stream::error::WhisperError;
+use whisper_stream::input;
+use whisper_stream::model::Whisper;
+use whisper_stream::token::{Gpt2Tokenizer, Language};
+use whisper_stream::transcribe::{
+    transcribe, CancellationToken, LanguageMode, TranscribeOptions, Transcript,
+};
+
+/// How often the watched directory is listed.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the interrupt flag is checked.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The loaded model and the options every file is transcribed with.
+pub struct Server<'a, B: Backend> {
+    pub whisper: &'a Whisper<B>,
+    pub bpe: &'a Gpt2Tokenizer,
+    pub options: TranscribeOptions,
+    pub language_switch_prob: f64,
+    /// Layout of the written transcripts, picked from the output path if not given.
+    pub output_format: Option<OutputFormat>,
+    pub model_name: &'a str,
+}
+
+impl<B: Backend> Server<'_, B> {
+    /// Transcribes `audio` in `lang`, detecting the language if not given, and writes the
+    /// transcript to `output` if given. A panic while decoding is returned as an error, so a
+    /// bad file cannot end the loop.
+    fn transcribe_file(
+        &self,
+        audio: &Path,
+        lang: Option<&str>,
+        output: Option<&Path>,
+    ) -> Result<Transcript, String> {
+        let language = match lang {
+            Some(lang) => language_mode(lang, self.language_switch_prob)
+                .ok_or_else(|| format!("invalid language: {lang}"))?,
+            None => LanguageMode::DetectOnce,
+        };
+        let options = self.options.clone().language_mode(language);
+
+        let run = || -> Result<Transcript, WhisperError> {
+            let audio = input::load_audio_file(audio)?;
+            let transcript = transcribe(
+                self.whisper,
+                self.bpe,
+                &audio.waveform,
+                SAMPLE_RATE,
+                &options,
+            )?;
+            if let Some(output) = output {
+                let format = self
+                    .output_format
+                    .unwrap_or_else(|| OutputFormat::from_path(output));
+                write_transcript(
+                    output,
+                    format,
+                    transcript.clone(),
+                    self.bpe,
+                    self.model_name,
+                )?;
+            }
+            Ok(transcript)
+        };
+
+        match panic::catch_unwind(AssertUnwindSafe(run)) {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err("transcription panicked".to_string()),
+        }
+    }
+}
+
+/// Parses a language code, `auto` or `auto-per-chunk`.
+fn language_mode(lang: &str, language_switch_prob: f64) -> Option<LanguageMode> {
+    match lang {
+        "auto" => Some(LanguageMode::DetectOnce),
+        "auto-per-chunk" => Some(LanguageMode::DetectPerChunk {
+            min_switch_prob: language_switch_prob,
+        }),
+        lang => Language::from_code(lang).map(LanguageMode::Fixed),
+    }
+}
+
+/// A line of stdin in `--serve` mode.
+#[derive(Deserialize)]
+struct Command {
+    audio: PathBuf,
+    /// A language code, `auto` or `auto-per-chunk`. The language is detected if missing.
+    #[serde(default)]
+    lang: Option<String>,
+    /// Where the transcript is written. The reply carries the text either way.
+    #[serde(default)]
+    output: Option<PathBuf>,
+}
+
+/// The line written to stdout for each command.
+#[derive(Serialize)]
+struct Reply {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Reply {
+    fn error(audio: Option<PathBuf>, error: String) -> Self {
+        Reply {
+            status: "error",
+            audio,
+            output: None,
+            language: None,
+            text: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// What the serve loop waits for.
+enum Input {
+    Line(String),
+    /// End of stdin, or an interrupt.
+    Stop,
+}
+
+/// Reads JSON commands like `{"audio": "a.wav", "lang": "en", "output": "a.txt"}` from stdin,
+/// one per line, and replies to each with a JSON line on stdout whose `status` is `ok` or
+/// `error`. Runs until stdin ends or an interrupt, which also cancels the file being
+/// transcribed. A line `{"status": "ready"}` is written once commands are accepted.
+pub fn serve<B: Backend>(mut server: Server<B>) {
+    let cancel = CancellationToken::new();
+    server.options = server.options.clone().cancel(cancel.clone());
+
+    let (sender, receiver) = mpsc::channel();
+    {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if sender.send(Input::Line(line)).is_err() {
+                    return;
+                }
+            }
+            let _ = sender.send(Input::Stop);
+        });
+    }
+    on_interrupt(cancel, move || {
+        let _ = sender.send(Input::Stop);
+    });
+
+    reply(&Reply {
+        status: "ready",
+        audio: None,
+        output: None,
+        language: None,
+        text: None,
+        error: None,
+    });
+    for input in receiver {
+        let line = match input {
+            Input::Line(line) => line,
+            Input::Stop => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let command: Command = match serde_json::from_str(&line) {
+            Ok(command) => command,
+            Err(e) => {
+                reply(&Reply::error(None, format!("invalid command: {e}")));
+                continue;
+            }
+        };
+        let output = command.output.as_deref();
+        match server.transcribe_file(&command.audio, command.lang.as_deref(), output) {
+            Ok(transcript) => reply(&Reply {
+                status: "ok",
+                audio: Some(command.audio),
+                output: command.output,
+                language: Some(transcript.language),
+                text: Some(transcript.text),
+                error: None,
+            }),
+            Err(e) => reply(&Reply::error(Some(command.audio), e)),
+        }
+    }
+}
+
+fn reply(reply: &Reply) {
+    let mut stdout = io::stdout().lock();
+    let line = serde_json::to_string(reply).expect("replies only contain string map keys");
+    let _ = writeln!(stdout, "{line}");
+    let _ = stdout.flush();
+}
+
+/// Transcribes the WAV files appearing in `dir` into sibling files with the extension of the
+/// output format, until interrupted. Files that already have a transcript are skipped, and a
+/// file is only read once its size stayed the same between two listings, so that files still
+/// being copied in are left alone. A file that fails is not retried until the next run.
+pub fn watch<B: Backend>(mut server: Server<B>, dir: &Path) {
+    let cancel = CancellationToken::new();
+    server.options = server.options.clone().cancel(cancel.clone());
+    let format = server.output_format.unwrap_or(OutputFormat::Text);
+
+    let (sender, receiver) = mpsc::channel();
+    on_interrupt(cancel.clone(), move || {
+        let _ = sender.send(());
+    });
+
+    eprintln!("Watching {} for WAV files", dir.display());
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+    let mut failed: HashSet<PathBuf> = HashSet::new();
+    loop {
+        let mut entries = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                eprintln!("Failed to list {}: {e}", dir.display());
+                Vec::new()
+            }
+        };
+        entries.sort();
+
+        for audio in entries {
+            let is_wav = audio
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"));
+            let output = audio.with_extension(format.extension());
+            if !is_wav || output.exists() || failed.contains(&audio) {
+                continue;
+            }
+            let Ok(size) = fs::metadata(&audio).map(|metadata| metadata.len()) else {
+                continue;
+            };
+            if sizes.insert(audio.clone(), size) != Some(size) {
+                continue;
+            }
+            if cancel.is_cancelled() {
+                return;
+            }
+
+            println!("Transcribing {}", audio.display());
+            match server.transcribe_file(&audio, None, Some(&output)) {
+                Ok(_) => println!("Wrote {}", output.display()),
+                Err(e) => {
+                    eprintln!("Failed to transcribe {}: {e}", audio.display());
+                    failed.insert(audio);
+                }
+            }
+        }
+
+        match receiver.recv_timeout(WATCH_POLL_INTERVAL) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Calls `stop` and cancels the transcription in progress on the first SIGINT or SIGTERM. A
+/// second one exits right away.
+fn on_interrupt(cancel: CancellationToken, stop: impl FnOnce() + Send + 'static) {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        let registered =
+            signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&interrupted))
+                .and_then(|_| signal_hook::flag::register(signal, Arc::clone(&interrupted)));
+        if let Err(e) = registered {
+            eprintln!("Failed to handle interrupts: {e}");
+        }
+    }
+
+    thread::spawn(move || {
+        while !interrupted.load(Ordering::Relaxed) {
+            thread::sleep(INTERRUPT_POLL_INTERVAL);
+        }
+        eprintln!("Interrupted, stopping");
+        cancel.cancel();
+        stop();
+    });
+}
@@ -2,9 +2,6 @@
 
 use burn::{
     backend::wgpu::{Wgpu, WgpuDevice},
-    config::Config,
-    module::Module,
-    record::{FullPrecisionSettings, NamedMpkFileRecorder, Recorder},
     tensor::backend::Backend,
 };
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
@@ -12,14 +9,19 @@ use rtrb::RingBuffer;
 use std::{
     env,
     fs::{File, OpenOptions},
-    iter, process,
+    iter,
+    path::Path,
+    process,
     sync::{mpsc, Arc, Mutex},
     time::Instant,
 };
 use strum::IntoEnumIterator;
 use webrtc_vad::{Vad, VadMode};
 use whisper_stream::{
-    model::*, token::Gpt2Tokenizer, token::Language, transcribe::waveform_to_text,
+    model::*,
+    token::Gpt2Tokenizer,
+    token::Language,
+    transcribe::{transcribe, Task, TranscribeOptions},
 };
 
 const BUFFER_FRAME_COUNT: usize = 35;
@@ -80,43 +82,14 @@ fn load_model<B: Backend>(
     model_name: &str,
     tensor_device_ref: &B::Device,
 ) -> (Gpt2Tokenizer, WhisperConfig, Whisper<B>) {
-    let bpe = match Gpt2Tokenizer::new(model_name) {
-        Ok(bpe) => bpe,
+    println!("Loading model...");
+    match load::load_named_model(Path::new("models"), model_name, tensor_device_ref) {
+        Ok(model) => model,
         Err(e) => {
-            eprintln!("Failed to load tokenizer: {e}");
+            eprintln!("Failed to load model: {e}");
             process::exit(1);
         }
-    };
-
-    let whisper_config =
-        match WhisperConfig::load(format!("models/{model_name}/{model_name}.cfg")) {
-            Ok(config) => config,
-            Err(e) => {
-                eprintln!("Failed to load whisper config: {e}");
-                process::exit(1);
-            }
-        };
-
-    println!("Loading model...");
-    let whisper: Whisper<B> = {
-        match NamedMpkFileRecorder::<FullPrecisionSettings>::new()
-            .load(
-                format!("models/{model_name}/{model_name}").into(),
-                tensor_device_ref,
-            )
-            .map(|record| whisper_config.init(tensor_device_ref).load_record(record))
-        {
-            Ok(whisper_model) => whisper_model,
-            Err(e) => {
-                eprintln!("Failed to load whisper model file: {e}");
-                process::exit(1);
-            }
-        }
-    };
-
-    let whisper = whisper.to_device(tensor_device_ref);
-
-    (bpe, whisper_config, whisper)
+    }
 }
 
 fn normalize_audio_data_to_16k(input_data: &[f32], input_sample_rate: &f32) -> Vec<f32> {
@@ -182,14 +155,16 @@ fn process_audio_data(
             .map(|x| x as f32 / 32767.0)
             .collect();
         let start_time = Instant::now(); // Capture the start time
-        let (text, _tokens) =
-            match waveform_to_text(&whisper, &bpe, lang, speech_segment_f32, 16000, true) {
-                Ok((text, tokens)) => (text, tokens),
-                Err(e) => {
-                    eprintln!("Error during transcription: {e}");
-                    process::exit(1);
-                }
-            };
+        let options = TranscribeOptions::new()
+            .language(lang)
+            .task(Task::Translate);
+        let text = match transcribe(&whisper, &bpe, &speech_segment_f32, 16000, &options) {
+            Ok(transcript) => transcript.text,
+            Err(e) => {
+                eprintln!("Error during transcription: {e}");
+                process::exit(1);
+            }
+        };
         println!(
             "\nText: {}, Iteration: {}, Time:{:?}",
             text,
@@ -0,0 +1,350 @@
+use crate::error::{Result, WhisperError};
+use crate::model::EncoderAttentionWindow;
+use burn::tensor::TensorData;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// Size the cache is allowed to grow to by default, in bytes.
+pub const DEFAULT_ENCODER_CACHE_BYTES: u64 = 4 << 30;
+
+/// Identifies an entry as encoder output written by this version of the cache.
+const ENTRY_MAGIC: &[u8; 4] = b"WEC1";
+const ENTRY_EXTENSION: &str = "enc";
+/// File in each model's directory recording the hash of the weights its entries came from.
+const WEIGHTS_HASH_FILE: &str = "weights.sha256";
+
+/// Stores the encoder output of each chunk on disk, so that decoding the same audio again,
+/// e.g. with other decoding options, skips the encoder. Entries are keyed by the chunk's
+/// samples, the mel count and the end padding, and kept apart per model. The least recently
+/// used entries of all models are evicted once the cache exceeds its size.
+#[derive(Debug)]
+pub struct EncoderCache {
+    root: PathBuf,
+    /// Directory of the entries of the opened model.
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: Mutex<Entries>,
+}
+
+/// Size and last use of every entry under the root.
+#[derive(Debug, Default)]
+struct Entries {
+    files: HashMap<PathBuf, (u64, SystemTime)>,
+    total_bytes: u64,
+}
+
+impl Entries {
+    fn insert(&mut self, path: PathBuf, size: u64, used: SystemTime) {
+        if let Some((old_size, _)) = self.files.insert(path, (size, used)) {
+            self.total_bytes -= old_size;
+        }
+        self.total_bytes += size;
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some((size, _)) = self.files.remove(path) {
+            self.total_bytes -= size;
+        }
+    }
+}
+
+impl EncoderCache {
+    /// Opens the cache under `root` for the model `model_name` with the weights at
+    /// `weights_path`. Entries written for different weights under the same name are deleted.
+    /// The cache holds at most `max_bytes` of entries across all models.
+    pub fn open(
+        root: impl Into<PathBuf>,
+        model_name: &str,
+        weights_path: &Path,
+        max_bytes: u64,
+    ) -> Result<Self> {
+        let root = root.into();
+        let dir = root.join(&hex_digest(model_name.as_bytes())[..16]);
+        let weights_hash = hash_file(weights_path)?;
+
+        // the entries of other weights would silently decode with the wrong encoder output
+        let hash_path = dir.join(WEIGHTS_HASH_FILE);
+        match fs::read_to_string(&hash_path) {
+            Ok(hash) if hash.trim() == weights_hash => {}
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {
+                if dir.exists() {
+                    fs::remove_dir_all(&dir)?;
+                }
+                fs::create_dir_all(&dir)?;
+                fs::write(&hash_path, &weights_hash)?;
+            }
+        }
+
+        let mut entries = Entries::default();
+        for model_dir in fs::read_dir(&root)? {
+            let model_dir = model_dir?.path();
+            if !model_dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&model_dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == ENTRY_EXTENSION) {
+                    let metadata = entry.metadata()?;
+                    entries.insert(path, metadata.len(), metadata.modified()?);
+                }
+            }
+        }
+
+        Ok(Self {
+            root,
+            dir,
+            max_bytes,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Total size of the entries of all models, in bytes.
+    pub fn size_bytes(&self) -> u64 {
+        self.entries.lock().unwrap().total_bytes
+    }
+
+    /// Key of the encoder output of `samples`, spectrograms of `n_mels` mels and
+    /// `end_padding_frames` frames of padding, encoded with the attention limited to
+    /// `attention_window`.
+    pub(crate) fn key(
+        samples: &[f32],
+        n_mels: usize,
+        end_padding_frames: usize,
+        attention_window: EncoderAttentionWindow,
+    ) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update((n_mels as u64).to_le_bytes());
+        hasher.update((end_padding_frames as u64).to_le_bytes());
+        // full attention keeps the keys of entries written before windows existed
+        if let EncoderAttentionWindow(Some(window)) = attention_window {
+            hasher.update(b"window");
+            hasher.update((window as u64).to_le_bytes());
+        }
+        for sample in samples {
+            hasher.update(sample.to_le_bytes());
+        }
+
+        hex(&hasher.finalize())
+    }
+
+    /// Reads the entry of `key`, which must be of `shape`. Unreadable or corrupt entries are
+    /// deleted and treated as missing.
+    pub(crate) fn get(&self, key: &str, shape: [usize; 3]) -> Option<TensorData> {
+        let path = self.entry_path(key);
+        let bytes = fs::read(&path).ok()?;
+
+        let Some(values) = parse_entry(&bytes, shape) else {
+            let _ = fs::remove_file(&path);
+            self.entries.lock().unwrap().remove(&path);
+            return None;
+        };
+
+        // the modification time orders the entries by last use across runs
+        let now = SystemTime::now();
+        if let Ok(file) = fs::File::options().write(true).open(&path) {
+            let _ = file.set_modified(now);
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path, bytes.len() as u64, now);
+
+        Some(TensorData::new(values, shape))
+    }
+
+    /// Stores `data` as the entry of `key`, then evicts the least recently used entries until
+    /// the cache fits its size.
+    pub(crate) fn insert(&self, key: &str, data: TensorData) -> Result<()> {
+        let data = data.convert::<f32>();
+        let values = data.as_slice::<f32>().map_err(|e| {
+            WhisperError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("{e:?}")))
+        })?;
+
+        let mut bytes =
+            Vec::with_capacity(ENTRY_MAGIC.len() + 8 * data.shape.len() + 4 * values.len());
+        bytes.extend(ENTRY_MAGIC);
+        bytes.extend((data.shape.len() as u64).to_le_bytes());
+        for &dim in &data.shape {
+            bytes.extend((dim as u64).to_le_bytes());
+        }
+        for value in values {
+            bytes.extend(value.to_le_bytes());
+        }
+
+        // written under another name first, so a crash never leaves a truncated entry behind
+        let path = self.entry_path(key);
+        let partial = path.with_extension("partial");
+        fs::write(&partial, &bytes)?;
+        fs::rename(&partial, &path)?;
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(path, bytes.len() as u64, SystemTime::now());
+        while entries.total_bytes > self.max_bytes {
+            let Some(oldest) = entries
+                .files
+                .iter()
+                .min_by_key(|(_, &(_, used))| used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            match fs::remove_file(&oldest) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => entries.remove(&oldest),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(key).with_extension(ENTRY_EXTENSION)
+    }
+}
+
+/// Values of an entry, or `None` if it is not an entry of `shape`.
+fn parse_entry(bytes: &[u8], shape: [usize; 3]) -> Option<Vec<f32>> {
+    let body = bytes.strip_prefix(ENTRY_MAGIC)?;
+    let (n_dims, body) = body.split_first_chunk::<8>()?;
+    if u64::from_le_bytes(*n_dims) != shape.len() as u64 {
+        return None;
+    }
+    let mut body = body;
+    for &expected in &shape {
+        let (dim, rest) = body.split_first_chunk::<8>()?;
+        if u64::from_le_bytes(*dim) != expected as u64 {
+            return None;
+        }
+        body = rest;
+    }
+    if body.len() != 4 * shape.iter().product::<usize>() {
+        return None;
+    }
+
+    Some(
+        body.chunks_exact(4)
+            .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::open(path).map_err(|e| WhisperError::WeightsCorrupt {
+        path: path.display().to_string(),
+        source: Box::new(e),
+    })?;
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex_digest(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHAPE: [usize; 3] = [1, 1, 2];
+
+    /// An empty cache root with a weights file next to it.
+    fn root(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("whisper-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let weights = dir.join("weights.mpk");
+        fs::write(&weights, b"weights").unwrap();
+
+        (dir.join("cache"), weights)
+    }
+
+    fn entry(value: f32) -> TensorData {
+        TensorData::new(vec![value; 2], SHAPE)
+    }
+
+    /// Bytes taken by an entry of `SHAPE`.
+    const ENTRY_BYTES: u64 = 4 + 8 + 3 * 8 + 2 * 4;
+
+    #[test]
+    fn truncated_entries_are_deleted_and_missing() {
+        let (root, weights) = root("truncated");
+        let cache = EncoderCache::open(&root, "tiny", &weights, 1 << 20).unwrap();
+        cache.insert("a", entry(1.0)).unwrap();
+        assert_eq!(cache.get("a", SHAPE), Some(entry(1.0)));
+
+        let path = cache.entry_path("a");
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+
+        assert_eq!(cache.get("a", SHAPE), None);
+        assert!(!path.exists());
+        assert_eq!(cache.size_bytes(), 0);
+    }
+
+    #[test]
+    fn entries_of_another_shape_are_deleted_and_missing() {
+        let (root, weights) = root("shape");
+        let cache = EncoderCache::open(&root, "tiny", &weights, 1 << 20).unwrap();
+        cache.insert("a", entry(1.0)).unwrap();
+
+        assert_eq!(cache.get("a", [1, 2, 1]), None);
+        assert!(!cache.entry_path("a").exists());
+        assert_eq!(cache.get("a", SHAPE), None);
+    }
+
+    #[test]
+    fn changed_weights_invalidate_the_entries() {
+        let (root, weights) = root("weights");
+        let cache = EncoderCache::open(&root, "tiny", &weights, 1 << 20).unwrap();
+        cache.insert("a", entry(1.0)).unwrap();
+
+        let reopened = EncoderCache::open(&root, "tiny", &weights, 1 << 20).unwrap();
+        assert_eq!(reopened.size_bytes(), ENTRY_BYTES);
+        assert_eq!(reopened.get("a", SHAPE), Some(entry(1.0)));
+
+        fs::write(&weights, b"other weights").unwrap();
+        let reopened = EncoderCache::open(&root, "tiny", &weights, 1 << 20).unwrap();
+        assert_eq!(reopened.size_bytes(), 0);
+        assert_eq!(reopened.get("a", SHAPE), None);
+    }
+
+    #[test]
+    fn the_least_recently_used_entry_is_evicted_first() {
+        let (root, weights) = root("eviction");
+        let cache = EncoderCache::open(&root, "tiny", &weights, 2 * ENTRY_BYTES).unwrap();
+        cache.insert("a", entry(1.0)).unwrap();
+        cache.insert("b", entry(2.0)).unwrap();
+        // reading `a` makes `b` the least recently used
+        assert!(cache.get("a", SHAPE).is_some());
+        cache.insert("c", entry(3.0)).unwrap();
+
+        assert_eq!(cache.size_bytes(), 2 * ENTRY_BYTES);
+        assert_eq!(cache.get("b", SHAPE), None);
+        assert_eq!(cache.get("a", SHAPE), Some(entry(1.0)));
+        assert_eq!(cache.get("c", SHAPE), Some(entry(3.0)));
+
+        // `c` was read after `a`
+        cache.insert("d", entry(4.0)).unwrap();
+        assert_eq!(cache.get("a", SHAPE), None);
+        assert!(cache.get("c", SHAPE).is_some());
+        assert!(cache.get("d", SHAPE).is_some());
+    }
+}
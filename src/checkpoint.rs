@@ -0,0 +1,180 @@
+use crate::audio::SAMPLE_RATE;
+use crate::error::{Result, WhisperError};
+use crate::transcribe::{ChunkState, DecodeOptions, Segment, TokenOrigin};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "std-io")]
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Progress of a transcription, saved after every chunk so that a long job can resume where it
+/// stopped instead of starting over.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Length and sample rate of the waveform being transcribed, checked when resuming.
+    pub n_samples: usize,
+    pub sample_rate: usize,
+    /// Window length and overlap the audio was cut into, checked when resuming. Missing from
+    /// checkpoints saved before they were recorded.
+    #[serde(default)]
+    pub chunk_length_s: Option<f32>,
+    #[serde(default)]
+    pub chunk_overlap_s: Option<f32>,
+    /// Tokens that no later chunk can change.
+    pub tokens: Vec<usize>,
+    /// Chunk each of `tokens` came from.
+    #[serde(default)]
+    pub token_origins: Vec<TokenOrigin>,
+    pub segments: Vec<Segment>,
+    #[serde(flatten)]
+    pub(crate) state: ChunkState,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(
+        n_samples: usize,
+        sample_rate: usize,
+        options: &DecodeOptions,
+        state: ChunkState,
+    ) -> Self {
+        Self {
+            n_samples,
+            sample_rate,
+            chunk_length_s: Some(options.chunk_length_s),
+            chunk_overlap_s: Some(options.chunk_overlap_s),
+            tokens: Vec::new(),
+            token_origins: Vec::new(),
+            segments: Vec::new(),
+            state,
+        }
+    }
+
+    /// Number of chunks already decoded.
+    pub fn n_chunks(&self) -> usize {
+        self.state.next_chunk
+    }
+
+    /// Seconds of audio already decoded.
+    pub fn duration(&self) -> f64 {
+        self.state.sample_offset as f64 / SAMPLE_RATE as f64
+    }
+
+    pub(crate) fn check_source(
+        &self,
+        n_samples: usize,
+        sample_rate: usize,
+        options: &DecodeOptions,
+    ) -> Result<()> {
+        if (self.n_samples, self.sample_rate) != (n_samples, sample_rate) {
+            return Err(WhisperError::Checkpoint(
+                format!(
+                    "saved for {} samples at {} Hz but the audio has {n_samples} samples at {sample_rate} Hz",
+                    self.n_samples, self.sample_rate
+                )
+                .into(),
+            ));
+        }
+        // other windows would start the remaining chunks at other samples than the saved state
+        let chunking = (options.chunk_length_s, options.chunk_overlap_s);
+        if let (Some(length), Some(overlap)) = (self.chunk_length_s, self.chunk_overlap_s) {
+            if (length, overlap) != chunking {
+                return Err(WhisperError::Checkpoint(
+                    format!(
+                        "saved for {length} s windows overlapping by {overlap} s but the options have {} s windows overlapping by {} s",
+                        chunking.0, chunking.1
+                    )
+                    .into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fills in the token origins missing from checkpoints saved before they were tracked. The
+    /// tokens are attributed to the last decoded chunk, spanning all the audio decoded so far.
+    pub(crate) fn restore_origins(&mut self) {
+        let (chunk, chunk_end_s) = (self.state.next_chunk.saturating_sub(1), self.duration());
+        let origin = |token| TokenOrigin {
+            token,
+            chunk,
+            chunk_start_s: 0.0,
+            chunk_end_s,
+            log_prob: None,
+        };
+        if self.token_origins.len() != self.tokens.len() {
+            self.token_origins = self.tokens.iter().map(|&token| origin(token)).collect();
+        }
+        if self.state.window_origins.len() != self.state.window.len() {
+            self.state.window_origins = self
+                .state
+                .window
+                .iter()
+                .map(|&token| origin(token))
+                .collect();
+        }
+    }
+}
+
+/// Receives the progress of a transcription after every chunk.
+pub trait CheckpointSink {
+    fn save(&mut self, checkpoint: &Checkpoint) -> Result<()>;
+}
+
+/// Saves checkpoints to a JSON file. The file is replaced atomically, so a crash while saving
+/// leaves the previous checkpoint intact.
+#[cfg(feature = "std-io")]
+#[derive(Clone, Debug)]
+pub struct CheckpointFile {
+    path: PathBuf,
+}
+
+#[cfg(feature = "std-io")]
+impl CheckpointFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Deletes the checkpoint, e.g. once the transcription has finished.
+    pub fn remove(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "std-io")]
+impl CheckpointSink for CheckpointFile {
+    fn save(&mut self, checkpoint: &Checkpoint) -> Result<()> {
+        let json =
+            serde_json::to_vec(checkpoint).map_err(|e| WhisperError::Checkpoint(Box::new(e)))?;
+
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".partial");
+        fs::write(&partial, json)?;
+        fs::rename(&partial, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Reads the checkpoint saved at `path` by a [`CheckpointFile`], or returns `None` if there is
+/// none yet.
+#[cfg(feature = "std-io")]
+pub fn resume_from(path: impl AsRef<Path>) -> Result<Option<Checkpoint>> {
+    let json = match fs::read(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    serde_json::from_slice(&json)
+        .map(Some)
+        .map_err(|e| WhisperError::Checkpoint(Box::new(e)))
+}
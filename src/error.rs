@@ -0,0 +1,162 @@
+use crate::transcribe::Transcript;
+use std::{error::Error, fmt, io, result};
+
+pub type Result<T> = result::Result<T, WhisperError>;
+
+/// Errors returned by the library for malformed input or missing model files.
+#[derive(Debug)]
+pub enum WhisperError {
+    /// The tokenizer failed to load, encode or decode.
+    Tokenizer(Box<dyn Error + Send + Sync>),
+    /// The tokenizer vocabulary lacks a special token required for decoding.
+    MissingSpecialToken(String),
+    /// The waveform has too few samples to compute a spectrogram.
+    AudioTooShort {
+        n_samples: usize,
+        min_samples: usize,
+    },
+    /// The spectrogram has more frames than the encoder accepts.
+    AudioTooLong { n_frames: usize, max_frames: usize },
+    /// The spectrogram's mel count does not match the encoder.
+    MelSizeMismatch { expected: usize, actual: usize },
+    /// The mel filterbank has channels that no frequency bin contributes to, which happens when
+    /// there are too many mels for the FFT size.
+    EmptyMelFilters { n_mels: usize, n_empty: usize },
+    /// The token sequence exceeds the decoder's context.
+    SequenceTooLong { len: usize, max: usize },
+    /// An encoder and decoder paired into one model have different state sizes.
+    StateSizeMismatch { encoder: usize, decoder: usize },
+    /// The decoder returned NaN or infinite logits for chunk `chunk` after `step` generated
+    /// tokens, see [`NonFinitePolicy`](crate::transcribe::NonFinitePolicy).
+    NumericalError { chunk: usize, step: usize },
+    /// An option is out of range or contradicts another.
+    InvalidOptions(String),
+    /// An audio file or stream could not be decoded. The timestamp locates the failure in
+    /// seconds, when known.
+    AudioDecode {
+        timestamp: Option<f64>,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// A model file is missing or could not be parsed.
+    ModelLoad {
+        path: String,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The directory of a named model does not exist.
+    ModelDirMissing(String),
+    /// The model directory lacks the `.cfg` file describing the architecture.
+    ConfigMissing(String),
+    /// The weights file is missing, cannot be decoded or does not match its published hash.
+    WeightsCorrupt {
+        path: String,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// A model file could not be downloaded.
+    Download {
+        url: String,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// A transcription checkpoint could not be parsed or belongs to different audio.
+    Checkpoint(Box<dyn Error + Send + Sync>),
+    /// Writing the output failed.
+    Io(io::Error),
+    /// Decoding stopped at a [`CancellationToken`](crate::transcribe::CancellationToken). Holds
+    /// the transcript of the chunks decoded before, when the call returns one.
+    Cancelled(Option<Box<Transcript>>),
+}
+
+impl fmt::Display for WhisperError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WhisperError::Tokenizer(e) => write!(f, "tokenizer error: {e}"),
+            WhisperError::MissingSpecialToken(token) => {
+                write!(f, "special token {token} is not in the vocabulary")
+            }
+            WhisperError::AudioTooShort {
+                n_samples,
+                min_samples,
+            } => write!(
+                f,
+                "audio has {n_samples} samples but at least {min_samples} are required"
+            ),
+            WhisperError::AudioTooLong {
+                n_frames,
+                max_frames,
+            } => write!(
+                f,
+                "audio length {n_frames} frames cannot exceed {max_frames}"
+            ),
+            WhisperError::MelSizeMismatch { expected, actual } => write!(
+                f,
+                "audio mel spectrum size is {actual} but the model expects {expected}"
+            ),
+            WhisperError::EmptyMelFilters { n_mels, n_empty } => write!(
+                f,
+                "{n_empty} of {n_mels} mel filters are empty, try fewer mels"
+            ),
+            WhisperError::SequenceTooLong { len, max } => {
+                write!(f, "token sequence length {len} must not exceed {max}")
+            }
+            WhisperError::StateSizeMismatch { encoder, decoder } => write!(
+                f,
+                "audio encoder state size {encoder} does not match text decoder state size {decoder}"
+            ),
+            WhisperError::NumericalError { chunk, step } => write!(
+                f,
+                "decoder logits are not finite in chunk {chunk} at step {step}"
+            ),
+            WhisperError::InvalidOptions(message) => write!(f, "invalid options: {message}"),
+            WhisperError::AudioDecode {
+                timestamp: Some(timestamp),
+                source,
+            } => write!(f, "failed to decode audio at {timestamp:.2}s: {source}"),
+            WhisperError::AudioDecode {
+                timestamp: None,
+                source,
+            } => write!(f, "failed to decode audio: {source}"),
+            WhisperError::ModelLoad { path, source } => {
+                write!(f, "failed to load {path}: {source}")
+            }
+            WhisperError::ModelDirMissing(path) => {
+                write!(f, "model directory {path} does not exist")
+            }
+            WhisperError::ConfigMissing(path) => write!(f, "model config {path} does not exist"),
+            WhisperError::WeightsCorrupt { path, source } => {
+                write!(f, "model weights {path} are missing or corrupt: {source}")
+            }
+            WhisperError::Download { url, source } => {
+                write!(f, "failed to download {url}: {source}")
+            }
+            WhisperError::Checkpoint(e) => write!(f, "invalid checkpoint: {e}"),
+            WhisperError::Io(e) => write!(f, "i/o error: {e}"),
+            WhisperError::Cancelled(_) => write!(f, "transcription was cancelled"),
+        }
+    }
+}
+
+impl Error for WhisperError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WhisperError::Tokenizer(e) => Some(e.as_ref()),
+            WhisperError::AudioDecode { source, .. } => Some(source.as_ref()),
+            WhisperError::ModelLoad { source, .. } => Some(source.as_ref()),
+            WhisperError::WeightsCorrupt { source, .. } => Some(source.as_ref()),
+            WhisperError::Download { source, .. } => Some(source.as_ref()),
+            WhisperError::Checkpoint(e) => Some(e.as_ref()),
+            WhisperError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Box<dyn Error + Send + Sync>> for WhisperError {
+    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+        WhisperError::Tokenizer(e)
+    }
+}
+
+impl From<io::Error> for WhisperError {
+    fn from(e: io::Error) -> Self {
+        WhisperError::Io(e)
+    }
+}
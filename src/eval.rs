@@ -0,0 +1,627 @@
+use crate::error::{Result, WhisperError};
+#[cfg(feature = "std-io")]
+use crate::{
+    audio::SAMPLE_RATE,
+    input,
+    model::Whisper,
+    token::Gpt2Tokenizer,
+    transcribe::{transcribe, TranscribeOptions},
+};
+#[cfg(feature = "std-io")]
+use burn::tensor::backend::Backend;
+use serde::{Deserialize, Serialize};
+use std::{fmt, ops::Add, path::PathBuf, sync::Arc};
+#[cfg(feature = "std-io")]
+use std::{fs, path::Path};
+
+/// Rewrites text before it is split into words, e.g. spelling out digits so that `21` and
+/// `twenty one` count as the same words.
+pub trait TextNormalizer: fmt::Debug + Send + Sync {
+    fn normalize(&self, text: &str) -> String;
+}
+
+/// How the reference and hypothesis are normalized before they are compared. The steps run in
+/// the order of the fields.
+#[derive(Clone, Debug)]
+pub struct NormalizeOptions {
+    pub lowercase: bool,
+    /// Run on the text before punctuation is stripped, so it still sees decimal points.
+    pub numbers: Option<Arc<dyn TextNormalizer>>,
+    /// Remove apostrophes and replace any other character that is neither a letter, a digit
+    /// nor whitespace with a space.
+    pub strip_punctuation: bool,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        Self {
+            lowercase: true,
+            numbers: None,
+            strip_punctuation: true,
+        }
+    }
+}
+
+impl NormalizeOptions {
+    /// Compares the text as is, only splitting it at whitespace.
+    pub fn none() -> Self {
+        Self {
+            lowercase: false,
+            numbers: None,
+            strip_punctuation: false,
+        }
+    }
+
+    /// Normalizes `text` and splits it into words.
+    pub fn words(&self, text: &str) -> Vec<String> {
+        let mut text = text.to_string();
+        if self.lowercase {
+            text = text.to_lowercase();
+        }
+        if let Some(numbers) = &self.numbers {
+            text = numbers.normalize(&text);
+        }
+        if self.strip_punctuation {
+            text = text
+                .chars()
+                .filter(|&c| c != '\'')
+                .map(|c| {
+                    if c.is_alphanumeric() || c.is_whitespace() {
+                        c
+                    } else {
+                        ' '
+                    }
+                })
+                .collect();
+        }
+
+        text.split_whitespace().map(str::to_string).collect()
+    }
+}
+
+/// One step of the alignment of a hypothesis to its reference.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum EditOp<T> {
+    Match {
+        reference: T,
+    },
+    Substitution {
+        reference: T,
+        hypothesis: T,
+    },
+    /// A reference item missing from the hypothesis.
+    Deletion {
+        reference: T,
+    },
+    /// A hypothesis item absent from the reference.
+    Insertion {
+        hypothesis: T,
+    },
+}
+
+/// Aligns `hypothesis` to `reference` with the fewest substitutions, deletions and insertions,
+/// each costing one.
+pub fn align<T: PartialEq + Clone>(reference: &[T], hypothesis: &[T]) -> Vec<EditOp<T>> {
+    let (n, m) = (reference.len(), hypothesis.len());
+    // distance[i][j] between the first i reference and the first j hypothesis items
+    let mut distance = vec![vec![0usize; m + 1]; n + 1];
+    distance[0] = (0..=m).collect();
+    for (i, row) in distance.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for i in 1..=n {
+        for j in 1..=m {
+            let substitution = (reference[i - 1] != hypothesis[j - 1]) as usize;
+            distance[i][j] = (distance[i - 1][j - 1] + substitution)
+                .min(distance[i - 1][j] + 1)
+                .min(distance[i][j - 1] + 1);
+        }
+    }
+
+    // walking back from the end, so extra or missing items are reported after the ones that
+    // were substituted rather than before them
+    let mut ops = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (n, m);
+    while i > 0 || j > 0 {
+        if i > 0
+            && j > 0
+            && reference[i - 1] == hypothesis[j - 1]
+            && distance[i][j] == distance[i - 1][j - 1]
+        {
+            ops.push(EditOp::Match {
+                reference: reference[i - 1].clone(),
+            });
+            i -= 1;
+            j -= 1;
+        } else if j > 0 && distance[i][j] == distance[i][j - 1] + 1 {
+            ops.push(EditOp::Insertion {
+                hypothesis: hypothesis[j - 1].clone(),
+            });
+            j -= 1;
+        } else if i > 0 && distance[i][j] == distance[i - 1][j] + 1 {
+            ops.push(EditOp::Deletion {
+                reference: reference[i - 1].clone(),
+            });
+            i -= 1;
+        } else {
+            ops.push(EditOp::Substitution {
+                reference: reference[i - 1].clone(),
+                hypothesis: hypothesis[j - 1].clone(),
+            });
+            i -= 1;
+            j -= 1;
+        }
+    }
+    ops.reverse();
+
+    ops
+}
+
+/// Edits needed to turn a reference into a hypothesis. Counts of several files add up to
+/// those of the corpus.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorCounts {
+    pub substitutions: usize,
+    pub deletions: usize,
+    pub insertions: usize,
+    /// Items of the reference, the denominator of the error rate.
+    pub reference_len: usize,
+}
+
+impl ErrorCounts {
+    pub fn from_ops<T>(ops: &[EditOp<T>]) -> Self {
+        let mut counts = Self::default();
+        for op in ops {
+            match op {
+                EditOp::Match { .. } => counts.reference_len += 1,
+                EditOp::Substitution { .. } => {
+                    counts.substitutions += 1;
+                    counts.reference_len += 1;
+                }
+                EditOp::Deletion { .. } => {
+                    counts.deletions += 1;
+                    counts.reference_len += 1;
+                }
+                EditOp::Insertion { .. } => counts.insertions += 1,
+            }
+        }
+
+        counts
+    }
+
+    pub fn errors(&self) -> usize {
+        self.substitutions + self.deletions + self.insertions
+    }
+
+    /// Errors per reference item. Insertions can push it above one. An empty reference has a
+    /// rate of zero if the hypothesis is empty too, and of one otherwise.
+    pub fn rate(&self) -> f64 {
+        match (self.reference_len, self.errors()) {
+            (0, 0) => 0.0,
+            (0, _) => 1.0,
+            (len, errors) => errors as f64 / len as f64,
+        }
+    }
+}
+
+impl Add for ErrorCounts {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            substitutions: self.substitutions + other.substitutions,
+            deletions: self.deletions + other.deletions,
+            insertions: self.insertions + other.insertions,
+            reference_len: self.reference_len + other.reference_len,
+        }
+    }
+}
+
+/// Word error counts of `hypothesis` against `reference` after normalization.
+pub fn word_errors(reference: &str, hypothesis: &str, options: &NormalizeOptions) -> ErrorCounts {
+    ErrorCounts::from_ops(&align(
+        &options.words(reference),
+        &options.words(hypothesis),
+    ))
+}
+
+/// Character error counts of `hypothesis` against `reference` after normalization. The words
+/// are joined by single spaces, which count as characters.
+pub fn char_errors(reference: &str, hypothesis: &str, options: &NormalizeOptions) -> ErrorCounts {
+    let chars = |text: &str| -> Vec<char> { options.words(text).join(" ").chars().collect() };
+
+    ErrorCounts::from_ops(&align(&chars(reference), &chars(hypothesis)))
+}
+
+/// Word error rate of `hypothesis` against `reference` after normalization.
+pub fn wer(reference: &str, hypothesis: &str, options: &NormalizeOptions) -> f64 {
+    word_errors(reference, hypothesis, options).rate()
+}
+
+/// Character error rate of `hypothesis` against `reference` after normalization.
+pub fn cer(reference: &str, hypothesis: &str, options: &NormalizeOptions) -> f64 {
+    char_errors(reference, hypothesis, options).rate()
+}
+
+/// An audio file and the text it should transcribe to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub audio: PathBuf,
+    #[serde(alias = "text")]
+    pub reference: String,
+}
+
+/// Parses a manifest of JSON lines with `audio` and `reference` (or `text`) fields, or of CSV
+/// lines `audio,reference` if `json` is not set. A CSV reference may be quoted, with `""`
+/// standing for a quote, and a first line of `audio,reference` or `audio,text` is a header.
+/// Blank lines are skipped.
+pub fn parse_manifest(contents: &str, json: bool) -> Result<Vec<ManifestEntry>> {
+    let invalid =
+        |line: usize, e: &str| WhisperError::InvalidOptions(format!("manifest line {line}: {e}"));
+
+    let mut entries = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let entry = if json {
+            serde_json::from_str(line).map_err(|e| invalid(index + 1, &e.to_string()))?
+        } else {
+            let (audio, reference) = line
+                .split_once(',')
+                .ok_or_else(|| invalid(index + 1, "expected audio,reference"))?;
+            if index == 0 && audio == "audio" && ["reference", "text"].contains(&reference) {
+                continue;
+            }
+            ManifestEntry {
+                audio: audio.trim().into(),
+                reference: unquote_csv(reference.trim()),
+            }
+        };
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn unquote_csv(field: &str) -> String {
+    match field
+        .strip_prefix('"')
+        .and_then(|field| field.strip_suffix('"'))
+    {
+        Some(quoted) => quoted.replace("\"\"", "\""),
+        None => field.to_string(),
+    }
+}
+
+/// Reads a manifest of JSON lines if its name ends in `.jsonl` or `.json`, and of CSV lines
+/// otherwise, see [`parse_manifest`]. Relative audio paths are resolved against the directory
+/// of the manifest.
+#[cfg(feature = "std-io")]
+pub fn read_manifest(path: impl AsRef<Path>) -> Result<Vec<ManifestEntry>> {
+    let path = path.as_ref();
+    let json = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("jsonl") || ext.eq_ignore_ascii_case("json"));
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut entries = parse_manifest(&fs::read_to_string(path)?, json)?;
+    for entry in &mut entries {
+        entry.audio = dir.join(&entry.audio);
+    }
+
+    Ok(entries)
+}
+
+/// Comparison of the transcription of one file with its reference.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileReport {
+    pub audio: PathBuf,
+    pub reference: String,
+    pub hypothesis: String,
+    pub words: ErrorCounts,
+    pub chars: ErrorCounts,
+    /// Word alignment of the normalized texts, showing where the errors are.
+    pub alignment: Vec<EditOp<String>>,
+}
+
+impl FileReport {
+    pub fn new(
+        audio: PathBuf,
+        reference: String,
+        hypothesis: String,
+        options: &NormalizeOptions,
+    ) -> Self {
+        let alignment = align(&options.words(&reference), &options.words(&hypothesis));
+
+        Self {
+            audio,
+            words: ErrorCounts::from_ops(&alignment),
+            chars: char_errors(&reference, &hypothesis, options),
+            reference,
+            hypothesis,
+            alignment,
+        }
+    }
+
+    pub fn wer(&self) -> f64 {
+        self.words.rate()
+    }
+
+    pub fn cer(&self) -> f64 {
+        self.chars.rate()
+    }
+
+    /// The alignment with errors in brackets: `[ref->hyp]` for substitutions, `[-ref]` for
+    /// deletions and `[+hyp]` for insertions.
+    pub fn diff(&self) -> String {
+        self.alignment
+            .iter()
+            .map(|op| match op {
+                EditOp::Match { reference } => reference.clone(),
+                EditOp::Substitution {
+                    reference,
+                    hypothesis,
+                } => format!("[{reference}->{hypothesis}]"),
+                EditOp::Deletion { reference } => format!("[-{reference}]"),
+                EditOp::Insertion { hypothesis } => format!("[+{hypothesis}]"),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Corpus error rates along with the report of every file. The corpus rates weigh each file
+/// by the length of its reference rather than averaging the rates of the files.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EvalReport {
+    pub words: ErrorCounts,
+    pub chars: ErrorCounts,
+    pub files: Vec<FileReport>,
+}
+
+impl EvalReport {
+    pub fn new(files: Vec<FileReport>) -> Self {
+        Self {
+            words: files
+                .iter()
+                .map(|file| file.words)
+                .fold(Default::default(), Add::add),
+            chars: files
+                .iter()
+                .map(|file| file.chars)
+                .fold(Default::default(), Add::add),
+            files,
+        }
+    }
+
+    pub fn wer(&self) -> f64 {
+        self.words.rate()
+    }
+
+    pub fn cer(&self) -> f64 {
+        self.chars.rate()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("reports only contain string map keys")
+    }
+}
+
+impl fmt::Display for EvalReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>7} {:>7} {:>5} {:>5} {:>5} {:>5}  file",
+            "wer %", "cer %", "sub", "del", "ins", "words"
+        )?;
+        let row = |f: &mut fmt::Formatter<'_>, words: &ErrorCounts, chars: &ErrorCounts| {
+            write!(
+                f,
+                "{:>7.2} {:>7.2} {:>5} {:>5} {:>5} {:>5}",
+                words.rate() * 100.0,
+                chars.rate() * 100.0,
+                words.substitutions,
+                words.deletions,
+                words.insertions,
+                words.reference_len
+            )
+        };
+        for file in &self.files {
+            row(f, &file.words, &file.chars)?;
+            writeln!(f, "  {}", file.audio.display())?;
+            if file.words.errors() > 0 {
+                writeln!(f, "{:>8}{}", "", file.diff())?;
+            }
+        }
+        row(f, &self.words, &self.chars)?;
+        writeln!(f, "  total of {} file(s)", self.files.len())
+    }
+}
+
+/// Transcribes every entry of a manifest with `options` and compares the text with the
+/// reference after normalizing both.
+#[cfg(feature = "std-io")]
+pub fn evaluate<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    entries: &[ManifestEntry],
+    options: &TranscribeOptions,
+    normalize: &NormalizeOptions,
+) -> Result<EvalReport> {
+    let files = entries
+        .iter()
+        .map(|entry| {
+            let audio = input::load_audio_file(&entry.audio)?;
+            let transcript = transcribe(whisper, bpe, &audio.waveform, SAMPLE_RATE, options)?;
+
+            Ok(FileReport::new(
+                entry.audio.clone(),
+                entry.reference.clone(),
+                transcript.text,
+                normalize,
+            ))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(EvalReport::new(files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(
+        substitutions: usize,
+        deletions: usize,
+        insertions: usize,
+        reference_len: usize,
+    ) -> ErrorCounts {
+        ErrorCounts {
+            substitutions,
+            deletions,
+            insertions,
+            reference_len,
+        }
+    }
+
+    #[derive(Debug)]
+    struct Digits;
+
+    impl TextNormalizer for Digits {
+        fn normalize(&self, text: &str) -> String {
+            text.replace("21", "twenty one")
+        }
+    }
+
+    #[test]
+    fn substitution_and_insertion_against_a_short_reference() {
+        let options = NormalizeOptions::default();
+        let errors = word_errors("the cat sat", "the cat sit on", &options);
+
+        assert_eq!(errors, counts(1, 0, 1, 3));
+        assert!((wer("the cat sat", "the cat sit on", &options) - 2.0 / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn alignment_reports_each_edit_in_order() {
+        let reference = ["a", "b", "c", "d"];
+        let hypothesis = ["a", "x", "c", "e", "f"];
+
+        assert_eq!(
+            align(&reference, &hypothesis),
+            vec![
+                EditOp::Match { reference: "a" },
+                EditOp::Substitution {
+                    reference: "b",
+                    hypothesis: "x"
+                },
+                EditOp::Match { reference: "c" },
+                EditOp::Substitution {
+                    reference: "d",
+                    hypothesis: "e"
+                },
+                EditOp::Insertion { hypothesis: "f" },
+            ]
+        );
+        assert_eq!(
+            ErrorCounts::from_ops(&align(&["a", "b", "c"], &["b"])),
+            counts(0, 2, 0, 3)
+        );
+    }
+
+    #[test]
+    fn insertions_can_push_the_rate_above_one() {
+        let options = NormalizeOptions::default();
+
+        assert_eq!(wer("yes", "yes yes yes", &options), 2.0);
+        assert_eq!(wer("", "", &options), 0.0);
+        assert_eq!(wer("", "anything", &options), 1.0);
+        assert_eq!(wer("gone", "", &options), 1.0);
+    }
+
+    #[test]
+    fn characters_count_the_spaces_between_words() {
+        let options = NormalizeOptions::default();
+
+        // k->s, e->i and an inserted g
+        assert_eq!(
+            char_errors("kitten", "sitting", &options),
+            counts(2, 0, 1, 6)
+        );
+        assert_eq!(cer("a b", "ab", &options), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn normalization_ignores_case_punctuation_and_spelled_numbers() {
+        let mut options = NormalizeOptions::default();
+        assert_eq!(wer("Don't stop, now!", "dont stop now", &options), 0.0);
+        assert_eq!(
+            wer("Don't stop", "dont stop", &NormalizeOptions::none()),
+            0.5
+        );
+        assert_eq!(wer("twenty one cats", "21 cats", &options), 2.0 / 3.0);
+
+        options.numbers = Some(Arc::new(Digits));
+        assert_eq!(wer("twenty one cats", "21 cats", &options), 0.0);
+    }
+
+    #[test]
+    fn corpus_rates_weigh_files_by_their_reference_length() {
+        let options = NormalizeOptions::default();
+        let report = EvalReport::new(vec![
+            FileReport::new("a.wav".into(), "one".into(), "two".into(), &options),
+            FileReport::new(
+                "b.wav".into(),
+                "one two three four five six seven eight nine".into(),
+                "one two three four five six seven eight nine".into(),
+                &options,
+            ),
+        ]);
+
+        assert_eq!(report.files[0].wer(), 1.0);
+        assert_eq!(report.words, counts(1, 0, 0, 10));
+        assert_eq!(report.wer(), 0.1);
+        assert_eq!(report.files[0].diff(), "[one->two]");
+    }
+
+    #[test]
+    fn diff_marks_every_kind_of_edit() {
+        let report = FileReport::new(
+            "a.wav".into(),
+            "the cat sat down".into(),
+            "the bat sat down here".into(),
+            &NormalizeOptions::default(),
+        );
+        assert_eq!(report.diff(), "the [cat->bat] sat down [+here]");
+
+        let report = FileReport::new(
+            "a.wav".into(),
+            "the cat sat".into(),
+            "the sat".into(),
+            &NormalizeOptions::default(),
+        );
+        assert_eq!(report.diff(), "the [-cat] sat");
+    }
+
+    #[test]
+    fn manifests_are_read_from_csv_and_json_lines() {
+        let csv = "audio,text\na.wav,hello there\n\nb.wav,\"say \"\"hi\"\", then go\"\n";
+        let entries = parse_manifest(csv, false).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].audio, PathBuf::from("a.wav"));
+        assert_eq!(entries[0].reference, "hello there");
+        assert_eq!(entries[1].reference, "say \"hi\", then go");
+
+        let json = "{\"audio\": \"a.wav\", \"text\": \"hello\"}\n{\"audio\": \"b.wav\", \"reference\": \"bye\"}";
+        let entries = parse_manifest(json, true).unwrap();
+        assert_eq!(entries[0].reference, "hello");
+        assert_eq!(entries[1].reference, "bye");
+
+        assert!(parse_manifest("a.wav", false).is_err());
+        assert!(parse_manifest("{\"audio\": \"a.wav\"}", true).is_err());
+    }
+}
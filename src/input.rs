@@ -0,0 +1,264 @@
+use crate::audio::{downmix, resample, SAMPLE_RATE};
+use crate::error::{Result, WhisperError};
+use hound::SampleFormat;
+use std::io::Read;
+use std::path::Path;
+
+/// A decoded audio file, mixed down to mono and resampled to [`SAMPLE_RATE`].
+#[derive(Clone, Debug)]
+pub struct AudioInput {
+    pub waveform: Vec<f32>,
+    /// Container format, e.g. `WAV` or `MP3`.
+    pub container: String,
+    /// Codec of the decoded track.
+    pub codec: String,
+    /// Sample rate and channel count of the source before conversion.
+    pub source_sample_rate: usize,
+    pub source_channels: usize,
+}
+
+impl AudioInput {
+    /// Duration of the audio in seconds.
+    pub fn duration(&self) -> f64 {
+        self.waveform.len() as f64 / SAMPLE_RATE as f64
+    }
+
+    fn new(
+        interleaved: &[f32],
+        container: String,
+        codec: String,
+        sample_rate: usize,
+        channels: usize,
+    ) -> Self {
+        let mono = downmix(interleaved, channels);
+
+        Self {
+            waveform: resample(&mono, sample_rate, SAMPLE_RATE),
+            container,
+            codec,
+            source_sample_rate: sample_rate,
+            source_channels: channels,
+        }
+    }
+}
+
+/// Sample encoding of raw PCM input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PcmFormat {
+    /// Little-endian 32-bit floats.
+    F32,
+    /// Little-endian signed 16-bit integers.
+    S16,
+}
+
+/// Reads headerless, interleaved PCM until the end of `reader`, e.g. piped from ffmpeg.
+pub fn read_raw_pcm(
+    mut reader: impl Read,
+    format: PcmFormat,
+    sample_rate: usize,
+    channels: usize,
+) -> Result<AudioInput> {
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(|e| WhisperError::AudioDecode {
+            timestamp: None,
+            source: Box::new(e),
+        })?;
+
+    let (samples, codec): (Vec<f32>, _) = match format {
+        PcmFormat::F32 => (
+            bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            "PCM f32le",
+        ),
+        PcmFormat::S16 => (
+            bytes
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32)
+                .collect(),
+            "PCM s16le",
+        ),
+    };
+
+    Ok(AudioInput::new(
+        &samples,
+        "raw".to_string(),
+        codec.to_string(),
+        sample_rate,
+        channels,
+    ))
+}
+
+/// Decodes an audio file. WAV is always supported, other formats require the
+/// `audio-formats` feature.
+pub fn load_audio_file(path: impl AsRef<Path>) -> Result<AudioInput> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+
+    match extension.as_deref() {
+        Some("wav") | Some("wave") => load_wav(path),
+        _ => load_other(path),
+    }
+}
+
+fn load_wav(path: &Path) -> Result<AudioInput> {
+    let decode_error = |e: hound::Error| WhisperError::AudioDecode {
+        timestamp: None,
+        source: Box::new(e),
+    };
+
+    let reader = hound::WavReader::open(path).map_err(decode_error)?;
+    let spec = reader.spec();
+
+    let channels = spec.channels as usize;
+    let sample_rate = spec.sample_rate as usize;
+    let bits_per_sample = spec.bits_per_sample;
+
+    let max_int_val = 2_u32.pow(bits_per_sample as u32 - 1) - 1;
+
+    let (floats, codec) = match spec.sample_format {
+        SampleFormat::Float => (
+            reader
+                .into_samples::<f32>()
+                .collect::<hound::Result<Vec<_>>>()
+                .map_err(decode_error)?,
+            format!("PCM f{bits_per_sample}"),
+        ),
+        SampleFormat::Int => (
+            reader
+                .into_samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / max_int_val as f32))
+                .collect::<hound::Result<Vec<_>>>()
+                .map_err(decode_error)?,
+            format!("PCM s{bits_per_sample}"),
+        ),
+    };
+
+    Ok(AudioInput::new(
+        &floats,
+        "WAV".to_string(),
+        codec,
+        sample_rate,
+        channels,
+    ))
+}
+
+#[cfg(not(feature = "audio-formats"))]
+fn load_other(path: &Path) -> Result<AudioInput> {
+    Err(WhisperError::AudioDecode {
+        timestamp: None,
+        source: format!(
+            "{} is not a WAV file, rebuild with `--features audio-formats` to decode other formats",
+            path.display()
+        )
+        .into(),
+    })
+}
+
+#[cfg(feature = "audio-formats")]
+fn load_other(path: &Path) -> Result<AudioInput> {
+    use symphonia::core::{
+        audio::SampleBuffer,
+        codecs::{DecoderOptions, CODEC_TYPE_NULL},
+        errors::Error as SymphoniaError,
+        formats::FormatOptions,
+        io::MediaSourceStream,
+        meta::MetadataOptions,
+        probe::Hint,
+    };
+
+    let decode_error = |timestamp: Option<f64>, e: SymphoniaError| WhisperError::AudioDecode {
+        timestamp,
+        source: Box::new(e),
+    };
+
+    let file = std::fs::File::open(path).map_err(|e| decode_error(None, e.into()))?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
+
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| decode_error(None, e))?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| decode_error(None, SymphoniaError::Unsupported("no audio track")))?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+
+    let codecs = symphonia::default::get_codecs();
+    let codec = codecs
+        .get_codec(params.codec)
+        .map(|descriptor| descriptor.short_name.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let mut decoder = codecs
+        .make(&params, &DecoderOptions::default())
+        .map_err(|e| decode_error(None, e))?;
+
+    let seconds = |ts: u64| {
+        params.time_base.map(|time_base| {
+            let time = time_base.calc_time(ts);
+            time.seconds as f64 + time.frac
+        })
+    };
+
+    let mut samples = Vec::new();
+    let mut sample_rate = params.sample_rate.unwrap_or(SAMPLE_RATE as u32) as usize;
+    let mut channels = params.channels.map(|c| c.count()).unwrap_or(1);
+    let mut last_ts = 0;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(decode_error(seconds(last_ts), e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        last_ts = packet.ts();
+
+        let decoded = decoder
+            .decode(&packet)
+            .map_err(|e| decode_error(seconds(packet.ts()), e))?;
+
+        let spec = *decoded.spec();
+        sample_rate = spec.rate as usize;
+        channels = spec.channels.count();
+
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend_from_slice(buffer.samples());
+    }
+
+    let container = extension.unwrap_or("unknown").to_uppercase();
+
+    Ok(AudioInput::new(
+        &samples,
+        container,
+        codec,
+        sample_rate,
+        channels,
+    ))
+}
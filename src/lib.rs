@@ -1,6 +1,18 @@
 pub mod audio;
 pub mod beam;
+#[cfg(feature = "std-io")]
+pub mod cache;
+pub mod checkpoint;
+pub mod error;
+pub mod eval;
 pub mod helper;
+#[cfg(feature = "std-io")]
+pub mod input;
+pub mod metrics;
 pub mod model;
+pub mod postprocess;
+#[cfg(feature = "tokio")]
+pub mod service;
 pub mod token;
+pub mod train;
 pub mod transcribe;
@@ -0,0 +1,121 @@
+use burn::tensor::backend::Backend;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use web_time::Instant;
+
+/// Where the time of a transcription went.
+///
+/// Mel spectrograms are computed ahead of the encoder, and the encoder may run on another
+/// device while the previous chunk decodes, so the stage times can add up to more than
+/// `wall_seconds`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Metrics {
+    /// One entry per chunk decoded in this run, in order.
+    pub chunks: Vec<ChunkMetrics>,
+    /// Seconds of audio transcribed in this run.
+    pub audio_seconds: f64,
+    /// Wall time of the run in seconds.
+    pub wall_seconds: f64,
+}
+
+/// Time spent on a single chunk, in seconds.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct ChunkMetrics {
+    pub mel_seconds: f64,
+    pub encode_seconds: f64,
+    /// Everything after the encoder, including temperature fallbacks and word alignment.
+    pub decode_seconds: f64,
+    /// Forward passes of the decoder.
+    pub decode_steps: usize,
+    /// Tokens in the accepted result.
+    pub tokens: usize,
+}
+
+impl ChunkMetrics {
+    pub fn seconds_per_step(&self) -> f64 {
+        self.decode_seconds / self.decode_steps.max(1) as f64
+    }
+}
+
+impl Metrics {
+    /// Wall time divided by the audio duration. Below one is faster than real time.
+    pub fn real_time_factor(&self) -> f64 {
+        if self.audio_seconds > 0.0 {
+            self.wall_seconds / self.audio_seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Sum of the metrics of all chunks.
+    pub fn total(&self) -> ChunkMetrics {
+        self.chunks
+            .iter()
+            .fold(ChunkMetrics::default(), |total, chunk| ChunkMetrics {
+                mel_seconds: total.mel_seconds + chunk.mel_seconds,
+                encode_seconds: total.encode_seconds + chunk.encode_seconds,
+                decode_seconds: total.decode_seconds + chunk.decode_seconds,
+                decode_steps: total.decode_steps + chunk.decode_steps,
+                tokens: total.tokens + chunk.tokens,
+            })
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>5} {:>8} {:>8} {:>8} {:>6} {:>9} {:>6}",
+            "chunk", "mel s", "encode s", "decode s", "steps", "ms/step", "tokens"
+        )?;
+        let row = |f: &mut fmt::Formatter<'_>, label: &str, chunk: &ChunkMetrics| {
+            writeln!(
+                f,
+                "{label:>5} {:>8.3} {:>8.3} {:>8.3} {:>6} {:>9.1} {:>6}",
+                chunk.mel_seconds,
+                chunk.encode_seconds,
+                chunk.decode_seconds,
+                chunk.decode_steps,
+                chunk.seconds_per_step() * 1000.0,
+                chunk.tokens
+            )
+        };
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            row(f, &index.to_string(), chunk)?;
+        }
+        row(f, "total", &self.total())?;
+        write!(
+            f,
+            "{:.1} s of audio in {:.1} s, real-time factor {:.3}",
+            self.audio_seconds,
+            self.wall_seconds,
+            self.real_time_factor()
+        )
+    }
+}
+
+/// Measures the wall time of a pipeline stage. Backends such as wgpu queue work and return
+/// immediately, so stopping the timer waits for the device to finish first; otherwise the
+/// time would be charged to whichever stage reads a tensor back next.
+pub(crate) struct Timer {
+    start: Instant,
+}
+
+impl Timer {
+    pub fn start() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+
+    /// Seconds since the timer started, once the queued work of `device` is done.
+    pub fn stop<B: Backend>(self, device: &B::Device) -> f64 {
+        B::sync(device);
+        self.start.elapsed().as_secs_f64()
+    }
+
+    /// Seconds since the timer started, for work that already finished on the CPU.
+    pub fn elapsed(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+}
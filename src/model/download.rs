@@ -0,0 +1,220 @@
+use crate::error::{Result, WhisperError};
+use std::path::{Path, PathBuf};
+
+/// Returns the directory holding `name/` once the model files of `repo_id` are cached.
+#[cfg(not(feature = "download"))]
+pub fn fetch_model(
+    repo_id: &str,
+    _revision: &str,
+    _name: &str,
+    _cache_dir: &Path,
+) -> Result<PathBuf> {
+    Err(WhisperError::Download {
+        url: repo_id.to_string(),
+        source: "downloading models requires rebuilding with `--features download`".into(),
+    })
+}
+
+/// Returns the directory holding `name/` once the model files of `repo_id` are cached,
+/// downloading the missing ones into `cache_dir/repo_id/revision/name/`.
+#[cfg(feature = "download")]
+pub fn fetch_model(repo_id: &str, revision: &str, name: &str, cache_dir: &Path) -> Result<PathBuf> {
+    use std::fs;
+
+    let dir = cache_dir.join(repo_id).join(revision);
+    let model_dir = dir.join(name);
+    let files = [
+        "tokenizer.json".to_string(),
+        format!("{name}.cfg"),
+        format!("{name}.mpk"),
+    ];
+    if files.iter().all(|file| model_dir.join(file).is_file()) {
+        return Ok(dir);
+    }
+
+    let repo = hub::Repo::fetch(repo_id, revision)?;
+    fs::create_dir_all(&model_dir)?;
+
+    for file in &files {
+        let path = model_dir.join(file);
+        if path.is_file() {
+            continue;
+        }
+
+        let remote = format!("{name}/{file}");
+        let compressed = format!("{remote}.gz");
+        match (repo.file(&remote), repo.file(&compressed)) {
+            (Some(entry), _) => repo.download(entry, &path, false)?,
+            // The published models store their weights gzip compressed.
+            (None, Some(entry)) if file.ends_with(".mpk") => repo.download(entry, &path, true)?,
+            _ => {
+                return Err(WhisperError::Download {
+                    url: repo.file_url(&remote),
+                    source: format!("{repo_id} has no file {remote}").into(),
+                })
+            }
+        }
+    }
+
+    Ok(dir)
+}
+
+#[cfg(feature = "download")]
+mod hub {
+    use crate::error::{Result, WhisperError};
+    use flate2::read::GzDecoder;
+    use serde::Deserialize;
+    use sha1::Sha1;
+    use sha2::{digest::DynDigest, Sha256};
+    use std::{
+        fs::{self, File},
+        io::{self, BufWriter, Read, Write},
+        path::{Path, PathBuf},
+    };
+
+    const ENDPOINT: &str = "https://huggingface.co";
+
+    /// File listing of a repository revision, as returned by the hub API.
+    #[derive(Deserialize)]
+    pub struct Repo {
+        #[serde(skip)]
+        repo_id: String,
+        #[serde(skip)]
+        revision: String,
+        siblings: Vec<RepoFile>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct RepoFile {
+        rfilename: String,
+        /// Git SHA-1 of the file, or of its pointer for LFS files.
+        #[serde(rename = "blobId")]
+        blob_id: String,
+        size: u64,
+        lfs: Option<LfsInfo>,
+    }
+
+    #[derive(Deserialize)]
+    struct LfsInfo {
+        sha256: String,
+    }
+
+    impl Repo {
+        pub fn fetch(repo_id: &str, revision: &str) -> Result<Self> {
+            let url = format!("{ENDPOINT}/api/models/{repo_id}/revision/{revision}?blobs=true");
+            let download_error =
+                |source: Box<dyn std::error::Error + Send + Sync>| WhisperError::Download {
+                    url: url.clone(),
+                    source,
+                };
+
+            let response = ureq::get(&url)
+                .call()
+                .map_err(|e| download_error(Box::new(e)))?;
+            let repo: Repo = serde_json::from_reader(response.into_reader())
+                .map_err(|e| download_error(Box::new(e)))?;
+
+            Ok(Repo {
+                repo_id: repo_id.to_string(),
+                revision: revision.to_string(),
+                ..repo
+            })
+        }
+
+        pub fn file(&self, name: &str) -> Option<&RepoFile> {
+            self.siblings.iter().find(|file| file.rfilename == name)
+        }
+
+        pub fn file_url(&self, name: &str) -> String {
+            format!(
+                "{ENDPOINT}/{}/resolve/{}/{name}",
+                self.repo_id, self.revision
+            )
+        }
+
+        /// Downloads `entry` to `path`, checking its hash before moving it into place.
+        pub fn download(&self, entry: &RepoFile, path: &Path, gunzip: bool) -> Result<()> {
+            let url = self.file_url(&entry.rfilename);
+            let download_error =
+                |source: Box<dyn std::error::Error + Send + Sync>| WhisperError::Download {
+                    url: url.clone(),
+                    source,
+                };
+
+            let response = ureq::get(&url)
+                .call()
+                .map_err(|e| download_error(Box::new(e)))?;
+            let partial = PathBuf::from(format!("{}.partial", path.display()));
+            let mut hasher = entry.hasher();
+            {
+                let mut reader = response.into_reader();
+                let mut writer = BufWriter::new(File::create(&partial)?);
+                let mut buffer = vec![0; 1 << 16];
+                loop {
+                    let n = reader
+                        .read(&mut buffer)
+                        .map_err(|e| download_error(Box::new(e)))?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buffer[..n]);
+                    writer.write_all(&buffer[..n])?;
+                }
+                writer.flush()?;
+            }
+
+            let actual: String = hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            let expected = entry.expected_hash();
+            if actual != expected {
+                let _ = fs::remove_file(&partial);
+                return Err(WhisperError::WeightsCorrupt {
+                    path: url.clone(),
+                    source: format!("downloaded file hash {actual} does not match {expected}")
+                        .into(),
+                });
+            }
+
+            if gunzip {
+                let compressed = partial.with_extension("partial.gz");
+                fs::rename(&partial, &compressed)?;
+                let mut decoder = GzDecoder::new(File::open(&compressed)?);
+                let mut writer = BufWriter::new(File::create(&partial)?);
+                io::copy(&mut decoder, &mut writer).map_err(|e| WhisperError::WeightsCorrupt {
+                    path: url.clone(),
+                    source: Box::new(e),
+                })?;
+                writer.flush()?;
+                fs::remove_file(&compressed)?;
+            }
+
+            fs::rename(&partial, path)?;
+            Ok(())
+        }
+    }
+
+    impl RepoFile {
+        /// Hasher matching [`RepoFile::expected_hash`]. Git hashes a header holding the
+        /// file size before the contents.
+        fn hasher(&self) -> Box<dyn DynDigest> {
+            match &self.lfs {
+                Some(_) => Box::new(Sha256::default()),
+                None => {
+                    let mut hasher: Box<dyn DynDigest> = Box::new(Sha1::default());
+                    hasher.update(format!("blob {}\0", self.size).as_bytes());
+                    hasher
+                }
+            }
+        }
+
+        fn expected_hash(&self) -> &str {
+            match &self.lfs {
+                Some(lfs) => &lfs.sha256,
+                None => &self.blob_id,
+            }
+        }
+    }
+}
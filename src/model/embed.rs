@@ -0,0 +1,83 @@
+use super::*;
+
+use crate::audio::{max_waveform_samples, prep_audio, resample, MIN_SAMPLES, SAMPLE_RATE};
+
+/// How the encoder output of the audio positions of a window is reduced to one embedding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pooling {
+    /// The mean of all positions.
+    #[default]
+    Mean,
+    /// The largest value of each dimension across the positions.
+    Max,
+    /// The first position, in place of a class token, which whisper lacks. It mostly reflects
+    /// the start of each window.
+    Cls,
+    /// Every position, one row per 20 ms of audio.
+    None,
+}
+
+impl<B: Backend> AudioEncoder<B> {
+    /// Encodes `waveform` into an embedding of shape `[1, n_state]`, or `[n_positions, n_state]`
+    /// without pooling. Audio longer than the encoder accepts, i.e. 30 seconds, is split into
+    /// windows whose pooled embeddings are averaged weighted by their length.
+    pub fn embed_audio(
+        &self,
+        waveform: &[f32],
+        sample_rate: usize,
+        pooling: Pooling,
+    ) -> error::Result<Tensor<B, 2>> {
+        let waveform = resample(waveform, sample_rate, SAMPLE_RATE);
+        if waveform.len() < MIN_SAMPLES {
+            return Err(WhisperError::AudioTooShort {
+                n_samples: waveform.len(),
+                min_samples: MIN_SAMPLES,
+            });
+        }
+
+        let device = self.devices()[0].clone();
+        let window = max_waveform_samples(2 * self.n_audio_ctx);
+        let mut embeddings = Vec::new();
+        for chunk in waveform.chunks(window) {
+            // a trailing chunk shorter than one FFT window is padded with silence
+            let mut samples = chunk.to_vec();
+            samples.resize(samples.len().max(MIN_SAMPLES), 0.0);
+            let samples: Tensor<B, 1> = Tensor::from_floats(samples.as_slice(), &device);
+            let mels = prep_audio(samples.unsqueeze(), SAMPLE_RATE as f64, self.n_mels)?;
+
+            let output = self.forward(mels)?;
+            let [_, n_positions, n_state] = output.dims();
+            let embedding = match pooling {
+                Pooling::Mean => output.mean_dim(1).reshape([1, n_state]),
+                Pooling::Max => output.max_dim(1).reshape([1, n_state]),
+                Pooling::Cls => output.slice([0..1, 0..1]).reshape([1, n_state]),
+                Pooling::None => output.reshape([n_positions, n_state]),
+            };
+            let weight = chunk.len() as f32 / waveform.len() as f32;
+            embeddings.push((embedding, weight));
+        }
+
+        if pooling == Pooling::None {
+            let positions = embeddings.into_iter().map(|(embedding, _)| embedding);
+            return Ok(Tensor::cat(positions.collect(), 0));
+        }
+
+        Ok(embeddings
+            .into_iter()
+            .map(|(embedding, weight)| embedding.mul_scalar(weight))
+            .reduce(|sum, embedding| sum + embedding)
+            .expect("the waveform holds at least one window"))
+    }
+}
+
+impl<B: Backend> Whisper<B> {
+    /// See [`AudioEncoder::embed_audio`].
+    pub fn embed_audio(
+        &self,
+        waveform: &[f32],
+        sample_rate: usize,
+        pooling: Pooling,
+    ) -> error::Result<Tensor<B, 2>> {
+        self.encoder.embed_audio(waveform, sample_rate, pooling)
+    }
+}
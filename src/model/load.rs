@@ -10,13 +10,28 @@ use burn::{
 
 use super::*;
 
+use crate::error::{Result, WhisperError};
+use crate::token::{Gpt2Tokenizer, VocabKind};
+use burn::module::{ModuleMapper, ParamId};
+#[cfg(feature = "std-io")]
+use burn::record::NamedMpkFileRecorder;
+#[cfg(feature = "std-io")]
+use burn::record::Record;
+use burn::record::{FullPrecisionSettings, NamedMpkBytesRecorder, Recorder};
 use burn::tensor::Shape;
 use npyz::{self, NpyFile, NpyReader};
-use std::{error::Error, fs::File};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::error::Error;
+use std::fs::File;
+#[cfg(feature = "std-io")]
+use std::path::{Path, PathBuf};
+use std::result;
 
 // TODO put in Burn
-fn numpy_to_tensor<B: Backend, const D: usize>(numpy_data: NpyReader<f32, File>) -> Tensor<B, D> {
-    let v = numpy_data.map(|x| x.unwrap()).collect::<Vec<f32>>();
+fn numpy_to_tensor<B: Backend, const D: usize>(
+    numpy_data: NpyReader<f32, File>,
+) -> std::io::Result<Tensor<B, D>> {
+    let v = numpy_data.collect::<std::io::Result<Vec<f32>>>()?;
 
     let shape: Shape = v[0..D]
         .iter()
@@ -26,35 +41,36 @@ fn numpy_to_tensor<B: Backend, const D: usize>(numpy_data: NpyReader<f32, File>)
 
     let tensor_device_ref = Default::default();
 
-    Tensor::<B, D>::from_floats(
+    Ok(Tensor::<B, D>::from_floats(
         TensorData::new(v[D..].to_vec(), shape).convert::<f32>(),
         &tensor_device_ref,
-    )
+    ))
 }
 
-fn load_tensor<B: Backend, const D: usize>(
-    name: &str,
-    path: &str,
-) -> Result<Tensor<B, D>, Box<dyn Error>> {
+fn load_tensor<B: Backend, const D: usize>(name: &str, path: &str) -> Result<Tensor<B, D>> {
     let tensor_path = format!("{path}/{name}.npy");
-    let file = std::fs::File::open(tensor_path)?;
-
-    let tensor_reader: NpyReader<f32, std::fs::File> = NpyFile::new(file)?.data()?;
 
-    let tensor = numpy_to_tensor(tensor_reader);
+    let load = || -> result::Result<Tensor<B, D>, Box<dyn Error + Send + Sync>> {
+        let file = std::fs::File::open(&tensor_path)?;
+        let tensor_reader: NpyReader<f32, std::fs::File> = NpyFile::new(file)?.data()?;
+        Ok(numpy_to_tensor(tensor_reader)?)
+    };
 
-    Ok(tensor)
+    load().map_err(|e| WhisperError::ModelLoad {
+        path: tensor_path.clone(),
+        source: e,
+    })
 }
 
-fn load_f32<B: Backend>(name: &str, path: &str) -> Result<f32, Box<dyn Error>> {
+fn load_f32<B: Backend>(name: &str, path: &str) -> Result<f32> {
     load_tensor::<B, 1>(name, path).map(|t| t.into_scalar().to_f32())
 }
 
-fn load_usize<B: Backend>(name: &str, path: &str) -> Result<usize, Box<dyn Error>> {
+fn load_usize<B: Backend>(name: &str, path: &str) -> Result<usize> {
     load_tensor::<B, 1>(name, path).map(|t| t.into_scalar().to_usize())
 }
 
-fn load_linear<B: Backend>(path: &str) -> Result<nn::Linear<B>, Box<dyn Error>> {
+fn load_linear<B: Backend, L: From<nn::Linear<B>>>(path: &str) -> Result<L> {
     let weight = load_tensor::<B, 2>("weight", path)?;
     let bias = load_tensor::<B, 1>("bias", path).ok();
     let tensor_device_ref = weight.device();
@@ -67,10 +83,10 @@ fn load_linear<B: Backend>(path: &str) -> Result<nn::Linear<B>, Box<dyn Error>>
     let linear: nn::Linear<B> = nn::LinearConfig::new(3, 3)
         .init(&tensor_device_ref)
         .load_record(record);
-    Ok(linear)
+    Ok(linear.into())
 }
 
-fn load_layer_norm<B: Backend>(path: &str) -> Result<nn::LayerNorm<B>, Box<dyn Error>> {
+fn load_layer_norm<B: Backend>(path: &str) -> Result<nn::LayerNorm<B>> {
     let weight = Param::from_tensor(load_tensor::<B, 1>("weight", path)?);
     let bias = Param::from_tensor(load_tensor::<B, 1>("bias", path)?);
     let eps = load_f32::<B>("eps", path)? as f64;
@@ -91,9 +107,9 @@ fn load_layer_norm<B: Backend>(path: &str) -> Result<nn::LayerNorm<B>, Box<dyn E
     Ok(layer_norm)
 }
 
-fn load_multihead_self_attention<B: Backend>(
+fn load_multihead_self_attention<B: Backend, L: From<nn::Linear<B>>>(
     path: &str,
-) -> Result<MultiHeadSelfAttention<B>, Box<dyn Error>> {
+) -> Result<MultiHeadSelfAttention<B, L>> {
     let query = load_linear(&format!("{}/{}", path, "query"))?;
     let key = load_linear(&format!("{}/{}", path, "key"))?;
     let value = load_linear(&format!("{}/{}", path, "value"))?;
@@ -107,14 +123,13 @@ fn load_multihead_self_attention<B: Backend>(
         key,
         value,
         out,
+        backend: PhantomData,
     };
 
     Ok(attention_block)
 }
 
-fn load_multihead_cross_attention<B: Backend>(
-    path: &str,
-) -> Result<MultiHeadCrossAttention<B>, Box<dyn Error>> {
+fn load_multihead_cross_attention<B: Backend>(path: &str) -> Result<MultiHeadCrossAttention<B>> {
     let query = load_linear(&format!("{}/{}", path, "query"))?;
     let key = load_linear(&format!("{}/{}", path, "key"))?;
     let value = load_linear(&format!("{}/{}", path, "value"))?;
@@ -134,7 +149,7 @@ fn load_multihead_cross_attention<B: Backend>(
     Ok(attention_block)
 }
 
-fn load_mlp<B: Backend>(path: &str) -> Result<MLP<B>, Box<dyn Error>> {
+fn load_mlp<B: Backend, L: From<nn::Linear<B>>>(path: &str) -> Result<MLP<B, L>> {
     let lin1 = load_linear(&format!("{}/{}", path, "mlp1"))?;
     let lin2 = load_linear(&format!("{}/{}", path, "mlp2"))?;
 
@@ -144,12 +159,13 @@ fn load_mlp<B: Backend>(path: &str) -> Result<MLP<B>, Box<dyn Error>> {
         lin1,
         lin2,
         gelu,
+        backend: PhantomData,
     };
 
     Ok(mlp)
 }
 
-fn load_conv1d<B: Backend>(path: &str, config: Conv1dConfig) -> Result<Conv1d<B>, Box<dyn Error>> {
+fn load_conv1d<B: Backend>(path: &str, config: Conv1dConfig) -> Result<Conv1d<B>> {
     let weight = Param::from_tensor(load_tensor::<B, 3>("weight", path)?);
     let bias = Param::from_tensor(load_tensor::<B, 1>("bias", path)?);
     let tensor_device_ref = weight.device();
@@ -170,7 +186,7 @@ fn load_conv1d<B: Backend>(path: &str, config: Conv1dConfig) -> Result<Conv1d<B>
 
 fn load_residual_encoder_attention_block<B: Backend>(
     path: &str,
-) -> Result<ResidualEncoderAttentionBlock<B>, Box<dyn Error>> {
+) -> Result<ResidualEncoderAttentionBlock<B>> {
     let attn = load_multihead_self_attention(&format!("{}/{}", path, "attn"))?;
     let attn_ln = load_layer_norm(&format!("{}/{}", path, "attn_ln"))?;
     let mlp = load_mlp(&format!("{}/{}", path, "mlp"))?;
@@ -188,7 +204,7 @@ fn load_residual_encoder_attention_block<B: Backend>(
 
 fn load_residual_decoder_attention_block<B: Backend>(
     path: &str,
-) -> Result<ResidualDecoderAttentionBlock<B>, Box<dyn Error>> {
+) -> Result<ResidualDecoderAttentionBlock<B>> {
     let attn = load_multihead_self_attention(&format!("{}/{}", path, "attn"))?;
     let attn_ln = load_layer_norm(&format!("{}/{}", path, "attn_ln"))?;
     let cross_attn = load_multihead_cross_attention(&format!("{}/{}", path, "cross_attn"))?;
@@ -208,9 +224,7 @@ fn load_residual_decoder_attention_block<B: Backend>(
     Ok(residual_block)
 }
 
-fn load_audio_encoder<B: Backend>(
-    path: &str,
-) -> Result<(AudioEncoder<B>, AudioEncoderConfig), Box<dyn Error>> {
+fn load_audio_encoder<B: Backend>(path: &str) -> Result<(AudioEncoder<B>, AudioEncoderConfig)> {
     let n_mels = load_usize::<B>("n_mels", path)?;
     let n_audio_state = load_usize::<B>("n_audio_state", path)?;
 
@@ -227,7 +241,7 @@ fn load_audio_encoder<B: Backend>(
 
     let blocks: Vec<ResidualEncoderAttentionBlock<B>> = (0..n_layer)
         .map(|i| load_residual_encoder_attention_block(&format!("{path}/block_{i}")))
-        .collect::<Result<_, _>>()?;
+        .collect::<Result<_>>()?;
 
     let ln_post = load_layer_norm(&format!("{}/{}", path, "ln_post"))?;
     let positional_embedding =
@@ -260,9 +274,7 @@ fn load_audio_encoder<B: Backend>(
     Ok((audio_encoder, config))
 }
 
-fn load_text_decoder<B: Backend>(
-    path: &str,
-) -> Result<(TextDecoder<B>, TextDecoderConfig), Box<dyn Error>> {
+fn load_text_decoder<B: Backend>(path: &str) -> Result<(TextDecoder<B>, TextDecoderConfig)> {
     let token_embedding = load_tensor::<B, 2>("token_embedding/weight", path)?;
     let positional_embedding = load_tensor::<B, 2>("positional_embedding", path)?;
     let tensor_device_ref = token_embedding.device();
@@ -270,7 +282,7 @@ fn load_text_decoder<B: Backend>(
     let n_layer = load_usize::<B>("n_layer", path)?;
     let blocks: Vec<ResidualDecoderAttentionBlock<B>> = (0..n_layer)
         .map(|i| load_residual_decoder_attention_block(&format!("{path}/block_{i}")))
-        .collect::<Result<_, _>>()?;
+        .collect::<Result<_>>()?;
 
     let n_text_head = blocks[0].attn.n_head;
 
@@ -287,7 +299,7 @@ fn load_text_decoder<B: Backend>(
         positional_embedding: Param::from_tensor(positional_embedding),
         blocks,
         ln,
-        mask: Param::from_tensor(mask),
+        mask,
         n_text_ctx,
         n_vocab,
     };
@@ -303,13 +315,10 @@ fn load_text_decoder<B: Backend>(
     Ok((text_decoder, config))
 }
 
-pub fn load_whisper<B: Backend>(path: &str) -> Result<(Whisper<B>, WhisperConfig), Box<dyn Error>> {
+pub fn load_whisper<B: Backend>(path: &str) -> Result<(Whisper<B>, WhisperConfig)> {
     let (encoder, encoder_config) = load_audio_encoder(&format!("{}/{}", path, "encoder"))?;
     let (decoder, decoder_config) = load_text_decoder(&format!("{}/{}", path, "decoder"))?;
-    let whisper = Whisper {
-        encoder,
-        decoder,
-    };
+    let whisper = Whisper { encoder, decoder };
 
     let config = WhisperConfig {
         audio_encoder_config: encoder_config,
@@ -318,3 +327,349 @@ pub fn load_whisper<B: Backend>(path: &str) -> Result<(Whisper<B>, WhisperConfig
 
     Ok((whisper, config))
 }
+
+/// Builds a model with random weights from `config`, the same weights for the same `seed`.
+/// Tests use it with a tiny config to run the whole pipeline without a converted model.
+///
+/// Unlike [`WhisperConfig::init`], which draws from the backend's global generator, the weights
+/// only depend on `seed`, so tests running in parallel get the same model. Constant weights such
+/// as layer norm scales keep their value; the others are drawn uniformly within
+/// `1 / sqrt(fan_in)`, like the default linear and convolution initializers, where the fan-in is
+/// taken from the shape alone so the draw never depends on the initial values.
+pub fn random_whisper<B: Backend>(
+    config: &WhisperConfig,
+    seed: u64,
+    tensor_device_ref: &B::Device,
+) -> Whisper<B> {
+    struct Reseed(StdRng);
+
+    impl<B: Backend> ModuleMapper<B> for Reseed {
+        fn map_float<const D: usize>(
+            &mut self,
+            _id: ParamId,
+            tensor: Tensor<B, D>,
+        ) -> Tensor<B, D> {
+            let shape = tensor.shape();
+            let device = tensor.device();
+            let values = tensor.into_data().to_vec::<f32>().unwrap();
+
+            if values.windows(2).all(|pair| pair[0] == pair[1]) {
+                return Tensor::from_data(TensorData::new(values, shape), &device);
+            }
+
+            let fan_in: usize = match shape.dims.as_slice() {
+                [n] => *n,
+                [_, rest @ ..] => rest.iter().product(),
+                [] => 1,
+            };
+            let bound = 1.0 / (fan_in.max(1) as f32).sqrt();
+            let values: Vec<f32> = values
+                .iter()
+                .map(|_| self.0.random_range(-bound..=bound))
+                .collect();
+
+            Tensor::from_data(TensorData::new(values, shape), &device)
+        }
+    }
+
+    config
+        .init(tensor_device_ref)
+        .map(&mut Reseed(StdRng::seed_from_u64(seed)))
+}
+
+/// Builds a model from a saved record, quantizing it first if the record holds int8 weights
+/// so the layer variants match.
+pub fn whisper_from_record<B: Backend>(
+    config: &WhisperConfig,
+    record: WhisperRecord<B>,
+    tensor_device_ref: &B::Device,
+) -> Whisper<B> {
+    let whisper = config.init(tensor_device_ref);
+    let whisper = if record.is_quantized() {
+        whisper.quantize()
+    } else {
+        whisper
+    };
+
+    whisper.load_record(record)
+}
+
+impl<B: Backend> Whisper<B> {
+    /// Builds a model from the contents of a `.mpk` file saved with [`NamedMpkFileRecorder`],
+    /// e.g. fetched over the network or embedded with `include_bytes!`.
+    pub fn from_record_bytes(
+        config: &WhisperConfig,
+        bytes: Vec<u8>,
+        tensor_device_ref: &B::Device,
+    ) -> Result<Self> {
+        let record = NamedMpkBytesRecorder::<FullPrecisionSettings>::new()
+            .load(bytes, tensor_device_ref)
+            .map_err(|e| WhisperError::WeightsCorrupt {
+                path: "in-memory record".to_string(),
+                source: Box::new(e),
+            })?;
+
+        Ok(whisper_from_record(config, record, tensor_device_ref))
+    }
+}
+
+/// Like [`load_named_model`], but from the contents of `tokenizer.json`, `name.cfg` and
+/// `name.mpk`, without touching the file system.
+pub fn load_model_bytes<B: Backend>(
+    tokenizer: &[u8],
+    config: &[u8],
+    weights: Vec<u8>,
+    tensor_device_ref: &B::Device,
+) -> Result<(Gpt2Tokenizer, WhisperConfig, Whisper<B>)> {
+    let whisper_config =
+        WhisperConfig::load_binary(config).map_err(|e| WhisperError::ModelLoad {
+            path: "in-memory config".to_string(),
+            source: Box::new(e),
+        })?;
+    let vocab_kind = VocabKind::from_n_vocab(whisper_config.n_vocab());
+    let bpe =
+        Gpt2Tokenizer::from_bytes(tokenizer, vocab_kind).map_err(|e| WhisperError::ModelLoad {
+            path: "in-memory tokenizer".to_string(),
+            source: e,
+        })?;
+    let whisper = Whisper::from_record_bytes(&whisper_config, weights, tensor_device_ref)?;
+
+    Ok((bpe, whisper_config, whisper))
+}
+
+/// Loads the tokenizer, config and weights of the converted model `name`, stored under
+/// `dir/name/` as `tokenizer.json`, `name.cfg` and `name.mpk`.
+#[cfg(feature = "std-io")]
+pub fn load_named_model<B: Backend>(
+    dir: &Path,
+    name: &str,
+    tensor_device_ref: &B::Device,
+) -> Result<(Gpt2Tokenizer, WhisperConfig, Whisper<B>)> {
+    let (model_dir, whisper_config) = load_named_config(dir, name)?;
+
+    // English-only models use a vocabulary with different special token ids
+    let tokenizer_path = model_dir.join("tokenizer.json");
+    let vocab_kind = VocabKind::from_n_vocab(whisper_config.n_vocab());
+    let bpe = Gpt2Tokenizer::from_file(&tokenizer_path, vocab_kind).map_err(|e| {
+        WhisperError::ModelLoad {
+            path: tokenizer_path.display().to_string(),
+            source: e,
+        }
+    })?;
+
+    let weights_path = model_dir.join(name);
+    let record = NamedMpkFileRecorder::<FullPrecisionSettings>::new()
+        .load(weights_path.clone(), tensor_device_ref)
+        .map_err(|e| WhisperError::WeightsCorrupt {
+            path: format!("{}.mpk", weights_path.display()),
+            source: Box::new(e),
+        })?;
+    let whisper = whisper_from_record(&whisper_config, record, tensor_device_ref);
+
+    Ok((bpe, whisper_config, whisper.to_device(tensor_device_ref)))
+}
+
+/// A [`Whisper`] without its decoder. The record of a full model loads into its record, which
+/// skips the decoder's weights instead of allocating them.
+#[cfg(feature = "std-io")]
+#[derive(Module, Debug)]
+struct EncoderOnly<B: Backend> {
+    encoder: AudioEncoder<B>,
+}
+
+/// Like [`load_named_model`], but loads the audio encoder alone, e.g. to compute embeddings
+/// with [`AudioEncoder::embed_audio`] without the memory of the decoder.
+#[cfg(feature = "std-io")]
+pub fn load_named_encoder<B: Backend>(
+    dir: &Path,
+    name: &str,
+    tensor_device_ref: &B::Device,
+) -> Result<(WhisperConfig, AudioEncoder<B>)> {
+    let (model_dir, whisper_config) = load_named_config(dir, name)?;
+
+    let weights_path = model_dir.join(name);
+    let record: EncoderOnlyRecord<B> = NamedMpkFileRecorder::<FullPrecisionSettings>::new()
+        .load(weights_path.clone(), tensor_device_ref)
+        .map_err(|e| WhisperError::WeightsCorrupt {
+            path: format!("{}.mpk", weights_path.display()),
+            source: Box::new(e),
+        })?;
+    let encoder = EncoderOnly {
+        encoder: whisper_config.audio_encoder_config.init(tensor_device_ref),
+    }
+    .load_record(record)
+    .encoder;
+
+    Ok((whisper_config, encoder.to_device(tensor_device_ref)))
+}
+
+/// Loads an audio encoder saved with [`save_encoder`] from `path.mpk`. Paired with a decoder
+/// from another checkpoint by [`Whisper::from_parts`].
+#[cfg(feature = "std-io")]
+pub fn load_encoder<B: Backend>(
+    path: &Path,
+    config: &AudioEncoderConfig,
+    tensor_device_ref: &B::Device,
+) -> Result<AudioEncoder<B>> {
+    let record = load_record(path, tensor_device_ref)?;
+
+    Ok(config.init(tensor_device_ref).load_record(record))
+}
+
+/// Loads a text decoder saved with [`save_decoder`] from `path.mpk`, quantizing it first if
+/// the record holds int8 weights. The layer count is independent of the encoder's.
+#[cfg(feature = "std-io")]
+pub fn load_decoder<B: Backend>(
+    path: &Path,
+    config: &TextDecoderConfig,
+    tensor_device_ref: &B::Device,
+) -> Result<TextDecoder<B>> {
+    let record: TextDecoderRecord<B> = load_record(path, tensor_device_ref)?;
+    let decoder = config.init(tensor_device_ref);
+    let decoder = if record.is_quantized() {
+        decoder.quantize()
+    } else {
+        decoder
+    };
+
+    Ok(decoder.load_record(record))
+}
+
+/// Saves the audio encoder alone to `path.mpk`, to be read back with [`load_encoder`].
+#[cfg(feature = "std-io")]
+pub fn save_encoder<B: Backend>(encoder: AudioEncoder<B>, path: &Path) -> Result<()> {
+    save_record(encoder.into_record(), path)
+}
+
+/// Saves the text decoder alone to `path.mpk`, to be read back with [`load_decoder`].
+#[cfg(feature = "std-io")]
+pub fn save_decoder<B: Backend>(decoder: TextDecoder<B>, path: &Path) -> Result<()> {
+    save_record(decoder.into_record(), path)
+}
+
+#[cfg(feature = "std-io")]
+fn load_record<B: Backend, R: Record<B>>(path: &Path, tensor_device_ref: &B::Device) -> Result<R> {
+    NamedMpkFileRecorder::<FullPrecisionSettings>::new()
+        .load(path.to_path_buf(), tensor_device_ref)
+        .map_err(|e| WhisperError::WeightsCorrupt {
+            path: format!("{}.mpk", path.display()),
+            source: Box::new(e),
+        })
+}
+
+#[cfg(feature = "std-io")]
+pub(super) fn save_record<B: Backend, R: Record<B>>(record: R, path: &Path) -> Result<()> {
+    // quantized weights are saved as i8, everything else at full precision
+    NamedMpkFileRecorder::<QuantizedPrecisionSettings>::new()
+        .record(record, path.to_path_buf())
+        .map_err(|e| WhisperError::Io(std::io::Error::other(e)))
+}
+
+/// Reads `dir/name/name.cfg`, returning the model's directory along with it.
+#[cfg(feature = "std-io")]
+fn load_named_config(dir: &Path, name: &str) -> Result<(PathBuf, WhisperConfig)> {
+    let model_dir = dir.join(name);
+    if !model_dir.is_dir() {
+        return Err(WhisperError::ModelDirMissing(
+            model_dir.display().to_string(),
+        ));
+    }
+
+    let config_path = model_dir.join(format!("{name}.cfg"));
+    if !config_path.is_file() {
+        return Err(WhisperError::ConfigMissing(
+            config_path.display().to_string(),
+        ));
+    }
+    let whisper_config =
+        WhisperConfig::load(&config_path).map_err(|e| WhisperError::ModelLoad {
+            path: config_path.display().to_string(),
+            source: Box::new(e),
+        })?;
+
+    Ok((model_dir, whisper_config))
+}
+
+/// Where a converted model is loaded from.
+#[cfg(feature = "std-io")]
+#[derive(Clone, Debug)]
+pub enum WhisperSource {
+    /// A model stored on disk in the layout read by [`load_named_model`].
+    Local { dir: PathBuf, name: String },
+    /// The model `name` from a Hugging Face repository that stores each model in its own
+    /// directory, like the `Gadersd/whisper-burn` repository. The files are downloaded into
+    /// `cache_dir` and verified against the hashes published by the hub on first use.
+    /// Requires the `download` feature.
+    HuggingFaceRepo {
+        repo_id: String,
+        revision: String,
+        name: String,
+        cache_dir: PathBuf,
+    },
+}
+
+#[cfg(feature = "std-io")]
+impl WhisperSource {
+    /// Name of the model within its directory or repository.
+    pub fn name(&self) -> &str {
+        match self {
+            WhisperSource::Local { name, .. } => name,
+            WhisperSource::HuggingFaceRepo { name, .. } => name,
+        }
+    }
+
+    /// Path of the weights file, which only exists once the model has been loaded.
+    pub fn weights_path(&self) -> PathBuf {
+        match self {
+            WhisperSource::Local { dir, name } => dir.join(name).join(format!("{name}.mpk")),
+            WhisperSource::HuggingFaceRepo {
+                repo_id,
+                revision,
+                name,
+                cache_dir,
+            } => cache_dir
+                .join(repo_id)
+                .join(revision)
+                .join(name)
+                .join(format!("{name}.mpk")),
+        }
+    }
+
+    /// Loads the model, downloading it first if it is not cached yet.
+    pub fn load<B: Backend>(
+        &self,
+        tensor_device_ref: &B::Device,
+    ) -> Result<(Gpt2Tokenizer, WhisperConfig, Whisper<B>)> {
+        match self {
+            WhisperSource::Local { dir, name } => load_named_model(dir, name, tensor_device_ref),
+            WhisperSource::HuggingFaceRepo {
+                repo_id,
+                revision,
+                name,
+                cache_dir,
+            } => {
+                let dir = download::fetch_model(repo_id, revision, name, cache_dir)?;
+                load_named_model(&dir, name, tensor_device_ref)
+            }
+        }
+    }
+
+    /// Loads the audio encoder alone, downloading the model first if it is not cached yet.
+    pub fn load_encoder<B: Backend>(
+        &self,
+        tensor_device_ref: &B::Device,
+    ) -> Result<(WhisperConfig, AudioEncoder<B>)> {
+        match self {
+            WhisperSource::Local { dir, name } => load_named_encoder(dir, name, tensor_device_ref),
+            WhisperSource::HuggingFaceRepo {
+                repo_id,
+                revision,
+                name,
+                cache_dir,
+            } => {
+                let dir = download::fetch_model(repo_id, revision, name, cache_dir)?;
+                load_named_encoder(&dir, name, tensor_device_ref)
+            }
+        }
+    }
+}
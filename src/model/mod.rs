@@ -1,5 +1,18 @@
 #![allow(clippy::single_range_in_vec_init)]
+#[cfg(feature = "std-io")]
+mod download;
+pub mod embed;
 pub mod load;
+pub mod quantize;
+#[cfg(feature = "std-io")]
+pub mod save;
+
+use crate::error::{self, WhisperError};
+pub use embed::Pooling;
+pub use quantize::{
+    Linear, LinearRecord, Projection, QuantizedLinear, QuantizedLinearRecord,
+    QuantizedPrecisionSettings,
+};
 
 use burn::{
     config::Config,
@@ -9,8 +22,13 @@ use burn::{
         conv::{Conv1d, Conv1dConfig},
         PaddingConfig1d,
     },
-    tensor::{activation::softmax, backend::Backend, module::embedding, Distribution, Int, Tensor},
+    tensor::{
+        activation::softmax, backend::Backend, module::embedding, Distribution, Int, Tensor,
+        TensorData,
+    },
 };
+use std::marker::PhantomData;
+use std::ops::Range;
 
 #[derive(Config, Debug)]
 pub struct WhisperConfig {
@@ -33,6 +51,19 @@ impl WhisperConfig {
 
         Whisper { encoder, decoder }
     }
+
+    /// Rows of the token embedding, which tells the English-only vocabulary apart.
+    pub fn n_vocab(&self) -> usize {
+        self.text_decoder_config.n_vocab
+    }
+
+    pub fn audio_encoder_config(&self) -> &AudioEncoderConfig {
+        &self.audio_encoder_config
+    }
+
+    pub fn text_decoder_config(&self) -> &TextDecoderConfig {
+        &self.text_decoder_config
+    }
 }
 
 #[derive(Module, Debug)]
@@ -42,22 +73,86 @@ pub struct Whisper<B: Backend> {
 }
 
 impl<B: Backend> Whisper<B> {
-    pub fn forward(&self, mel: Tensor<B, 3>, tokens: Tensor<B, 2, Int>) -> Tensor<B, 3> {
-        self.decoder.forward(tokens, self.encoder.forward(mel))
+    /// Pairs an encoder and a decoder loaded separately, e.g. a full-size encoder with a
+    /// distilled decoder of fewer layers. The decoder attends to the encoder output, so their
+    /// state sizes must match.
+    pub fn from_parts(encoder: AudioEncoder<B>, decoder: TextDecoder<B>) -> error::Result<Self> {
+        let encoder_state = encoder.positional_embedding.dims()[1];
+        let decoder_state = decoder.token_embedding.dims()[1];
+        if encoder_state != decoder_state {
+            return Err(WhisperError::StateSizeMismatch {
+                encoder: encoder_state,
+                decoder: decoder_state,
+            });
+        }
+
+        Ok(Whisper { encoder, decoder })
     }
 
-    pub fn forward_encoder(&self, mel: Tensor<B, 3>) -> Tensor<B, 3> {
+    pub fn into_parts(self) -> (AudioEncoder<B>, TextDecoder<B>) {
+        (self.encoder, self.decoder)
+    }
+
+    pub fn forward(
+        &self,
+        mel: Tensor<B, 3>,
+        tokens: Tensor<B, 2, Int>,
+    ) -> error::Result<Tensor<B, 3>> {
+        self.decoder.forward(tokens, self.encoder.forward(mel)?)
+    }
+
+    pub fn forward_encoder(&self, mel: Tensor<B, 3>) -> error::Result<Tensor<B, 3>> {
         self.encoder.forward(mel)
     }
 
+    /// Like `forward_encoder`, but the self-attention of each frame is limited to `window`,
+    /// which bounds the memory of long windows at some cost in accuracy.
+    pub fn forward_encoder_windowed(
+        &self,
+        mel: Tensor<B, 3>,
+        window: EncoderAttentionWindow,
+    ) -> error::Result<Tensor<B, 3>> {
+        self.encoder.forward_windowed(mel, window)
+    }
+
+    /// Returns the logits of every position of `tokens`. An `encoder_output` with a batch of one
+    /// is shared by all token sequences, so beams decoding the same audio need not repeat it,
+    /// and its keys and values are projected once per layer.
     pub fn forward_decoder(
         &self,
         tokens: Tensor<B, 2, Int>,
         encoder_output: Tensor<B, 3>,
-    ) -> Tensor<B, 3> {
+    ) -> error::Result<Tensor<B, 3>> {
         self.decoder.forward(tokens, encoder_output)
     }
 
+    /// Like `forward_decoder`, but also returns each layer's cross-attention weights
+    /// with shape (batch, head, text_ctx, audio_ctx).
+    pub fn forward_decoder_with_attn(
+        &self,
+        tokens: Tensor<B, 2, Int>,
+        encoder_output: Tensor<B, 3>,
+    ) -> error::Result<(Tensor<B, 3>, Vec<Tensor<B, 4>>)> {
+        self.decoder.forward_with_attn(tokens, encoder_output)
+    }
+
+    /// Places the encoder and decoder on separate devices, e.g. two GPUs, so that encoding the
+    /// next chunk can overlap with decoding the current one.
+    pub fn to_devices(self, encoder_device: &B::Device, decoder_device: &B::Device) -> Self {
+        Whisper {
+            encoder: self.encoder.to_device(encoder_device),
+            decoder: self.decoder.to_device(decoder_device),
+        }
+    }
+
+    pub fn encoder_device(&self) -> B::Device {
+        self.encoder.devices()[0].clone()
+    }
+
+    pub fn decoder_device(&self) -> B::Device {
+        self.decoder.devices()[0].clone()
+    }
+
     pub fn encoder_ctx_size(&self) -> usize {
         self.encoder.ctx_size()
     }
@@ -65,6 +160,11 @@ impl<B: Backend> Whisper<B> {
         self.encoder.n_mels
     }
 
+    /// Width of the encoder output of each audio position.
+    pub fn encoder_state_size(&self) -> usize {
+        self.encoder.positional_embedding.dims()[1]
+    }
+
     pub fn decoder_ctx_size(&self) -> usize {
         self.decoder.ctx_size()
     }
@@ -99,7 +199,7 @@ impl TextDecoderConfig {
             .collect();
         let ln = nn::LayerNormConfig::new(self.n_text_state).init(tensor_device_ref);
 
-        let mask = Param::from_tensor(attn_decoder_mask(self.n_text_ctx, tensor_device_ref));
+        let mask = attn_decoder_mask(self.n_text_ctx, tensor_device_ref);
 
         let n_vocab = self.n_vocab;
         let n_text_ctx = self.n_text_ctx;
@@ -122,21 +222,31 @@ pub struct TextDecoder<B: Backend> {
     positional_embedding: Param<Tensor<B, 2>>,
     blocks: Vec<ResidualDecoderAttentionBlock<B>>,
     ln: nn::LayerNorm<B>,
-    mask: Param<Tensor<B, 2>>,
+    /// Causal mask, a constant rebuilt on init that is neither trained nor saved. Records
+    /// that still store it as a parameter load fine, the stored copy is ignored.
+    mask: Tensor<B, 2>,
     n_vocab: usize,
     n_text_ctx: usize,
 }
 
 impl<B: Backend> TextDecoder<B> {
-    fn forward(&self, x: Tensor<B, 2, Int>, xa: Tensor<B, 3>) -> Tensor<B, 3> {
+    fn forward(&self, x: Tensor<B, 2, Int>, xa: Tensor<B, 3>) -> error::Result<Tensor<B, 3>> {
+        self.forward_with_attn(x, xa).map(|(logits, _)| logits)
+    }
+
+    fn forward_with_attn(
+        &self,
+        x: Tensor<B, 2, Int>,
+        xa: Tensor<B, 3>,
+    ) -> error::Result<(Tensor<B, 3>, Vec<Tensor<B, 4>>)> {
         let [_n_batch, seq_len] = x.dims();
 
-        assert!(
-            seq_len <= self.n_text_ctx,
-            "Token sequence length {} must not exceed {}.",
-            seq_len,
-            self.n_text_ctx
-        );
+        if seq_len > self.n_text_ctx {
+            return Err(WhisperError::SequenceTooLong {
+                len: seq_len,
+                max: self.n_text_ctx,
+            });
+        }
 
         let x = embedding(self.token_embedding.val(), x)
             + self
@@ -145,15 +255,18 @@ impl<B: Backend> TextDecoder<B> {
                 .slice([0..seq_len])
                 .unsqueeze::<3>();
 
-        //let mask = attn_decoder_mask(seq_len);
-
         let mut x = x;
+        let mut cross_attn_weights = Vec::with_capacity(self.blocks.len());
         for block in &self.blocks {
-            x = block.forward(x, xa.clone(), self.mask.val());
+            let (out, weights) = block.forward_with_attn(x, xa.clone(), self.mask.clone());
+            x = out;
+            cross_attn_weights.push(weights);
         }
 
         let x = self.ln.forward(x);
-        x.matmul(self.token_embedding.val().transpose().unsqueeze::<3>())
+        let logits = x.matmul(self.token_embedding.val().transpose().unsqueeze::<3>());
+
+        Ok((logits, cross_attn_weights))
     }
 
     fn ctx_size(&self) -> usize {
@@ -223,21 +336,38 @@ pub struct AudioEncoder<B: Backend> {
     n_audio_ctx: usize,
 }
 
+/// Encoder frames on either side of a frame that its self-attention reaches. `None` attends to
+/// the whole window like the reference implementation. A limited window never materializes the
+/// full `[frames, frames]` attention matrix, computing the attention in blocks of frames instead.
+/// Windows reaching every frame give the exact result.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EncoderAttentionWindow(pub Option<usize>);
+
 impl<B: Backend> AudioEncoder<B> {
-    fn forward(&self, x: Tensor<B, 3>) -> Tensor<B, 3> {
+    fn forward(&self, x: Tensor<B, 3>) -> error::Result<Tensor<B, 3>> {
+        self.forward_windowed(x, EncoderAttentionWindow::default())
+    }
+
+    fn forward_windowed(
+        &self,
+        x: Tensor<B, 3>,
+        window: EncoderAttentionWindow,
+    ) -> error::Result<Tensor<B, 3>> {
         let [_, n_mels, n_ctx] = x.dims();
 
-        assert!(
-            n_mels == self.n_mels,
-            "Audio mel spectrum size must be {}.",
-            self.n_mels
-        );
-        assert!(
-            n_ctx <= self.n_audio_ctx,
-            "Audio length {} cannot exceed {}.",
-            n_ctx,
-            self.n_audio_ctx
-        );
+        if n_mels != self.n_mels {
+            return Err(WhisperError::MelSizeMismatch {
+                expected: self.n_mels,
+                actual: n_mels,
+            });
+        }
+        // the stride 2 convolution halves the frames before the positional embedding
+        if n_ctx.div_ceil(2) > self.n_audio_ctx {
+            return Err(WhisperError::AudioTooLong {
+                n_frames: n_ctx,
+                max_frames: 2 * self.n_audio_ctx,
+            });
+        }
 
         let x = self.gelu1.forward(self.conv1.forward(x));
         let x = self.gelu2.forward(self.conv2.forward(x));
@@ -253,10 +383,10 @@ impl<B: Backend> AudioEncoder<B> {
 
         let mut x = x;
         for block in &self.blocks {
-            x = block.forward(x);
+            x = block.forward(x, window);
         }
 
-        self.ln_post.forward(x)
+        Ok(self.ln_post.forward(x))
     }
 
     fn ctx_size(&self) -> usize {
@@ -290,18 +420,20 @@ impl ResidualEncoderAttentionBlockConfig {
     }
 }
 
+/// Keeps the plain linear layers of the original model files, since only the decoder is ever
+/// quantized.
 #[derive(Module, Debug)]
 pub struct ResidualEncoderAttentionBlock<B: Backend> {
-    attn: MultiHeadSelfAttention<B>,
+    attn: MultiHeadSelfAttention<B, nn::Linear<B>>,
     attn_ln: nn::LayerNorm<B>,
-    mlp: MLP<B>,
+    mlp: MLP<B, nn::Linear<B>>,
     mlp_ln: nn::LayerNorm<B>,
 }
 
 impl<B: Backend> ResidualEncoderAttentionBlock<B> {
-    fn forward(&self, x: Tensor<B, 3>) -> Tensor<B, 3> {
-        let x = x.clone() + self.attn.forward(self.attn_ln.forward(x), None);
-        
+    fn forward(&self, x: Tensor<B, 3>, window: EncoderAttentionWindow) -> Tensor<B, 3> {
+        let x = x.clone() + self.attn.forward_windowed(self.attn_ln.forward(x), window);
+
         x.clone() + self.mlp.forward(self.mlp_ln.forward(x))
     }
 }
@@ -341,20 +473,31 @@ impl ResidualDecoderAttentionBlockConfig {
 
 #[derive(Module, Debug)]
 pub struct ResidualDecoderAttentionBlock<B: Backend> {
-    attn: MultiHeadSelfAttention<B>,
+    attn: MultiHeadSelfAttention<B, Linear<B>>,
     attn_ln: nn::LayerNorm<B>,
     cross_attn: MultiHeadCrossAttention<B>,
     cross_attn_ln: nn::LayerNorm<B>,
-    mlp: MLP<B>,
+    mlp: MLP<B, Linear<B>>,
     mlp_ln: nn::LayerNorm<B>,
 }
 
 impl<B: Backend> ResidualDecoderAttentionBlock<B> {
-    fn forward(&self, x: Tensor<B, 3>, xa: Tensor<B, 3>, mask: Tensor<B, 2>) -> Tensor<B, 3> {
+    fn forward_with_attn(
+        &self,
+        x: Tensor<B, 3>,
+        xa: Tensor<B, 3>,
+        mask: Tensor<B, 2>,
+    ) -> (Tensor<B, 3>, Tensor<B, 4>) {
         let x = x.clone() + self.attn.forward(self.attn_ln.forward(x), Some(mask));
-        let x = x.clone() + self.cross_attn.forward(self.cross_attn_ln.forward(x), xa);
-        
-        x.clone() + self.mlp.forward(self.mlp_ln.forward(x))
+        let (cross, weights) = self
+            .cross_attn
+            .forward_with_attn(self.cross_attn_ln.forward(x.clone()), xa);
+        let x = x + cross;
+
+        (
+            x.clone() + self.mlp.forward(self.mlp_ln.forward(x)),
+            weights,
+        )
     }
 }
 
@@ -364,27 +507,36 @@ pub struct MLPConfig {
 }
 
 impl MLPConfig {
-    pub fn init<B: Backend>(&self, tensor_device_ref: &B::Device) -> MLP<B> {
+    pub fn init<B: Backend, L: From<nn::Linear<B>>>(
+        &self,
+        tensor_device_ref: &B::Device,
+    ) -> MLP<B, L> {
         let lin1 = nn::LinearConfig::new(self.n_state, 4 * self.n_state).init(tensor_device_ref);
         let gelu = nn::Gelu::new();
         let lin2 = nn::LinearConfig::new(4 * self.n_state, self.n_state).init(tensor_device_ref);
 
-        MLP { lin1, gelu, lin2 }
+        MLP {
+            lin1: lin1.into(),
+            gelu,
+            lin2: lin2.into(),
+            backend: PhantomData,
+        }
     }
 }
 
+/// `L` is the type of the projections, see [`Projection`].
 #[derive(Module, Debug)]
-pub struct MLP<B: Backend> {
-    lin1: nn::Linear<B>,
+pub struct MLP<B: Backend, L> {
+    lin1: L,
     gelu: nn::Gelu,
-    lin2: nn::Linear<B>,
+    lin2: L,
+    backend: PhantomData<B>,
 }
 
-impl<B: Backend> MLP<B> {
+impl<B: Backend, L: Projection<B>> MLP<B, L> {
     pub fn forward(&self, x: Tensor<B, 3>) -> Tensor<B, 3> {
         let x = self.lin1.forward(x);
         let x = self.gelu.forward(x);
-        
 
         self.lin2.forward(x)
     }
@@ -397,9 +549,12 @@ pub struct MultiHeadSelfAttentionConfig {
 }
 
 impl MultiHeadSelfAttentionConfig {
-    fn init<B: Backend>(&self, tensor_device_ref: &B::Device) -> MultiHeadSelfAttention<B> {
+    fn init<B: Backend, L: From<nn::Linear<B>>>(
+        &self,
+        tensor_device_ref: &B::Device,
+    ) -> MultiHeadSelfAttention<B, L> {
         assert!(
-            self.n_state % self.n_head == 0,
+            self.n_state.is_multiple_of(self.n_head),
             "State size {} must be a multiple of head size {}",
             self.n_state,
             self.n_head
@@ -415,24 +570,27 @@ impl MultiHeadSelfAttentionConfig {
 
         MultiHeadSelfAttention {
             n_head,
-            query,
-            key,
-            value,
-            out,
+            query: query.into(),
+            key: key.into(),
+            value: value.into(),
+            out: out.into(),
+            backend: PhantomData,
         }
     }
 }
 
+/// `L` is the type of the projections, see [`Projection`].
 #[derive(Module, Debug)]
-pub struct MultiHeadSelfAttention<B: Backend> {
+pub struct MultiHeadSelfAttention<B: Backend, L> {
     n_head: usize,
-    query: nn::Linear<B>,
-    key: nn::Linear<B>,
-    value: nn::Linear<B>,
-    out: nn::Linear<B>,
+    query: L,
+    key: L,
+    value: L,
+    out: L,
+    backend: PhantomData<B>,
 }
 
-impl<B: Backend> MultiHeadSelfAttention<B> {
+impl<B: Backend, L: Projection<B>> MultiHeadSelfAttention<B, L> {
     pub fn forward(&self, x: Tensor<B, 3>, mask: Option<Tensor<B, 2>>) -> Tensor<B, 3> {
         let q = self.query.forward(x.clone());
         let k = self.key.forward(x.clone());
@@ -442,6 +600,24 @@ impl<B: Backend> MultiHeadSelfAttention<B> {
 
         self.out.forward(wv)
     }
+
+    /// Unmasked attention limited to `window`, see [`qkv_attention_windowed`].
+    pub fn forward_windowed(
+        &self,
+        x: Tensor<B, 3>,
+        window: EncoderAttentionWindow,
+    ) -> Tensor<B, 3> {
+        let q = self.query.forward(x.clone());
+        let k = self.key.forward(x.clone());
+        let v = self.value.forward(x);
+
+        let wv = match window.0 {
+            Some(window) => qkv_attention_windowed(q, k, v, window, self.n_head),
+            None => qkv_attention(q, k, v, None, self.n_head),
+        };
+
+        self.out.forward(wv)
+    }
 }
 
 #[derive(Config)]
@@ -453,7 +629,7 @@ pub struct MultiHeadCrossAttentionConfig {
 impl MultiHeadCrossAttentionConfig {
     fn init<B: Backend>(&self, tensor_device_ref: &B::Device) -> MultiHeadCrossAttention<B> {
         assert!(
-            self.n_state % self.n_head == 0,
+            self.n_state.is_multiple_of(self.n_head),
             "State size {} must be a multiple of head size {}",
             self.n_state,
             self.n_head
@@ -469,10 +645,10 @@ impl MultiHeadCrossAttentionConfig {
 
         MultiHeadCrossAttention {
             n_head,
-            query,
-            key,
-            value,
-            out,
+            query: query.into(),
+            key: key.into(),
+            value: value.into(),
+            out: out.into(),
         }
     }
 }
@@ -480,21 +656,31 @@ impl MultiHeadCrossAttentionConfig {
 #[derive(Module, Debug)]
 pub struct MultiHeadCrossAttention<B: Backend> {
     n_head: usize,
-    query: nn::Linear<B>,
-    key: nn::Linear<B>,
-    value: nn::Linear<B>,
-    out: nn::Linear<B>,
+    query: Linear<B>,
+    key: Linear<B>,
+    value: Linear<B>,
+    out: Linear<B>,
 }
 
 impl<B: Backend> MultiHeadCrossAttention<B> {
     pub fn forward(&self, x: Tensor<B, 3>, xa: Tensor<B, 3>) -> Tensor<B, 3> {
+        self.forward_with_attn(x, xa).0
+    }
+
+    /// Returns the attention output along with the attention weights of shape
+    /// (batch, head, text_ctx, audio_ctx).
+    pub fn forward_with_attn(
+        &self,
+        x: Tensor<B, 3>,
+        xa: Tensor<B, 3>,
+    ) -> (Tensor<B, 3>, Tensor<B, 4>) {
         let q = self.query.forward(x);
         let k = self.key.forward(xa.clone());
         let v = self.value.forward(xa);
 
-        let wv = qkv_attention(q, k, v, None, self.n_head);
+        let (wv, w) = qkv_attention_with_weights(q, k, v, None, self.n_head);
 
-        self.out.forward(wv)
+        (self.out.forward(wv), w)
     }
 }
 
@@ -505,8 +691,20 @@ pub fn qkv_attention<B: Backend>(
     mask: Option<Tensor<B, 2>>,
     n_head: usize,
 ) -> Tensor<B, 3> {
+    qkv_attention_with_weights(q, k, v, mask, n_head).0
+}
+
+/// Computes attention and also returns the normalized weights of shape (batch, head, q_ctx, k_ctx).
+/// Keys and values with a batch of one are shared by every query in the batch.
+pub fn qkv_attention_with_weights<B: Backend>(
+    q: Tensor<B, 3>,
+    k: Tensor<B, 3>,
+    v: Tensor<B, 3>,
+    mask: Option<Tensor<B, 2>>,
+    n_head: usize,
+) -> (Tensor<B, 3>, Tensor<B, 4>) {
     let [n_batch, n_qctx, n_state] = q.dims();
-    let [_, n_ctx, _] = k.dims();
+    let [n_kbatch, n_ctx, _] = k.dims();
 
     let scale = (n_state as f64 / n_head as f64).powf(-0.25);
     let n_hstate = n_state / n_head;
@@ -516,41 +714,460 @@ pub fn qkv_attention<B: Backend>(
         .swap_dims(1, 2)
         * scale;
     let k = k
-        .reshape([n_batch, n_ctx, n_head, n_hstate])
+        .reshape([n_kbatch, n_ctx, n_head, n_hstate])
         .swap_dims(1, 2)
         .transpose()
         * scale;
     let v = v
-        .reshape([n_batch, n_ctx, n_head, n_hstate])
+        .reshape([n_kbatch, n_ctx, n_head, n_hstate])
         .swap_dims(1, 2);
+    // expanding is a broadcast view, so shared keys and values are not copied per query
+    let (k, v) = if n_kbatch == n_batch {
+        (k, v)
+    } else {
+        (
+            k.expand([n_batch, n_head, n_hstate, n_ctx]),
+            v.expand([n_batch, n_head, n_ctx, n_hstate]),
+        )
+    };
 
     let qk = q.matmul(k);
 
-    // apply mask
-    let qk = if let Some(mask) = mask {
-        qk + mask.slice([0..n_qctx, 0..n_ctx]).unsqueeze::<4>()
+    // normalize value weightings
+    let w = if let Some(mask) = mask {
+        let qk = qk + mask.slice([0..n_qctx, 0..n_ctx]).unsqueeze::<4>();
+        // the softmax of a row that masks every key is NaN, so such rows attend to nothing
+        let masked_rows = qk
+            .clone()
+            .max_dim(3)
+            .equal_elem(f32::NEG_INFINITY)
+            .expand([n_batch, n_head, n_qctx, n_ctx]);
+        softmax(qk, 3).mask_fill(masked_rows, 0.0)
     } else {
-        qk
+        softmax(qk, 3)
     };
 
-    // normalize value weightings
-    let w = softmax(qk, 3);
-    
+    (w.clone().matmul(v).swap_dims(1, 2).flatten(2, 3), w)
+}
+
+/// Fewest query positions computed at once by [`qkv_attention_windowed`], so that narrow windows
+/// do not split the attention into many tiny matrix products.
+const MIN_ATTENTION_BLOCK: usize = 64;
+
+/// Self-attention in which position `i` only attends to positions `j` with `|i - j| <= window`.
+/// The queries are processed in blocks against the keys their window reaches, each with a
+/// banded mask, so the memory of the attention weights grows linearly with the sequence length.
+pub fn qkv_attention_windowed<B: Backend>(
+    q: Tensor<B, 3>,
+    k: Tensor<B, 3>,
+    v: Tensor<B, 3>,
+    window: usize,
+    n_head: usize,
+) -> Tensor<B, 3> {
+    let [n_batch, n_ctx, n_state] = q.dims();
+    if window >= n_ctx {
+        return qkv_attention(q, k, v, None, n_head);
+    }
 
-    w.matmul(v).swap_dims(1, 2).flatten(2, 3)
+    let device = q.device();
+    let block_size = window.max(MIN_ATTENTION_BLOCK);
+    let outputs = (0..n_ctx)
+        .step_by(block_size)
+        .map(|start| {
+            let end = (start + block_size).min(n_ctx);
+            let keys = start.saturating_sub(window)..(end + window).min(n_ctx);
+            let mask = attn_band_mask(start..end, keys.clone(), window, &device);
+
+            qkv_attention(
+                q.clone().slice([0..n_batch, start..end, 0..n_state]),
+                k.clone().slice([0..n_batch, keys.clone(), 0..n_state]),
+                v.clone().slice([0..n_batch, keys, 0..n_state]),
+                Some(mask),
+                n_head,
+            )
+        })
+        .collect();
+
+    Tensor::cat(outputs, 1)
 }
 
+/// Mask of the `queries` by `keys` positions, `-inf` where they are more than `window` apart.
+fn attn_band_mask<B: Backend>(
+    queries: Range<usize>,
+    keys: Range<usize>,
+    window: usize,
+    tensor_device_ref: &B::Device,
+) -> Tensor<B, 2> {
+    let shape = [queries.len(), keys.len()];
+    let values: Vec<f32> = queries
+        .flat_map(|i| {
+            keys.clone().map(move |j| {
+                if i.abs_diff(j) > window {
+                    f32::NEG_INFINITY
+                } else {
+                    0.0
+                }
+            })
+        })
+        .collect();
+
+    Tensor::from_data(TensorData::new(values, shape), tensor_device_ref)
+}
+
+/// Causal mask hiding later positions, `-inf` above the diagonal and zero elsewhere.
 pub fn attn_decoder_mask<B: Backend>(
     seq_length: usize,
     tensor_device_ref: &B::Device,
 ) -> Tensor<B, 2> {
-    let mut mask = Tensor::<B, 2>::zeros([seq_length, seq_length], tensor_device_ref);
+    let values: Vec<f32> = (0..seq_length)
+        .flat_map(|i| (0..seq_length).map(move |j| if j > i { f32::NEG_INFINITY } else { 0.0 }))
+        .collect();
+
+    Tensor::from_data(
+        TensorData::new(values, [seq_length, seq_length]),
+        tensor_device_ref,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::record::{FullPrecisionSettings, NamedMpkBytesRecorder, Recorder};
+
+    type TestBackend = burn::backend::NdArray;
+
+    /// The modules as they were saved before layers could be quantized or the causal mask left
+    /// the parameters, by the `.mpk` files converted back then.
+    mod baseline {
+        use super::*;
+
+        #[derive(Module, Debug)]
+        pub struct Whisper<B: Backend> {
+            pub encoder: AudioEncoder<B>,
+            pub decoder: TextDecoder<B>,
+        }
+
+        #[derive(Module, Debug)]
+        pub struct AudioEncoder<B: Backend> {
+            pub conv1: Conv1d<B>,
+            pub gelu1: nn::Gelu,
+            pub conv2: Conv1d<B>,
+            pub gelu2: nn::Gelu,
+            pub blocks: Vec<EncoderBlock<B>>,
+            pub ln_post: nn::LayerNorm<B>,
+            pub positional_embedding: Param<Tensor<B, 2>>,
+            pub n_mels: usize,
+            pub n_audio_ctx: usize,
+        }
 
-    for i in 0..(seq_length - 1) {
-        let values = Tensor::<B, 2>::zeros([1, seq_length - (i + 1)], tensor_device_ref)
-            .add_scalar(f32::NEG_INFINITY);
-        mask = mask.slice_assign([i..i + 1, i + 1..seq_length], values);
+        #[derive(Module, Debug)]
+        pub struct TextDecoder<B: Backend> {
+            pub token_embedding: Param<Tensor<B, 2>>,
+            pub positional_embedding: Param<Tensor<B, 2>>,
+            pub blocks: Vec<DecoderBlock<B>>,
+            pub ln: nn::LayerNorm<B>,
+            pub mask: Param<Tensor<B, 2>>,
+            pub n_vocab: usize,
+            pub n_text_ctx: usize,
+        }
+
+        #[derive(Module, Debug)]
+        pub struct EncoderBlock<B: Backend> {
+            pub attn: Attention<B>,
+            pub attn_ln: nn::LayerNorm<B>,
+            pub mlp: Mlp<B>,
+            pub mlp_ln: nn::LayerNorm<B>,
+        }
+
+        #[derive(Module, Debug)]
+        pub struct DecoderBlock<B: Backend> {
+            pub attn: Attention<B>,
+            pub attn_ln: nn::LayerNorm<B>,
+            pub cross_attn: Attention<B>,
+            pub cross_attn_ln: nn::LayerNorm<B>,
+            pub mlp: Mlp<B>,
+            pub mlp_ln: nn::LayerNorm<B>,
+        }
+
+        /// Self and cross attention were saved alike.
+        #[derive(Module, Debug)]
+        pub struct Attention<B: Backend> {
+            pub n_head: usize,
+            pub query: nn::Linear<B>,
+            pub key: nn::Linear<B>,
+            pub value: nn::Linear<B>,
+            pub out: nn::Linear<B>,
+        }
+
+        #[derive(Module, Debug)]
+        pub struct Mlp<B: Backend> {
+            pub lin1: nn::Linear<B>,
+            pub gelu: nn::Gelu,
+            pub lin2: nn::Linear<B>,
+        }
+
+        fn float<B: Backend>(linear: Linear<B>) -> nn::Linear<B> {
+            match linear {
+                Linear::Float(linear) => linear,
+                Linear::Quantized(_) => panic!("the baseline layout has no quantized layers"),
+            }
+        }
+
+        fn mlp<B: Backend, L: Into<Linear<B>>>(mlp: super::MLP<B, L>) -> Mlp<B> {
+            Mlp {
+                lin1: float(mlp.lin1.into()),
+                gelu: mlp.gelu,
+                lin2: float(mlp.lin2.into()),
+            }
+        }
+
+        fn self_attention<B: Backend, L: Into<Linear<B>>>(
+            attn: super::MultiHeadSelfAttention<B, L>,
+        ) -> Attention<B> {
+            Attention {
+                n_head: attn.n_head,
+                query: float(attn.query.into()),
+                key: float(attn.key.into()),
+                value: float(attn.value.into()),
+                out: float(attn.out.into()),
+            }
+        }
+
+        fn cross_attention<B: Backend>(attn: super::MultiHeadCrossAttention<B>) -> Attention<B> {
+            Attention {
+                n_head: attn.n_head,
+                query: float(attn.query),
+                key: float(attn.key),
+                value: float(attn.value),
+                out: float(attn.out),
+            }
+        }
+
+        pub fn encoder<B: Backend>(encoder: super::AudioEncoder<B>) -> AudioEncoder<B> {
+            AudioEncoder {
+                conv1: encoder.conv1,
+                gelu1: encoder.gelu1,
+                conv2: encoder.conv2,
+                gelu2: encoder.gelu2,
+                blocks: encoder
+                    .blocks
+                    .into_iter()
+                    .map(|block| EncoderBlock {
+                        attn: self_attention(block.attn),
+                        attn_ln: block.attn_ln,
+                        mlp: mlp(block.mlp),
+                        mlp_ln: block.mlp_ln,
+                    })
+                    .collect(),
+                ln_post: encoder.ln_post,
+                positional_embedding: encoder.positional_embedding,
+                n_mels: encoder.n_mels,
+                n_audio_ctx: encoder.n_audio_ctx,
+            }
+        }
+
+        pub fn decoder<B: Backend>(decoder: super::TextDecoder<B>) -> TextDecoder<B> {
+            TextDecoder {
+                token_embedding: decoder.token_embedding,
+                positional_embedding: decoder.positional_embedding,
+                blocks: decoder
+                    .blocks
+                    .into_iter()
+                    .map(|block| DecoderBlock {
+                        attn: self_attention(block.attn),
+                        attn_ln: block.attn_ln,
+                        cross_attn: cross_attention(block.cross_attn),
+                        cross_attn_ln: block.cross_attn_ln,
+                        mlp: mlp(block.mlp),
+                        mlp_ln: block.mlp_ln,
+                    })
+                    .collect(),
+                ln: decoder.ln,
+                mask: Param::from_tensor(decoder.mask),
+                n_vocab: decoder.n_vocab,
+                n_text_ctx: decoder.n_text_ctx,
+            }
+        }
+
+        pub fn whisper<B: Backend>(whisper: super::Whisper<B>) -> Whisper<B> {
+            Whisper {
+                encoder: encoder(whisper.encoder),
+                decoder: decoder(whisper.decoder),
+            }
+        }
+    }
+
+    #[test]
+    fn records_of_the_baseline_layout_still_load() {
+        let device = Default::default();
+        let config = WhisperConfig::new(
+            AudioEncoderConfig::new(8, 50, 32, 2, 2),
+            TextDecoderConfig::new(50, 16, 32, 2, 2),
+        );
+        let whisper: Whisper<TestBackend> = config.init(&device);
+
+        let recorder = NamedMpkBytesRecorder::<FullPrecisionSettings>::new();
+        let bytes = recorder
+            .record(baseline::whisper(whisper.clone()).into_record(), ())
+            .unwrap();
+        let record: WhisperRecord<TestBackend> = recorder.load(bytes, &device).unwrap();
+        assert!(!record.is_quantized());
+        let loaded = config.init::<TestBackend>(&device).load_record(record);
+
+        // the encoder, which is never quantized, is still saved in the baseline layout
+        let bytes = recorder
+            .record(loaded.encoder.clone().into_record(), ())
+            .unwrap();
+        let _: baseline::AudioEncoderRecord<TestBackend> = recorder.load(bytes, &device).unwrap();
+
+        let mels = Tensor::random([1, 8, 100], Distribution::Default, &device);
+        let tokens = Tensor::from_ints([[1, 2, 3, 4]], &device);
+        let expected = whisper.forward(mels.clone(), tokens.clone()).unwrap();
+        let actual = loaded.forward(mels, tokens).unwrap();
+        actual
+            .into_data()
+            .assert_approx_eq::<f32>(&expected.into_data(), Default::default());
+    }
+
+    #[test]
+    fn decoder_records_with_a_mask_parameter_still_load() {
+        let device = Default::default();
+        let config = TextDecoderConfig::new(50, 16, 32, 2, 2);
+        let decoder: TextDecoder<TestBackend> = config.init(&device);
+        let old = baseline::decoder(decoder.clone());
+        // the mask is no longer among the parameters
+        assert_eq!(old.num_params() - decoder.num_params(), 16 * 16);
+
+        let recorder = NamedMpkBytesRecorder::<FullPrecisionSettings>::new();
+        let bytes = recorder.record(old.into_record(), ()).unwrap();
+        let record = recorder.load(bytes, &device).unwrap();
+        let loaded = config.init::<TestBackend>(&device).load_record(record);
+
+        let tokens = Tensor::from_ints([[1, 2, 3, 4]], &device);
+        let xa = Tensor::random([1, 10, 32], Distribution::Default, &device);
+        let expected = decoder.forward(tokens.clone(), xa.clone()).unwrap();
+        let actual = loaded.forward(tokens, xa).unwrap();
+        actual
+            .into_data()
+            .assert_approx_eq::<f32>(&expected.into_data(), Default::default());
     }
 
-    mask
+    fn attention_inputs(n_ctx: usize) -> [Tensor<TestBackend, 3>; 3] {
+        let device = Default::default();
+        [0, 1, 2].map(|_| Tensor::random([2, n_ctx, 32], Distribution::Default, &device))
+    }
+
+    #[test]
+    fn windows_reaching_every_position_match_exact_attention() {
+        // more positions than a block, so windows short of the whole sequence go through the blocks
+        let n_ctx = 150;
+        let [q, k, v] = attention_inputs(n_ctx);
+        let expected = qkv_attention(q.clone(), k.clone(), v.clone(), None, 4).into_data();
+
+        for window in [n_ctx - 1, n_ctx, 4 * n_ctx] {
+            let actual = qkv_attention_windowed(q.clone(), k.clone(), v.clone(), window, 4);
+            actual
+                .into_data()
+                .assert_approx_eq::<f32>(&expected, Default::default());
+        }
+    }
+
+    #[test]
+    fn narrow_windows_match_a_banded_mask_over_the_whole_sequence() {
+        let n_ctx = 150;
+        let [q, k, v] = attention_inputs(n_ctx);
+
+        for window in [0, 10, 70] {
+            let mask = attn_band_mask(0..n_ctx, 0..n_ctx, window, &Default::default());
+            let expected = qkv_attention(q.clone(), k.clone(), v.clone(), Some(mask), 4);
+            let actual = qkv_attention_windowed(q.clone(), k.clone(), v.clone(), window, 4);
+            actual
+                .into_data()
+                .assert_approx_eq::<f32>(&expected.into_data(), Default::default());
+        }
+    }
+
+    #[test]
+    fn encoder_with_a_window_reaching_every_frame_is_exact() {
+        let device = Default::default();
+        let encoder: AudioEncoder<TestBackend> =
+            AudioEncoderConfig::new(8, 100, 32, 2, 2).init(&device);
+        let mel = Tensor::random([1, 8, 200], Distribution::Default, &device);
+
+        let expected = encoder.forward(mel.clone()).unwrap().into_data();
+        for window in [99, 100] {
+            let actual = encoder
+                .forward_windowed(mel.clone(), EncoderAttentionWindow(Some(window)))
+                .unwrap();
+            actual
+                .into_data()
+                .assert_approx_eq::<f32>(&expected, Default::default());
+        }
+    }
+
+    #[test]
+    fn rows_masking_every_key_attend_to_nothing() {
+        let device = Default::default();
+        let [q, k, v] = attention_inputs(4);
+        // the second query sees no key, the third only the first one
+        let inf = f32::NEG_INFINITY;
+        let mask = Tensor::<TestBackend, 2>::from_floats(
+            [
+                [0.0, 0.0, 0.0, 0.0],
+                [inf, inf, inf, inf],
+                [0.0, inf, inf, inf],
+                [0.0, 0.0, 0.0, 0.0],
+            ],
+            &device,
+        );
+
+        let (out, weights) = qkv_attention_with_weights(q, k, v.clone(), Some(mask), 4);
+        let out = out.into_data().to_vec::<f32>().unwrap();
+        let weights = weights.into_data().to_vec::<f32>().unwrap();
+
+        assert!(out.iter().all(|x| x.is_finite()));
+        assert!(weights.iter().all(|w| w.is_finite()));
+        // batch, head and query of every row of 4 weights
+        for (row, weights) in weights.chunks(4).enumerate() {
+            let expected = match row % 4 {
+                1 => vec![0.0; 4],
+                2 => vec![1.0, 0.0, 0.0, 0.0],
+                _ => continue,
+            };
+            assert_eq!(weights, expected);
+        }
+        for (row, out) in out.chunks(32).enumerate() {
+            match row % 4 {
+                1 => assert!(out.iter().all(|&x| x == 0.0)),
+                // the only key visible to the third query gives its value
+                2 => {
+                    let batch = row / 4;
+                    let first_value = v.clone().slice([batch..batch + 1, 0..1, 0..32]);
+                    assert_eq!(out, first_value.into_data().to_vec::<f32>().unwrap());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn nan_queries_stay_in_their_row() {
+        let device = Default::default();
+        let [q, k, v] = attention_inputs(4);
+        let nan_row = Tensor::<TestBackend, 3>::full([2, 1, 32], f32::NAN, &device);
+        let q = q.slice_assign([0..2, 1..2, 0..32], nan_row);
+        let mask = attn_decoder_mask::<TestBackend>(4, &device);
+
+        let out = qkv_attention(q, k, v, Some(mask), 4)
+            .into_data()
+            .to_vec::<f32>()
+            .unwrap();
+
+        // what the NaN row itself holds depends on how the backend takes the maximum of NaN
+        for (row, out) in out.chunks(32).enumerate() {
+            if row % 4 != 1 {
+                assert!(out.iter().all(|x| x.is_finite()), "row {row}");
+            }
+        }
+    }
 }
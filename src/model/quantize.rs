@@ -0,0 +1,622 @@
+use super::*;
+
+use burn::module::{
+    AutodiffModule, Content, Devices, ModuleDisplay, ModuleDisplayDefault, ModuleMapper,
+    ModuleVisitor,
+};
+use burn::record::{PrecisionSettings, Record};
+use burn::tensor::backend::AutodiffBackend;
+use burn::tensor::ElementConversion;
+use serde::{Deserialize, Serialize};
+
+/// Largest magnitude of a quantized weight.
+const Q_MAX: f32 = 127.0;
+
+/// A linear projection of the attention and MLP layers. The encoder keeps plain
+/// [`nn::Linear`] layers, while the decoder's are [`Linear`] layers that may be quantized.
+pub trait Projection<B: Backend>: Module<B> {
+    fn forward<const D: usize>(&self, input: Tensor<B, D>) -> Tensor<B, D>;
+}
+
+impl<B: Backend> Projection<B> for nn::Linear<B> {
+    fn forward<const D: usize>(&self, input: Tensor<B, D>) -> Tensor<B, D> {
+        nn::Linear::forward(self, input)
+    }
+}
+
+impl<B: Backend> Projection<B> for Linear<B> {
+    fn forward<const D: usize>(&self, input: Tensor<B, D>) -> Tensor<B, D> {
+        Linear::forward(self, input)
+    }
+}
+
+/// A linear layer holding either full precision or int8 quantized weights.
+#[derive(Clone, Debug)]
+pub enum Linear<B: Backend> {
+    Float(nn::Linear<B>),
+    Quantized(QuantizedLinear<B>),
+}
+
+impl<B: Backend> Linear<B> {
+    pub fn forward<const D: usize>(&self, input: Tensor<B, D>) -> Tensor<B, D> {
+        match self {
+            Linear::Float(linear) => linear.forward(input),
+            Linear::Quantized(linear) => linear.forward(input),
+        }
+    }
+
+    fn quantize(self) -> Self {
+        match self {
+            Linear::Float(linear) => Linear::Quantized(QuantizedLinear::from_linear(linear)),
+            quantized => quantized,
+        }
+    }
+
+    fn is_quantized(&self) -> bool {
+        matches!(self, Linear::Quantized(_))
+    }
+}
+
+impl<B: Backend> From<nn::Linear<B>> for Linear<B> {
+    fn from(linear: nn::Linear<B>) -> Self {
+        Linear::Float(linear)
+    }
+}
+
+/// The saved form of a [`Linear`] layer.
+pub enum LinearRecord<B: Backend> {
+    Float(nn::LinearRecord<B>),
+    Quantized(QuantizedLinearRecord<B>),
+}
+
+/// The serialized form of a [`LinearRecord`]. It is untagged, so that a full precision layer is
+/// stored exactly like the [`nn::Linear`] of model files saved before layers could be quantized,
+/// and the variant is told apart by the scales only quantized layers have.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged, bound = "")]
+pub enum LinearRecordItem<B: Backend, S: PrecisionSettings> {
+    // tried first, since a quantized layer also has every field of a full precision one
+    Quantized(<QuantizedLinearRecord<B> as Record<B>>::Item<S>),
+    Float(<nn::LinearRecord<B> as Record<B>>::Item<S>),
+}
+
+impl<B: Backend> Record<B> for LinearRecord<B> {
+    type Item<S: PrecisionSettings> = LinearRecordItem<B, S>;
+
+    fn into_item<S: PrecisionSettings>(self) -> Self::Item<S> {
+        match self {
+            LinearRecord::Float(record) => LinearRecordItem::Float(record.into_item()),
+            LinearRecord::Quantized(record) => LinearRecordItem::Quantized(record.into_item()),
+        }
+    }
+
+    fn from_item<S: PrecisionSettings>(item: Self::Item<S>, device: &B::Device) -> Self {
+        match item {
+            LinearRecordItem::Float(item) => {
+                LinearRecord::Float(Record::from_item::<S>(item, device))
+            }
+            LinearRecordItem::Quantized(item) => {
+                LinearRecord::Quantized(Record::from_item::<S>(item, device))
+            }
+        }
+    }
+}
+
+// Implemented by hand for the untagged record above.
+impl<B: Backend> Module<B> for Linear<B> {
+    type Record = LinearRecord<B>;
+
+    fn collect_devices(&self, devices: Devices<B>) -> Devices<B> {
+        match self {
+            Linear::Float(linear) => linear.collect_devices(devices),
+            Linear::Quantized(linear) => linear.collect_devices(devices),
+        }
+    }
+
+    fn fork(self, device: &B::Device) -> Self {
+        match self {
+            Linear::Float(linear) => Linear::Float(linear.fork(device)),
+            Linear::Quantized(linear) => Linear::Quantized(linear.fork(device)),
+        }
+    }
+
+    fn to_device(self, device: &B::Device) -> Self {
+        match self {
+            Linear::Float(linear) => Linear::Float(linear.to_device(device)),
+            Linear::Quantized(linear) => Linear::Quantized(linear.to_device(device)),
+        }
+    }
+
+    fn visit<V: ModuleVisitor<B>>(&self, visitor: &mut V) {
+        match self {
+            Linear::Float(linear) => linear.visit(visitor),
+            Linear::Quantized(linear) => linear.visit(visitor),
+        }
+    }
+
+    fn map<M: ModuleMapper<B>>(self, mapper: &mut M) -> Self {
+        match self {
+            Linear::Float(linear) => Linear::Float(linear.map(mapper)),
+            Linear::Quantized(linear) => Linear::Quantized(linear.map(mapper)),
+        }
+    }
+
+    fn load_record(self, record: Self::Record) -> Self {
+        match (self, record) {
+            (Linear::Float(linear), LinearRecord::Float(record)) => {
+                Linear::Float(linear.load_record(record))
+            }
+            (Linear::Quantized(linear), LinearRecord::Quantized(record)) => {
+                Linear::Quantized(linear.load_record(record))
+            }
+            // the loaders quantize the model first when its record is quantized
+            _ => panic!("a linear layer can only load a record of its own precision"),
+        }
+    }
+
+    fn into_record(self) -> Self::Record {
+        match self {
+            Linear::Float(linear) => LinearRecord::Float(linear.into_record()),
+            Linear::Quantized(linear) => LinearRecord::Quantized(linear.into_record()),
+        }
+    }
+}
+
+impl<B: AutodiffBackend> AutodiffModule<B> for Linear<B> {
+    type InnerModule = Linear<B::InnerBackend>;
+
+    fn valid(&self) -> Self::InnerModule {
+        match self {
+            Linear::Float(linear) => Linear::Float(linear.valid()),
+            Linear::Quantized(linear) => Linear::Quantized(linear.valid()),
+        }
+    }
+}
+
+impl<B: Backend> ModuleDisplayDefault for Linear<B> {
+    fn content(&self, content: Content) -> Option<Content> {
+        match self {
+            Linear::Float(linear) => linear.content(content),
+            Linear::Quantized(linear) => linear.content(content),
+        }
+    }
+}
+
+impl<B: Backend> ModuleDisplay for Linear<B> {}
+
+/// A linear layer holding its weights as integers in `[-127, 127]` with one f32 scale per output
+/// channel. The integers stay packed in memory, as many to an element of the backend's int
+/// tensors as fit, and each forward pass unpacks them and scales the output.
+#[derive(Clone, Debug)]
+pub struct QuantizedLinear<B: Backend> {
+    /// Quantized weights of shape `[ceil(d_input / n), d_output]`, where `n` is the byte width
+    /// of the backend's int elements. Row `r` holds input rows `r * n` to `r * n + n - 1`, the
+    /// lowest byte first.
+    weight: Param<Tensor<B, 2, Int>>,
+    /// Scale of each output channel, of shape `[d_output]`.
+    scales: Param<Tensor<B, 1>>,
+    bias: Option<Param<Tensor<B, 1>>>,
+    d_input: usize,
+}
+
+/// The saved form of a [`QuantizedLinear`].
+#[derive(Record)]
+pub struct QuantizedLinearRecord<B: Backend> {
+    /// Quantized weights of shape `[d_input, d_output]`.
+    weight: Param<Tensor<B, 2, Int>>,
+    /// Scale of each output channel, of shape `[d_output]`.
+    scales: Param<Tensor<B, 1>>,
+    bias: Option<Param<Tensor<B, 1>>>,
+}
+
+/// Record settings that store floats at full precision and integers, which only the weights of
+/// [`QuantizedLinear`] are, as i8. Records saved with them load with [`FullPrecisionSettings`].
+///
+/// [`FullPrecisionSettings`]: burn::record::FullPrecisionSettings
+#[derive(Debug, Default, Clone)]
+pub struct QuantizedPrecisionSettings;
+
+impl PrecisionSettings for QuantizedPrecisionSettings {
+    type FloatElem = f32;
+    type IntElem = i8;
+}
+
+/// Quantizes `weight` symmetrically per output channel, returning the integers and the scale of
+/// each channel.
+fn quantize_weight<B: Backend>(weight: Tensor<B, 2>) -> (Tensor<B, 2, Int>, Tensor<B, 1>) {
+    let scales = weight
+        .clone()
+        .abs()
+        .max_dim(0)
+        .div_scalar(Q_MAX)
+        .clamp_min(f32::MIN_POSITIVE);
+    let quantized = (weight / scales.clone()).round().clamp(-Q_MAX, Q_MAX).int();
+
+    (quantized, scales.squeeze(0))
+}
+
+/// Number of quantized weights packed into one int element of the backend.
+fn weights_per_element<B: Backend>() -> usize {
+    std::mem::size_of::<B::IntElem>()
+}
+
+/// Packs the rows of `quantized` byte by byte into int elements, padding the last element with
+/// zeros.
+fn pack_weight<B: Backend>(quantized: Tensor<B, 2, Int>) -> Tensor<B, 2, Int> {
+    let n = weights_per_element::<B>();
+    let [d_input, d_output] = quantized.dims();
+    let rows = d_input.div_ceil(n);
+    let quantized = if rows * n > d_input {
+        let padding = Tensor::zeros([rows * n - d_input, d_output], &quantized.device());
+        Tensor::cat(vec![quantized, padding], 0)
+    } else {
+        quantized
+    };
+    let bytes = quantized
+        .bitwise_and_scalar(0xff.elem())
+        .reshape([rows, n, d_output]);
+
+    (0..n)
+        .map(|k| {
+            bytes
+                .clone()
+                .slice([0..rows, k..k + 1, 0..d_output])
+                .squeeze::<2>(1)
+                .bitwise_left_shift_scalar(((8 * k) as i32).elem())
+        })
+        .reduce(|packed, byte| packed.bitwise_or(byte))
+        .expect("int elements are at least a byte wide")
+}
+
+/// Reverses [`pack_weight`], returning the `d_input` rows of quantized weights.
+fn unpack_weight<B: Backend>(packed: Tensor<B, 2, Int>, d_input: usize) -> Tensor<B, 2, Int> {
+    let n = weights_per_element::<B>();
+    let [rows, d_output] = packed.dims();
+    let bytes = (0..n)
+        .map(|k| {
+            // the xor and subtraction sign extend the byte
+            packed
+                .clone()
+                .bitwise_right_shift_scalar(((8 * k) as i32).elem())
+                .bitwise_and_scalar(0xff.elem())
+                .bitwise_xor_scalar(0x80.elem())
+                .sub_scalar(0x80)
+        })
+        .collect();
+
+    let unpacked = Tensor::stack::<3>(bytes, 1).reshape([rows * n, d_output]);
+    if d_input == rows * n {
+        return unpacked;
+    }
+
+    // selecting rather than slicing copies the rows, since a slice of the ndarray backend reads
+    // back the whole tensor it views
+    let device = unpacked.device();
+    unpacked.select(0, Tensor::arange(0..d_input as i64, &device))
+}
+
+impl<B: Backend> QuantizedLinear<B> {
+    /// Quantizes the weights of `linear` symmetrically per output channel.
+    pub fn from_linear(linear: nn::Linear<B>) -> Self {
+        let [d_input, _] = linear.weight.dims();
+        let (quantized, scales) = quantize_weight(linear.weight.val());
+
+        Self {
+            weight: Param::initialized(linear.weight.id, pack_weight(quantized)),
+            scales: Param::from_tensor(scales),
+            bias: linear.bias,
+            d_input,
+        }
+    }
+
+    pub fn forward<const D: usize>(&self, input: Tensor<B, D>) -> Tensor<B, D> {
+        if D == 1 {
+            // Insert and remove an extra batch dimension for the batch matmul to work.
+            return self.forward::<2>(input.unsqueeze()).flatten(0, 1);
+        }
+
+        // scaling the output rather than the weights keeps the multiplications per output
+        // channel instead of per weight
+        let weight = unpack_weight(self.weight.val(), self.d_input).float();
+        let output = input.matmul(weight.unsqueeze()) * self.scales.val().unsqueeze();
+
+        match &self.bias {
+            Some(bias) => output + bias.val().unsqueeze(),
+            None => output,
+        }
+    }
+}
+
+// Implemented by hand since the record holds the weights unpacked, one integer per weight, so
+// that it does not depend on the int width of the backend that saved it.
+impl<B: Backend> Module<B> for QuantizedLinear<B> {
+    type Record = QuantizedLinearRecord<B>;
+
+    fn collect_devices(&self, devices: Devices<B>) -> Devices<B> {
+        let devices = self.weight.collect_devices(devices);
+        let devices = self.scales.collect_devices(devices);
+        self.bias.collect_devices(devices)
+    }
+
+    fn fork(self, device: &B::Device) -> Self {
+        Self {
+            weight: self.weight.fork(device),
+            scales: self.scales.fork(device),
+            bias: self.bias.fork(device),
+            d_input: self.d_input,
+        }
+    }
+
+    fn to_device(self, device: &B::Device) -> Self {
+        Self {
+            weight: self.weight.to_device(device),
+            scales: self.scales.to_device(device),
+            bias: self.bias.to_device(device),
+            d_input: self.d_input,
+        }
+    }
+
+    fn visit<V: ModuleVisitor<B>>(&self, visitor: &mut V) {
+        self.weight.visit(visitor);
+        self.scales.visit(visitor);
+        self.bias.visit(visitor);
+    }
+
+    fn map<M: ModuleMapper<B>>(self, mapper: &mut M) -> Self {
+        Self {
+            weight: Module::map(self.weight, mapper),
+            scales: Module::map(self.scales, mapper),
+            bias: Module::map(self.bias, mapper),
+            d_input: self.d_input,
+        }
+    }
+
+    fn load_record(self, record: Self::Record) -> Self {
+        let [d_input, _] = record.weight.dims();
+
+        Self {
+            weight: Param::initialized(record.weight.id, pack_weight(record.weight.val())),
+            scales: self.scales.load_record(record.scales),
+            bias: self.bias.load_record(record.bias),
+            d_input,
+        }
+    }
+
+    fn into_record(self) -> Self::Record {
+        QuantizedLinearRecord {
+            weight: Param::initialized(
+                self.weight.id,
+                unpack_weight(self.weight.val(), self.d_input),
+            ),
+            scales: self.scales.into_record(),
+            bias: self.bias.into_record(),
+        }
+    }
+}
+
+impl<B: AutodiffBackend> AutodiffModule<B> for QuantizedLinear<B> {
+    type InnerModule = QuantizedLinear<B::InnerBackend>;
+
+    fn valid(&self) -> Self::InnerModule {
+        QuantizedLinear {
+            weight: self.weight.valid(),
+            scales: self.scales.valid(),
+            bias: self.bias.valid(),
+            d_input: self.d_input,
+        }
+    }
+}
+
+impl<B: Backend> ModuleDisplayDefault for QuantizedLinear<B> {
+    fn content(&self, content: Content) -> Option<Content> {
+        content
+            .add("d_input", &self.d_input)
+            .add("weight", &self.weight)
+            .add("scales", &self.scales)
+            .add("bias", &self.bias)
+            .optional()
+    }
+}
+
+impl<B: Backend> ModuleDisplay for QuantizedLinear<B> {}
+
+impl<B: Backend> Whisper<B> {
+    /// Converts the decoder's attention and MLP projections to int8 weights.
+    pub fn quantize(self) -> Self {
+        Whisper {
+            encoder: self.encoder,
+            decoder: self.decoder.quantize(),
+        }
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        self.decoder
+            .blocks
+            .iter()
+            .any(|block| block.mlp.lin1.is_quantized())
+    }
+}
+
+impl<B: Backend> WhisperRecord<B> {
+    /// Whether the record was saved from a quantized model.
+    pub fn is_quantized(&self) -> bool {
+        self.decoder.is_quantized()
+    }
+}
+
+impl<B: Backend> TextDecoderRecord<B> {
+    /// Whether the record was saved from a quantized decoder.
+    pub fn is_quantized(&self) -> bool {
+        self.blocks
+            .iter()
+            .any(|block| matches!(block.mlp.lin1, LinearRecord::Quantized(_)))
+    }
+}
+
+impl<B: Backend> TextDecoder<B> {
+    pub(super) fn quantize(self) -> Self {
+        TextDecoder {
+            blocks: self
+                .blocks
+                .into_iter()
+                .map(|block| ResidualDecoderAttentionBlock {
+                    attn: block.attn.quantize(),
+                    cross_attn: block.cross_attn.quantize(),
+                    mlp: block.mlp.quantize(),
+                    ..block
+                })
+                .collect(),
+            ..self
+        }
+    }
+}
+
+impl<B: Backend> MultiHeadSelfAttention<B, Linear<B>> {
+    fn quantize(self) -> Self {
+        MultiHeadSelfAttention {
+            query: self.query.quantize(),
+            key: self.key.quantize(),
+            value: self.value.quantize(),
+            out: self.out.quantize(),
+            ..self
+        }
+    }
+}
+
+impl<B: Backend> MultiHeadCrossAttention<B> {
+    fn quantize(self) -> Self {
+        MultiHeadCrossAttention {
+            query: self.query.quantize(),
+            key: self.key.quantize(),
+            value: self.value.quantize(),
+            out: self.out.quantize(),
+            ..self
+        }
+    }
+}
+
+impl<B: Backend> MLP<B, Linear<B>> {
+    fn quantize(self) -> Self {
+        MLP {
+            lin1: self.lin1.quantize(),
+            lin2: self.lin2.quantize(),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::nn::LinearConfig;
+    use burn::record::{FullPrecisionSettings, NamedMpkBytesRecorder, Recorder};
+    use burn::tensor::Distribution;
+
+    type TestBackend = burn::backend::NdArray;
+
+    fn random_linear(d_input: usize, d_output: usize) -> nn::Linear<TestBackend> {
+        LinearConfig::new(d_input, d_output).init(&Default::default())
+    }
+
+    fn random_input() -> Tensor<TestBackend, 3> {
+        Tensor::random(
+            [2, 5, 64],
+            Distribution::Normal(0.0, 1.0),
+            &Default::default(),
+        )
+    }
+
+    fn max_abs(tensor: Tensor<TestBackend, 3>) -> f32 {
+        tensor.abs().max().into_scalar()
+    }
+
+    fn record_bytes<S: PrecisionSettings, M: Module<TestBackend>>(module: M) -> Vec<u8> {
+        NamedMpkBytesRecorder::<S>::new()
+            .record(module.into_record(), ())
+            .unwrap()
+    }
+
+    #[test]
+    fn quantized_output_is_close_to_full_precision() {
+        let linear = random_linear(64, 256);
+        let input = random_input();
+        let expected = linear.forward(input.clone());
+
+        let actual = QuantizedLinear::from_linear(linear).forward(input);
+
+        let error = max_abs(actual - expected.clone()) / max_abs(expected);
+        assert!(error < 0.01, "relative error {error}");
+    }
+
+    #[test]
+    fn packed_weights_unpack_to_the_same_integers() {
+        // 67 rows leave the last int element partly filled
+        let quantized = Tensor::<TestBackend, 2>::random(
+            [67, 5],
+            Distribution::Uniform(-Q_MAX as f64, Q_MAX as f64),
+            &Default::default(),
+        )
+        .round()
+        .int();
+
+        let packed = pack_weight(quantized.clone());
+        assert_eq!(
+            packed.dims()[0],
+            67_usize.div_ceil(weights_per_element::<TestBackend>())
+        );
+
+        unpack_weight(packed, 67)
+            .into_data()
+            .assert_eq(&quantized.into_data(), true);
+    }
+
+    #[test]
+    fn quantized_record_holds_one_byte_per_weight() {
+        let linear = random_linear(256, 256);
+
+        let full = record_bytes::<FullPrecisionSettings, _>(linear.clone()).len();
+        let quantized =
+            record_bytes::<QuantizedPrecisionSettings, _>(QuantizedLinear::from_linear(linear))
+                .len();
+
+        // 256 KiB of f32 weights against 64 KiB of i8 ones and 1 KiB of scales
+        assert!(full > 256 * 1024, "{full} bytes");
+        assert!(quantized < 68 * 1024, "{quantized} bytes");
+    }
+
+    #[test]
+    fn quantized_record_loads_the_same_weights() {
+        let quantized = QuantizedLinear::from_linear(random_linear(64, 256));
+        let input = random_input();
+        let expected = quantized.forward(input.clone());
+
+        let bytes = record_bytes::<QuantizedPrecisionSettings, _>(quantized);
+        let record = NamedMpkBytesRecorder::<FullPrecisionSettings>::new()
+            .load(bytes, &Default::default())
+            .unwrap();
+        let loaded = QuantizedLinear::from_linear(random_linear(64, 256)).load_record(record);
+
+        let error = max_abs(loaded.forward(input) - expected.clone()) / max_abs(expected);
+        assert!(error < 1e-6, "relative error {error}");
+    }
+
+    #[test]
+    fn quantized_whisper_predicts_like_full_precision() {
+        let config = WhisperConfig::new(
+            AudioEncoderConfig::new(8, 50, 64, 2, 2),
+            TextDecoderConfig::new(500, 16, 64, 2, 2),
+        );
+        let device = Default::default();
+        let whisper = config.init::<TestBackend>(&device);
+        let mels = Tensor::random([1, 8, 100], Distribution::Normal(0.0, 1.0), &device);
+        let tokens = Tensor::<TestBackend, 1, Int>::arange(0..16, &device).unsqueeze();
+
+        let expected = whisper.forward(mels.clone(), tokens.clone()).unwrap();
+        let quantized = whisper.quantize();
+        assert!(quantized.is_quantized());
+        let actual = quantized.forward(mels, tokens).unwrap();
+
+        let error = max_abs(actual - expected.clone()) / max_abs(expected);
+        assert!(error < 0.02, "relative error {error}");
+    }
+}
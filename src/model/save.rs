@@ -0,0 +1,98 @@
+use super::load::{load_named_model, save_record};
+use super::*;
+
+use crate::error::Result;
+use crate::token::Gpt2Tokenizer;
+use burn::module::{ModuleVisitor, ParamId};
+use std::fs;
+use std::path::Path;
+
+/// Writes `whisper` to `dir/name/` in the layout read by [`load_named_model`]: the weights as
+/// `name.mpk`, `config` as `name.cfg` and the tokenizer as `tokenizer.json`. Missing
+/// directories are created and existing files are overwritten. Quantized weights are saved as
+/// they are and quantized again on load.
+///
+/// The tokenizer is passed in because the model does not hold one, and the bundle cannot be
+/// loaded without its `tokenizer.json`.
+pub fn save_whisper_bundle<B: Backend>(
+    whisper: &Whisper<B>,
+    config: &WhisperConfig,
+    bpe: &Gpt2Tokenizer,
+    dir: &Path,
+    name: &str,
+) -> Result<()> {
+    let model_dir = dir.join(name);
+    fs::create_dir_all(&model_dir)?;
+
+    save_record(whisper.clone().into_record(), &model_dir.join(name))?;
+    config.save(model_dir.join(format!("{name}.cfg")))?;
+    bpe.save(model_dir.join("tokenizer.json"))?;
+
+    Ok(())
+}
+
+/// Loads the model saved under `dir/name/` and checks the shape of every weight against those
+/// its config describes. Loading alone does not, since a record replaces the weights of the
+/// model it is loaded into whatever their shapes.
+pub fn verify_bundle<B: Backend>(
+    dir: &Path,
+    name: &str,
+    tensor_device_ref: &B::Device,
+) -> Result<()> {
+    let (_bpe, config, whisper) = load_named_model::<B>(dir, name, tensor_device_ref)?;
+
+    // the weights of a freshly initialized model have the shapes of the config
+    let expected = config.init::<B>(tensor_device_ref);
+    let expected = if whisper.is_quantized() {
+        expected.quantize()
+    } else {
+        expected
+    };
+    let expected = param_shapes(&expected);
+    let actual = param_shapes(&whisper);
+
+    let mismatch = if actual.len() != expected.len() {
+        format!(
+            "{} weights were saved but the config has {}",
+            actual.len(),
+            expected.len()
+        )
+    } else if let Some((i, (actual, expected))) = actual
+        .iter()
+        .zip(&expected)
+        .enumerate()
+        .find(|(_, (actual, expected))| actual != expected)
+    {
+        format!("weight {i} has shape {actual:?} but the config expects {expected:?}")
+    } else {
+        return Ok(());
+    };
+
+    Err(WhisperError::WeightsCorrupt {
+        path: dir
+            .join(name)
+            .join(format!("{name}.mpk"))
+            .display()
+            .to_string(),
+        source: mismatch.into(),
+    })
+}
+
+/// Shapes of the weights of `module`, in the order they are visited.
+fn param_shapes<B: Backend, M: Module<B>>(module: &M) -> Vec<Vec<usize>> {
+    struct Shapes(Vec<Vec<usize>>);
+
+    impl<B: Backend> ModuleVisitor<B> for Shapes {
+        fn visit_float<const D: usize>(&mut self, _id: ParamId, tensor: &Tensor<B, D>) {
+            self.0.push(tensor.dims().to_vec());
+        }
+
+        fn visit_int<const D: usize>(&mut self, _id: ParamId, tensor: &Tensor<B, D, Int>) {
+            self.0.push(tensor.dims().to_vec());
+        }
+    }
+
+    let mut shapes = Shapes(Vec::new());
+    module.visit(&mut shapes);
+    shapes.0
+}
@@ -0,0 +1,355 @@
+use crate::error::{Result, WhisperError};
+use regex::Regex;
+use serde::Deserialize;
+#[cfg(feature = "std-io")]
+use std::{fs, path::Path};
+
+/// Clean-up applied to the text of the transcript and of each segment once it is decoded.
+/// Only the text changes, never the timestamps. Every step is off by default and they run in
+/// the order of the fields.
+#[derive(Clone, Debug, Default)]
+pub struct PostprocessOptions {
+    /// Collapse runs of spaces, remove spaces before commas, periods and closing brackets,
+    /// add the space missing after a comma or between sentences, and trim the ends. Spaces
+    /// before `;`, `:`, `!` and `?` are kept, as French typography requires them.
+    pub normalize_spacing: bool,
+    /// Drop a word that repeats the word right before it, as left behind by an imperfect
+    /// merge of overlapping chunks. Only words of the Latin script are compared, since
+    /// repetition is idiomatic in others, e.g. Japanese.
+    pub collapse_repeated_words: bool,
+    /// Uppercase the first letter after sentence-final punctuation. Only Latin letters are
+    /// changed.
+    pub capitalize_sentences: bool,
+    /// Replacements applied last, in order.
+    pub replacements: Replacements,
+}
+
+impl PostprocessOptions {
+    /// Spacing, repeated word and capitalization fixes, without replacements.
+    pub fn all() -> Self {
+        Self {
+            normalize_spacing: true,
+            collapse_repeated_words: true,
+            capitalize_sentences: true,
+            replacements: Replacements::default(),
+        }
+    }
+
+    /// Whether any step is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.normalize_spacing
+            || self.collapse_repeated_words
+            || self.capitalize_sentences
+            || !self.replacements.rules.is_empty()
+    }
+
+    /// Runs the enabled steps on `text`.
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        if self.normalize_spacing {
+            text = normalize_spacing(&text);
+        }
+        if self.collapse_repeated_words {
+            text = collapse_repeated_words(&text);
+        }
+        if self.capitalize_sentences {
+            text = capitalize_sentences(&text);
+        }
+
+        self.replacements.apply(&text)
+    }
+}
+
+/// Regular expressions replaced by fixed strings, e.g. to correct names the model keeps
+/// misspelling. The replacement may refer to capture groups as `$1` or `${name}`.
+#[derive(Clone, Debug, Default)]
+pub struct Replacements {
+    rules: Vec<(Regex, String)>,
+}
+
+/// A rule as written in a rules file.
+#[derive(Deserialize)]
+struct ReplacementRule {
+    pattern: String,
+    replacement: String,
+}
+
+/// Layout of a rules file, a list of `[[replace]]` tables in TOML or a `replace` array in JSON.
+#[derive(Deserialize)]
+struct ReplacementFile {
+    replace: Vec<ReplacementRule>,
+}
+
+impl Replacements {
+    /// Compiles `rules` of patterns and their replacements.
+    pub fn new<'a>(rules: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(|(pattern, replacement)| {
+                let regex = Regex::new(pattern).map_err(|e| {
+                    WhisperError::InvalidOptions(format!("invalid pattern {pattern:?}: {e}"))
+                })?;
+                Ok((regex, replacement.to_string()))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Parses rules from TOML, or from JSON if `json` is set.
+    pub fn parse(contents: &str, json: bool) -> Result<Self> {
+        let file: ReplacementFile = if json {
+            serde_json::from_str(contents).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(contents).map_err(|e| e.to_string())
+        }
+        .map_err(|e| WhisperError::InvalidOptions(format!("invalid replacement rules: {e}")))?;
+
+        Self::new(
+            file.replace
+                .iter()
+                .map(|rule| (rule.pattern.as_str(), rule.replacement.as_str())),
+        )
+    }
+
+    /// Reads rules from a TOML file, or a JSON file if its name ends in `.json`.
+    #[cfg(feature = "std-io")]
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+        Self::parse(&fs::read_to_string(path)?, json)
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (regex, replacement) in &self.rules {
+            text = regex.replace_all(&text, replacement.as_str()).into_owned();
+        }
+
+        text
+    }
+}
+
+/// See [`PostprocessOptions::normalize_spacing`].
+pub fn normalize_spacing(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let chars: Vec<char> = text.trim().chars().collect();
+    for (i, &c) in chars.iter().enumerate() {
+        if c == ' ' || c == '\t' {
+            let next = chars[i + 1..]
+                .iter()
+                .find(|&&next| next != ' ' && next != '\t');
+            // a line break or closing punctuation absorbs the spaces before it
+            if out.ends_with([' ', '\n'])
+                || matches!(next, Some(',' | '.' | ')' | ']' | '}' | '\n'))
+            {
+                continue;
+            }
+            out.push(' ');
+            continue;
+        }
+
+        // "end.Next" from chunks joined without a space, but not abbreviations like "U.S." or
+        // numbers like "1,000"
+        let (before, after) = (out.chars().last(), chars.get(i + 1));
+        out.push(c);
+        let missing_space = match c {
+            '.' | '!' | '?' => {
+                before.is_some_and(char::is_lowercase) && after.is_some_and(|c| c.is_uppercase())
+            }
+            ',' => {
+                before.is_some_and(char::is_alphabetic) && after.is_some_and(|c| c.is_alphabetic())
+            }
+            _ => false,
+        };
+        if missing_space {
+            out.push(' ');
+        }
+    }
+
+    out
+}
+
+/// See [`PostprocessOptions::collapse_repeated_words`].
+pub fn collapse_repeated_words(text: &str) -> String {
+    let normalized = |word: &str| {
+        word.trim_matches(|c: char| !c.is_alphanumeric())
+            .to_lowercase()
+    };
+
+    let mut out = String::with_capacity(text.len());
+    // start in `out` and normalized form of the last word kept
+    let mut previous: Option<(usize, String)> = None;
+    for piece in text.split_inclusive(char::is_whitespace) {
+        let key = normalized(piece);
+        if let Some((start, previous_key)) = &previous {
+            // punctuation after a word ends a phrase, which the next one may start the same way
+            let previous_word = out[*start..].trim_end().to_string();
+            let ends_phrase = previous_word
+                .chars()
+                .last()
+                .is_some_and(|c| !c.is_alphanumeric());
+            let is_latin_word = key.chars().any(is_latin)
+                && key
+                    .chars()
+                    .all(|c| is_latin(c) || c.is_ascii_digit() || !c.is_alphanumeric());
+            if is_latin_word && key == *previous_key && !ends_phrase {
+                // keep the first spelling, e.g. a capital, with what follows the second
+                let word_end = piece
+                    .char_indices()
+                    .rfind(|(_, c)| c.is_alphanumeric())
+                    .map_or(0, |(i, c)| i + c.len_utf8());
+                out.truncate(*start);
+                out.push_str(&previous_word);
+                out.push_str(&piece[word_end..]);
+                continue;
+            }
+        }
+
+        previous = Some((out.len(), key));
+        out.push_str(piece);
+    }
+
+    out
+}
+
+/// See [`PostprocessOptions::capitalize_sentences`].
+pub fn capitalize_sentences(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    // right after sentence-final punctuation, and once whitespace followed it
+    let (mut after_punctuation, mut at_sentence_start) = (false, false);
+    let mut previous = None;
+    for c in text.chars() {
+        if at_sentence_start && !c.is_whitespace() {
+            if c.is_lowercase() && is_latin(c) {
+                out.extend(c.to_uppercase());
+                at_sentence_start = false;
+                previous = Some(c);
+                continue;
+            }
+            // opening quotes and brackets may precede the first letter
+            at_sentence_start = matches!(c, '"' | '\'' | '(' | '[' | '«' | '“' | '‘' | '¿' | '¡');
+        }
+
+        if c.is_whitespace() {
+            at_sentence_start |= after_punctuation;
+            after_punctuation = false;
+        } else {
+            // an ellipsis often trails off in the middle of a sentence, and closing quotes and
+            // brackets may follow the end of one
+            let ends_sentence =
+                matches!(c, '.' | '!' | '?') && !(c == '.' && previous == Some('.'));
+            let closes = matches!(c, '"' | '\'' | ')' | ']' | '»' | '”' | '’');
+            after_punctuation = ends_sentence || (after_punctuation && closes);
+        }
+        out.push(c);
+        previous = Some(c);
+    }
+
+    out
+}
+
+/// Whether `c` is a letter of the Latin script.
+fn is_latin(c: char) -> bool {
+    c.is_ascii_alphabetic()
+        || matches!(c, '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}') && c != '×' && c != '÷'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spacing_is_normalized_around_punctuation() {
+        assert_eq!(
+            normalize_spacing("  so ,  we  went home .Then   we (slept )  "),
+            "so, we went home. Then we (slept)"
+        );
+        assert_eq!(
+            normalize_spacing("it was done.Next,we ate"),
+            "it was done. Next, we ate"
+        );
+        // abbreviations and numbers are left alone
+        assert_eq!(
+            normalize_spacing("the U.S. paid 1,000.50"),
+            "the U.S. paid 1,000.50"
+        );
+    }
+
+    #[test]
+    fn repeated_words_from_a_merge_are_collapsed() {
+        assert_eq!(collapse_repeated_words("The the cat sat"), "The cat sat");
+        assert_eq!(
+            collapse_repeated_words("we went went, home"),
+            "we went, home"
+        );
+        // a phrase may start with the word that ended the previous one
+        assert_eq!(
+            collapse_repeated_words("I said no. No way"),
+            "I said no. No way"
+        );
+    }
+
+    #[test]
+    fn sentences_are_capitalized() {
+        assert_eq!(
+            capitalize_sentences("it rained. we stayed in! did we? \"yes.\" wait... really"),
+            "it rained. We stayed in! Did we? \"Yes.\" Wait... really"
+        );
+    }
+
+    #[test]
+    fn french_is_not_mangled() {
+        let text = "Il a dit : « c'est fini ! » Vraiment ? Oui. élise est là ; très bien.";
+
+        assert_eq!(
+            PostprocessOptions::all().apply(text),
+            text.replace("élise", "Élise")
+        );
+        assert_eq!(
+            collapse_repeated_words("nous nous sommes levés"),
+            "nous sommes levés"
+        );
+    }
+
+    #[test]
+    fn japanese_is_not_mangled() {
+        let text = "そうそう、わかりました。 はい はい、ありがとう。";
+
+        assert_eq!(PostprocessOptions::all().apply(text), text);
+    }
+
+    #[test]
+    fn replacement_rules_are_parsed_from_toml_and_json() {
+        let toml = r#"
+            [[replace]]
+            pattern = "(?i)\\bwhisper burn\\b"
+            replacement = "whisper-burn"
+
+            [[replace]]
+            pattern = "(\\d+) percent"
+            replacement = "$1%"
+        "#;
+        let json = r#"{"replace": [
+            {"pattern": "(?i)\\bwhisper burn\\b", "replacement": "whisper-burn"},
+            {"pattern": "(\\d+) percent", "replacement": "$1%"}
+        ]}"#;
+
+        for rules in [
+            Replacements::parse(toml, false).unwrap(),
+            Replacements::parse(json, true).unwrap(),
+        ] {
+            assert_eq!(
+                rules.apply("Whisper Burn is 90 percent done"),
+                "whisper-burn is 90% done"
+            );
+        }
+        assert!(matches!(
+            Replacements::new([("(", "")]),
+            Err(WhisperError::InvalidOptions(_))
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::error::Result;
+use crate::model::Whisper;
+use crate::token::Gpt2Tokenizer;
+use crate::transcribe::{
+    transcribe, CancellationToken, DecodeEvent, TranscribeOptions, Transcript,
+};
+use burn::tensor::backend::Backend;
+use futures_core::Stream;
+use std::{
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll},
+    thread,
+};
+use tokio::sync::{mpsc as async_mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+
+/// Transcribes from async code, e.g. the handlers of a web server. Each request decodes on a
+/// thread of its own, so no thread of the async runtime blocks, and at most `max_concurrency`
+/// requests decode at once while the others wait for their turn. Cloning the service shares
+/// the model.
+pub struct WhisperService<B: Backend> {
+    /// Burn modules are not `Sync`, so each request decodes with a clone, which shares the
+    /// weights.
+    whisper: Arc<Mutex<Whisper<B>>>,
+    bpe: Arc<Gpt2Tokenizer>,
+    permits: Arc<Semaphore>,
+}
+
+impl<B: Backend> Clone for WhisperService<B> {
+    fn clone(&self) -> Self {
+        Self {
+            whisper: self.whisper.clone(),
+            bpe: self.bpe.clone(),
+            permits: self.permits.clone(),
+        }
+    }
+}
+
+/// A message of [`WhisperService::transcribe_stream`].
+#[derive(Debug)]
+pub enum TranscriptUpdate {
+    /// Progress reported while decoding, as through [`DecodeOptions::events`](crate::transcribe::DecodeOptions::events).
+    Event(DecodeEvent),
+    /// The outcome of the transcription, always the last update.
+    Done(Result<Transcript>),
+}
+
+impl<B: Backend> WhisperService<B> {
+    pub fn new(whisper: Whisper<B>, bpe: Gpt2Tokenizer, max_concurrency: usize) -> Self {
+        Self {
+            whisper: Arc::new(Mutex::new(whisper)),
+            bpe: Arc::new(bpe),
+            permits: Arc::new(Semaphore::new(max_concurrency.max(1))),
+        }
+    }
+
+    /// Like [`transcribe`]. Dropping the future cancels the transcription before its next chunk
+    /// or decoding step.
+    pub async fn transcribe(
+        &self,
+        waveform: Vec<f32>,
+        sample_rate: usize,
+        options: TranscribeOptions,
+    ) -> Result<Transcript> {
+        let permit = self.acquire().await;
+        let cancel = CancelOnDrop(CancellationToken::new());
+        let (sender, receiver) = oneshot::channel();
+        self.spawn(
+            waveform,
+            sample_rate,
+            options,
+            &cancel.0,
+            permit,
+            move |result| {
+                let _ = sender.send(result);
+            },
+        );
+
+        receiver.await.expect("the decoding thread panicked")
+    }
+
+    /// Like [`WhisperService::transcribe`], but yields the events of the partial text and the
+    /// progress as the audio is decoded, then the transcript. The stream is returned once the
+    /// transcription gets its turn, and dropping it cancels the transcription.
+    pub async fn transcribe_stream(
+        &self,
+        waveform: Vec<f32>,
+        sample_rate: usize,
+        options: TranscribeOptions,
+    ) -> TranscriptStream {
+        let permit = self.acquire().await;
+        let cancel = CancelOnDrop(CancellationToken::new());
+        let (sender, receiver) = async_mpsc::unbounded_channel();
+
+        // the decoder reports its events on a std channel, which is bridged on a thread of its
+        // own so that decoding never waits for the receiver. The transcript follows the last
+        // event, once the decoder has dropped its sender.
+        let (events, event_receiver) = mpsc::channel();
+        let (done, done_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for event in event_receiver {
+                let _ = sender.send(TranscriptUpdate::Event(event));
+            }
+            if let Ok(result) = done_receiver.recv() {
+                let _ = sender.send(TranscriptUpdate::Done(result));
+            }
+        });
+
+        let mut options = options;
+        options.decode.events = Some(events);
+        self.spawn(
+            waveform,
+            sample_rate,
+            options,
+            &cancel.0,
+            permit,
+            move |result| {
+                let _ = done.send(result);
+            },
+        );
+
+        TranscriptStream {
+            receiver,
+            _cancel: cancel,
+        }
+    }
+
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed")
+    }
+
+    /// Decodes on a new thread, which holds `permit` until it is done and passes the outcome
+    /// to `finish`.
+    fn spawn(
+        &self,
+        waveform: Vec<f32>,
+        sample_rate: usize,
+        mut options: TranscribeOptions,
+        cancel: &CancellationToken,
+        permit: OwnedSemaphorePermit,
+        finish: impl FnOnce(Result<Transcript>) + Send + 'static,
+    ) {
+        options.decode.cancel = Some(cancel.clone());
+        let whisper = self.whisper.lock().unwrap().clone();
+        let bpe = self.bpe.clone();
+        thread::spawn(move || {
+            let result = transcribe(&whisper, &bpe, &waveform, sample_rate, &options);
+            drop((options, permit));
+            finish(result);
+        });
+    }
+}
+
+/// The updates of [`WhisperService::transcribe_stream`].
+pub struct TranscriptStream {
+    receiver: async_mpsc::UnboundedReceiver<TranscriptUpdate>,
+    _cancel: CancelOnDrop,
+}
+
+impl Stream for TranscriptStream {
+    type Item = TranscriptUpdate;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Cancels the transcription it guards once dropped.
+struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
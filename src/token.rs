@@ -1,20 +1,152 @@
 use serde::ser::StdError;
-use std::{fmt, result};
+use std::{fmt, path::Path, result, sync::OnceLock};
 
 use tokenizers::AddedToken;
 
-pub type Result<T> = result::Result<T, Box<(dyn StdError + Send + Sync + 'static)>>;
+pub type Result<T> = result::Result<T, Box<dyn StdError + Send + Sync + 'static>>;
+
+/// Vocabulary size of the English-only models.
+pub const N_VOCAB_ENGLISH_ONLY: usize = 51864;
+/// Vocabulary size of large-v3, which added a language token for Cantonese.
+pub const N_VOCAB_LARGE_V3: usize = 51866;
+
+/// Layout of the special tokens, which follow the ordinary tokens of the vocabulary.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VocabKind {
+    /// The vocabulary of the multilingual models, with a token for each language.
+    Multilingual,
+    /// The vocabulary of large-v3, whose language tokens end with Cantonese, moving the special
+    /// tokens after them one id later.
+    MultilingualV3,
+    /// The GPT-2 vocabulary of the `.en` models, which has no language tokens and starts its
+    /// special tokens one id earlier.
+    EnglishOnly,
+}
+
+impl VocabKind {
+    /// The layout used by a model with a token embedding of `n_vocab` rows.
+    pub fn from_n_vocab(n_vocab: usize) -> Self {
+        match n_vocab {
+            N_VOCAB_ENGLISH_ONLY => VocabKind::EnglishOnly,
+            N_VOCAB_LARGE_V3 => VocabKind::MultilingualV3,
+            _ => VocabKind::Multilingual,
+        }
+    }
+
+    pub fn is_multilingual(&self) -> bool {
+        *self != VocabKind::EnglishOnly
+    }
+
+    /// Id of `<|endoftext|>`, the first special token.
+    fn end_of_text(&self) -> usize {
+        match self {
+            VocabKind::Multilingual | VocabKind::MultilingualV3 => 50257,
+            VocabKind::EnglishOnly => 50256,
+        }
+    }
+
+    /// Number of language tokens, which [`LANGUAGES`] lists in order.
+    fn n_languages(&self) -> usize {
+        match self {
+            VocabKind::MultilingualV3 => LANGUAGES.len(),
+            VocabKind::Multilingual | VocabKind::EnglishOnly => LANGUAGES.len() - 1,
+        }
+    }
+
+    /// Id of `token` in this layout, or `None` for language tokens of the English-only
+    /// vocabulary and languages Whisper was not trained on.
+    pub fn special_token(&self, token: &SpecialToken) -> Option<usize> {
+        let start_of_transcript = self.end_of_text() + 1;
+        // the English-only vocabulary keeps the ids of the language tokens but cannot produce
+        // them, so the tokens after them are at the same offset in both layouts
+        let after_languages = start_of_transcript + 1 + self.n_languages();
+        let id = match token {
+            SpecialToken::EndofText => self.end_of_text(),
+            SpecialToken::StartofTranscript => start_of_transcript,
+            SpecialToken::Language(lang) => {
+                if !self.is_multilingual() {
+                    return None;
+                }
+                let index = LANGUAGES[..self.n_languages()]
+                    .iter()
+                    .position(|code| *code == lang.as_str())?;
+                start_of_transcript + 1 + index
+            }
+            SpecialToken::Translate => after_languages,
+            SpecialToken::Transcribe => after_languages + 1,
+            SpecialToken::StartofLM => after_languages + 2,
+            SpecialToken::StartofPrev => after_languages + 3,
+            SpecialToken::NoSpeech => after_languages + 4,
+            SpecialToken::NoTimeStamps => after_languages + 5,
+            SpecialToken::Timestamp(seconds) => {
+                let index = (seconds / TIMESTAMP_SECONDS).round();
+                if !(0.0..N_TIMESTAMPS as f64).contains(&index) {
+                    return None;
+                }
+                after_languages + 6 + index as usize
+            }
+        };
+        Some(id)
+    }
+}
+
+/// Seconds between consecutive timestamp tokens.
+const TIMESTAMP_SECONDS: f64 = 0.02;
+/// Number of timestamp tokens, covering 0 to 30 seconds.
+const N_TIMESTAMPS: usize = 1501;
 
 pub struct Gpt2Tokenizer {
     tokenizer: tokenizers::Tokenizer,
+    kind: VocabKind,
+    /// Computed on first use, since every decoded chunk suppresses them.
+    non_speech_tokens: OnceLock<Vec<usize>>,
 }
 
 impl Gpt2Tokenizer {
+    /// Loads `models/<model_name>/tokenizer.json`, assuming the English-only vocabulary for
+    /// names ending in `.en` or `_en` and the large-v3 one for names containing `large-v3`.
     pub fn new(model_name: &str) -> Result<Self> {
-        let tokenizer =
-            tokenizers::Tokenizer::from_file(format!("models/{}/tokenizer.json", &model_name))?;
+        let kind = if model_name.ends_with(".en") || model_name.ends_with("_en") {
+            VocabKind::EnglishOnly
+        } else if model_name.contains("large-v3") || model_name.contains("large_v3") {
+            VocabKind::MultilingualV3
+        } else {
+            VocabKind::Multilingual
+        };
+        Self::from_file(format!("models/{}/tokenizer.json", &model_name), kind)
+    }
+
+    /// Loads a `tokenizer.json` from an arbitrary path. `kind` must match the model the
+    /// tokenizer is used with, see [`VocabKind::from_n_vocab`].
+    pub fn from_file(path: impl AsRef<Path>, kind: VocabKind) -> Result<Self> {
+        let tokenizer = tokenizers::Tokenizer::from_file(path)?;
+
+        Ok(Self::with_kind(tokenizer, kind))
+    }
+
+    /// Parses the contents of a `tokenizer.json`, for platforms without a file system.
+    pub fn from_bytes(bytes: impl AsRef<[u8]>, kind: VocabKind) -> Result<Self> {
+        let tokenizer = tokenizers::Tokenizer::from_bytes(bytes)?;
+
+        Ok(Self::with_kind(tokenizer, kind))
+    }
+
+    fn with_kind(tokenizer: tokenizers::Tokenizer, kind: VocabKind) -> Self {
+        Self {
+            tokenizer,
+            kind,
+            non_speech_tokens: OnceLock::new(),
+        }
+    }
 
-        Ok(Self { tokenizer })
+    /// Writes the tokenizer as a `tokenizer.json`, which [`Gpt2Tokenizer::from_file`] reads
+    /// back.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.tokenizer.save(path, false)
+    }
+
+    pub fn kind(&self) -> VocabKind {
+        self.kind
     }
 
     pub fn encode(&self, text: &str) -> Vec<usize> {
@@ -22,10 +154,24 @@ impl Gpt2Tokenizer {
         tokens.get_ids().iter().map(|t| *t as usize).collect()
     }
 
+    /// Encodes text without adding any special tokens around it.
+    pub fn encode_ordinary(&self, text: &str) -> Vec<usize> {
+        let tokens = self.tokenizer.encode(text, false).unwrap();
+        tokens.get_ids().iter().map(|t| *t as usize).collect()
+    }
+
+    /// Id of a special token, looked up in the vocabulary or, for tokenizers that lack it,
+    /// placed by the layout of [`Gpt2Tokenizer::kind`]. Language tokens are `None` for the
+    /// English-only vocabulary, which has them but was never trained on them.
     pub fn special_token(&self, token: SpecialToken) -> Option<usize> {
+        if matches!(token, SpecialToken::Language(_)) && !self.kind.is_multilingual() {
+            return None;
+        }
+
         self.tokenizer
             .token_to_id(&token.to_string())
-            .map(|t| t as usize)
+            .map(|id| id as usize)
+            .or_else(|| self.kind.special_token(&token))
     }
 
     pub fn decode(&self, tokens: &[usize], skip_special: bool) -> Result<String> {
@@ -35,12 +181,59 @@ impl Gpt2Tokenizer {
         )
     }
 
-    pub fn is_special(&self, token: usize) -> bool {
+    /// Whether `token` continues the word of the token before it. Tokens starting with a space
+    /// or newline marker, punctuation and special tokens begin something new.
+    pub fn continues_word(&self, token: usize) -> bool {
         self.tokenizer
-            .decode(vec![token as u32].as_slice(), true)
-            .ok()
-            .map(|s| s.is_empty())
-            .unwrap_or(false)
+            .id_to_token(token as u32)
+            .and_then(|token| token.chars().next())
+            .is_some_and(|c| c.is_alphanumeric() && c != 'Ġ' && c != 'Ċ')
+    }
+
+    /// Whether `token` is `<|endoftext|>` or one of the special tokens that follow it.
+    pub fn is_special(&self, token: usize) -> bool {
+        self.special_token(SpecialToken::EndofText)
+            .is_some_and(|end_of_text| token >= end_of_text)
+    }
+
+    /// Token ids of symbols that are rarely speech, such as music notes and bracketed
+    /// sound effect markers, following the reference implementation's list. The ids are looked
+    /// up in the vocabulary, so they hold for multilingual and English-only models alike.
+    pub fn non_speech_tokens(&self) -> &[usize] {
+        self.non_speech_tokens
+            .get_or_init(|| self.find_non_speech_tokens())
+    }
+
+    fn find_non_speech_tokens(&self) -> Vec<usize> {
+        #[rustfmt::skip]
+        const SYMBOLS: [&str; 47] = [
+            "\"", "#", "(", ")", "*", "+", "/", ":", ";", "<", "=", ">", "@", "[", "\\", "]", "^",
+            "_", "`", "{", "|", "}", "~", "「", "」", "『", "』", "<<", ">>", "<<<", ">>>", "--",
+            "---", "-(", "-[", "('", "(\"", "((", "))", "(((", ")))", "[[", "]]", "{{", "}}", "♪♪",
+            "♪♪♪",
+        ];
+        const MISCELLANEOUS: [&str; 7] = ["♩", "♪", "♫", "♬", "♭", "♮", "♯"];
+
+        let mut tokens: Vec<usize> = [" -", " '"]
+            .iter()
+            .filter_map(|symbol| self.encode_ordinary(symbol).first().copied())
+            .collect();
+
+        for symbol in SYMBOLS.iter().chain(MISCELLANEOUS.iter()) {
+            let is_misc = MISCELLANEOUS.contains(symbol);
+            for encoded in [
+                self.encode_ordinary(symbol),
+                self.encode_ordinary(&format!(" {symbol}")),
+            ] {
+                if encoded.len() == 1 || (is_misc && !encoded.is_empty()) {
+                    tokens.push(encoded[0]);
+                }
+            }
+        }
+
+        tokens.sort_unstable();
+        tokens.dedup();
+        tokens
     }
 
     pub fn vocab_size(&self) -> usize {
@@ -48,19 +241,20 @@ impl Gpt2Tokenizer {
     }
 }
 
-pub const LANGUAGES: [&str; 98] = [
+pub const LANGUAGES: [&str; 100] = [
     "en", "zh", "de", "es", "ru", "ko", "fr", "ja", "pt", "tr", "pl", "ca", "nl", "ar", "sv", "it",
     "id", "hi", "fi", "vi", "he", "uk", "el", "ms", "cs", "ro", "da", "hu", "ta", "no", "th", "ur",
     "hr", "bg", "lt", "la", "mi", "ml", "cy", "sk", "te", "fa", "lv", "bn", "sr", "az", "sl", "kn",
     "et", "mk", "br", "eu", "is", "hy", "ne", "mn", "bs", "kk", "sq", "sw", "gl", "mr", "pa", "si",
     "km", "sn", "yo", "so", "af", "oc", "ka", "be", "tg", "sd", "gu", "am", "yi", "lo", "uz", "fo",
-    "ht", "ps", "tk", "nn", "mt", "sa", "lb", "my", "bo", "tl", "mg", "as", "tt", "ln", "ha", "ba",
-    "jw", "su",
+    "ht", "ps", "tk", "nn", "mt", "sa", "lb", "my", "bo", "tl", "mg", "as", "tt", "haw", "ln",
+    "ha", "ba", "jw", "su", "yue",
 ];
 
+use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
-#[derive(Debug, Copy, Clone, EnumIter)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumIter)]
 pub enum Language {
     English,
     Chinese,
@@ -159,9 +353,28 @@ pub enum Language {
     Bashkir,
     Javanese,
     Sundanese,
+    Cantonese,
 }
 
 impl Language {
+    /// The language with the code `code`, e.g. `en`.
+    pub fn from_code(code: &str) -> Option<Self> {
+        Language::iter().find(|lang| lang.as_str() == code)
+    }
+
+    /// Name of the language in lowercase words, e.g. `haitian creole`.
+    pub fn name(&self) -> String {
+        let mut name = String::new();
+        for c in format!("{self:?}").chars() {
+            if c.is_uppercase() && !name.is_empty() {
+                name.push(' ');
+            }
+            name.extend(c.to_lowercase());
+        }
+
+        name
+    }
+
     pub fn as_str(&self) -> &str {
         match self {
             Language::English => "en",
@@ -261,6 +474,7 @@ impl Language {
             Language::Bashkir => "ba",
             Language::Javanese => "jw",
             Language::Sundanese => "su",
+            Language::Cantonese => "yue",
         }
     }
 }
@@ -309,8 +523,8 @@ fn _construct_special_tokens() -> Vec<AddedToken> {
         "<|notimestamps|>",
     ];
 
-    let range_keys = (0..1501)
-        .map(|i| i as f64 * 0.02)
+    let range_keys = (0..N_TIMESTAMPS)
+        .map(|i| i as f64 * TIMESTAMP_SECONDS)
         .map(|f| format!("<|{f:.2}|>"));
 
     SPEC1
@@ -322,3 +536,135 @@ fn _construct_special_tokens() -> Vec<AddedToken> {
         .map(|tok| AddedToken::from(tok, true))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(kind: VocabKind, token: SpecialToken) -> Option<usize> {
+        kind.special_token(&token)
+    }
+
+    fn lang(kind: VocabKind, lang: Language) -> Option<usize> {
+        kind.special_token(&SpecialToken::Language(lang))
+    }
+
+    // the ids below are those of the reference implementation's tokenizers
+
+    #[test]
+    fn multilingual_layout() {
+        let kind = VocabKind::from_n_vocab(51865);
+        assert_eq!(kind, VocabKind::Multilingual);
+        assert_eq!(id(kind, SpecialToken::EndofText), Some(50257));
+        assert_eq!(id(kind, SpecialToken::StartofTranscript), Some(50258));
+        assert_eq!(lang(kind, Language::English), Some(50259));
+        assert_eq!(lang(kind, Language::Chinese), Some(50260));
+        assert_eq!(lang(kind, Language::Sundanese), Some(50357));
+        assert_eq!(lang(kind, Language::Cantonese), None);
+        assert_eq!(id(kind, SpecialToken::Translate), Some(50358));
+        assert_eq!(id(kind, SpecialToken::Transcribe), Some(50359));
+        assert_eq!(id(kind, SpecialToken::StartofLM), Some(50360));
+        assert_eq!(id(kind, SpecialToken::StartofPrev), Some(50361));
+        assert_eq!(id(kind, SpecialToken::NoSpeech), Some(50362));
+        assert_eq!(id(kind, SpecialToken::NoTimeStamps), Some(50363));
+        assert_eq!(id(kind, SpecialToken::Timestamp(0.0)), Some(50364));
+        assert_eq!(id(kind, SpecialToken::Timestamp(30.0)), Some(51864));
+        assert_eq!(id(kind, SpecialToken::Timestamp(30.02)), None);
+    }
+
+    #[test]
+    fn large_v3_layout() {
+        let kind = VocabKind::from_n_vocab(51866);
+        assert_eq!(kind, VocabKind::MultilingualV3);
+        assert!(kind.is_multilingual());
+        assert_eq!(id(kind, SpecialToken::EndofText), Some(50257));
+        assert_eq!(id(kind, SpecialToken::StartofTranscript), Some(50258));
+        assert_eq!(lang(kind, Language::English), Some(50259));
+        assert_eq!(lang(kind, Language::Sundanese), Some(50357));
+        assert_eq!(lang(kind, Language::Cantonese), Some(50358));
+        assert_eq!(id(kind, SpecialToken::Translate), Some(50359));
+        assert_eq!(id(kind, SpecialToken::Transcribe), Some(50360));
+        assert_eq!(id(kind, SpecialToken::StartofLM), Some(50361));
+        assert_eq!(id(kind, SpecialToken::StartofPrev), Some(50362));
+        assert_eq!(id(kind, SpecialToken::NoSpeech), Some(50363));
+        assert_eq!(id(kind, SpecialToken::NoTimeStamps), Some(50364));
+        assert_eq!(id(kind, SpecialToken::Timestamp(0.0)), Some(50365));
+        assert_eq!(id(kind, SpecialToken::Timestamp(30.0)), Some(51865));
+    }
+
+    #[test]
+    fn english_only_layout() {
+        let kind = VocabKind::from_n_vocab(51864);
+        assert_eq!(kind, VocabKind::EnglishOnly);
+        assert_eq!(id(kind, SpecialToken::EndofText), Some(50256));
+        assert_eq!(id(kind, SpecialToken::StartofTranscript), Some(50257));
+        assert_eq!(lang(kind, Language::English), None);
+        assert_eq!(id(kind, SpecialToken::Translate), Some(50357));
+        assert_eq!(id(kind, SpecialToken::Transcribe), Some(50358));
+        assert_eq!(id(kind, SpecialToken::StartofLM), Some(50359));
+        assert_eq!(id(kind, SpecialToken::StartofPrev), Some(50360));
+        assert_eq!(id(kind, SpecialToken::NoSpeech), Some(50361));
+        assert_eq!(id(kind, SpecialToken::NoTimeStamps), Some(50362));
+        assert_eq!(id(kind, SpecialToken::Timestamp(0.0)), Some(50363));
+        assert_eq!(id(kind, SpecialToken::Timestamp(30.0)), Some(51863));
+    }
+
+    #[test]
+    fn tokenizer_ids_take_precedence_over_layout() {
+        let tokenizer = br#"{
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"a": 0, "<|endoftext|>": 1, "<|en|>": 2},
+                "unk_token": "a"
+            }
+        }"#;
+
+        let bpe = Gpt2Tokenizer::from_bytes(tokenizer, VocabKind::Multilingual).unwrap();
+        assert_eq!(bpe.special_token(SpecialToken::EndofText), Some(1));
+        let english = SpecialToken::Language(Language::English);
+        assert_eq!(bpe.special_token(english), Some(2));
+        // missing from the vocabulary, so placed by the layout
+        assert_eq!(bpe.special_token(SpecialToken::Transcribe), Some(50359));
+
+        let bpe = Gpt2Tokenizer::from_bytes(tokenizer, VocabKind::EnglishOnly).unwrap();
+        let english = SpecialToken::Language(Language::English);
+        assert_eq!(bpe.special_token(english), None);
+    }
+
+    #[test]
+    fn non_speech_tokens_are_symbols_of_the_vocabulary() {
+        let bpe = Gpt2Tokenizer::from_bytes(
+            include_bytes!("../tests/fixtures/tokenizer.json"),
+            VocabKind::Multilingual,
+        )
+        .unwrap();
+        let non_speech = bpe.non_speech_tokens();
+
+        // the miniature vocabulary only has single byte symbols, and the first bytes of the
+        // space marker and the music notes, which are kept whatever follows them
+        let single = |symbol: &str| bpe.encode_ordinary(symbol)[0];
+        for symbol in [
+            "\"", "#", "(", ")", "*", "<", "@", "[", "\\", "^", "_", "{", "~",
+        ] {
+            assert!(non_speech.contains(&single(symbol)), "{symbol}");
+        }
+        assert!(non_speech.contains(&single(" ")));
+        assert!(non_speech.contains(&single("♪")));
+        assert_eq!(non_speech.len(), 25);
+
+        // words, letters, digits and sentence punctuation are speech
+        for text in [
+            " the", " a", "he", "a", "Z", "7", ".", ",", "?", "!", "-", "'",
+        ] {
+            assert!(!non_speech.contains(&single(text)), "{text}");
+        }
+    }
+}
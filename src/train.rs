@@ -0,0 +1,105 @@
+use crate::error::Result;
+use crate::model::Whisper;
+use burn::tensor::{activation::log_softmax, backend::Backend, Int, Tensor, TensorData};
+
+/// A training sample.
+#[derive(Clone, Debug)]
+pub struct WhisperItem<B: Backend> {
+    /// Log mel spectrogram of shape (n_mels, n_frames), as computed by `prep_audio`.
+    pub mels: Tensor<B, 2>,
+    /// Transcript tokens, from the start of transcript prefix through the end of text token.
+    pub tokens: Vec<usize>,
+}
+
+/// Training samples padded to the longest one in the batch.
+#[derive(Clone, Debug)]
+pub struct WhisperBatch<B: Backend> {
+    /// Spectrograms of shape (n_batch, n_mels, n_frames), zero padded at the end.
+    pub mels: Tensor<B, 3>,
+    /// Number of frames of each spectrogram before padding.
+    pub mel_lengths: Vec<usize>,
+    /// Token sequences of shape (n_batch, seq_len), padded at the end with `pad_token`.
+    pub tokens: Tensor<B, 2, Int>,
+    pub pad_token: usize,
+}
+
+/// Pads the spectrograms and token sequences of `items` to a common length and stacks them.
+/// Padding only ever follows the content, so the decoder's causal mask keeps it from affecting
+/// the predictions of real tokens.
+///
+/// Panics if `items` is empty.
+pub fn collate<B: Backend>(
+    items: Vec<WhisperItem<B>>,
+    pad_token: usize,
+    device: &B::Device,
+) -> WhisperBatch<B> {
+    assert!(!items.is_empty(), "cannot collate an empty batch");
+
+    let n_batch = items.len();
+    let max_frames = items.iter().map(|item| item.mels.dims()[1]).max().unwrap();
+    let seq_len = items.iter().map(|item| item.tokens.len()).max().unwrap();
+
+    let tokens: Vec<u32> = items
+        .iter()
+        .flat_map(|item| {
+            let n_pad = seq_len - item.tokens.len();
+            item.tokens
+                .iter()
+                .chain(std::iter::repeat_n(&pad_token, n_pad))
+                .map(|&tok| tok as u32)
+        })
+        .collect();
+    let tokens = Tensor::from_ints(TensorData::new(tokens, [n_batch, seq_len]), device);
+
+    let mel_lengths = items.iter().map(|item| item.mels.dims()[1]).collect();
+    let mels = items
+        .into_iter()
+        .map(|item| {
+            let [n_mels, n_frames] = item.mels.dims();
+            let mels = item.mels.to_device(device);
+            let mels = if n_frames < max_frames {
+                Tensor::cat(
+                    vec![mels, Tensor::zeros([n_mels, max_frames - n_frames], device)],
+                    1,
+                )
+            } else {
+                mels
+            };
+            mels.unsqueeze::<3>()
+        })
+        .collect();
+
+    WhisperBatch {
+        mels: Tensor::cat(mels, 0),
+        mel_lengths,
+        tokens,
+        pad_token,
+    }
+}
+
+/// Teacher forced cross-entropy of predicting each token of `target_tokens` from the tokens
+/// before it, averaged over all positions whose label is not `pad_token`. The pad token must
+/// therefore not be a label that should be learned, such as the end of text token.
+///
+/// Gradients are available when `B` is an autodiff backend.
+pub fn compute_loss<B: Backend>(
+    whisper: &Whisper<B>,
+    mels: Tensor<B, 3>,
+    target_tokens: Tensor<B, 2, Int>,
+    pad_token: usize,
+) -> Result<Tensor<B, 1>> {
+    let [n_batch, seq_len] = target_tokens.dims();
+
+    let inputs = target_tokens.clone().slice([0..n_batch, 0..seq_len - 1]);
+    let labels = target_tokens.slice([0..n_batch, 1..seq_len]);
+
+    let log_probs = log_softmax(whisper.forward(mels, inputs)?, 2);
+    let label_log_probs = log_probs
+        .gather(2, labels.clone().unsqueeze_dim(2))
+        .squeeze::<2>(2);
+
+    let mask = labels.not_equal_elem(pad_token as i64).float();
+    let n_labels = mask.clone().sum().clamp_min(1.0);
+
+    Ok((label_log_probs * mask).sum().neg() / n_labels)
+}
@@ -1,280 +1,4872 @@
-use crate::audio::{max_waveform_samples, prep_audio};
-use crate::beam;
+use crate::audio::{
+    detect_speaker_turns, detect_speech_segments, n_frames, prep_audio, preprocess, resample,
+    rms_db, PreprocessOptions, TurnOptions, VadOptions, MIN_SAMPLES, SAMPLE_RATE,
+};
+use crate::beam::{self, LengthPenalty};
+#[cfg(feature = "std-io")]
+use crate::cache::EncoderCache;
+use crate::checkpoint::{Checkpoint, CheckpointSink};
+use crate::error::{Result, WhisperError};
+use crate::metrics::{ChunkMetrics, Metrics, Timer};
 use crate::model::*;
-use crate::token::{self, *};
+use crate::postprocess::PostprocessOptions;
+use crate::token::*;
 use burn::tensor::TensorData;
-use burn::{
-    module::Module,
-    tensor::{activation::log_softmax, backend::Backend, ElementConversion, Tensor},
+use burn::tensor::{activation::log_softmax, backend::Backend, ElementConversion, Int, Tensor};
+use flate2::{write::ZlibEncoder, Compression};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    f32, fmt,
+    io::Write,
+    iter,
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
 };
-use std::{f32, iter, ops::Div};
+use strum::IntoEnumIterator;
+
+/// Options controlling how each audio chunk is decoded.
+#[derive(Clone, Debug)]
+pub struct DecodeOptions {
+    /// Whether the speech is written down in its own language or translated to English.
+    pub task: Task,
+    /// Number of beams searched at zero temperature. A size of one decodes greedily.
+    pub beam_size: usize,
+    /// Beam search stops once `round(beam_size * patience)` hypotheses have finished.
+    pub patience: f64,
+    /// How finished beam hypotheses of different lengths are ranked against each other.
+    pub length_penalty: LengthPenalty,
+    /// Number of distinct beam search hypotheses kept in [`DecodingResult::alternatives`]. At
+    /// most `round(beam_size * patience)` hypotheses finish, and none are kept below two.
+    pub nbest: usize,
+    /// Text used to condition the first chunk, e.g. names or jargon expected in the audio.
+    pub prompt: Option<String>,
+    /// Temperatures tried in order. Zero selects beam search, anything higher samples.
+    pub temperatures: Vec<f64>,
+    /// Results whose compression ratio exceeds this are retried at the next temperature.
+    pub compression_ratio_threshold: Option<f64>,
+    /// Results whose average log probability is below this are retried at the next temperature.
+    pub log_prob_threshold: Option<f64>,
+    /// Results in which some n-gram of tokens repeats back to back more than this many times
+    /// are retried at the next temperature.
+    pub max_ngram_repeats: Option<usize>,
+    /// Token ids that may never be emitted.
+    pub suppress_tokens: Vec<usize>,
+    /// Also suppress the vocabulary's non-speech symbols, such as music notes and brackets.
+    pub suppress_non_speech: bool,
+    /// Prevents a blank or end of text token from being the first token emitted.
+    pub suppress_blank: bool,
+    /// Prevents any n-gram of this size from repeating within a sequence.
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Breaks out of sequences that loop on the same phrase.
+    pub repetition: Option<RepetitionOptions>,
+    /// Predict timestamp tokens between the text rather than selecting `<|notimestamps|>`.
+    pub timestamps: bool,
+    /// Added to the logit of each token at every step, before the suppression options above.
+    pub logit_bias: HashMap<usize, f32>,
+    /// Extra filters run on the logits of every step, after the suppression options above.
+    pub logit_filters: Vec<Arc<dyn LogitFilter>>,
+    /// Align the decoded words to the audio using cross-attention.
+    pub word_timestamps: bool,
+    /// Filters applied to the waveform before it is split into chunks.
+    pub preprocess: PreprocessOptions,
+    /// Clean-up of the decoded text of the transcript and of each segment. The text written by
+    /// [`waveform_to_text_streaming`] is not cleaned up.
+    pub postprocess: PostprocessOptions,
+    /// Cut chunks at detected silences and skip silent audio. Without it the audio is
+    /// split into fixed, overlapping windows.
+    pub vad: Option<VadOptions>,
+    /// Cut chunks at probable speaker turns, which are numbered in [`Segment::turn_index`].
+    pub turn_detection: Option<TurnOptions>,
+    /// Longest audio decoded in one window, in seconds. The window and its padding must fit
+    /// the encoder, i.e. at most 28 seconds with the default padding.
+    pub chunk_length_s: f32,
+    /// Audio shared by consecutive fixed windows, in seconds, whose tokens are merged. Must be
+    /// shorter than `chunk_length_s`. Zero decodes the windows independently.
+    pub chunk_overlap_s: f32,
+    /// Spectrogram frames of silence appended to each window, which helps whisper determine
+    /// the end of text. Too little padding can make chunks repeat themselves endlessly. The
+    /// window and its padding must fit the encoder, which rejects them with
+    /// [`WhisperError::AudioTooLong`] otherwise.
+    pub end_padding_frames: usize,
+    /// Limits the encoder's self-attention to nearby frames, which lowers the peak memory of
+    /// encoding a window at some cost in accuracy.
+    pub encoder_attention_window: EncoderAttentionWindow,
+    /// Reuses the encoder output of chunks encoded before, e.g. when the same audio is decoded
+    /// again with different options.
+    #[cfg(feature = "std-io")]
+    pub encoder_cache: Option<Arc<EncoderCache>>,
+    /// Receives the partial text of each chunk as it is decoded.
+    pub events: Option<Sender<DecodeEvent>>,
+    /// Stops decoding before the next chunk or decoding step once cancelled, returning
+    /// [`WhisperError::Cancelled`].
+    pub cancel: Option<CancellationToken>,
+    /// Number of chunks whose mel spectrogram is computed in the background ahead of the encoder.
+    /// Zero computes each spectrogram when it is needed without spawning any thread, which is
+    /// always the case on wasm32.
+    pub mel_prefetch: usize,
+    /// Chunks whose RMS level is below this many dBFS are not decoded and yield an empty segment.
+    pub silence_threshold_db: Option<f32>,
+    /// How the tokens of overlapping chunks are joined.
+    pub merge_strategy: MergeStrategy,
+    /// When [`MergeStrategy::Confidence`] finds that two chunks agree on too little of their
+    /// overlap, decode one more window centered on the seam between them and splice its tokens
+    /// in between. Only the functions decoding a waveform have the audio for this;
+    /// [`decode_encoded`] merges the chunks as they are.
+    pub reseam: bool,
+    /// Chunks whose no speech probability exceeds this are treated as silent, unless their
+    /// average log probability is above `log_prob_threshold`.
+    pub no_speech_threshold: Option<f64>,
+    /// Seed of the generator used for sampling at non-zero temperatures. A random seed is chosen
+    /// when unset and reported in [`TranscriptMetadata::seed`].
+    pub seed: Option<u64>,
+    /// What gives way when the prompt and the tokens a window may hold exceed the decoder context.
+    pub context_policy: ContextPolicy,
+    /// What happens when the decoder returns NaN or infinite logits, e.g. from overflow at
+    /// half precision.
+    pub non_finite_logits: NonFinitePolicy,
+    /// Prompt each chunk with the text of the previous ones. The prompt is dropped after a
+    /// chunk that fell back to a temperature above 0.5 or failed the quality thresholds.
+    pub condition_on_previous_text: bool,
+    /// Detect the language of every chunk instead of decoding all of them in the language
+    /// passed in, for audio that switches between languages. A chunk only switches away from
+    /// the language of the previous one if its detected language is at least this likely,
+    /// which keeps ambiguous chunks from flapping. Ignored by English-only models.
+    pub detect_language_per_chunk: Option<f64>,
+}
+
+/// Decodes like the original implementation: fixed 13 second windows overlapping by 3 seconds
+/// and merged on their common tokens, a beam search of 5 ranked by total log probability, no
+/// temperature fallback and none of the filters, quality checks or prompting added since. See
+/// [`DecodeOptions::recommended`] for settings closer to the reference implementation.
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            task: Task::Transcribe,
+            beam_size: 5,
+            patience: 1.0,
+            length_penalty: LengthPenalty::None,
+            nbest: 1,
+            prompt: None,
+            temperatures: vec![0.0],
+            compression_ratio_threshold: None,
+            log_prob_threshold: None,
+            max_ngram_repeats: None,
+            suppress_tokens: Vec::new(),
+            suppress_non_speech: false,
+            suppress_blank: false,
+            no_repeat_ngram_size: None,
+            repetition: None,
+            timestamps: false,
+            logit_bias: HashMap::new(),
+            logit_filters: Vec::new(),
+            word_timestamps: false,
+            preprocess: PreprocessOptions::default(),
+            postprocess: PostprocessOptions::default(),
+            vad: None,
+            turn_detection: None,
+            chunk_length_s: 13.0,
+            chunk_overlap_s: 3.0,
+            end_padding_frames: 200,
+            encoder_attention_window: EncoderAttentionWindow::default(),
+            #[cfg(feature = "std-io")]
+            encoder_cache: None,
+            events: None,
+            cancel: None,
+            mel_prefetch: 2,
+            silence_threshold_db: None,
+            merge_strategy: MergeStrategy::TokenOverlap,
+            reseam: false,
+            no_speech_threshold: None,
+            seed: None,
+            context_policy: ContextPolicy::TruncatePrompt,
+            non_finite_logits: NonFinitePolicy::Abort,
+            condition_on_previous_text: false,
+            detect_language_per_chunk: None,
+        }
+    }
+}
+
+impl DecodeOptions {
+    /// The reference implementation's decoding: a length-normalized beam search falling back
+    /// to sampling at rising temperatures when a result looks degenerate, prompted with the
+    /// previous text, with blank and non-speech tokens suppressed and silent chunks skipped.
+    /// Chunks are cut at silences and merged at word boundaries.
+    pub fn recommended() -> Self {
+        Self {
+            length_penalty: LengthPenalty::Google(1.0),
+            temperatures: vec![0.0, 0.2, 0.4, 0.6, 0.8, 1.0],
+            compression_ratio_threshold: Some(2.4),
+            log_prob_threshold: Some(-1.0),
+            max_ngram_repeats: Some(4),
+            suppress_non_speech: true,
+            suppress_blank: true,
+            repetition: Some(RepetitionOptions::default()),
+            vad: Some(VadOptions::default()),
+            silence_threshold_db: Some(-60.0),
+            merge_strategy: MergeStrategy::WordBoundary,
+            no_speech_threshold: Some(0.6),
+            condition_on_previous_text: true,
+            ..Self::default()
+        }
+    }
+}
+
+/// How the decoder context is shared between the prompt and the generated tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContextPolicy {
+    /// Drop the oldest prompt tokens, keeping the special tokens, until the longest text a
+    /// window may hold fits after the prompt.
+    #[default]
+    TruncatePrompt,
+    /// Keep the whole prompt and stop generating once the context is full, finishing the chunk
+    /// with the tokens decoded so far.
+    Stop,
+}
+
+/// How NaN or infinite logits from the decoder are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonFinitePolicy {
+    /// Stop with [`WhisperError::NumericalError`] instead of decoding garbage.
+    #[default]
+    Abort,
+    /// Clamp infinities to a large finite logit, make NaN logits the least likely and go on.
+    Clamp,
+}
+
+/// Largest logit magnitude left by [`NonFinitePolicy::Clamp`], far beyond any finite logit
+/// whisper produces.
+const MAX_LOGIT: f64 = 1e4;
+
+impl NonFinitePolicy {
+    /// Returns whether decoding may go on with `logits`, clamping them first if that is the
+    /// policy.
+    fn check(self, logits: &mut [f64]) -> bool {
+        if logits.iter().all(|logit| logit.is_finite()) {
+            return true;
+        }
+
+        match self {
+            NonFinitePolicy::Abort => false,
+            NonFinitePolicy::Clamp => {
+                for logit in logits {
+                    *logit = if logit.is_nan() {
+                        -MAX_LOGIT
+                    } else {
+                        logit.clamp(-MAX_LOGIT, MAX_LOGIT)
+                    };
+                }
+                true
+            }
+        }
+    }
+}
+
+/// What whisper produces from the speech.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Task {
+    /// Text in the spoken language.
+    #[default]
+    Transcribe,
+    /// English text, whatever the spoken language.
+    Translate,
+}
+
+impl Task {
+    fn special_token(self) -> SpecialToken {
+        match self {
+            Task::Transcribe => SpecialToken::Transcribe,
+            Task::Translate => SpecialToken::Translate,
+        }
+    }
+}
+
+/// Probability a chunk's detected language needs to switch away from the language of the
+/// previous chunk, for [`LanguageMode::DetectPerChunk`].
+pub const DEFAULT_MIN_LANGUAGE_SWITCH_PROB: f64 = 0.5;
+
+/// Fraction of the overlap two chunks need to agree on before [`MergeStrategy::Confidence`]
+/// decodes their seam again.
+pub const DEFAULT_MIN_OVERLAP_AGREEMENT: f64 = 0.5;
+
+/// How [`transcribe`] chooses the language of the audio.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LanguageMode {
+    /// Decode all of the audio in this language.
+    Fixed(Language),
+    /// Detect the language from the first window and decode all of the audio in it.
+    #[default]
+    DetectOnce,
+    /// Detect the language from the first window, then again for every chunk. See
+    /// [`DecodeOptions::detect_language_per_chunk`].
+    DetectPerChunk { min_switch_prob: f64 },
+}
+
+/// Everything [`transcribe`] needs besides the model and the audio. The default detects the
+/// language and decodes with the defaults of [`DecodeOptions`], which
+/// [`TranscribeOptions::recommended`] swaps for [`DecodeOptions::recommended`].
+#[derive(Clone, Debug, Default)]
+pub struct TranscribeOptions {
+    /// Set through [`TranscribeOptions::language_mode`], which keeps
+    /// [`DecodeOptions::detect_language_per_chunk`] in line with it.
+    pub language: LanguageMode,
+    pub decode: DecodeOptions,
+}
+
+impl TranscribeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Detects the language and decodes with [`DecodeOptions::recommended`].
+    pub fn recommended() -> Self {
+        Self {
+            language: LanguageMode::default(),
+            decode: DecodeOptions::recommended(),
+        }
+    }
+
+    pub fn language(self, language: Language) -> Self {
+        self.language_mode(LanguageMode::Fixed(language))
+    }
+
+    /// Detect the language from the first window of the audio.
+    pub fn detect_language(self) -> Self {
+        self.language_mode(LanguageMode::DetectOnce)
+    }
+
+    pub fn language_mode(mut self, mode: LanguageMode) -> Self {
+        self.language = mode;
+        self.decode.detect_language_per_chunk = match mode {
+            LanguageMode::DetectPerChunk { min_switch_prob } => Some(min_switch_prob),
+            _ => None,
+        };
+        self
+    }
+
+    pub fn task(mut self, task: Task) -> Self {
+        self.decode.task = task;
+        self
+    }
+
+    pub fn beam_size(mut self, beam_size: usize) -> Self {
+        self.decode.beam_size = beam_size;
+        self
+    }
+
+    pub fn nbest(mut self, nbest: usize) -> Self {
+        self.decode.nbest = nbest;
+        self
+    }
+
+    pub fn patience(mut self, patience: f64) -> Self {
+        self.decode.patience = patience;
+        self
+    }
+
+    pub fn temperatures(mut self, temperatures: Vec<f64>) -> Self {
+        self.decode.temperatures = temperatures;
+        self
+    }
+
+    pub fn prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.decode.prompt = Some(prompt.into());
+        self
+    }
+
+    pub fn timestamps(mut self, timestamps: bool) -> Self {
+        self.decode.timestamps = timestamps;
+        self
+    }
+
+    pub fn word_timestamps(mut self, word_timestamps: bool) -> Self {
+        self.decode.word_timestamps = word_timestamps;
+        self
+    }
+
+    pub fn compression_ratio_threshold(mut self, threshold: Option<f64>) -> Self {
+        self.decode.compression_ratio_threshold = threshold;
+        self
+    }
+
+    pub fn log_prob_threshold(mut self, threshold: Option<f64>) -> Self {
+        self.decode.log_prob_threshold = threshold;
+        self
+    }
+
+    pub fn no_speech_threshold(mut self, threshold: Option<f64>) -> Self {
+        self.decode.no_speech_threshold = threshold;
+        self
+    }
+
+    pub fn max_ngram_repeats(mut self, max_repeats: Option<usize>) -> Self {
+        self.decode.max_ngram_repeats = max_repeats;
+        self
+    }
+
+    pub fn context_policy(mut self, policy: ContextPolicy) -> Self {
+        self.decode.context_policy = policy;
+        self
+    }
+
+    pub fn non_finite_logits(mut self, policy: NonFinitePolicy) -> Self {
+        self.decode.non_finite_logits = policy;
+        self
+    }
+
+    pub fn merge_strategy(mut self, strategy: MergeStrategy) -> Self {
+        self.decode.merge_strategy = strategy;
+        self
+    }
+
+    pub fn reseam(mut self, reseam: bool) -> Self {
+        self.decode.reseam = reseam;
+        self
+    }
+
+    pub fn condition_on_previous_text(mut self, condition: bool) -> Self {
+        self.decode.condition_on_previous_text = condition;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.decode.seed = Some(seed);
+        self
+    }
+
+    pub fn suppress_non_speech(mut self, suppress: bool) -> Self {
+        self.decode.suppress_non_speech = suppress;
+        self
+    }
+
+    pub fn preprocess(mut self, preprocess: PreprocessOptions) -> Self {
+        self.decode.preprocess = preprocess;
+        self
+    }
+
+    pub fn postprocess(mut self, postprocess: PostprocessOptions) -> Self {
+        self.decode.postprocess = postprocess;
+        self
+    }
+
+    pub fn vad(mut self, vad: Option<VadOptions>) -> Self {
+        self.decode.vad = vad;
+        self
+    }
+
+    pub fn turn_detection(mut self, turn_detection: Option<TurnOptions>) -> Self {
+        self.decode.turn_detection = turn_detection;
+        self
+    }
+
+    pub fn chunk_length(mut self, seconds: f32) -> Self {
+        self.decode.chunk_length_s = seconds;
+        self
+    }
+
+    pub fn chunk_overlap(mut self, seconds: f32) -> Self {
+        self.decode.chunk_overlap_s = seconds;
+        self
+    }
+
+    /// Append `frames` of silence to each window. Models with a shorter encoder context than
+    /// whisper's need less than the default to fit a window.
+    pub fn end_padding(mut self, frames: usize) -> Self {
+        self.decode.end_padding_frames = frames;
+        self
+    }
+
+    /// Let each encoder frame attend only to the frames at most `frames` away, e.g. when
+    /// encoding a full window runs out of memory.
+    pub fn encoder_attention_window(mut self, frames: Option<usize>) -> Self {
+        self.decode.encoder_attention_window = EncoderAttentionWindow(frames);
+        self
+    }
+
+    #[cfg(feature = "std-io")]
+    pub fn encoder_cache(mut self, cache: Arc<EncoderCache>) -> Self {
+        self.decode.encoder_cache = Some(cache);
+        self
+    }
+
+    /// Report the partial text of each chunk to `events` while decoding.
+    pub fn events(mut self, events: Sender<DecodeEvent>) -> Self {
+        self.decode.events = Some(events);
+        self
+    }
+
+    /// Stop decoding once `cancel` is cancelled from another thread.
+    pub fn cancel(mut self, cancel: CancellationToken) -> Self {
+        self.decode.cancel = Some(cancel);
+        self
+    }
+
+    pub fn logit_bias(mut self, logit_bias: HashMap<usize, f32>) -> Self {
+        self.decode.logit_bias = logit_bias;
+        self
+    }
+
+    /// Run `filter` on the logits of every decoding step.
+    pub fn logit_filter(mut self, filter: Arc<dyn LogitFilter>) -> Self {
+        self.decode.logit_filters.push(filter);
+        self
+    }
+}
+
+/// Cancels a transcription from another thread. Decoding checks it before every chunk and every
+/// decoding step, so it stops within one decoder forward pass.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl DecodeOptions {
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+}
+
+/// Attaches the transcript decoded so far to a cancellation.
+fn with_partial(result: Result<()>, partial: impl FnOnce() -> Result<Transcript>) -> Result<()> {
+    match result {
+        Err(WhisperError::Cancelled(_)) => Err(WhisperError::Cancelled(Some(Box::new(partial()?)))),
+        result => result,
+    }
+}
+
+/// Progress reported while decoding through [`DecodeOptions::events`].
+#[derive(Clone, Debug)]
+pub enum DecodeEvent {
+    /// A token was appended to the partial text of the chunk.
+    Token {
+        chunk: usize,
+        token: usize,
+        text: String,
+    },
+    /// The leading hypothesis changed, so `text` replaces the partial text of the chunk.
+    Revised { chunk: usize, text: String },
+    /// The chunk finished decoding with `text`.
+    ChunkFinished { chunk: usize, text: String },
+    /// A chunk finished decoding, after its [`DecodeEvent::ChunkFinished`].
+    Progress(Progress),
+}
+
+/// How far a transcription has come. Events are delivered over a channel, so a receiver that
+/// panics or goes away never affects decoding.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    /// Number of chunks decoded, including those of a resumed checkpoint.
+    pub chunks_done: usize,
+    /// Number of chunks of the whole audio, unless it is not known up front.
+    pub total_chunks: Option<usize>,
+    /// Seconds of audio decoded.
+    pub audio_seconds: f64,
+    /// Duration of the whole audio, unless it is not known up front.
+    pub total_audio_seconds: Option<f64>,
+    /// Seconds since this call started decoding.
+    pub elapsed_seconds: f64,
+    /// Seconds left at the real-time factor of this call so far, once it is known.
+    pub remaining_seconds: Option<f64>,
+}
+
+impl Progress {
+    /// Share of the audio decoded, between 0 and 1, when the duration is known.
+    pub fn fraction(&self) -> Option<f64> {
+        self.total_audio_seconds
+            .filter(|&total| total > 0.0)
+            .map(|total| (self.audio_seconds / total).min(1.0))
+    }
+}
+
+/// Sends the leading hypothesis of a chunk to [`DecodeOptions::events`] whenever it changes.
+struct EventEmitter<'a> {
+    sender: Option<&'a Sender<DecodeEvent>>,
+    bpe: &'a Gpt2Tokenizer,
+    chunk: usize,
+    end_token: usize,
+    emitted: RefCell<Vec<usize>>,
+}
+
+impl EventEmitter<'_> {
+    fn update(&self, tokens: &[usize]) {
+        let Some(sender) = self.sender else {
+            return;
+        };
+        let tokens: Vec<usize> = tokens
+            .iter()
+            .copied()
+            .filter(|&tok| tok < self.end_token)
+            .collect();
+
+        let mut emitted = self.emitted.borrow_mut();
+        if *emitted == tokens {
+            return;
+        }
+
+        let text = self.bpe.decode(&tokens, true).unwrap_or_default();
+        let event = match tokens.split_last() {
+            Some((&token, prefix)) if prefix == emitted.as_slice() => DecodeEvent::Token {
+                chunk: self.chunk,
+                token,
+                text,
+            },
+            _ => DecodeEvent::Revised {
+                chunk: self.chunk,
+                text,
+            },
+        };
+        // a dropped receiver only means nobody is listening anymore
+        let _ = sender.send(event);
+        *emitted = tokens;
+    }
+
+    fn finish(&self, tokens: &[usize]) {
+        self.update(tokens);
+        if let Some(sender) = self.sender {
+            let text = self
+                .bpe
+                .decode(&self.emitted.borrow(), true)
+                .unwrap_or_default();
+            let _ = sender.send(DecodeEvent::ChunkFinished {
+                chunk: self.chunk,
+                text,
+            });
+        }
+    }
+}
+
+/// What to do once a sequence is caught repeating itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RepetitionAction {
+    /// Suppress the token that would continue the loop.
+    Suppress,
+    /// End the sequence.
+    Terminate,
+}
+
+/// Detects a window of tokens repeated back to back at the end of a sequence.
+#[derive(Clone, Debug)]
+pub struct RepetitionOptions {
+    /// Longest repeating window searched for, in tokens.
+    pub max_window: usize,
+    /// Number of consecutive copies of a window that counts as a loop.
+    pub max_repeats: usize,
+    pub action: RepetitionAction,
+}
+
+impl Default for RepetitionOptions {
+    fn default() -> Self {
+        Self {
+            max_window: 16,
+            max_repeats: 4,
+            action: RepetitionAction::Terminate,
+        }
+    }
+}
+
+impl RepetitionOptions {
+    /// Returns the length of the shortest window that ends `tokens` and repeats at least
+    /// `max_repeats` times in a row.
+    pub fn detect(&self, tokens: &[usize]) -> Option<usize> {
+        let max_repeats = self.max_repeats.max(2);
+        (1..=self.max_window).find(|&window| {
+            let Some(span_start) = tokens.len().checked_sub(window * max_repeats) else {
+                return false;
+            };
+            let tail = &tokens[tokens.len() - window..];
+            tokens[span_start..]
+                .chunks_exact(window)
+                .all(|chunk| chunk == tail)
+        })
+    }
+}
+
+/// Transcribes `waveform`, detecting its language first unless `options` sets one or the
+/// model is English-only.
+pub fn transcribe<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    waveform: &[f32],
+    sample_rate: usize,
+    options: &TranscribeOptions,
+) -> Result<Transcript> {
+    let lang = match options.language {
+        LanguageMode::Fixed(lang) => lang,
+        _ if !bpe.kind().is_multilingual() => Language::English,
+        LanguageMode::DetectOnce | LanguageMode::DetectPerChunk { .. } => {
+            whisper
+                .detect_language(bpe, waveform, sample_rate, &options.decode)?
+                .0
+        }
+    };
+
+    waveform_to_transcript(whisper, bpe, lang, waveform, sample_rate, &options.decode)
+}
+
+/// Transcribes `waveform` in `lang` with the default options. `streaming_mode` has no effect.
+#[deprecated(note = "use `transcribe` with `TranscribeOptions`")]
+pub fn waveform_to_text<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    waveform: Vec<f32>,
+    sample_rate: usize,
+    _streaming_mode: bool,
+) -> Result<(String, Vec<usize>)> {
+    let options = TranscribeOptions::new().language(lang);
+    let transcript = transcribe(whisper, bpe, &waveform, sample_rate, &options)?;
+
+    Ok((transcript.text, transcript.tokens))
+}
+
+/// Like [`transcribe`] with a known language, but writes the text to `writer` as soon as no later chunk can
+/// change it. Only the trailing tokens that may still be merged with the next chunk are kept,
+/// so memory use does not grow with the length of the audio.
+pub fn waveform_to_text_streaming<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    waveform: &[f32],
+    sample_rate: usize,
+    options: &DecodeOptions,
+    writer: &mut impl Write,
+) -> Result<Metrics> {
+    let mut stream = TextStream::new(bpe);
+    let mut metrics = Metrics::default();
+
+    let mut state = ChunkState::new(bpe, options);
+    let result = transcribe_chunks(
+        whisper,
+        bpe,
+        lang,
+        waveform,
+        sample_rate,
+        options,
+        &mut state,
+        &mut metrics,
+        |_segment, confirmed, _state| {
+            let confirmed: Vec<usize> = confirmed.iter().map(|origin| origin.token).collect();
+            writer.write_all(stream.push(&confirmed)?.as_bytes())?;
+            Ok(())
+        },
+    );
+    // a cancelled transcription still writes out the text of the chunks decoded before
+    if let Ok(()) | Err(WhisperError::Cancelled(_)) = result {
+        writer.write_all(stream.finish(&state.window)?.as_bytes())?;
+    }
+    result?;
+
+    Ok(metrics)
+}
+
+/// Like [`waveform_to_transcript`], but saves a [`Checkpoint`] to `sink` after every chunk. Decoding
+/// continues after the chunks already recorded in `resume`, which must have been saved for the
+/// same waveform and options.
+#[allow(clippy::too_many_arguments)]
+pub fn waveform_to_text_checkpointed<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    waveform: &[f32],
+    sample_rate: usize,
+    options: &DecodeOptions,
+    resume: Option<Checkpoint>,
+    sink: &mut impl CheckpointSink,
+) -> Result<Transcript> {
+    let mut checkpoint = match resume {
+        Some(mut checkpoint) => {
+            checkpoint.check_source(waveform.len(), sample_rate, options)?;
+            checkpoint.restore_origins();
+            checkpoint
+        }
+        None => Checkpoint::new(
+            waveform.len(),
+            sample_rate,
+            options,
+            ChunkState::new(bpe, options),
+        ),
+    };
+
+    let duration = waveform.len() as f64 / sample_rate as f64;
+    let mut state = checkpoint.state.clone();
+    let mut metrics = Metrics::default();
+    let result = transcribe_chunks(
+        whisper,
+        bpe,
+        lang,
+        waveform,
+        sample_rate,
+        options,
+        &mut state,
+        &mut metrics,
+        |segment, confirmed, state| {
+            checkpoint
+                .tokens
+                .extend(confirmed.iter().map(|origin| origin.token));
+            checkpoint.token_origins.extend(confirmed);
+            checkpoint.segments.push(segment);
+            checkpoint.state = state.clone();
+            sink.save(&checkpoint)
+        },
+    );
+    with_partial(result, || {
+        let origins = [checkpoint.token_origins.as_slice(), &state.window_origins].concat();
+        let segments = checkpoint.segments.clone();
+        Transcript::new(
+            bpe,
+            lang,
+            options,
+            state.seed,
+            origins,
+            segments,
+            metrics.clone(),
+            duration,
+        )
+    })?;
+
+    let mut origins = checkpoint.token_origins;
+    origins.extend(state.window_origins);
+
+    Transcript::new(
+        bpe,
+        lang,
+        options,
+        state.seed,
+        origins,
+        checkpoint.segments,
+        metrics,
+        duration,
+    )
+}
+
+/// Like [`transcribe`], but with a known language and the decoding options alone.
+pub fn waveform_to_transcript<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    waveform: &[f32],
+    sample_rate: usize,
+    options: &DecodeOptions,
+) -> Result<Transcript> {
+    let mut origins = Vec::new();
+    let mut segments = Vec::new();
+
+    let duration = waveform.len() as f64 / sample_rate as f64;
+    let mut state = ChunkState::new(bpe, options);
+    let mut metrics = Metrics::default();
+    let result = transcribe_chunks(
+        whisper,
+        bpe,
+        lang,
+        waveform,
+        sample_rate,
+        options,
+        &mut state,
+        &mut metrics,
+        |segment, confirmed, _state| {
+            origins.extend(confirmed);
+            segments.push(segment);
+            Ok(())
+        },
+    );
+    origins.extend(&state.window_origins);
+    with_partial(result, || {
+        Transcript::new(
+            bpe,
+            lang,
+            options,
+            state.seed,
+            origins.clone(),
+            segments.clone(),
+            metrics.clone(),
+            duration,
+        )
+    })?;
+
+    Transcript::new(
+        bpe, lang, options, state.seed, origins, segments, metrics, duration,
+    )
+}
+
+/// Splits `waveform` into chunks and decodes those after `state.next_chunk` in order. See
+/// [`decode_chunks`].
+#[allow(clippy::too_many_arguments)]
+fn transcribe_chunks<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    waveform: &[f32],
+    sample_rate: usize,
+    options: &DecodeOptions,
+    state: &mut ChunkState,
+    metrics: &mut Metrics,
+    on_chunk: impl FnMut(Segment, &[TokenOrigin], &ChunkState) -> Result<()>,
+) -> Result<()> {
+    let timer = Timer::start();
+    let (waveform, chunks) = whisper.split_chunks(waveform, sample_rate, options)?;
+    let total = Some((chunks.len(), waveform.len() as f64 / SAMPLE_RATE as f64));
+    let chunks = chunks.into_iter().skip(state.next_chunk);
+    // a resumed run only transcribes the audio after the checkpoint
+    metrics.audio_seconds =
+        waveform.len().saturating_sub(state.sample_offset) as f64 / SAMPLE_RATE as f64;
+
+    // the browser cannot block its only thread on another one
+    let result = if options.mel_prefetch == 0 || cfg!(target_arch = "wasm32") {
+        let encoded = chunks.map(|chunk| whisper.encode_chunk(&waveform, chunk, options));
+        decode_chunks(
+            whisper,
+            bpe,
+            lang,
+            encoded,
+            Some(&waveform),
+            total,
+            options,
+            state,
+            metrics,
+            on_chunk,
+        )
+    } else {
+        thread::scope(|scope| {
+            let mels = prefetch_mels::<B>(
+                scope,
+                &waveform,
+                chunks,
+                whisper.encoder_mel_size(),
+                whisper.encoder_device(),
+                options.mel_prefetch,
+            );
+
+            if whisper.encoder_device() == whisper.decoder_device() {
+                let encoded = mels.into_iter().map(|mels| {
+                    mels.and_then(|(mels, chunk, mel_seconds)| {
+                        whisper.encode_chunk_mels(mels, chunk, mel_seconds, options)
+                    })
+                });
+                decode_chunks(
+                    whisper,
+                    bpe,
+                    lang,
+                    encoded,
+                    Some(&waveform),
+                    total,
+                    options,
+                    state,
+                    metrics,
+                    on_chunk,
+                )
+            } else {
+                // encode the next chunk on the encoder's device while the current one decodes
+                let (sender, receiver) = mpsc::sync_channel(1);
+                let encoder = whisper.clone();
+                scope.spawn(move || {
+                    for mels in mels {
+                        let encoded = mels.and_then(|(mels, chunk, mel_seconds)| {
+                            encoder.encode_chunk_mels(mels, chunk, mel_seconds, options)
+                        });
+                        // the receiver is gone once decoding fails
+                        if sender.send(encoded).is_err() {
+                            break;
+                        }
+                    }
+                });
+
+                decode_chunks(
+                    whisper,
+                    bpe,
+                    lang,
+                    receiver,
+                    Some(&waveform),
+                    total,
+                    options,
+                    state,
+                    metrics,
+                    on_chunk,
+                )
+            }
+        })
+    };
+    metrics.wall_seconds = timer.elapsed();
+
+    result
+}
+
+/// Computes the mel spectrogram of each chunk on a background thread, keeping up to `depth`
+/// of them ready ahead of the consumer. The spectrograms are handed over as host data so that
+/// the work is done by the time they are received, along with the seconds it took. The thread
+/// stops once the receiver is dropped.
+fn prefetch_mels<'scope, B: Backend>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    waveform: &'scope [f32],
+    chunks: impl Iterator<Item = Chunk> + Send + 'scope,
+    n_mels: usize,
+    device: B::Device,
+    depth: usize,
+) -> mpsc::Receiver<Result<(TensorData, Chunk, f64)>> {
+    let (sender, receiver) = mpsc::sync_channel(depth);
+    scope.spawn(move || {
+        for chunk in chunks {
+            let timer = Timer::start();
+            let mels = chunk_mels::<B>(waveform, chunk.range.clone(), n_mels, &device);
+            let mel_seconds = timer.elapsed();
+            if sender
+                .send(mels.map(|mels| (mels, chunk, mel_seconds)))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+fn chunk_mels<B: Backend>(
+    waveform: &[f32],
+    range: Range<usize>,
+    n_mels: usize,
+    device: &B::Device,
+) -> Result<TensorData> {
+    // clips shorter than one FFT window are padded with silence, which adds no content frames
+    let mut samples = waveform[range].to_vec();
+    samples.resize(samples.len().max(MIN_SAMPLES), 0.0);
+
+    let slice: Tensor<B, 1> = Tensor::from_floats(samples.as_slice(), device);
+    let mels = prep_audio(slice.unsqueeze(), SAMPLE_RATE as f64, n_mels)?;
+
+    Ok(mels.into_data())
+}
+
+/// Audio whose spectrogram is computed at once when looking for speaker turns.
+const TURN_MEL_BLOCK_SAMPLES: usize = 30 * SAMPLE_RATE;
+
+/// Sample offsets of the probable speaker turns in `waveform`. The spectrogram is computed on
+/// `device` a block at a time and scored on the host.
+fn speaker_turns<B: Backend>(
+    waveform: &[f32],
+    n_mels: usize,
+    device: &B::Device,
+    options: &TurnOptions,
+) -> Result<Vec<usize>> {
+    let mut mels = Vec::with_capacity(n_frames(waveform.len()) * n_mels);
+    for start in (0..waveform.len()).step_by(TURN_MEL_BLOCK_SAMPLES) {
+        let end = (start + TURN_MEL_BLOCK_SAMPLES).min(waveform.len());
+        let block = chunk_mels::<B>(waveform, start..end, n_mels, device)?
+            .to_vec::<f32>()
+            .unwrap();
+
+        // the detector reads the spectrogram frame by frame, without the padding of short blocks
+        let n_block_frames = block.len() / n_mels;
+        for t in 0..n_frames(end - start).min(n_block_frames) {
+            mels.extend((0..n_mels).map(|m| block[m * n_block_frames + t]));
+        }
+    }
+
+    Ok(detect_speaker_turns(&mels, n_mels, options))
+}
+
+/// Decodes tokens as they are confirmed. Tokens ending in an incomplete character are held
+/// back, so the concatenated output matches decoding all tokens at once.
+struct TextStream<'a> {
+    bpe: &'a Gpt2Tokenizer,
+    held: Vec<usize>,
+}
+
+impl<'a> TextStream<'a> {
+    fn new(bpe: &'a Gpt2Tokenizer) -> Self {
+        Self {
+            bpe,
+            held: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, tokens: &[usize]) -> Result<String> {
+        self.held.extend(tokens);
+        let text = self.bpe.decode(&self.held, true)?;
+        if text.ends_with(char::REPLACEMENT_CHARACTER) {
+            return Ok(String::new());
+        }
+
+        self.held.clear();
+        Ok(text)
+    }
+
+    fn finish(mut self, tokens: &[usize]) -> Result<String> {
+        self.held.extend(tokens);
+        Ok(self.bpe.decode(&self.held, true)?)
+    }
+}
+
+/// The encoder output of every chunk of a waveform, ready to be decoded any number of times.
+///
+/// Cloning is cheap since the tensors are reference counted.
+#[derive(Clone, Debug)]
+pub struct EncodedAudio<B: Backend> {
+    chunks: Vec<EncodedChunk<B>>,
+    n_samples: usize,
+}
+
+#[derive(Clone, Debug)]
+struct EncodedChunk<B: Backend> {
+    encoder_output: Tensor<B, 3>,
+    /// Number of encoder input frames holding audio rather than padding.
+    n_content_frames: usize,
+    range: Range<usize>,
+    overlaps_previous: bool,
+    level_db: f32,
+    turn: usize,
+    /// Time spent computing the spectrogram and encoding it.
+    metrics: ChunkMetrics,
+}
+
+impl<B: Backend> EncodedChunk<B> {
+    fn new(
+        chunk: Chunk,
+        encoder_output: Tensor<B, 3>,
+        n_content_frames: usize,
+        metrics: ChunkMetrics,
+    ) -> Self {
+        Self {
+            encoder_output,
+            n_content_frames: n_content_frames.min(n_frames(chunk.range.len())),
+            range: chunk.range,
+            overlaps_previous: chunk.overlaps_previous,
+            level_db: chunk.level_db,
+            turn: chunk.turn,
+            metrics,
+        }
+    }
+}
+
+impl<B: Backend> EncodedAudio<B> {
+    pub fn n_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Duration of the encoded audio in seconds.
+    pub fn duration(&self) -> f64 {
+        self.n_samples as f64 / SAMPLE_RATE as f64
+    }
+
+    /// Start of each chunk in seconds.
+    pub fn chunk_offsets(&self) -> Vec<f64> {
+        self.chunks
+            .iter()
+            .map(|chunk| chunk.range.start as f64 / SAMPLE_RATE as f64)
+            .collect()
+    }
+}
+
+impl<B: Backend> Whisper<B> {
+    /// Splits `waveform` into windows and runs the encoder on each of them. The waveform is
+    /// filtered according to `options.preprocess` first. The windows are cut at silences
+    /// detected with `options.vad`, or are fixed and overlapping without it, and are as long
+    /// as `options.chunk_length_s` at most.
+    pub fn encode_audio(
+        &self,
+        waveform: &[f32],
+        sample_rate: usize,
+        options: &DecodeOptions,
+    ) -> Result<EncodedAudio<B>> {
+        let (waveform, chunks) = self.split_chunks(waveform, sample_rate, options)?;
+        let chunks = chunks
+            .into_iter()
+            .map(|chunk| self.encode_chunk(&waveform, chunk, options))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(EncodedAudio {
+            chunks,
+            n_samples: waveform.len(),
+        })
+    }
+
+    /// Detects the spoken language from the first window of `waveform`, like the reference
+    /// implementation. Returns the most likely language with its probability among the
+    /// languages of the vocabulary. Audio without any speech is reported as English. The audio
+    /// is preprocessed and chunked according to `options`. English-only models have no
+    /// language tokens and return [`WhisperError::MissingSpecialToken`].
+    pub fn detect_language(
+        &self,
+        bpe: &Gpt2Tokenizer,
+        waveform: &[f32],
+        sample_rate: usize,
+        options: &DecodeOptions,
+    ) -> Result<(Language, f64)> {
+        let (waveform, chunks) = self.split_chunks(waveform, sample_rate, options)?;
+        let Some(chunk) = chunks.into_iter().next() else {
+            return Ok((Language::English, 0.0));
+        };
+        let encoded = self.encode_chunk(&waveform, chunk, options)?;
+
+        let probs = language_probs(self, bpe, encoded.encoder_output)?;
+        Ok(most_likely_language(probs))
+    }
+
+    /// Resamples and preprocesses `waveform`, then splits it into the windows decoded one at
+    /// a time.
+    fn split_chunks(
+        &self,
+        waveform: &[f32],
+        sample_rate: usize,
+        options: &DecodeOptions,
+    ) -> Result<(Vec<f32>, Vec<Chunk>)> {
+        let (n_waveform_samples_per_window, chunk_overlap) = self.chunk_lengths(options)?;
+
+        // the mel filterbank and window sizes assume the rate whisper was trained on
+        let mut waveform = resample(waveform, sample_rate, SAMPLE_RATE);
+        let sample_rate = SAMPLE_RATE;
+        preprocess(&mut waveform, sample_rate, &options.preprocess);
+
+        let turns = match &options.turn_detection {
+            Some(turn_options) => speaker_turns::<B>(
+                &waveform,
+                self.encoder_mel_size(),
+                &self.encoder_device(),
+                turn_options,
+            )?,
+            None => Vec::new(),
+        };
+        let speech = options
+            .vad
+            .as_ref()
+            .map(|vad| detect_speech_segments(&waveform, sample_rate, vad));
+
+        // chunks never cross a turn, so that each segment belongs to a single speaker
+        let bounds: Vec<usize> = iter::once(0)
+            .chain(turns)
+            .chain(iter::once(waveform.len()))
+            .collect();
+        let mut chunks = Vec::new();
+        for (turn, span) in bounds.windows(2).enumerate() {
+            let span = span[0]..span[1];
+            let turn_chunks = match &speech {
+                Some(speech) => {
+                    let segments: Vec<Range<usize>> = speech
+                        .iter()
+                        .map(|segment| segment.start.max(span.start)..segment.end.min(span.end))
+                        .filter(|segment| !segment.is_empty())
+                        .collect();
+                    vad_chunks(
+                        &waveform,
+                        &segments,
+                        n_waveform_samples_per_window,
+                        chunk_overlap,
+                    )
+                }
+                None => fixed_chunks(
+                    &waveform,
+                    span,
+                    n_waveform_samples_per_window,
+                    chunk_overlap,
+                ),
+            };
+            chunks.extend(turn_chunks.into_iter().map(|chunk| Chunk { turn, ..chunk }));
+        }
+
+        #[cfg(feature = "std-io")]
+        if options.encoder_cache.is_some() {
+            for chunk in &mut chunks {
+                chunk.cache_key = Some(EncoderCache::key(
+                    &waveform[chunk.range.clone()],
+                    self.encoder_mel_size(),
+                    options.end_padding_frames,
+                    options.encoder_attention_window,
+                ));
+            }
+        }
+
+        Ok((waveform, chunks))
+    }
+
+    /// Window length and overlap of `options` in samples, checked against each other and
+    /// against the spectrogram frames the encoder accepts.
+    fn chunk_lengths(&self, options: &DecodeOptions) -> Result<(usize, usize)> {
+        let (length, overlap) = (options.chunk_length_s, options.chunk_overlap_s);
+        if !(length.is_finite() && length > 0.0) {
+            return Err(WhisperError::InvalidOptions(format!(
+                "chunk length must be positive, got {length} s"
+            )));
+        }
+        if !(overlap.is_finite() && (0.0..length).contains(&overlap)) {
+            return Err(WhisperError::InvalidOptions(format!(
+                "chunk overlap must be at least 0 s and shorter than the {length} s chunk length, got {overlap} s"
+            )));
+        }
+
+        let window_samples = (length as f64 * SAMPLE_RATE as f64).round() as usize;
+        let overlap_samples = (overlap as f64 * SAMPLE_RATE as f64).round() as usize;
+        let n_window_frames = n_frames(window_samples) + options.end_padding_frames;
+        if n_window_frames > self.encoder_max_frames() {
+            return Err(WhisperError::AudioTooLong {
+                n_frames: n_window_frames,
+                max_frames: self.encoder_max_frames(),
+            });
+        }
+
+        // both round to whole samples, which can leave them equal
+        Ok((window_samples, overlap_samples.min(window_samples - 1)))
+    }
+
+    /// Spectrogram frames the encoder accepts, which halves them with its strided convolution.
+    fn encoder_max_frames(&self) -> usize {
+        2 * self.encoder_ctx_size()
+    }
+
+    fn encode_chunk(
+        &self,
+        waveform: &[f32],
+        chunk: Chunk,
+        options: &DecodeOptions,
+    ) -> Result<EncodedChunk<B>> {
+        let timer = Timer::start();
+        let mels = chunk_mels::<B>(
+            waveform,
+            chunk.range.clone(),
+            self.encoder_mel_size(),
+            &self.encoder_device(),
+        )?;
+        self.encode_chunk_mels(mels, chunk, timer.elapsed(), options)
+    }
+
+    /// Uploads the mel spectrogram of `chunk` to the encoder's device and encodes it with
+    /// `options.end_padding_frames` of silence appended, unless `options.encoder_cache` holds
+    /// its encoder output already. `mel_seconds` is the time it took to compute the spectrogram.
+    fn encode_chunk_mels(
+        &self,
+        mels: TensorData,
+        chunk: Chunk,
+        mel_seconds: f64,
+        options: &DecodeOptions,
+    ) -> Result<EncodedChunk<B>> {
+        let device = self.encoder_device();
+        let timer = Timer::start();
+
+        #[cfg(feature = "std-io")]
+        let cache = options.encoder_cache.as_ref().zip(chunk.cache_key.as_ref());
+        #[cfg(feature = "std-io")]
+        if let Some((cache, key)) = cache {
+            // the spectrogram of a clip shorter than one FFT window has frames of padding
+            let n_frames = mels.shape[2];
+            let shape = [
+                1,
+                (n_frames + options.end_padding_frames).div_ceil(2),
+                self.encoder_state_size(),
+            ];
+            if let Some(data) = cache.get(key, shape) {
+                let encoder_output = Tensor::from_data(data, &device);
+                return Ok(EncodedChunk::new(
+                    chunk,
+                    encoder_output,
+                    n_frames,
+                    ChunkMetrics {
+                        mel_seconds,
+                        encode_seconds: timer.stop::<B>(&device),
+                        ..Default::default()
+                    },
+                ));
+            }
+        }
+
+        let mels = Tensor::from_data(mels, &device);
+        let (encoder_output, n_content_frames) = encode_mels(
+            self,
+            mels,
+            options.end_padding_frames,
+            options.encoder_attention_window,
+        )?;
+        let encode_seconds = timer.stop::<B>(&device);
+
+        #[cfg(feature = "std-io")]
+        if let Some((cache, key)) = cache {
+            cache.insert(key, encoder_output.to_data())?;
+        }
+
+        Ok(EncodedChunk::new(
+            chunk,
+            encoder_output,
+            n_content_frames,
+            ChunkMetrics {
+                mel_seconds,
+                encode_seconds,
+                ..Default::default()
+            },
+        ))
+    }
+}
+
+/// A decoded chunk, positioned in seconds from the start of the audio.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Segment {
+    /// Index of the chunk.
+    pub id: usize,
+    pub start: f64,
+    pub end: f64,
+    /// Code of the language the chunk was decoded in, when it was detected for each chunk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Index of the speaker turn the chunk lies in. It only advances at the turns found with
+    /// [`DecodeOptions::turn_detection`].
+    #[serde(default)]
+    pub turn_index: usize,
+    #[serde(flatten)]
+    pub result: DecodingResult,
+}
+
+/// The chunk a token of the merged transcript was decoded from. Tokens that consecutive chunks
+/// agree on in their overlap belong to the later chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenOrigin {
+    pub token: usize,
+    /// Index of the chunk, i.e. [`Segment::id`].
+    pub chunk: usize,
+    /// Span of the chunk in seconds from the start of the audio.
+    pub chunk_start_s: f64,
+    pub chunk_end_s: f64,
+    /// Log probability the chunk gave the token. Missing for tokens restored from checkpoints
+    /// saved before it was tracked.
+    #[serde(default)]
+    pub log_prob: Option<f64>,
+}
+
+/// The transcription of a whole waveform. Serialized, the field names follow the JSON output
+/// of the reference implementation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transcript {
+    pub text: String,
+    /// Tokens of all chunks, with the overlap between consecutive chunks merged.
+    pub tokens: Vec<usize>,
+    /// Chunk each of `tokens` came from, which places the text in time without timestamp
+    /// tokens or word alignment.
+    #[serde(default)]
+    pub token_origins: Vec<TokenOrigin>,
+    pub segments: Vec<Segment>,
+    /// Code of the transcribed language, e.g. `en`.
+    pub language: String,
+    pub metadata: TranscriptMetadata,
+}
+
+/// How a transcript was produced.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TranscriptMetadata {
+    /// Name of the model, if the caller provided it.
+    pub model: Option<String>,
+    pub beam_size: usize,
+    #[serde(default)]
+    pub task: Task,
+    /// Temperatures that were available for fallback. Each segment records the one it used.
+    pub temperatures: Vec<f64>,
+    /// Seed of the sampling generator. Passing it as [`DecodeOptions::seed`] samples the same
+    /// tokens again.
+    pub seed: u64,
+    /// Timings of the chunks decoded by the call that returned the transcript.
+    #[serde(default)]
+    pub metrics: Metrics,
+    /// Length of the whole audio in seconds.
+    #[serde(default)]
+    pub duration: f64,
+}
+
+impl Transcript {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        bpe: &Gpt2Tokenizer,
+        lang: Language,
+        options: &DecodeOptions,
+        seed: u64,
+        token_origins: Vec<TokenOrigin>,
+        segments: Vec<Segment>,
+        metrics: Metrics,
+        duration: f64,
+    ) -> Result<Self> {
+        let tokens: Vec<usize> = token_origins.iter().map(|origin| origin.token).collect();
+        Ok(Self {
+            text: options.postprocess.apply(&bpe.decode(&tokens[..], true)?),
+            tokens,
+            token_origins,
+            segments,
+            language: lang.as_str().to_string(),
+            metadata: TranscriptMetadata {
+                model: None,
+                beam_size: options.beam_size,
+                task: options.task,
+                temperatures: options.temperatures.clone(),
+                seed,
+                metrics,
+                duration,
+            },
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("transcripts only contain string map keys")
+    }
+
+    /// Serializes the transcript like the `verbose_json` response format of OpenAI's
+    /// transcription API. Segments list their text tokens without the special tokens of the
+    /// prefix, and `seek` is the spectrogram frame the chunk starts at.
+    pub fn to_verbose_json(&self, bpe: &Gpt2Tokenizer) -> String {
+        let language = Language::from_code(&self.language)
+            .map(|lang| lang.name())
+            .unwrap_or_else(|| self.language.clone());
+        let verbose = VerboseJson {
+            task: self.metadata.task,
+            language,
+            duration: self.metadata.duration,
+            text: &self.text,
+            segments: self
+                .segments
+                .iter()
+                .map(|segment| VerboseSegment {
+                    id: segment.id,
+                    seek: (segment.start * MEL_FRAMES_PER_SECOND).round() as usize,
+                    start: segment.start,
+                    end: segment.end,
+                    text: &segment.result.text,
+                    tokens: segment
+                        .result
+                        .tokens
+                        .iter()
+                        .copied()
+                        .filter(|&tok| !bpe.is_special(tok))
+                        .collect(),
+                    temperature: segment.result.temperature,
+                    avg_logprob: segment.result.avg_log_prob,
+                    compression_ratio: segment.result.compression_ratio,
+                    no_speech_prob: segment.result.no_speech_prob,
+                })
+                .collect(),
+        };
+
+        serde_json::to_string_pretty(&verbose).expect("transcripts only contain string map keys")
+    }
+}
+
+/// Spectrogram frames per second of audio, before the stride-2 convolution of the encoder.
+const MEL_FRAMES_PER_SECOND: f64 = 100.0;
+
+/// Layout of OpenAI's `verbose_json` transcription response.
+#[derive(Serialize)]
+struct VerboseJson<'a> {
+    task: Task,
+    /// Name of the language in lowercase, e.g. `english`.
+    language: String,
+    duration: f64,
+    text: &'a str,
+    segments: Vec<VerboseSegment<'a>>,
+}
+
+#[derive(Serialize)]
+struct VerboseSegment<'a> {
+    id: usize,
+    seek: usize,
+    start: f64,
+    end: f64,
+    text: &'a str,
+    tokens: Vec<usize>,
+    temperature: f64,
+    avg_logprob: f64,
+    compression_ratio: f64,
+    no_speech_prob: f64,
+}
+
+/// Decodes audio previously encoded with [`Whisper::encode_audio`]. Chunking already happened
+/// during encoding, so `options.vad` is ignored.
+pub fn decode_encoded<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    encoded: &EncodedAudio<B>,
+    options: &DecodeOptions,
+) -> Result<Transcript> {
+    let chunks = encoded.chunks.iter().cloned().map(Ok);
+
+    let mut origins = Vec::new();
+    let mut segments = Vec::new();
+    let mut state = ChunkState::new(bpe, options);
+    let duration = encoded.duration();
+    let timer = Timer::start();
+    let mut metrics = Metrics {
+        audio_seconds: duration,
+        ..Default::default()
+    };
+    let result = decode_chunks(
+        whisper,
+        bpe,
+        lang,
+        chunks,
+        None,
+        Some((encoded.n_chunks(), encoded.duration())),
+        options,
+        &mut state,
+        &mut metrics,
+        |segment, confirmed, _state| {
+            origins.extend(confirmed);
+            segments.push(segment);
+            Ok(())
+        },
+    );
+    origins.extend(&state.window_origins);
+    metrics.wall_seconds = timer.elapsed();
+    with_partial(result, || {
+        Transcript::new(
+            bpe,
+            lang,
+            options,
+            state.seed,
+            origins.clone(),
+            segments.clone(),
+            metrics.clone(),
+            duration,
+        )
+    })?;
+
+    Transcript::new(
+        bpe, lang, options, state.seed, origins, segments, metrics, duration,
+    )
+}
+
+/// Number of trailing tokens of the merged transcript searched for the overlap with a chunk.
+const MAX_OVERLAP_OFFSETS: usize = 40;
+
+/// Chunks decoded at a higher temperature than this do not condition the next one, like in
+/// the reference implementation.
+const MAX_CONDITIONING_TEMPERATURE: f64 = 0.5;
+
+/// How the tokens of a chunk are joined to the end of the transcript when the chunk overlaps
+/// the previous one. All strategies align the token sequences at the start of the aligned
+/// region, where the first two switch to the newer chunk, which heard more of the audio around
+/// the overlap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergeStrategy {
+    /// Switch at the first matching token, even if it lies inside a word.
+    TokenOverlap,
+    /// Move the switch forward to the first word start that both chunks agree on. When the
+    /// previous chunk ran into the length limit, its last word may be cut short and is
+    /// replaced by the newer chunk's version.
+    WordBoundary,
+    /// Align like `TokenOverlap`, then keep each token of the aligned region from the chunk
+    /// that gave it the higher probability, the newer one on ties, since either chunk may have
+    /// heard too little context at its edge. Chunks agreeing on less than `min_agreement` of
+    /// the aligned tokens, or not aligning at all, are decoded again across the seam when
+    /// [`DecodeOptions::reseam`] is set.
+    Confidence { min_agreement: f64 },
+}
+
+impl MergeStrategy {
+    /// Returns the number of tokens of `prev` to keep and the index of the first token of
+    /// `curr` to append after them, or `None` if no overlap was found.
+    fn merge_point(
+        self,
+        bpe: &Gpt2Tokenizer,
+        prev: &[usize],
+        curr: &[usize],
+        end_token: usize,
+    ) -> Option<(usize, usize)> {
+        match self {
+            MergeStrategy::TokenOverlap | MergeStrategy::Confidence { .. } => {
+                find_chunk_overlap(prev, curr, MAX_OVERLAP_OFFSETS, 3)
+            }
+            MergeStrategy::WordBoundary => {
+                let continues_word =
+                    |tok: Option<&usize>| tok.is_some_and(|&tok| bpe.continues_word(tok));
+
+                // without an end of text the chunk stopped at the length limit, possibly mid-word
+                let prev = if prev.last() == Some(&end_token) {
+                    prev
+                } else {
+                    let last_word = prev
+                        .iter()
+                        .rposition(|&tok| !bpe.continues_word(tok))
+                        .unwrap_or(0);
+                    &prev[..last_word]
+                };
+
+                let (prev_index, curr_index) =
+                    find_chunk_overlap(prev, curr, MAX_OVERLAP_OFFSETS, 3)?;
+                let boundary = (0..curr.len() - curr_index)
+                    .map(|k| (prev_index + k, curr_index + k))
+                    .find(|&(p, c)| !continues_word(prev.get(p)) && !continues_word(curr.get(c)));
+
+                // languages written without spaces have no boundaries to align to
+                Some(boundary.unwrap_or((prev_index, curr_index)))
+            }
+        }
+    }
+
+    /// Whether a seam joined at `merge_point` should be decoded again.
+    fn needs_reseam(
+        self,
+        prev: &[usize],
+        curr: &[usize],
+        merge_point: Option<(usize, usize)>,
+    ) -> bool {
+        let MergeStrategy::Confidence { min_agreement } = self else {
+            return false;
+        };
+        // without a merge point the chunks would be joined end to end, repeating their overlap
+        merge_point
+            .is_none_or(|merge_point| overlap_agreement(prev, curr, merge_point) < min_agreement)
+    }
+}
+
+/// Fraction of the tokens aligned from `merge_point` on that `prev` and `curr` agree on.
+fn overlap_agreement(
+    prev: &[usize],
+    curr: &[usize],
+    (prev_index, curr_index): (usize, usize),
+) -> f64 {
+    let aligned = prev[prev_index..].iter().zip(&curr[curr_index..]);
+    let n_aligned = aligned.len();
+    if n_aligned == 0 {
+        return 0.0;
+    }
+
+    aligned.filter(|(prev, curr)| prev == curr).count() as f64 / n_aligned as f64
+}
+
+/// Replaces the tokens of `prev` from the merge point on with those of `curr`, or appends all
+/// of `curr` without a merge point. By confidence, each aligned position keeps the more likely
+/// of the two tokens instead, the newer one on ties, and text is never replaced by a special
+/// token such as the end of text that ended the previous chunk.
+fn splice_tokens(
+    prev: &mut Vec<TokenOrigin>,
+    curr: &[TokenOrigin],
+    merge_point: Option<(usize, usize)>,
+    by_confidence: bool,
+    end_token: usize,
+) {
+    let Some((prev_index, curr_index)) = merge_point else {
+        prev.extend(curr);
+        return;
+    };
+
+    let replaced = prev.split_off(prev_index);
+    let curr = &curr[curr_index..];
+    if by_confidence {
+        let n_aligned = replaced.len().min(curr.len());
+        prev.extend(replaced.iter().zip(curr).map(|(old, new)| {
+            let keep_old = old.token < end_token
+                && match (old.log_prob, new.log_prob) {
+                    (Some(old), Some(new)) => old > new,
+                    _ => false,
+                };
+            if keep_old {
+                *old
+            } else {
+                *new
+            }
+        }));
+        prev.extend(&curr[n_aligned..]);
+    } else {
+        prev.extend(curr);
+    }
+}
+
+/// What carries over from one chunk to the next.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ChunkState {
+    /// Index of the next chunk to decode.
+    pub(crate) next_chunk: usize,
+    /// End of the last decoded chunk, in samples at [`SAMPLE_RATE`].
+    pub(crate) sample_offset: usize,
+    /// Trailing tokens of the transcript that may still merge with the next chunk. A merge only
+    /// replaces tokens among the last `MAX_OVERLAP_OFFSETS` and never shortens the transcript,
+    /// so earlier tokens are final.
+    pub(crate) window: Vec<usize>,
+    /// Chunk each token of `window` came from.
+    #[serde(default)]
+    pub(crate) window_origins: Vec<TokenOrigin>,
+    /// Tokens conditioning the next chunk.
+    pub(crate) prompt_tokens: Vec<usize>,
+    /// Number of tokens at the end of `prompt_tokens` taken from `window`, which the next
+    /// merge replaces along with the window.
+    #[serde(default)]
+    pub(crate) prompt_window: usize,
+    /// Seed of the sampling generator, which is reseeded for each chunk from this and the
+    /// chunk index so that resuming samples the same tokens.
+    #[serde(default)]
+    pub(crate) seed: u64,
+    /// Code of the language of the last decoded chunk, when it is detected for each chunk.
+    #[serde(default)]
+    pub(crate) language: Option<String>,
+}
+
+impl ChunkState {
+    fn new(bpe: &Gpt2Tokenizer, options: &DecodeOptions) -> Self {
+        // the user prompt conditions the first chunk, after which the previous transcription takes over
+        let prompt_tokens = options
+            .prompt
+            .as_deref()
+            .map(|prompt| bpe.encode_ordinary(&format!(" {}", prompt.trim())))
+            .unwrap_or_default();
+
+        Self {
+            next_chunk: 0,
+            sample_offset: 0,
+            window: Vec::new(),
+            window_origins: Vec::new(),
+            prompt_tokens,
+            prompt_window: 0,
+            seed: options.seed.unwrap_or_else(rand::random),
+            language: None,
+        }
+    }
+}
+
+/// Decodes `chunks` in order, merging the tokens of overlapping chunks. `on_chunk` receives each
+/// decoded segment along with the origins of the tokens that no later chunk can merge away
+/// anymore and the updated state. The tokens that were never confirmed remain in `state.window`
+/// and their origins in `state.window_origins`. The time spent
+/// on each chunk is appended to `metrics`. When the language is detected for each chunk, `lang`
+/// is the language assumed before the first one. `total` holds the number of chunks and seconds
+/// of the whole audio, when known, for the progress events. `waveform` is the preprocessed
+/// audio the chunks were cut from, if still available for [`DecodeOptions::reseam`].
+#[allow(clippy::too_many_arguments)]
+fn decode_chunks<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    chunks: impl IntoIterator<Item = Result<EncodedChunk<B>>>,
+    waveform: Option<&[f32]>,
+    total: Option<(usize, f64)>,
+    options: &DecodeOptions,
+    state: &mut ChunkState,
+    metrics: &mut Metrics,
+    mut on_chunk: impl FnMut(Segment, &[TokenOrigin], &ChunkState) -> Result<()>,
+) -> Result<()> {
+    let end_token = special_token(bpe, SpecialToken::EndofText)?;
+    let run_timer = Timer::start();
+    let resumed_seconds = state.sample_offset as f64 / SAMPLE_RATE as f64;
+
+    for chunk in chunks {
+        if options.is_cancelled() {
+            return Err(WhisperError::Cancelled(None));
+        }
+        let chunk = chunk?;
+        let timer = Timer::start();
+        let silent = options
+            .silence_threshold_db
+            .is_some_and(|threshold| chunk.level_db < threshold);
+
+        let previous_lang = state
+            .language
+            .as_deref()
+            .and_then(Language::from_code)
+            .unwrap_or(lang);
+        let chunk_lang = match options.detect_language_per_chunk {
+            Some(min_switch_prob) if !silent && bpe.kind().is_multilingual() => {
+                let probs = language_probs(whisper, bpe, chunk.encoder_output.clone())?;
+                let (detected, prob) = most_likely_language(probs);
+                if prob >= min_switch_prob {
+                    detected
+                } else {
+                    previous_lang
+                }
+            }
+            _ => previous_lang,
+        };
+        let segment_lang = options
+            .detect_language_per_chunk
+            .map(|_| chunk_lang.as_str().to_string());
+
+        let (result, decode_steps) = if silent {
+            if let Some(sender) = &options.events {
+                let _ = sender.send(DecodeEvent::ChunkFinished {
+                    chunk: state.next_chunk,
+                    text: String::new(),
+                });
+            }
+            (DecodingResult::default(), 0)
+        } else {
+            decode_chunk(
+                whisper,
+                bpe,
+                chunk_lang,
+                chunk.encoder_output.clone(),
+                chunk.n_content_frames,
+                state.next_chunk,
+                state.seed,
+                &state.prompt_tokens,
+                options,
+            )?
+        };
+        metrics.chunks.push(ChunkMetrics {
+            decode_seconds: timer.stop::<B>(&whisper.decoder_device()),
+            decode_steps,
+            tokens: result.tokens.len(),
+            ..chunk.metrics
+        });
+        let new_tokens = &result.tokens;
+        // whether the next chunk is prompted with the text so far, decided here but applied
+        // once the tokens are merged; `None` keeps the prompt as it is
+        let condition = if result.is_no_speech(options) {
+            None
+        } else {
+            Some(
+                options.condition_on_previous_text
+                    && result.temperature <= MAX_CONDITIONING_TEMPERATURE
+                    && !result.flags.any(),
+            )
+        };
+
+        let strategy = options.merge_strategy;
+        let by_confidence = matches!(strategy, MergeStrategy::Confidence { .. });
+        let merge_point = |window: &[usize], tokens: &[usize]| {
+            if chunk.overlaps_previous {
+                strategy.merge_point(bpe, window, tokens, end_token)
+            } else {
+                None
+            }
+        };
+
+        let mut overlap = merge_point(&state.window, new_tokens);
+        if let Some(waveform) = waveform.filter(|_| options.reseam && chunk.overlaps_previous) {
+            if strategy.needs_reseam(&state.window, new_tokens, overlap) {
+                let timer = Timer::start();
+                let (bridge, steps) =
+                    decode_seam(whisper, bpe, chunk_lang, waveform, &chunk, state, options)?;
+                if let Some(chunk_metrics) = metrics.chunks.last_mut() {
+                    chunk_metrics.decode_seconds += timer.stop::<B>(&whisper.decoder_device());
+                    chunk_metrics.decode_steps += steps;
+                }
+
+                // the bridge takes over from the previous chunk, and this chunk from the bridge
+                let bridge_tokens: Vec<usize> = bridge.iter().map(|origin| origin.token).collect();
+                let bridge_point = merge_point(&state.window, &bridge_tokens);
+                if bridge_point.is_some() {
+                    splice_tokens(
+                        &mut state.window_origins,
+                        &bridge,
+                        bridge_point,
+                        by_confidence,
+                        end_token,
+                    );
+                    state.window = state
+                        .window_origins
+                        .iter()
+                        .map(|origin| origin.token)
+                        .collect();
+                    overlap = merge_point(&state.window, new_tokens);
+                }
+            }
+        }
+
+        let chunk_start_s = chunk.range.start as f64 / SAMPLE_RATE as f64;
+        let chunk_end_s = chunk.range.end as f64 / SAMPLE_RATE as f64;
+        let new_origins: Vec<TokenOrigin> = new_tokens
+            .iter()
+            .zip(&result.token_log_probs)
+            .map(|(&token, &log_prob)| TokenOrigin {
+                token,
+                chunk: state.next_chunk,
+                chunk_start_s,
+                chunk_end_s,
+                log_prob: Some(log_prob),
+            })
+            .collect();
+        // the tokens taken from this chunk replace those of the previous one in the overlap
+        splice_tokens(
+            &mut state.window_origins,
+            &new_origins,
+            overlap,
+            by_confidence,
+            end_token,
+        );
+        let window = &mut state.window;
+        window.clear();
+        window.extend(state.window_origins.iter().map(|origin| origin.token));
+
+        let n_confirmed = window.len().saturating_sub(MAX_OVERLAP_OFFSETS);
+        window.drain(..n_confirmed);
+        let confirmed: Vec<TokenOrigin> = state.window_origins.drain(..n_confirmed).collect();
+
+        let n_window_text = window.iter().filter(|&&tok| tok < end_token).count();
+        let prompt_tokens = &mut state.prompt_tokens;
+        match condition {
+            // nothing was said, so the prompt carries over to the next chunk, whose merge can
+            // only replace what is left of the window
+            None => state.prompt_window = state.prompt_window.min(n_window_text),
+            Some(true) => {
+                // the merged tokens take the place of the previous window, so the overlap
+                // decoded by both chunks appears in the prompt once. A previous window left out
+                // of the prompt stays out of it.
+                let replaces_window = state.prompt_window > 0;
+                let prompted = |origins: &[TokenOrigin]| -> Vec<usize> {
+                    origins
+                        .iter()
+                        .filter(|origin| replaces_window || origin.chunk == state.next_chunk)
+                        .map(|origin| origin.token)
+                        .filter(|&tok| tok < end_token)
+                        .collect()
+                };
+                let window_prompt = prompted(&state.window_origins);
+                prompt_tokens.truncate(prompt_tokens.len() - state.prompt_window);
+                prompt_tokens.extend(prompted(&confirmed));
+                prompt_tokens.extend(&window_prompt);
+                let n_stale = prompt_tokens
+                    .len()
+                    .saturating_sub(whisper.decoder_ctx_size());
+                prompt_tokens.drain(..n_stale);
+                state.prompt_window = window_prompt.len().min(prompt_tokens.len());
+            }
+            Some(false) => {
+                // a likely hallucination would otherwise carry over into the following chunks
+                prompt_tokens.clear();
+                state.prompt_window = 0;
+            }
+        }
+
+        state.next_chunk += 1;
+        state.sample_offset = chunk.range.end;
+        state.language.clone_from(&segment_lang);
+
+        on_chunk(
+            Segment {
+                id: state.next_chunk - 1,
+                start: chunk_start_s,
+                end: chunk_end_s,
+                language: segment_lang,
+                turn_index: chunk.turn,
+                result: DecodingResult {
+                    text: options.postprocess.apply(&result.text),
+                    ..result
+                },
+            },
+            &confirmed,
+            state,
+        )?;
+
+        if let Some(sender) = &options.events {
+            let audio_seconds = chunk.range.end as f64 / SAMPLE_RATE as f64;
+            let elapsed_seconds = run_timer.elapsed();
+            let seconds_per_audio_second = elapsed_seconds / (audio_seconds - resumed_seconds);
+            let _ = sender.send(DecodeEvent::Progress(Progress {
+                chunks_done: state.next_chunk,
+                total_chunks: total.map(|(n_chunks, _)| n_chunks),
+                audio_seconds,
+                total_audio_seconds: total.map(|(_, seconds)| seconds),
+                elapsed_seconds,
+                remaining_seconds: total
+                    .map(|(_, seconds)| {
+                        (seconds - audio_seconds).max(0.0) * seconds_per_audio_second
+                    })
+                    .filter(|remaining| remaining.is_finite()),
+            }));
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes a window as long as a chunk centered on the seam between `chunk` and the previous
+/// one, without a prompt so that neither chunk's text steers it. Its tokens belong to `chunk`
+/// but span the window.
+fn decode_seam<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    waveform: &[f32],
+    chunk: &EncodedChunk<B>,
+    state: &ChunkState,
+    options: &DecodeOptions,
+) -> Result<(Vec<TokenOrigin>, usize)> {
+    let (window_samples, _) = whisper.chunk_lengths(options)?;
+    let seam = (chunk.range.start + state.sample_offset) / 2;
+    let start = seam
+        .saturating_sub(window_samples / 2)
+        .min(waveform.len().saturating_sub(window_samples));
+    let end = (start + window_samples).min(waveform.len());
+
+    let mut bridge = Chunk::new(waveform, start..end, false);
+    bridge.turn = chunk.turn;
+    let encoded = whisper.encode_chunk(waveform, bridge, options)?;
+    // the bridge is not a chunk of its own, so its partial text is not reported
+    let options = &DecodeOptions {
+        events: None,
+        ..options.clone()
+    };
+    let (result, steps) = decode_chunk(
+        whisper,
+        bpe,
+        lang,
+        encoded.encoder_output,
+        encoded.n_content_frames,
+        state.next_chunk,
+        state.seed,
+        &[],
+        options,
+    )?;
+
+    let (start_s, end_s) = (
+        start as f64 / SAMPLE_RATE as f64,
+        end as f64 / SAMPLE_RATE as f64,
+    );
+    let origins = result
+        .tokens
+        .iter()
+        .zip(&result.token_log_probs)
+        .map(|(&token, &log_prob)| TokenOrigin {
+            token,
+            chunk: state.next_chunk,
+            chunk_start_s: start_s,
+            chunk_end_s: end_s,
+            log_prob: Some(log_prob),
+        })
+        .collect();
+
+    Ok((origins, steps))
+}
+
+/// A span of the waveform decoded in one encoder window.
+struct Chunk {
+    range: Range<usize>,
+    /// Whether the chunk shares audio with the previous one, requiring their tokens to be merged.
+    overlaps_previous: bool,
+    /// RMS level of the span in dBFS.
+    level_db: f32,
+    /// Index of the speaker turn the span lies in.
+    turn: usize,
+    /// Key of the span's encoder output in [`DecodeOptions::encoder_cache`], when set.
+    #[cfg(feature = "std-io")]
+    cache_key: Option<String>,
+}
+
+impl Chunk {
+    fn new(waveform: &[f32], range: Range<usize>, overlaps_previous: bool) -> Self {
+        Self {
+            level_db: rms_db(&waveform[range.clone()]),
+            range,
+            overlaps_previous,
+            turn: 0,
+            #[cfg(feature = "std-io")]
+            cache_key: None,
+        }
+    }
+}
+
+/// Splits `span` into windows of `window_length_samples`, each overlapping the previous by
+/// `chunk_overlap`, which must be shorter than a window. The last window ends at the end of
+/// the span, so no window lies entirely within the overlap of its predecessor.
+fn fixed_chunks(
+    waveform: &[f32],
+    span: Range<usize>,
+    window_length_samples: usize,
+    chunk_overlap: usize,
+) -> Vec<Chunk> {
+    let shift = window_length_samples - chunk_overlap;
+    let iter_len = span
+        .len()
+        .saturating_sub(window_length_samples)
+        .div_ceil(shift)
+        + 1;
+
+    (0..iter_len)
+        .map(|i| {
+            let start = span.start + i * shift;
+            let end = (start + window_length_samples).min(span.end);
+            // without an overlap there is nothing to merge
+            Chunk::new(waveform, start..end, i > 0 && chunk_overlap > 0)
+        })
+        .collect()
+}
+
+/// Packs consecutive speech segments into windows, cutting at the silence closest to the
+/// window length. Segments longer than a window fall back to fixed, overlapping windows.
+fn vad_chunks(
+    waveform: &[f32],
+    segments: &[Range<usize>],
+    window_length_samples: usize,
+    chunk_overlap: usize,
+) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut current: Option<Range<usize>> = None;
+
+    for segment in segments {
+        if let Some(range) = &mut current {
+            if segment.end - range.start <= window_length_samples {
+                range.end = segment.end;
+                continue;
+            }
+
+            chunks.push(Chunk::new(waveform, range.clone(), false));
+        }
+
+        if segment.len() > window_length_samples {
+            chunks.extend(fixed_chunks(
+                waveform,
+                segment.clone(),
+                window_length_samples,
+                chunk_overlap,
+            ));
+            current = None;
+        } else {
+            current = Some(segment.clone());
+        }
+    }
+
+    if let Some(range) = current {
+        chunks.push(Chunk::new(waveform, range, false));
+    }
+
+    chunks
+}
+
+#[derive(Clone)]
+struct BeamSearchToken {
+    token: usize,
+    log_prob: f64,
+}
+
+/// Per-sequence logit manipulations applied before each decoding step.
+#[derive(Debug)]
+struct TokenFilters {
+    logit_bias: HashMap<usize, f32>,
+    suppress_tokens: Vec<usize>,
+    blank_tokens: Vec<usize>,
+    /// Index of the first token generated after the initial prefix.
+    sample_begin: usize,
+    no_repeat_ngram_size: Option<usize>,
+    repetition: Option<RepetitionOptions>,
+    end_token: usize,
+}
+
+impl LogitFilter for TokenFilters {
+    fn apply(&self, seq: &[usize], logits: &mut [f64]) {
+        for (&token, &bias) in &self.logit_bias {
+            if let Some(logit) = logits.get_mut(token) {
+                *logit += bias as f64;
+            }
+        }
+
+        let mut suppress = |token: usize| {
+            if let Some(logit) = logits.get_mut(token) {
+                *logit = f64::NEG_INFINITY;
+            }
+        };
+
+        self.suppress_tokens.iter().for_each(|&tok| suppress(tok));
+
+        if seq.len() == self.sample_begin {
+            self.blank_tokens.iter().for_each(|&tok| suppress(tok));
+        }
+
+        if let Some(n) = self.no_repeat_ngram_size.filter(|&n| n > 0) {
+            let generated = &seq[self.sample_begin.min(seq.len())..];
+            if generated.len() + 1 >= n {
+                // any earlier occurrence of the trailing n - 1 tokens bans the token that followed it
+                let prefix = &generated[generated.len() + 1 - n..];
+                generated
+                    .windows(n)
+                    .filter(|ngram| &ngram[..n - 1] == prefix)
+                    .for_each(|ngram| suppress(ngram[n - 1]));
+            }
+        }
+
+        if let Some(repetition) = &self.repetition {
+            let generated = &seq[self.sample_begin.min(seq.len())..];
+            if let Some(window) = repetition.detect(generated) {
+                match repetition.action {
+                    // the loop would continue with the first token of the repeated window
+                    RepetitionAction::Suppress => suppress(generated[generated.len() - window]),
+                    RepetitionAction::Terminate => {
+                        for (token, logit) in logits.iter_mut().enumerate() {
+                            if token != self.end_token {
+                                *logit = f64::NEG_INFINITY;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn ignores_logits(&self) -> bool {
+        true
+    }
+}
+
+/// Adjusts the logits of the next token before it is selected.
+pub trait LogitFilter: fmt::Debug + Send + Sync {
+    /// `seq` holds every token so far, including the prompt and the start of transcript prefix.
+    /// Tokens that must not follow are set to negative infinity.
+    fn apply(&self, seq: &[usize], logits: &mut [f64]);
+
+    /// Whether the filter only adds to or suppresses logits chosen from `seq` alone, whatever
+    /// their values. The beam search applies such filters as a mask on the device instead of
+    /// reading back the whole distribution.
+    fn ignores_logits(&self) -> bool {
+        false
+    }
+}
+
+/// Favors phrases such as voice commands. The first token of each phrase is boosted at every
+/// step, and every later token only right after the tokens that precede it in the phrase, so a
+/// word of a phrase is not favored on its own.
+#[derive(Clone, Debug)]
+pub struct LogitBias {
+    phrases: Vec<Vec<usize>>,
+    boost: f64,
+}
+
+impl LogitBias {
+    /// Boosts the logits of the tokens of `phrases` by `boost`. The phrases are tokenized as
+    /// they appear within a sentence, i.e. after a space, and match the case they are given in.
+    pub fn from_phrases(bpe: &Gpt2Tokenizer, phrases: &[&str], boost: f32) -> Self {
+        Self {
+            phrases: phrases
+                .iter()
+                .map(|phrase| phrase.trim())
+                // a blank phrase would boost the space before every word
+                .filter(|phrase| !phrase.is_empty())
+                .map(|phrase| bpe.encode_ordinary(&format!(" {phrase}")))
+                .collect(),
+            boost: boost as f64,
+        }
+    }
+}
+
+impl LogitFilter for LogitBias {
+    fn apply(&self, seq: &[usize], logits: &mut [f64]) {
+        for phrase in &self.phrases {
+            // a token continuing several partial matches of the same phrase is boosted once
+            let mut boosted = Vec::new();
+            for (i, &token) in phrase.iter().enumerate() {
+                if seq.ends_with(&phrase[..i]) && !boosted.contains(&token) {
+                    if let Some(logit) = logits.get_mut(token) {
+                        *logit += self.boost;
+                    }
+                    boosted.push(token);
+                }
+            }
+        }
+    }
+
+    fn ignores_logits(&self) -> bool {
+        true
+    }
+}
+
+/// Keeps the control tokens of the prefix out of the transcript. With timestamps enabled, the
+/// timestamp tokens are made to follow the grammar whisper was trained on: they come in pairs
+/// around each piece of text, never decrease, and one opens the transcript.
+#[derive(Clone, Debug)]
+pub struct SpecialTokenPolicy {
+    /// Index of the first token generated after the prefix.
+    pub sample_begin: usize,
+    pub end_token: usize,
+    /// The `<|0.00|>` token, after which all tokens are timestamps.
+    pub timestamp_begin: usize,
+    pub timestamps: bool,
+    /// Latest timestamp allowed to open the transcript, in timestamp steps.
+    pub max_initial_timestamp_index: Option<usize>,
+}
+
+impl SpecialTokenPolicy {
+    pub fn new(bpe: &Gpt2Tokenizer, sample_begin: usize, timestamps: bool) -> Result<Self> {
+        Ok(Self {
+            sample_begin,
+            end_token: special_token(bpe, SpecialToken::EndofText)?,
+            timestamp_begin: special_token(bpe, SpecialToken::Timestamp(0.0))?,
+            timestamps,
+            // one second, like the reference implementation
+            max_initial_timestamp_index: Some(50),
+        })
+    }
+
+    fn apply_timestamp_rules(&self, seq: &[usize], logits: &mut [f64]) {
+        let timestamp_begin = self.timestamp_begin.min(logits.len());
+        let end_token = self.end_token.min(logits.len());
+        let generated = &seq[self.sample_begin.min(seq.len())..];
+
+        let is_timestamp = |tok: usize| tok >= self.timestamp_begin;
+        let last_was_timestamp = generated.last().is_some_and(|&tok| is_timestamp(tok));
+        let penultimate_was_timestamp =
+            generated.len() < 2 || is_timestamp(generated[generated.len() - 2]);
+
+        if last_was_timestamp {
+            if penultimate_was_timestamp {
+                // a pair of timestamps must be followed by text
+                suppress_range(logits, timestamp_begin..logits.len());
+            } else {
+                // a closing timestamp must be followed by an opening one or the end
+                suppress_range(logits, 0..end_token);
+            }
+        }
+
+        if let Some(&last_timestamp) = generated.iter().rev().find(|&&tok| is_timestamp(tok)) {
+            // timestamps may not decrease, and a segment must not be empty
+            let min_timestamp = if last_was_timestamp && !penultimate_was_timestamp {
+                last_timestamp
+            } else {
+                last_timestamp + 1
+            };
+            suppress_range(logits, timestamp_begin..min_timestamp.min(logits.len()));
+        }
+
+        if generated.is_empty() {
+            suppress_range(logits, 0..timestamp_begin);
+            if let Some(index) = self.max_initial_timestamp_index {
+                let last_allowed = self.timestamp_begin + index;
+                suppress_range(logits, (last_allowed + 1).min(logits.len())..logits.len());
+            }
+        }
+
+        // prefer a timestamp whenever all of them together are more likely than any text token
+        let log_probs = log_softmax_vec(logits, 1.0);
+        let (text, timestamps) = log_probs.split_at(timestamp_begin);
+        let max_text_log_prob = text.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        if log_sum_exp(timestamps) > max_text_log_prob {
+            suppress_range(logits, 0..timestamp_begin);
+        }
+    }
+}
+
+impl LogitFilter for SpecialTokenPolicy {
+    fn apply(&self, seq: &[usize], logits: &mut [f64]) {
+        // everything between the end of text and the timestamps only belongs in the prefix
+        let timestamp_begin = self.timestamp_begin.min(logits.len());
+        suppress_range(
+            logits,
+            (self.end_token + 1).min(timestamp_begin)..timestamp_begin,
+        );
+
+        if self.timestamps {
+            self.apply_timestamp_rules(seq, logits);
+        } else {
+            suppress_range(logits, timestamp_begin..logits.len());
+        }
+    }
+
+    // timestamps are preferred by comparing their probabilities with those of the text
+    fn ignores_logits(&self) -> bool {
+        !self.timestamps
+    }
+}
+
+fn suppress_range(logits: &mut [f64], range: Range<usize>) {
+    if let Some(logits) = logits.get_mut(range) {
+        logits.fill(f64::NEG_INFINITY);
+    }
+}
+
+/// The outcome of decoding a single mel window.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DecodingResult {
+    /// The decoded text, without special tokens.
+    pub text: String,
+    /// The tokens following the prompt, starting with the start of transcript prefix.
+    pub tokens: Vec<usize>,
+    /// Log probability of each of `tokens` given the ones before it. The prefix is given, so
+    /// its tokens have zero.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub token_log_probs: Vec<f64>,
+    /// Mean log probability of the sampled tokens, counting the end of text.
+    #[serde(rename = "avg_logprob")]
+    pub avg_log_prob: f64,
+    /// Ratio of the text length to its zlib-compressed length. High values indicate loops.
+    pub compression_ratio: f64,
+    /// The temperature of the attempt that was accepted.
+    pub temperature: f64,
+    /// Probability of the no speech token at the start of the transcript. Chunks judged silent
+    /// by [`DecodeOptions::no_speech_threshold`] keep their statistics but no text or tokens.
+    #[serde(default)]
+    pub no_speech_prob: f64,
+    /// Quality thresholds the result failed. Set when every temperature failed, so the caller
+    /// can decide whether to keep the text.
+    #[serde(default)]
+    pub flags: SegmentFlags,
+    /// The best distinct beam search hypotheses, starting with the one above, if
+    /// [`DecodeOptions::nbest`] asks for more than one. Sampled results have none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<Alternative>,
+    /// Word timings relative to the window start, if requested.
+    pub words: Vec<WordTiming>,
+}
+
+/// A beam search hypothesis of a window.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Alternative {
+    pub text: String,
+    /// The tokens following the prompt, like [`DecodingResult::tokens`].
+    pub tokens: Vec<usize>,
+    /// Log probability with the length penalty of [`DecodeOptions::length_penalty`] applied.
+    pub score: f64,
+}
+
+/// Quality thresholds of [`DecodeOptions`] a decoding result failed, which usually means
+/// whisper hallucinated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentFlags {
+    /// The compression ratio exceeds `compression_ratio_threshold`.
+    pub compression_ratio: bool,
+    /// The average log probability is below `log_prob_threshold`.
+    pub log_prob: bool,
+    /// An n-gram repeats more often than `max_ngram_repeats`.
+    pub repetition: bool,
+}
+
+impl SegmentFlags {
+    pub fn any(&self) -> bool {
+        self.compression_ratio || self.log_prob || self.repetition
+    }
+}
+
+impl DecodingResult {
+    /// Checks the result against the quality thresholds of `options`. `text_tokens` are its
+    /// tokens without the special and timestamp tokens.
+    fn evaluate_flags(&self, text_tokens: &[usize], options: &DecodeOptions) -> SegmentFlags {
+        SegmentFlags {
+            // zlib's fixed overhead keeps the ratio of short text low, repetitive or not
+            compression_ratio: self.text.len() >= MIN_COMPRESSION_RATIO_BYTES
+                && options
+                    .compression_ratio_threshold
+                    .is_some_and(|threshold| self.compression_ratio > threshold),
+            log_prob: options
+                .log_prob_threshold
+                .is_some_and(|threshold| self.avg_log_prob < threshold),
+            repetition: options.max_ngram_repeats.is_some_and(|max_repeats| {
+                max_ngram_repeats(text_tokens, MAX_REPEATED_NGRAM) > max_repeats
+            }),
+        }
+    }
+
+    /// Whether the chunk is considered silent: the no speech probability exceeds the threshold
+    /// and the text is not confident enough to overrule it.
+    fn is_no_speech(&self, options: &DecodeOptions) -> bool {
+        options
+            .no_speech_threshold
+            .is_some_and(|threshold| self.no_speech_prob > threshold)
+            && !options
+                .log_prob_threshold
+                .is_some_and(|threshold| self.avg_log_prob > threshold)
+    }
+}
+
+/// Decodes a single mel window, retrying at increasing temperatures when the
+/// result fails the quality thresholds in `options`.
+pub fn mels_to_text<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    mels: Tensor<B, 3>,
+    padding: usize,
+    prompt_tokens: &[usize],
+    options: &DecodeOptions,
+) -> Result<DecodingResult> {
+    let (encoder_output, n_content_frames) =
+        encode_mels(whisper, mels, padding, options.encoder_attention_window)?;
+
+    decode_chunk(
+        whisper,
+        bpe,
+        lang,
+        encoder_output,
+        n_content_frames,
+        0,
+        options.seed.unwrap_or_else(rand::random),
+        prompt_tokens,
+        options,
+    )
+    .map(|(result, _steps)| result)
+}
+
+/// Pads `mels` and runs the encoder with its attention limited to `window`, returning its output
+/// and the number of frames holding audio.
+fn encode_mels<B: Backend>(
+    whisper: &Whisper<B>,
+    mels: Tensor<B, 3>,
+    padding: usize,
+    window: EncoderAttentionWindow,
+) -> Result<(Tensor<B, 3>, usize)> {
+    let device = mels.device();
+
+    let [_n_channel, n_mel, n_ctx] = mels.dims();
+    if n_ctx + padding > whisper.encoder_max_frames() {
+        return Err(WhisperError::AudioTooLong {
+            n_frames: n_ctx + padding,
+            max_frames: whisper.encoder_max_frames(),
+        });
+    }
+
+    // the zero padding helps whisper determine end of text
+    let n_content_frames = n_ctx;
+    let mels = Tensor::cat(vec![mels, Tensor::zeros([1, n_mel, padding], &device)], 2);
+
+    Ok((
+        whisper.forward_encoder_windowed(mels, window)?,
+        n_content_frames,
+    ))
+}
+
+/// Probability of each language of the vocabulary, read from the prediction following the
+/// start of transcript token.
+fn language_probs<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    encoder_output: Tensor<B, 3>,
+) -> Result<Vec<(Language, f64)>> {
+    let device = whisper.decoder_device();
+    let start_token = special_token(bpe, SpecialToken::StartofTranscript)?;
+    let languages: Vec<(Language, usize)> = Language::iter()
+        .filter_map(|lang| Some((lang, bpe.special_token(SpecialToken::Language(lang))?)))
+        .collect();
+    if languages.is_empty() {
+        return Err(WhisperError::MissingSpecialToken(
+            SpecialToken::Language(Language::English).to_string(),
+        ));
+    }
+
+    let token_tensor =
+        Tensor::from_ints(TensorData::new(vec![start_token as u32], [1, 1]), &device);
+    let logits = whisper
+        .forward_decoder(token_tensor, encoder_output.to_device(&device))?
+        .flatten::<1>(0, 2)
+        .into_data()
+        .to_vec::<f32>()
+        .unwrap();
+
+    let language_logits: Vec<f64> = languages
+        .iter()
+        .map(|&(_, token)| logits[token] as f64)
+        .collect();
+    let log_probs = log_softmax_vec(&language_logits, 1.0);
+
+    Ok(languages
+        .into_iter()
+        .zip(log_probs)
+        .map(|((lang, _), log_prob)| (lang, log_prob.exp()))
+        .collect())
+}
+
+fn most_likely_language(probs: Vec<(Language, f64)>) -> (Language, f64) {
+    probs
+        .into_iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .expect("language_probs returns at least one language")
+}
+
+/// Everything decoding a single window needs, shared by the greedy and beam search paths and by
+/// every temperature of the fallback.
+struct ChunkDecoder<'a, B: Backend> {
+    whisper: &'a Whisper<B>,
+    options: &'a DecodeOptions,
+    device: B::Device,
+    encoder_output: Tensor<B, 3>,
+    chunk_index: usize,
+    /// `<|startofprev|>` and the prompt, if any, followed by the start of transcript sequence
+    /// and `<|notimestamps|>` unless timestamps are predicted.
+    initial_tokens: Vec<usize>,
+    /// Length of `<|startofprev|>` and the prompt at the start of `initial_tokens`.
+    prompt_len: usize,
+    /// The start of transcript token, followed by the language and task for multilingual models.
+    start_sequence: Vec<usize>,
+    notimestamp: usize,
+    end_token: usize,
+    max_depth: usize,
+    token_filters: TokenFilters,
+    special_token_policy: SpecialTokenPolicy,
+    events: EventEmitter<'a>,
+    /// Token whose probability after the start of transcript token is the chance of silence.
+    no_speech_token: usize,
+    /// Read from the first decoding step, which sees the start of transcript token.
+    no_speech_prob: Cell<Option<f64>>,
+    /// Decoder forward passes run so far.
+    n_steps: Cell<usize>,
+}
+
+impl<'a, B: Backend> ChunkDecoder<'a, B> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        whisper: &'a Whisper<B>,
+        bpe: &'a Gpt2Tokenizer,
+        lang: Language,
+        encoder_output: Tensor<B, 3>,
+        n_content_frames: usize,
+        chunk_index: usize,
+        prompt_tokens: &[usize],
+        options: &'a DecodeOptions,
+    ) -> Result<Self> {
+        let device = whisper.decoder_device();
+        let encoder_output = encoder_output.to_device(&device);
+        let n_ctx_max_decoder = whisper.decoder_ctx_size();
+
+        let start_token = special_token(bpe, SpecialToken::StartofTranscript)?;
+        let start_of_prev_token = special_token(bpe, SpecialToken::StartofPrev)?;
+        // like the reference implementation, English-only models are not told the language and
+        // task, which their vocabulary cannot express
+        let mut start_sequence = vec![start_token];
+        if bpe.kind().is_multilingual() {
+            start_sequence.push(special_token(bpe, SpecialToken::Language(lang))?);
+            start_sequence.push(special_token(bpe, options.task.special_token())?);
+        }
+        let end_token = special_token(bpe, SpecialToken::EndofText)?;
+        let notimestamp = special_token(bpe, SpecialToken::NoTimeStamps)?;
+        let no_speech_token = bpe
+            .special_token(SpecialToken::NoSpeech)
+            .unwrap_or(end_token);
+
+        // bound the sequence by how much speech the window can hold, like the reference
+        // implementation never sampling more than half the decoder context
+        let audio_seconds = n_content_frames as f64 / 2.0 / FRAMES_PER_SECOND;
+        let max_audio_tokens = ((audio_seconds * MAX_TOKENS_PER_SECOND).ceil() as usize).max(16);
+        let wanted_depth = max_audio_tokens.min(n_ctx_max_decoder / 2);
+
+        // like the reference implementation, the prompt may take up at most half the decoder
+        // context
+        let mut max_prompt_len = (n_ctx_max_decoder / 2).saturating_sub(1);
+        if options.context_policy == ContextPolicy::TruncatePrompt {
+            // <|startofprev|>, the start of transcript sequence and <|notimestamps|>
+            let n_prefix = 1 + start_sequence.len() + usize::from(!options.timestamps);
+            let room = n_ctx_max_decoder.saturating_sub(n_prefix + wanted_depth);
+            max_prompt_len = max_prompt_len.min(room);
+        }
+        let prompt_tokens = &prompt_tokens[prompt_tokens.len().saturating_sub(max_prompt_len)..];
+
+        let mut initial_tokens = Vec::new();
+        if !prompt_tokens.is_empty() {
+            initial_tokens.push(start_of_prev_token);
+            initial_tokens.extend(prompt_tokens);
+        }
+        let prompt_len = initial_tokens.len();
+        initial_tokens.extend(&start_sequence);
+        if !options.timestamps {
+            initial_tokens.push(notimestamp);
+        }
+
+        let mut suppress_tokens = options.suppress_tokens.clone();
+        if options.suppress_non_speech {
+            suppress_tokens.extend(bpe.non_speech_tokens());
+        }
+        let token_filters = TokenFilters {
+            logit_bias: options.logit_bias.clone(),
+            suppress_tokens,
+            blank_tokens: if options.suppress_blank {
+                let mut blank_tokens = bpe.encode_ordinary(" ");
+                blank_tokens.push(end_token);
+                blank_tokens
+            } else {
+                Vec::new()
+            },
+            sample_begin: initial_tokens.len(),
+            no_repeat_ngram_size: options.no_repeat_ngram_size,
+            repetition: options.repetition.clone(),
+            end_token,
+        };
+        let special_token_policy =
+            SpecialTokenPolicy::new(bpe, initial_tokens.len(), options.timestamps)?;
+
+        let events = EventEmitter {
+            sender: options.events.as_ref(),
+            bpe,
+            chunk: chunk_index,
+            end_token,
+            emitted: RefCell::new(Vec::new()),
+        };
+
+        // generation stops once the context is full, whatever the policy
+        let max_depth = wanted_depth.min(n_ctx_max_decoder.saturating_sub(initial_tokens.len()));
+
+        Ok(Self {
+            whisper,
+            options,
+            device,
+            encoder_output,
+            chunk_index,
+            initial_tokens,
+            prompt_len,
+            start_sequence,
+            notimestamp,
+            end_token,
+            max_depth,
+            token_filters,
+            special_token_policy,
+            events,
+            no_speech_token,
+            no_speech_prob: Cell::new(None),
+            n_steps: Cell::new(0),
+        })
+    }
+
+    /// Index of the first generated token in a sequence.
+    fn sample_begin(&self) -> usize {
+        self.initial_tokens.len()
+    }
+
+    fn filters(&self) -> impl Iterator<Item = &dyn LogitFilter> {
+        iter::once(&self.token_filters as &dyn LogitFilter)
+            .chain(
+                self.options
+                    .logit_filters
+                    .iter()
+                    .map(|filter| filter.as_ref()),
+            )
+            .chain(iter::once(&self.special_token_policy as &dyn LogitFilter))
+    }
+
+    fn apply_filters(&self, seq: &[usize], logits: &mut [f64]) {
+        self.filters().for_each(|filter| filter.apply(seq, logits));
+    }
+
+    /// The `k` most likely next tokens of each of `seqs` with their log probabilities, best
+    /// first, from the `[seqs.len(), n_vocab]` logits of their last positions. Tokens the filters
+    /// suppressed are left out, so fewer than `k` remain when fewer may follow. Returns `None` if
+    /// the logits are not finite and the policy aborts.
+    fn top_continuations(
+        &self,
+        logits: Tensor<B, 2>,
+        seqs: &[Vec<usize>],
+        k: usize,
+    ) -> Option<Vec<Vec<(usize, f64)>>> {
+        if self.filters().all(|filter| filter.ignores_logits()) {
+            self.top_continuations_on_device(logits, seqs, k)
+        } else {
+            self.top_continuations_on_host(logits, seqs, k)
+        }
+    }
+
+    /// Masks, normalizes and ranks the logits on the device, reading back `k` tokens per
+    /// sequence.
+    fn top_continuations_on_device(
+        &self,
+        logits: Tensor<B, 2>,
+        seqs: &[Vec<usize>],
+        k: usize,
+    ) -> Option<Vec<Vec<(usize, f64)>>> {
+        let [n_seqs, n_vocab] = logits.dims();
+        // infinities and NaN are the only values that do not vanish when subtracted from
+        // themselves; ordered comparisons are false for NaN, unlike the SIMD equality some
+        // backends use for `is_nan`
+        let n_non_finite = (logits.clone() - logits.clone())
+            .lower_equal_elem(0.0)
+            .bool_not()
+            .int()
+            .sum()
+            .into_scalar()
+            .elem::<i64>();
+        let logits = match self.options.non_finite_logits {
+            _ if n_non_finite == 0 => logits,
+            NonFinitePolicy::Abort => return None,
+            NonFinitePolicy::Clamp => {
+                let nan = logits
+                    .clone()
+                    .greater_equal_elem(f32::NEG_INFINITY)
+                    .bool_not();
+                logits
+                    .mask_fill(nan, -MAX_LOGIT)
+                    .clamp(-MAX_LOGIT, MAX_LOGIT)
+            }
+        };
+
+        // the filters only depend on the sequences, so applied to zeros they give the mask
+        let mut mask = vec![0.0f32; n_seqs * n_vocab];
+        let mut adjustments = vec![0.0; n_vocab];
+        for (seq, mask) in seqs.iter().zip(mask.chunks_mut(n_vocab)) {
+            adjustments.fill(0.0);
+            self.apply_filters(seq, &mut adjustments);
+            for (mask, &adjustment) in mask.iter_mut().zip(&adjustments) {
+                *mask = adjustment as f32;
+            }
+        }
+        let mask = Tensor::from_data(TensorData::new(mask, [n_seqs, n_vocab]), &self.device);
+
+        let log_probs = log_softmax(logits + mask, 1);
+        let (log_probs, tokens) = top_k_on_device(log_probs, k);
+        let log_probs = log_probs
+            .into_data()
+            .convert::<f64>()
+            .to_vec::<f64>()
+            .unwrap();
+        let tokens = tokens.into_data().convert::<i64>().to_vec::<i64>().unwrap();
+        let k = tokens.len() / n_seqs.max(1);
+
+        Some(
+            tokens
+                .chunks(k)
+                .zip(log_probs.chunks(k))
+                .map(|(tokens, log_probs)| {
+                    // a row runs out of finite values after its last allowed token, and the
+                    // picks after it repeat a suppressed one
+                    tokens
+                        .iter()
+                        .zip(log_probs)
+                        .filter(|(_, log_prob)| log_prob.is_finite())
+                        .map(|(&token, &log_prob)| (token as usize, log_prob))
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+
+    /// Reads back the whole distribution for the filters that depend on the logits.
+    fn top_continuations_on_host(
+        &self,
+        logits: Tensor<B, 2>,
+        seqs: &[Vec<usize>],
+        k: usize,
+    ) -> Option<Vec<Vec<(usize, f64)>>> {
+        let [_, n_vocab] = logits.dims();
+        let logits = logits.into_data().to_vec::<f32>().unwrap();
+        let mut seq_logits: Vec<Vec<f64>> = logits
+            .chunks(n_vocab)
+            .map(|logits| logits.iter().map(|&logit| logit as f64).collect())
+            .collect();
+        for logits in &mut seq_logits {
+            if !self.options.non_finite_logits.check(logits) {
+                return None;
+            }
+        }
+
+        Some(
+            seq_logits
+                .into_iter()
+                .zip(seqs)
+                .map(|(mut log_probs, seq)| {
+                    self.apply_filters(seq, &mut log_probs);
+                    log_softmax_in_place(&mut log_probs);
+                    top_k(&log_probs, k)
+                        .into_iter()
+                        .filter(|&token| log_probs[token].is_finite())
+                        .map(|token| (token, log_probs[token]))
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+
+    fn is_finished(&self, seq: &[BeamSearchToken]) -> bool {
+        seq.last().is_some_and(|btok| btok.token == self.end_token)
+    }
+
+    fn numerical_error(&self, step: usize) -> WhisperError {
+        WhisperError::NumericalError {
+            chunk: self.chunk_index,
+            step,
+        }
+    }
+
+    /// Probability of no speech, taken from the decoding so far or, if no step ran, from a
+    /// pass of its own.
+    fn no_speech_prob(&self) -> Result<f64> {
+        if let Some(prob) = self.no_speech_prob.get() {
+            return Ok(prob);
+        }
+
+        let sot_index = self.prompt_len;
+        let token_ids = self.initial_tokens[..=sot_index]
+            .iter()
+            .map(|&tok| tok as u32)
+            .collect();
+        let token_tensor =
+            Tensor::from_ints(TensorData::new(token_ids, [1, sot_index + 1]), &self.device);
+        self.n_steps.set(self.n_steps.get() + 1);
+        let logits = self
+            .whisper
+            .forward_decoder(token_tensor, self.encoder_output.clone())?;
+        if !self.record_no_speech_prob(logits) {
+            return Err(self.numerical_error(0));
+        }
+
+        Ok(self.no_speech_prob.get().unwrap_or_default())
+    }
+
+    /// Records the probability of no speech from the decoder's `logits` of sequences starting
+    /// with the initial tokens, unless already known. Like the reference implementation, it is
+    /// read from the prediction following the start of transcript token, before any logit
+    /// filter runs. Returns `false` if the logits are not finite and the policy aborts.
+    fn record_no_speech_prob(&self, logits: Tensor<B, 3>) -> bool {
+        if self.no_speech_prob.get().is_some() {
+            return true;
+        }
+
+        let sot_index = self.prompt_len;
+        let mut logits: Vec<f64> = logits
+            .slice([0..1, sot_index..sot_index + 1])
+            .into_data()
+            .to_vec::<f32>()
+            .unwrap()
+            .into_iter()
+            .map(|logit| logit as f64)
+            .collect();
+        if !self.options.non_finite_logits.check(&mut logits) {
+            return false;
+        }
+        let prob = log_softmax_vec(&logits, 1.0)[self.no_speech_token].exp();
+        self.no_speech_prob.set(Some(prob));
+
+        true
+    }
+
+    fn initial_seq(&self) -> Vec<BeamSearchToken> {
+        self.initial_tokens
+            .iter()
+            .map(|&tok| BeamSearchToken {
+                token: tok,
+                log_prob: 0.0,
+            })
+            .collect()
+    }
+
+    /// Decodes a single sequence, taking the most likely token at zero temperature and sampling
+    /// from the temperature-scaled distribution otherwise. Stops early once cancelled.
+    fn greedy(&self, temperature: f64, rng: &mut StdRng) -> Result<Vec<BeamSearchToken>> {
+        let n_ctx_max_decoder = self.whisper.decoder_ctx_size();
+        let sample_begin = self.sample_begin();
+        let mut seq = self.initial_seq();
+        // kept alongside the sequence for the logit filters and the events
+        let mut tokens: Vec<usize> = Vec::with_capacity(n_ctx_max_decoder);
+        tokens.extend(seq.iter().map(|btok| btok.token));
+
+        for _ in 0..self.max_depth {
+            if self.is_finished(&seq)
+                || seq.len() >= n_ctx_max_decoder
+                || self.options.is_cancelled()
+            {
+                break;
+            }
+
+            let seq_len = seq.len();
+            let token_ids = tokens.iter().map(|&token| token as u32).collect();
+            let token_tensor =
+                Tensor::from_ints(TensorData::new(token_ids, [1, seq_len]), &self.device);
+
+            self.n_steps.set(self.n_steps.get() + 1);
+            let logits = self
+                .whisper
+                .forward_decoder(token_tensor, self.encoder_output.clone())?;
+            if !self.record_no_speech_prob(logits.clone()) {
+                return Err(self.numerical_error(0));
+            }
+            let logits = logits
+                .slice([0..1, (seq_len - 1)..seq_len])
+                .flatten::<1>(0, 2);
+            let mut logits: Vec<f64> = logits
+                .into_data()
+                .to_vec::<f32>()
+                .unwrap()
+                .into_iter()
+                .map(|logit| logit as f64)
+                .collect();
+            if !self.options.non_finite_logits.check(&mut logits) {
+                return Err(self.numerical_error(seq_len - sample_begin));
+            }
+
+            self.apply_filters(&tokens, &mut logits);
+
+            let log_probs = log_softmax_vec(&logits, 1.0);
+            let token = if temperature > 0.0 {
+                sample_index(&log_softmax_vec(&logits, temperature), rng)
+            } else {
+                argmax(&log_probs)
+            };
+
+            seq.push(BeamSearchToken {
+                token,
+                log_prob: log_probs[token],
+            });
+            tokens.push(token);
+            self.events.update(&tokens[sample_begin..]);
+        }
+
+        Ok(seq)
+    }
+
+    /// Runs a beam search of `beam_size` beams, returning every finished hypothesis with its
+    /// length-penalized score, best first, or only the best unless `options.nbest` asks for
+    /// more. Stops early once cancelled.
+    fn beam_search(&self, beam_size: usize) -> Result<Vec<(Vec<BeamSearchToken>, f64)>> {
+        type BeamNode = beam::BeamNode<BeamSearchToken>;
+
+        let sample_begin = self.sample_begin();
+        let generated_tokens = |seq: &[BeamSearchToken]| -> Vec<usize> {
+            seq[sample_begin.min(seq.len())..]
+                .iter()
+                .map(|btok| btok.token)
+                .collect()
+        };
+
+        // generated tokens of the step whose logits were not finite, reported after the search
+        let non_finite_step = Cell::new(None);
+        // error of a decoder pass, reported after the search like the non-finite logits
+        let decoder_error = RefCell::new(None);
+
+        let next = |beams: &[BeamNode]| {
+            // no continuations end every beam, and the cancellation is reported after the search
+            if self.options.is_cancelled()
+                || non_finite_step.get().is_some()
+                || decoder_error.borrow().is_some()
+            {
+                return vec![Vec::new(); beams.len()];
+            }
+
+            // beams arrive sorted, so the first one leads
+            if let Some(leader) = beams.first() {
+                self.events.update(&generated_tokens(&leader.seq));
+            }
+
+            // every step extends each beam by one token, starting from the single initial beam,
+            // so the beams always have the same length and are decoded in one pass without
+            // padding
+            let seq_len = beams.first().map_or(0, |beam| beam.seq.len());
+            debug_assert!(beams.iter().all(|beam| beam.seq.len() == seq_len));
+            let token_ids = beams
+                .iter()
+                .flat_map(|beam| beam.seq.iter().map(|btok| btok.token as u32))
+                .collect();
+            let token_tensor = Tensor::from_ints(
+                TensorData::new(token_ids, [beams.len(), seq_len]),
+                &self.device,
+            );
+
+            self.n_steps.set(self.n_steps.get() + 1);
+            // all beams attend to the same encoder output, which the decoder broadcasts
+            let logits = match self
+                .whisper
+                .forward_decoder(token_tensor, self.encoder_output.clone())
+            {
+                Ok(logits) => logits,
+                Err(error) => {
+                    *decoder_error.borrow_mut() = Some(error);
+                    return vec![Vec::new(); beams.len()];
+                }
+            };
+            if !self.record_no_speech_prob(logits.clone()) {
+                non_finite_step.set(Some(0));
+                return vec![Vec::new(); beams.len()];
+            }
+
+            let [_, _, n_vocab] = logits.dims();
+            let last_logits = logits
+                .slice([0..beams.len(), (seq_len - 1)..seq_len])
+                .reshape([beams.len(), n_vocab]);
+            let seqs: Vec<Vec<usize>> = beams
+                .iter()
+                .map(|beam| beam.seq.iter().map(|btok| btok.token).collect())
+                .collect();
+
+            // only the few best continuations of each beam can make it into the next step
+            let Some(continuations) = self.top_continuations(last_logits, &seqs, beam_size + 1)
+            else {
+                non_finite_step.set(Some(seq_len - sample_begin));
+                return vec![Vec::new(); beams.len()];
+            };
+
+            continuations
+                .into_iter()
+                .zip(beams)
+                .map(|(continuations, beam)| {
+                    continuations
+                        .into_iter()
+                        .map(|(token, log_prob)| {
+                            (
+                                BeamSearchToken { token, log_prob },
+                                beam.log_prob + log_prob,
+                            )
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+
+        let config = beam::BeamSearchConfig {
+            beam_size,
+            max_depth: self.max_depth,
+            patience: self.options.patience,
+            length_penalty: self.options.length_penalty,
+        };
+        // every hypothesis is kept, since some may be dropped as duplicates
+        let n = if self.options.nbest > 1 {
+            usize::MAX
+        } else {
+            1
+        };
+        let initial_beam = BeamNode {
+            seq: self.initial_seq(),
+            log_prob: 0.0,
+        };
+        let hypotheses = beam::beam_search_nbest(
+            vec![initial_beam],
+            next,
+            |seq: &[BeamSearchToken]| self.is_finished(seq),
+            &config,
+            n,
+        );
+
+        if let Some(step) = non_finite_step.get() {
+            return Err(self.numerical_error(step));
+        }
+        if let Some(error) = decoder_error.take() {
+            return Err(error);
+        }
+
+        Ok(hypotheses)
+    }
+}
+
+/// Decodes the encoder output of a single window, retrying at increasing temperatures when the
+/// result fails the quality thresholds in `options`. Also returns the number of decoder forward
+/// passes it took.
+#[allow(clippy::too_many_arguments)]
+fn decode_chunk<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    lang: Language,
+    encoder_output: Tensor<B, 3>,
+    n_content_frames: usize,
+    chunk_index: usize,
+    seed: u64,
+    prompt_tokens: &[usize],
+    options: &DecodeOptions,
+) -> Result<(DecodingResult, usize)> {
+    let decoder = ChunkDecoder::new(
+        whisper,
+        bpe,
+        lang,
+        encoder_output,
+        n_content_frames,
+        chunk_index,
+        prompt_tokens,
+        options,
+    )?;
+    let prompt_len = decoder.prompt_len;
+    let end_token = decoder.end_token;
+    let sample_begin = decoder.sample_begin();
+
+    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(chunk_index as u64));
+    let mut result = None;
+    for &temperature in &options.temperatures {
+        let (seq, hypotheses) = if temperature > 0.0 || options.beam_size <= 1 {
+            (decoder.greedy(temperature, &mut rng)?, Vec::new())
+        } else {
+            let hypotheses = decoder.beam_search(options.beam_size)?;
+            let seq = hypotheses
+                .first()
+                .map(|(seq, _score)| seq.clone())
+                .unwrap_or_default();
+            (seq, hypotheses)
+        };
+        if options.is_cancelled() {
+            return Err(WhisperError::Cancelled(None));
+        }
+        let alternatives = if options.nbest > 1 {
+            distinct_hypotheses(bpe, &hypotheses, prompt_len, options.nbest)?
+        } else {
+            Vec::new()
+        };
+
+        let seq = &seq[prompt_len.min(seq.len())..];
+        let tokens: Vec<usize> = seq.iter().map(|btok| btok.token).collect();
+        let token_log_probs: Vec<f64> = seq.iter().map(|btok| btok.log_prob).collect();
+        let text = bpe.decode(&tokens[..], true)?;
+
+        let n_text_tokens = tokens.iter().filter(|&&tok| tok < end_token).count();
+        let sum_log_prob: f64 = seq.iter().map(|btok| btok.log_prob).sum();
+        let avg_log_prob = sum_log_prob / (n_text_tokens + 1) as f64;
+        let compression_ratio = compression_ratio(&text);
+        let no_speech_prob = decoder.no_speech_prob()?;
+
+        let mut attempt = DecodingResult {
+            text,
+            tokens,
+            token_log_probs,
+            avg_log_prob,
+            compression_ratio,
+            temperature,
+            no_speech_prob,
+            flags: SegmentFlags::default(),
+            alternatives,
+            words: Vec::new(),
+        };
+        let text_tokens: Vec<usize> = attempt
+            .tokens
+            .iter()
+            .copied()
+            .filter(|&tok| tok < end_token)
+            .collect();
+        attempt.flags = attempt.evaluate_flags(&text_tokens, options);
+        // silence is expected to decode poorly, so it is not retried
+        let needs_fallback = attempt.flags.any() && !attempt.is_no_speech(options);
+        result = Some(attempt);
+
+        if !needs_fallback {
+            break;
+        }
+    }
+
+    // an empty temperature schedule means no decoding was attempted
+    let mut result = result.unwrap_or_default();
+    let no_speech = result.is_no_speech(options);
+    if no_speech {
+        result.text.clear();
+        result.tokens.clear();
+        result.token_log_probs.clear();
+        result.alternatives.clear();
+    }
+
+    let text_start = sample_begin
+        .saturating_sub(prompt_len)
+        .min(result.tokens.len());
+    decoder.events.finish(&result.tokens[text_start..]);
+
+    if options.word_timestamps && !no_speech {
+        let text_tokens: Vec<usize> = result
+            .tokens
+            .iter()
+            .copied()
+            .filter(|&tok| tok < end_token)
+            .collect();
+        result.words = align(
+            whisper,
+            bpe,
+            decoder.encoder_output.clone(),
+            &[decoder.start_sequence.as_slice(), &[decoder.notimestamp]].concat(),
+            &text_tokens,
+            n_content_frames / 2,
+        )?;
+    }
+
+    Ok((result, decoder.n_steps.get()))
+}
+
+/// The `n` best hypotheses of a beam search without the prompt, dropping those that decode to
+/// the same text as a better one.
+fn distinct_hypotheses(
+    bpe: &Gpt2Tokenizer,
+    hypotheses: &[(Vec<BeamSearchToken>, f64)],
+    prompt_len: usize,
+    n: usize,
+) -> Result<Vec<Alternative>> {
+    let mut alternatives: Vec<Alternative> = Vec::new();
+    for (seq, score) in hypotheses {
+        if alternatives.len() >= n {
+            break;
+        }
+
+        let tokens: Vec<usize> = seq[prompt_len.min(seq.len())..]
+            .iter()
+            .map(|btok| btok.token)
+            .collect();
+        let text = bpe.decode(&tokens, true)?;
+        if alternatives
+            .iter()
+            .all(|alternative| alternative.text.trim() != text.trim())
+        {
+            alternatives.push(Alternative {
+                text,
+                tokens,
+                score: *score,
+            });
+        }
+    }
+
+    Ok(alternatives)
+}
+
+/// Number of encoder output frames per second of audio, after the stride-2 convolution.
+const FRAMES_PER_SECOND: f64 = 50.0;
+
+/// Upper bound on the token rate of fast speech, used to limit decoding length.
+const MAX_TOKENS_PER_SECOND: f64 = 10.0;
+
+/// Timing of a single word within a decoded window, in seconds from the window start.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WordTiming {
+    pub word: String,
+    pub start: f64,
+    pub end: f64,
+    /// Mean probability of the word's tokens.
+    pub probability: f64,
+}
+
+/// Aligns `text_tokens` to the first `n_frames` encoder frames by running dynamic time warping
+/// over the cross-attention weights of the decoder's upper half of layers.
+/// `sot_sequence` is the prefix up to and including `<|notimestamps|>`.
+pub fn align<B: Backend>(
+    whisper: &Whisper<B>,
+    bpe: &Gpt2Tokenizer,
+    encoder_output: Tensor<B, 3>,
+    sot_sequence: &[usize],
+    text_tokens: &[usize],
+    n_frames: usize,
+) -> Result<Vec<WordTiming>> {
+    let end_token = special_token(bpe, SpecialToken::EndofText)?;
+    if text_tokens.is_empty() || n_frames == 0 {
+        return Ok(Vec::new());
+    }
+
+    let device = encoder_output.device();
+    let tokens: Vec<usize> = sot_sequence
+        .iter()
+        .chain(text_tokens)
+        .chain(iter::once(&end_token))
+        .copied()
+        .collect();
+    let n_tokens = tokens.len();
+    let token_tensor = Tensor::from_ints(
+        TensorData::new(
+            tokens.iter().map(|&tok| tok as u32).collect(),
+            [1, n_tokens],
+        ),
+        &device,
+    );
+
+    let (logits, weights) = whisper.forward_decoder_with_attn(token_tensor, encoder_output)?;
+
+    // rows predicting each text token, starting at <|notimestamps|>
+    let first_row = sot_sequence.len() - 1;
+    let n_rows = text_tokens.len() + 1;
+
+    let logits: Vec<f64> = logits
+        .slice([0..1, first_row..first_row + text_tokens.len()])
+        .into_data()
+        .to_vec::<f32>()
+        .unwrap()
+        .into_iter()
+        .map(|logit| logit as f64)
+        .collect();
+    let vocab_size = logits.len() / text_tokens.len();
+    let token_probs: Vec<f64> = logits
+        .chunks(vocab_size)
+        .zip(text_tokens)
+        .map(|(row, &tok)| log_softmax_vec(&row[..end_token], 1.0)[tok].exp())
+        .collect();
+
+    // average the standardized, median filtered attention of the alignment heads
+    let n_layers = weights.len();
+    let n_frames = n_frames.min(weights[0].dims()[3]);
+    let mut matrix = vec![0.0; n_rows * n_frames];
+    let mut n_heads = 0;
+    for layer_weights in weights.into_iter().skip(n_layers / 2) {
+        let [_, n_head, _, _] = layer_weights.dims();
+        let values = layer_weights
+            .slice([0..1, 0..n_head, first_row..first_row + n_rows, 0..n_frames])
+            .into_data()
+            .to_vec::<f32>()
+            .unwrap();
+
+        for head in values.chunks(n_rows * n_frames) {
+            let head = standardize_columns(head, n_rows, n_frames);
+            for (row, acc) in head.chunks(n_frames).zip(matrix.chunks_mut(n_frames)) {
+                for (value, acc) in median_filter(row, 7).into_iter().zip(acc) {
+                    *acc += value;
+                }
+            }
+            n_heads += 1;
+        }
+    }
+    let cost: Vec<f64> = matrix.iter().map(|v| -v / n_heads.max(1) as f64).collect();
+
+    let path = dtw(&cost, n_rows, n_frames);
+    let mut jump_times = Vec::with_capacity(n_rows);
+    let mut prev_row = None;
+    for (row, frame) in path {
+        if prev_row != Some(row) {
+            jump_times.push(frame as f64 / FRAMES_PER_SECOND);
+            prev_row = Some(row);
+        }
+    }
+
+    let mut timings = Vec::new();
+    let mut word_start = 0;
+    for word_tokens in split_words(bpe, text_tokens)? {
+        let word_end = word_start + word_tokens.len();
+        let probability =
+            token_probs[word_start..word_end].iter().sum::<f64>() / word_tokens.len() as f64;
+
+        timings.push(WordTiming {
+            word: bpe.decode(&word_tokens, true)?,
+            start: jump_times[word_start],
+            end: jump_times[word_end],
+            probability,
+        });
+
+        word_start = word_end;
+    }
+
+    Ok(timings)
+}
+
+/// Groups tokens into words, starting a new word at each token that begins with a space.
+fn split_words(bpe: &Gpt2Tokenizer, tokens: &[usize]) -> Result<Vec<Vec<usize>>> {
+    let mut words: Vec<Vec<usize>> = Vec::new();
+    for &tok in tokens {
+        let piece = bpe.decode(&[tok], false)?;
+        match words.last_mut() {
+            Some(word) if !piece.starts_with(' ') => word.push(tok),
+            _ => words.push(vec![tok]),
+        }
+    }
+
+    Ok(words)
+}
+
+/// Normalizes each column of a row-major matrix to zero mean and unit variance.
+fn standardize_columns(values: &[f32], n_rows: usize, n_cols: usize) -> Vec<f64> {
+    let mut out: Vec<f64> = values.iter().map(|&v| v as f64).collect();
+    for col in 0..n_cols {
+        let mean = (0..n_rows).map(|row| out[row * n_cols + col]).sum::<f64>() / n_rows as f64;
+        let var = (0..n_rows)
+            .map(|row| (out[row * n_cols + col] - mean).powi(2))
+            .sum::<f64>()
+            / n_rows as f64;
+        let std = var.sqrt().max(1e-10);
+        for row in 0..n_rows {
+            out[row * n_cols + col] = (out[row * n_cols + col] - mean) / std;
+        }
+    }
+
+    out
+}
+
+fn median_filter(values: &[f64], width: usize) -> Vec<f64> {
+    let half = width / 2;
+    (0..values.len())
+        .map(|i| {
+            let mut window: Vec<f64> =
+                values[i.saturating_sub(half)..(i + half + 1).min(values.len())].to_vec();
+            window.sort_by(|a, b| a.total_cmp(b));
+            window[window.len() / 2]
+        })
+        .collect()
+}
+
+/// Dynamic time warping over a row-major cost matrix, returning the monotonic
+/// path of (row, col) pairs from the top left to the bottom right corner.
+fn dtw(cost: &[f64], n_rows: usize, n_cols: usize) -> Vec<(usize, usize)> {
+    let width = n_cols + 1;
+    let mut acc = vec![f64::INFINITY; (n_rows + 1) * width];
+    let mut trace = vec![0u8; (n_rows + 1) * width];
+    acc[0] = 0.0;
+
+    for j in 1..=n_cols {
+        for i in 1..=n_rows {
+            let diag = acc[(i - 1) * width + j - 1];
+            let up = acc[(i - 1) * width + j];
+            let left = acc[i * width + j - 1];
+
+            let (c, t) = if diag < up && diag < left {
+                (diag, 0)
+            } else if up < diag && up < left {
+                (up, 1)
+            } else {
+                (left, 2)
+            };
+
+            acc[i * width + j] = cost[(i - 1) * n_cols + j - 1] + c;
+            trace[i * width + j] = t;
+        }
+    }
+
+    let (mut i, mut j) = (n_rows, n_cols);
+    let mut path = Vec::with_capacity(n_rows + n_cols);
+    while i > 0 && j > 0 {
+        path.push((i - 1, j - 1));
+        match trace[i * width + j] {
+            0 => {
+                i -= 1;
+                j -= 1;
+            }
+            1 => i -= 1,
+            _ => j -= 1,
+        }
+    }
+    // walk the remaining edge of the matrix
+    while i > 0 {
+        path.push((i - 1, 0));
+        i -= 1;
+    }
+    path.reverse();
+
+    path
+}
+
+//HELPERS
+fn special_token(bpe: &Gpt2Tokenizer, token: SpecialToken) -> Result<usize> {
+    let name = token.to_string();
+    bpe.special_token(token)
+        .ok_or(WhisperError::MissingSpecialToken(name))
+}
+
+/// [`log_softmax_vec`] at a temperature of one, without allocating.
+fn log_softmax_in_place(logits: &mut [f64]) {
+    let log_sum_exp = log_sum_exp(logits);
+    for logit in logits {
+        *logit -= log_sum_exp;
+    }
+}
+
+fn log_softmax_vec(logits: &[f64], temperature: f64) -> Vec<f64> {
+    let max = logits
+        .iter()
+        .map(|logit| logit / temperature)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let log_sum_exp = logits
+        .iter()
+        .map(|logit| (logit / temperature - max).exp())
+        .sum::<f64>()
+        .ln()
+        + max;
+
+    logits
+        .iter()
+        .map(|logit| logit / temperature - log_sum_exp)
+        .collect()
+}
+
+fn log_sum_exp(values: &[f64]) -> f64 {
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return max;
+    }
+
+    values
+        .iter()
+        .map(|value| (value - max).exp())
+        .sum::<f64>()
+        .ln()
+        + max
+}
+
+fn sample_index(log_probs: &[f64], rng: &mut impl Rng) -> usize {
+    let mut threshold = rng.random::<f64>();
+    for (index, log_prob) in log_probs.iter().enumerate() {
+        threshold -= log_prob.exp();
+        if threshold <= 0.0 {
+            return index;
+        }
+    }
+
+    // rounding can leave a sliver of probability mass unclaimed
+    argmax(log_probs)
+}
+
+/// Indices of the `k` largest values, largest first. Ties go to the lower index so the
+/// result does not depend on the selection algorithm.
+fn top_k(values: &[f64], k: usize) -> Vec<usize> {
+    let descending = |a: &usize, b: &usize| values[*b].total_cmp(&values[*a]).then(a.cmp(b));
+    let mut indices: Vec<usize> = (0..values.len()).collect();
+    if k < indices.len() {
+        indices.select_nth_unstable_by(k, descending);
+        indices.truncate(k);
+    }
+    indices.sort_unstable_by(descending);
+
+    indices
+}
+
+/// The `k` largest values of each row of `values`, best first, with their indices, the lower
+/// index on ties. Found on the device one maximum at a time, since a sort reads the whole tensor
+/// back on some backends. Once a row has no finite value left, its remaining picks repeat an
+/// index already taken.
+fn top_k_on_device<B: Backend>(
+    values: Tensor<B, 2>,
+    k: usize,
+) -> (Tensor<B, 2>, Tensor<B, 2, Int>) {
+    let [n_rows, n_columns] = values.dims();
+    let device = values.device();
+    let mut remaining = values;
+    let mut top_values = Vec::with_capacity(k);
+    let mut top_indices = Vec::with_capacity(k);
+    for _ in 0..k.min(n_columns) {
+        let (value, index) = remaining.clone().max_dim_with_indices(1);
+        // a value summed with negative infinity is never picked again
+        remaining = remaining.scatter(
+            1,
+            index.clone(),
+            Tensor::full([n_rows, 1], f32::NEG_INFINITY, &device),
+        );
+        top_values.push(value);
+        top_indices.push(index);
+    }
+
+    (Tensor::cat(top_values, 1), Tensor::cat(top_indices, 1))
+}
+
+/// Index of the largest value, the lowest one on ties.
+fn argmax(values: &[f64]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1).then(b.0.cmp(&a.0)))
+        .map(|(index, _)| index)
+        .unwrap_or(0)
+}
+
+/// Text shorter than this many bytes is never flagged for its compression ratio.
+const MIN_COMPRESSION_RATIO_BYTES: usize = 32;
+
+/// Longest n-gram counted by [`max_ngram_repeats`] when flagging results.
+const MAX_REPEATED_NGRAM: usize = 16;
+
+/// Largest number of times an n-gram of up to `max_n` tokens appears back to back anywhere in
+/// `tokens`.
+pub fn max_ngram_repeats(tokens: &[usize], max_n: usize) -> usize {
+    let mut max_repeats = tokens.len().min(1);
+    for n in 1..=max_n.min(tokens.len() / 2) {
+        for start in 0..n {
+            // count consecutive equal n-grams along the grid of n-grams beginning at `start`
+            let mut repeats = 1;
+            let mut ngrams = tokens[start..].chunks_exact(n);
+            let Some(mut prev) = ngrams.next() else {
+                continue;
+            };
+            for ngram in ngrams {
+                if ngram == prev {
+                    repeats += 1;
+                    max_repeats = max_repeats.max(repeats);
+                } else {
+                    repeats = 1;
+                }
+                prev = ngram;
+            }
+        }
+    }
+
+    max_repeats
+}
+
+/// Ratio of the byte length of `text` to its zlib-compressed length, as used by
+/// the reference implementation to detect repetitive output.
+pub fn compression_ratio(text: &str) -> f64 {
+    if text.is_empty() {
+        return 0.0;
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(text.as_bytes()).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    text.len() as f64 / compressed.len() as f64
+}
+
+fn find_chunk_overlap(
+    prev_tokens: &[usize],
+    curr_tokens: &[usize],
+    max_n_offsets: usize,
+    min_n_overlaps: usize,
+) -> Option<(usize, usize)> {
+    let mut max_overlap = 0;
+    let mut max_overlap_indices = (0, 0);
+    let n_offsets = prev_tokens.len().min(curr_tokens.len()).min(max_n_offsets);
+
+    for offset in 0..n_offsets {
+        let prev_start_index = prev_tokens.len() - 1 - offset;
+        let mut overlap_iter = prev_tokens
+            .iter()
+            .skip(prev_start_index)
+            .zip(curr_tokens.iter())
+            .enumerate()
+            .filter(|(_, (&old, &new))| old == new);
+
+        let n_overlap = overlap_iter.clone().count();
+        if n_overlap > max_overlap {
+            max_overlap = n_overlap;
+
+            let curr_overlap_index = overlap_iter.next().unwrap().0;
+            let prev_overlap_index = prev_start_index + curr_overlap_index;
+            max_overlap_indices = (prev_overlap_index, curr_overlap_index)
+        }
+    }
+
+    if max_overlap >= min_n_overlaps {
+        Some(max_overlap_indices)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::load::random_whisper;
+    use burn::module::{Module, ModuleMapper, ParamId};
+
+    type TestBackend = burn::backend::NdArray;
+
+    const SOT: usize = 100;
+    const END: usize = 99;
+    /// Size of the vocabulary of the miniature tokenizer in `tests/fixtures`.
+    const N_VOCAB: usize = 1868;
+    const N_MELS: usize = 8;
+
+    /// A randomly initialized model whose encoder holds 4 seconds of audio, with the
+    /// miniature tokenizer of the integration tests.
+    fn tiny_model(n_text_ctx: usize) -> (Whisper<TestBackend>, Gpt2Tokenizer) {
+        let config = WhisperConfig::new(
+            AudioEncoderConfig::new(N_MELS, 200, 64, 2, 2),
+            TextDecoderConfig::new(N_VOCAB, n_text_ctx, 64, 2, 2),
+        );
+        let whisper = random_whisper(&config, 0, &Default::default());
+        let bpe = Gpt2Tokenizer::from_bytes(
+            include_bytes!("../tests/fixtures/tokenizer.json"),
+            VocabKind::from_n_vocab(N_VOCAB),
+        )
+        .unwrap();
+
+        (whisper, bpe)
+    }
+
+    fn random_mels(n_frames: usize) -> Tensor<TestBackend, 3> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let values: Vec<f32> = (0..N_MELS * n_frames)
+            .map(|_| rng.random_range(-1.0..=1.0))
+            .collect();
+
+        Tensor::from_data(
+            TensorData::new(values, [1, N_MELS, n_frames]),
+            &Default::default(),
+        )
+    }
+
+    /// Decodes at zero temperature only, without discarding the result as silence.
+    fn deterministic_options() -> DecodeOptions {
+        DecodeOptions {
+            temperatures: vec![0.0],
+            no_speech_threshold: None,
+            seed: Some(0),
+            ..DecodeOptions::recommended()
+        }
+    }
+
+    fn token_filters() -> TokenFilters {
+        TokenFilters {
+            logit_bias: HashMap::new(),
+            suppress_tokens: Vec::new(),
+            blank_tokens: Vec::new(),
+            sample_begin: 1,
+            no_repeat_ngram_size: None,
+            repetition: None,
+            end_token: END,
+        }
+    }
+
+    fn filtered(filter: &impl LogitFilter, seq: &[usize]) -> Vec<f64> {
+        let mut logits = vec![0.0; 101];
+        filter.apply(seq, &mut logits);
+        logits
+    }
+
+    fn suppressed(logits: &[f64]) -> Vec<usize> {
+        (0..logits.len())
+            .filter(|&token| logits[token] == f64::NEG_INFINITY)
+            .collect()
+    }
+
+    #[test]
+    fn suppress_tokens_apply_at_every_step() {
+        let filters = TokenFilters {
+            suppress_tokens: vec![3, 7],
+            ..token_filters()
+        };
+
+        assert_eq!(suppressed(&filtered(&filters, &[SOT])), [3, 7]);
+        assert_eq!(suppressed(&filtered(&filters, &[SOT, 1, 2])), [3, 7]);
+    }
+
+    #[test]
+    fn blank_tokens_are_suppressed_for_the_first_token_only() {
+        let filters = TokenFilters {
+            blank_tokens: vec![5, END],
+            ..token_filters()
+        };
+
+        assert_eq!(suppressed(&filtered(&filters, &[SOT])), [5, END]);
+        assert!(suppressed(&filtered(&filters, &[SOT, 1])).is_empty());
+    }
+
+    #[test]
+    fn no_repeat_ngram_bans_the_token_completing_a_repeat() {
+        let filters = TokenFilters {
+            no_repeat_ngram_size: Some(3),
+            ..token_filters()
+        };
+
+        // 5 6 was followed by 7 before
+        assert_eq!(suppressed(&filtered(&filters, &[SOT, 5, 6, 7, 5, 6])), [7]);
+        assert!(suppressed(&filtered(&filters, &[SOT, 5, 6, 7, 6])).is_empty());
+        // the prefix does not count as generated
+        let filters = TokenFilters {
+            sample_begin: 3,
+            ..filters
+        };
+        assert!(suppressed(&filtered(&filters, &[5, 6, 7, 5, 6])).is_empty());
+    }
+
+    #[test]
+    fn greedy_decoding_matches_a_beam_search_with_one_beam() {
+        let (whisper, bpe) = tiny_model(64);
+        // the random model loops, which would end decoding after a few tokens
+        let options = DecodeOptions {
+            repetition: None,
+            ..deterministic_options()
+        };
+        let (encoder_output, n_content_frames) = encode_mels(
+            &whisper,
+            random_mels(300),
+            50,
+            options.encoder_attention_window,
+        )
+        .unwrap();
+        let decoder = ChunkDecoder::new(
+            &whisper,
+            &bpe,
+            Language::English,
+            encoder_output,
+            n_content_frames,
+            0,
+            &[],
+            &options,
+        )
+        .unwrap();
+
+        let greedy = decoder.greedy(0.0, &mut StdRng::seed_from_u64(0)).unwrap();
+        let beam = decoder.beam_search(1).unwrap().remove(0).0;
+
+        assert!(greedy.len() > decoder.sample_begin());
+        assert_eq!(greedy.len(), beam.len());
+        for (greedy, beam) in greedy.iter().zip(&beam) {
+            assert_eq!(greedy.token, beam.token);
+            // the beam search normalizes the logits in single precision on the device
+            assert!((greedy.log_prob - beam.log_prob).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn no_speech_prob_is_read_from_the_first_decoding_step() {
+        let (whisper, bpe) = tiny_model(64);
+        let options = DecodeOptions {
+            repetition: None,
+            ..deterministic_options()
+        };
+        let (encoder_output, n_content_frames) = encode_mels(
+            &whisper,
+            random_mels(300),
+            50,
+            options.encoder_attention_window,
+        )
+        .unwrap();
+        let prompt = bpe.encode_ordinary(" a prompt");
+        let new_decoder = || {
+            ChunkDecoder::new(
+                &whisper,
+                &bpe,
+                Language::English,
+                encoder_output.clone(),
+                n_content_frames,
+                0,
+                &prompt,
+                &options,
+            )
+            .unwrap()
+        };
 
-pub fn waveform_to_text<B: Backend>(
-    whisper: &Whisper<B>,
-    bpe: &Gpt2Tokenizer,
-    lang: Language,
-    waveform: Vec<f32>,
-    sample_rate: usize,
-    streaming_mode: bool,
-) -> token::Result<(String, Vec<usize>)> {
-    let device = whisper.devices()[0].clone();
+        let decoder = new_decoder();
+        let seq = decoder.greedy(0.0, &mut StdRng::seed_from_u64(0)).unwrap();
+        let prob = decoder.no_speech_prob().unwrap();
+        // a forward pass per generated token, none for the probability of no speech
+        assert_eq!(decoder.n_steps.get(), seq.len() - decoder.sample_begin());
 
-    let n_ctx_max_encoder = whisper.encoder_ctx_size();
-    let padding = 200; //ADJUST THIS IF CHINKS ARE REPEATING THEMSELVES ENDLESSLY
-    let n_waveform_samples_per_window = max_waveform_samples(n_ctx_max_encoder - padding);
+        let undecoded = new_decoder();
+        assert!((undecoded.no_speech_prob().unwrap() - prob).abs() < 1e-9);
+        assert_eq!(undecoded.n_steps.get(), 1);
 
-    let n_mels = whisper.encoder_mel_size();
-    let mel_iter = waveform_to_mel_tensor(
-        waveform,
-        sample_rate,
-        n_waveform_samples_per_window,
-        device,
-        n_mels,
-    );
+        let beam_decoder = new_decoder();
+        beam_decoder.beam_search(2).unwrap();
+        assert!((beam_decoder.no_speech_prob().unwrap() - prob).abs() < 1e-9);
+    }
 
-    let mut text = String::new();
-    let mut tokens: Vec<usize> = Vec::new();
+    /// A decoder for a window of random mels with the deterministic options.
+    fn random_window_decoder<'a>(
+        whisper: &'a Whisper<TestBackend>,
+        bpe: &'a Gpt2Tokenizer,
+        options: &'a DecodeOptions,
+    ) -> ChunkDecoder<'a, TestBackend> {
+        let (encoder_output, n_content_frames) = encode_mels(
+            whisper,
+            random_mels(300),
+            50,
+            options.encoder_attention_window,
+        )
+        .unwrap();
 
-    //IN THE FOLLOWING CODE, WE WILL PRETTY MUCH ALWAYS ITERATE JUST ONCE, SINCE WE ARE SENDING SUCH SHORT CLIPS OF AUDIO. THIS MEANS FIND CHUNK OVERLAP IS NOT NECESSARY BUT CAN LEAVE IT FOR THE FUTURE
-    for mel in mel_iter {
-        let (_new_text, new_tokens) =
-            mels_to_text(whisper, bpe, lang, mel, padding, streaming_mode)?;
+        ChunkDecoder::new(
+            whisper,
+            bpe,
+            Language::English,
+            encoder_output,
+            n_content_frames,
+            0,
+            &[],
+            options,
+        )
+        .unwrap()
+    }
 
-        if let Some((prev_index, curr_index)) =
-            find_chunk_overlap(&tokens[..], &new_tokens[..], 40, 3)
-        {
-            tokens.truncate(prev_index);
-            tokens.extend(&new_tokens[curr_index..]);
-        } else {
-            tokens.extend(new_tokens);
+    /// Ranks the same random logits of `seqs` on the device and on the host, checks that both
+    /// agree and returns the continuations.
+    fn assert_ranked_alike(
+        decoder: &ChunkDecoder<TestBackend>,
+        seqs: &[Vec<usize>],
+        k: usize,
+    ) -> Vec<Vec<(usize, f64)>> {
+        assert!(decoder.filters().all(|filter| filter.ignores_logits()));
+        let logits = Tensor::<TestBackend, 2>::random(
+            [seqs.len(), N_VOCAB],
+            burn::tensor::Distribution::Normal(0.0, 3.0),
+            &decoder.device,
+        );
+
+        let on_device = decoder
+            .top_continuations_on_device(logits.clone(), seqs, k)
+            .unwrap();
+        let on_host = decoder.top_continuations_on_host(logits, seqs, k).unwrap();
+
+        assert_eq!(on_device.len(), seqs.len());
+        for (on_device, on_host) in on_device.iter().zip(&on_host) {
+            assert_eq!(on_device.len(), on_host.len());
+            for (&(device_token, device_log_prob), &(host_token, host_log_prob)) in
+                on_device.iter().zip(on_host)
+            {
+                assert_eq!(device_token, host_token);
+                assert!((device_log_prob - host_log_prob).abs() < 1e-5);
+            }
         }
 
-        text = bpe.decode(&tokens[..], true)?;
+        on_device
     }
 
-    Ok((text, tokens))
-}
+    #[test]
+    fn beams_are_ranked_on_the_device_like_on_the_host() {
+        let (whisper, bpe) = tiny_model(64);
+        let options = deterministic_options();
+        let decoder = random_window_decoder(&whisper, &bpe, &options);
 
-fn waveform_to_mel_tensor<B: Backend>(
-    waveform: Vec<f32>,
-    sample_rate: usize,
-    window_length_samples: usize,
-    device: B::Device,
-    n_mels: usize,
-) -> impl Iterator<Item = Tensor<B, 3>> {
-    let chunk_overlap = sample_rate * 3;
-    let n_samples_per_tensor = window_length_samples;
-    let shift = n_samples_per_tensor.saturating_sub(chunk_overlap).max(1);
-    let iter_len = waveform.len().saturating_sub(1).div(shift) + 1;
+        // the blank tokens are only suppressed at the start, the others at every step
+        let mut seqs = vec![decoder.initial_tokens.clone(); 2];
+        seqs[1].push(bpe.encode_ordinary(" a")[0]);
 
-    (0..iter_len).map(move |i| {
-        let start = i * shift;
-        let end = (start + n_samples_per_tensor).min(waveform.len());
+        let continuations = assert_ranked_alike(&decoder, &seqs, 6);
+        assert!(continuations.iter().all(|tokens| tokens.len() == 6));
+    }
 
-        let slice = &waveform[start..end];
+    #[test]
+    fn beams_with_fewer_allowed_tokens_than_beams_keep_only_those() {
+        let (whisper, bpe) = tiny_model(64);
+        let options = deterministic_options();
+        let decoder = random_window_decoder(&whisper, &bpe, &options);
 
-        let waveform: Tensor<B, 1> = Tensor::from_floats(slice, &device);
+        // a token repeated enough times leaves only the end of text to follow
+        let repeated = bpe.encode_ordinary(" a")[0];
+        let mut looping = decoder.initial_tokens.clone();
+        looping.extend([repeated; 8]);
+        let mut seqs = vec![decoder.initial_tokens.clone(), looping];
+        seqs[0].push(repeated);
 
-        
+        let continuations = assert_ranked_alike(&decoder, &seqs, 6);
+        assert_eq!(continuations[0].len(), 6);
+        assert_eq!(continuations[1].len(), 1);
+        assert_eq!(continuations[1][0].0, decoder.end_token);
+    }
 
-        prep_audio(waveform.unsqueeze(), sample_rate as f64, n_mels)
-    })
-}
+    #[test]
+    fn repetition_is_detected_once_a_window_repeats_enough_times() {
+        let repetition = RepetitionOptions {
+            max_window: 4,
+            max_repeats: 3,
+            action: RepetitionAction::Terminate,
+        };
 
-#[derive(Clone)]
-struct BeamSearchToken {
-    token: usize,
-}
+        assert_eq!(repetition.detect(&[1, 2, 3, 1, 2, 3, 1, 2, 3]), Some(3));
+        assert_eq!(repetition.detect(&[4, 1, 2, 3, 1, 2, 3]), None);
+        assert_eq!(repetition.detect(&[1, 2, 3, 1, 2, 3, 1, 2, 4]), None);
+        // the shortest repeating window is reported
+        assert_eq!(repetition.detect(&[5, 5, 5, 5, 5, 5]), Some(1));
+        // windows longer than max_window are not searched
+        assert_eq!(repetition.detect(&[1, 2, 3, 4, 5].repeat(3)), None);
+    }
 
-fn mels_to_text<B: Backend>(
-    whisper: &Whisper<B>,
-    bpe: &Gpt2Tokenizer,
-    lang: Language,
-    mels: Tensor<B, 3>,
-    padding: usize,
-    _streaming_mode: bool,
-) -> token::Result<(String, Vec<usize>)> {
-    let device = mels.device();
+    #[test]
+    fn repetition_suppresses_the_next_token_of_the_loop_or_ends_the_sequence() {
+        let repetition = RepetitionOptions {
+            max_window: 4,
+            max_repeats: 3,
+            action: RepetitionAction::Suppress,
+        };
+        let filters = TokenFilters {
+            repetition: Some(repetition.clone()),
+            ..token_filters()
+        };
+        let looping = [SOT, 1, 2, 1, 2, 1, 2];
 
-    let n_ctx_max_encoder = whisper.encoder_ctx_size();
-    let _n_ctx_max_decoder = whisper.decoder_ctx_size();
+        assert_eq!(suppressed(&filtered(&filters, &looping)), [1]);
+        assert!(suppressed(&filtered(&filters, &looping[..5])).is_empty());
 
-    let [_n_channel, n_mel, n_ctx] = mels.dims();
-    if n_ctx + padding > n_ctx_max_encoder {
-        println!(
-            "Audio has length of {} which exceeds maximum length {}. It will be clipped.",
-            n_ctx + padding,
-            n_ctx_max_encoder
-        );
+        let filters = TokenFilters {
+            repetition: Some(RepetitionOptions {
+                action: RepetitionAction::Terminate,
+                ..repetition
+            }),
+            ..token_filters()
+        };
+        let logits = filtered(&filters, &looping);
+        assert!(logits
+            .iter()
+            .enumerate()
+            .all(|(token, &logit)| (token == END) == logit.is_finite()));
     }
 
-    // the zero padding helps whisper determine end of text
-    let mels = Tensor::cat(
-        vec![
-            mels.slice([0..1, 0..n_mel, 0..(n_ctx).min(n_ctx_max_encoder - padding)]),
-            Tensor::zeros([1, n_mel, padding], &device),
-        ],
-        2,
-    );
-    let encoder_output = whisper.forward_encoder(mels);
-
-    let start_token = bpe.special_token(SpecialToken::StartofTranscript).unwrap();
-    let transcription_token = bpe.special_token(SpecialToken::Transcribe).unwrap();
-    let _start_of_prev_token = bpe.special_token(SpecialToken::StartofPrev).unwrap();
-    let lang_token = bpe.special_token(SpecialToken::Language(lang)).unwrap();
-    let _first_timestamp_token = bpe.special_token(SpecialToken::Timestamp(0.0)).unwrap();
-    let end_token = bpe.special_token(SpecialToken::EndofText).unwrap();
-    let notimestamp = bpe.special_token(SpecialToken::NoTimeStamps).unwrap();
-
-    let mut initial_tokens = Vec::new();
-    initial_tokens.extend([start_token, lang_token, transcription_token, notimestamp]);
-
-    type BeamNode = beam::BeamNode<BeamSearchToken>;
-    let initial_tokens = BeamNode {
-        seq: initial_tokens
-            .into_iter()
-            .map(|tok| BeamSearchToken { token: tok })
-            .collect(),
-        log_prob: 0.0,
-    };
+    /// Text tokens below the end of text at 50, control tokens up to 60 and timestamps from
+    /// there on, with text favored over timestamps.
+    fn policy_filtered(policy: &SpecialTokenPolicy, seq: &[usize]) -> Vec<usize> {
+        let mut logits: Vec<f64> = (0..70)
+            .map(|tok| if tok < 50 { 5.0 } else { 0.0 })
+            .collect();
+        policy.apply(seq, &mut logits);
+        (0..logits.len())
+            .filter(|&token| logits[token].is_finite())
+            .collect()
+    }
+
+    fn special_token_policy(timestamps: bool) -> SpecialTokenPolicy {
+        SpecialTokenPolicy {
+            sample_begin: 1,
+            end_token: 50,
+            timestamp_begin: 60,
+            timestamps,
+            max_initial_timestamp_index: Some(3),
+        }
+    }
 
-    let neg_infty = -f32::INFINITY;
+    #[test]
+    fn control_tokens_and_timestamps_are_suppressed_without_timestamps() {
+        let policy = special_token_policy(false);
+        let text_and_end: Vec<usize> = (0..=50).collect();
 
-    let vocab_size = bpe.vocab_size();
-    let special_tokens_maskout: Vec<f32> = (0..vocab_size)
-        .map(|token| {
-            if bpe.is_special(token) {
-                neg_infty
-            } else {
-                0.0
-            }
-        })
-        .collect();
-    //special_tokens_maskout[end_token] = 1.0;
+        // the end of text is allowed from the first step on
+        assert_eq!(policy_filtered(&policy, &[SOT]), text_and_end);
+        assert_eq!(policy_filtered(&policy, &[SOT, 1, 2]), text_and_end);
+    }
+
+    #[test]
+    fn timestamps_follow_the_grammar() {
+        let policy = special_token_policy(true);
+        let text: Vec<usize> = (0..50).collect();
 
-    let special_tokens_maskout: Tensor<B, 1> =
-        Tensor::from_data(special_tokens_maskout.as_slice(), &device);
+        // an early timestamp opens the transcript
+        assert_eq!(policy_filtered(&policy, &[SOT]), [60, 61, 62, 63]);
+        // text continues, or a timestamp after the opening one closes it
+        let after_text = [text.clone(), vec![50], (62..70).collect()].concat();
+        assert_eq!(policy_filtered(&policy, &[SOT, 61, 5]), after_text);
+        // a closing timestamp is followed by an opening one or the end, and the opening
+        // timestamps together outweigh the end
+        let after_closing: Vec<usize> = (62..70).collect();
+        assert_eq!(policy_filtered(&policy, &[SOT, 61, 5, 62]), after_closing);
+        // a pair of timestamps is followed by text
+        let after_pair = [text, vec![50]].concat();
+        assert_eq!(policy_filtered(&policy, &[SOT, 61, 5, 62, 62]), after_pair);
+    }
+
+    #[test]
+    fn timestamps_win_when_together_more_likely_than_any_text_token() {
+        let policy = special_token_policy(true);
+        let mut logits = vec![0.0; 70];
+        logits[60..].fill(3.0);
+
+        policy.apply(&[SOT, 61, 5], &mut logits);
+
+        let allowed: Vec<usize> = (0..70).filter(|&tok| logits[tok].is_finite()).collect();
+        assert_eq!(allowed, (62..70).collect::<Vec<_>>());
+    }
+
+    /// Windows of 2.5 seconds overlapping by 1 second, which with their padding fit the
+    /// 4 seconds of the tiny encoder.
+    fn tiny_window_options() -> DecodeOptions {
+        DecodeOptions {
+            vad: None,
+            chunk_length_s: 2.5,
+            chunk_overlap_s: 1.0,
+            end_padding_frames: 50,
+            ..deterministic_options()
+        }
+    }
+
+    fn sine(seconds: f32) -> Vec<f32> {
+        (0..(seconds * SAMPLE_RATE as f32) as usize)
+            .map(|i| 0.5 * (std::f32::consts::TAU * 440.0 * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn short_clip_is_decoded_within_a_few_tokens() {
+        let (whisper, bpe) = tiny_model(64);
+        let options = tiny_window_options();
+
+        let transcript = waveform_to_transcript(
+            &whisper,
+            &bpe,
+            Language::English,
+            &sine(0.3),
+            SAMPLE_RATE,
+            &options,
+        )
+        .unwrap();
+
+        // 0.3 seconds of audio cap the sequence at the 16 tokens allowed for any chunk
+        assert_eq!(transcript.segments.len(), 1);
+        assert!(transcript.segments[0].result.tokens.len() <= 16);
+        assert!(transcript.segments[0].end <= 0.3 + 1e-6);
+    }
+
+    #[test]
+    fn silent_clip_yields_an_empty_segment() {
+        let (whisper, bpe) = tiny_model(64);
+        let options = tiny_window_options();
+        let silence = vec![0.0; SAMPLE_RATE];
 
-    let beamsearch_next = |beams: &[BeamNode]| {
-        // convert tokens into tensor
-        let max_seq_len = beams.iter().map(|beam| beam.seq.len()).max().unwrap_or(0);
-        let flattened_tokens: Vec<_> = beams
+        let transcript = waveform_to_transcript(
+            &whisper,
+            &bpe,
+            Language::English,
+            &silence,
+            SAMPLE_RATE,
+            &options,
+        )
+        .unwrap();
+
+        assert!(transcript.tokens.is_empty());
+        assert!(transcript.text.is_empty());
+        assert_eq!(transcript.segments.len(), 1);
+        assert!(transcript.segments[0].result.tokens.is_empty());
+    }
+
+    #[test]
+    fn span_exactly_one_window_long_is_a_single_chunk() {
+        let waveform = vec![0.0; 100];
+
+        let chunks = fixed_chunks(&waveform, 0..40, 40, 10);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].range, 0..40);
+
+        // the second window ends at the end of the span instead of being followed by a third
+        // that only repeats its overlap
+        let ranges: Vec<_> = fixed_chunks(&waveform, 0..70, 40, 10)
+            .into_iter()
+            .map(|chunk| chunk.range)
+            .collect();
+        assert_eq!(ranges, [0..40, 30..70]);
+        assert_eq!(fixed_chunks(&waveform, 0..71, 40, 10).len(), 3);
+
+        // and no frame of ten hops of 160 samples is made of padding alone
+        assert_eq!(n_frames(10 * 160), 10);
+    }
+
+    /// Pieces of the GPT-2 vocabulary under their ids in it, as byte level symbols where `Ġ`
+    /// stands for a space. Only decoded, so the merges are left out.
+    const GPT2_PIECES: [(&str, usize); 17] = [
+        (".", 13),
+        ("c", 66),
+        ("p", 79),
+        ("r", 81),
+        ("s", 82),
+        ("t", 83),
+        ("Ġt", 256),
+        ("Ġthe", 262),
+        ("Ġw", 266),
+        ("an", 272),
+        ("ion", 295),
+        ("Ġis", 318),
+        ("Ġon", 319),
+        ("ay", 323),
+        ("Ġwe", 356),
+        ("ri", 380),
+        ("Ġare", 389),
+    ];
+    const GPT2_END: usize = 50256;
+
+    /// An English-only tokenizer knowing nothing but [`GPT2_PIECES`].
+    fn gpt2_subset() -> Gpt2Tokenizer {
+        let vocab: serde_json::Map<_, _> = GPT2_PIECES
             .iter()
-            .flat_map(|beam| {
-                let additional_tokens = max_seq_len - beam.seq.len();
-                beam.seq
-                    .iter()
-                    .map(|btok| btok.token as u32)
-                    .chain(iter::once(0).cycle().take(additional_tokens))
-            })
+            .map(|&(piece, id)| (piece.to_string(), id.into()))
             .collect();
+        let tokenizer = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [{
+                "id": GPT2_END,
+                "content": "<|endoftext|>",
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true
+            }],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true},
+            "post_processor": null,
+            "decoder": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true},
+            "model": {"type": "BPE", "vocab": vocab, "merges": []}
+        });
+
+        Gpt2Tokenizer::from_bytes(tokenizer.to_string(), VocabKind::EnglishOnly).unwrap()
+    }
 
-        let token_tensor = Tensor::from_ints(
-            TensorData::new(flattened_tokens, [beams.len(), max_seq_len]),
-            &device,
+    /// Ids of the space separated `pieces` in [`GPT2_PIECES`].
+    fn gpt2_ids(pieces: &str) -> Vec<usize> {
+        pieces
+            .split(' ')
+            .map(|piece| match piece {
+                "<|endoftext|>" => GPT2_END,
+                piece => GPT2_PIECES.iter().find(|(p, _)| *p == piece).unwrap().1,
+            })
+            .collect()
+    }
+
+    /// Joins `curr` to `prev` where `strategy` finds they overlap, as text.
+    fn merged(strategy: MergeStrategy, prev: &[usize], curr: &[usize]) -> String {
+        let bpe = gpt2_subset();
+        let end_token = special_token(&bpe, SpecialToken::EndofText).unwrap();
+        let (prev_index, curr_index) = strategy.merge_point(&bpe, prev, curr, end_token).unwrap();
+        let tokens = [&prev[..prev_index], &curr[curr_index..]].concat();
+
+        bpe.decode(&tokens, true).unwrap()
+    }
+
+    #[test]
+    fn word_boundary_merge_keeps_the_word_the_newer_chunk_starts_in() {
+        // the newer chunk starts after "transcri" and mishears the rest of the word
+        let prev = gpt2_ids("Ġthe Ġt r an s c ri p t ion Ġis Ġon <|endoftext|>");
+        let curr = gpt2_ids("p s ion Ġis Ġon Ġthe Ġw ay");
+
+        assert_eq!(
+            merged(MergeStrategy::TokenOverlap, &prev, &curr),
+            " the transcripsion is on the way"
         );
+        assert_eq!(
+            merged(MergeStrategy::WordBoundary, &prev, &curr),
+            " the transcription is on the way"
+        );
+    }
 
-        let logits = whisper.forward_decoder(
-            token_tensor,
-            encoder_output.clone().repeat(&[beams.len(), 1, 1]),
+    #[test]
+    fn word_boundary_merge_drops_a_word_cut_by_the_length_limit() {
+        // without an end of text the previous chunk stopped within "transcription"
+        let prev = gpt2_ids("Ġwe Ġare Ġon Ġthe Ġt r an s c ri");
+        let curr = gpt2_ids("Ġare Ġon Ġthe Ġt r an s c ri p t ion .");
+
+        assert_eq!(
+            merged(MergeStrategy::WordBoundary, &prev, &curr),
+            " we are on the transcription."
         );
-        let logits = if max_seq_len > 5 {
-            logits
-        } else {
-            logits + special_tokens_maskout.clone().unsqueeze()
+    }
+
+    #[test]
+    fn ties_go_to_the_lower_index() {
+        let values = [1.0, 3.0, 2.0, 3.0, 2.0, 3.0];
+
+        assert_eq!(argmax(&values), 1);
+        assert_eq!(top_k(&values, 2), [1, 3]);
+        assert_eq!(top_k(&values, 5), [1, 3, 5, 2, 4]);
+        assert_eq!(top_k(&values, 10), [1, 3, 5, 2, 4, 0]);
+
+        let values = Tensor::<TestBackend, 1>::from_floats(values, &Default::default());
+        let (top_values, indices) = top_k_on_device(values.unsqueeze::<2>(), 5);
+        assert_eq!(
+            indices
+                .into_data()
+                .convert::<i64>()
+                .to_vec::<i64>()
+                .unwrap(),
+            [1, 3, 5, 2, 4]
+        );
+        assert_eq!(
+            top_values.into_data().to_vec::<f32>().unwrap(),
+            [3.0, 3.0, 3.0, 2.0, 2.0]
+        );
+    }
+
+    /// Flags of a confident result whose tokens are the words of `text`.
+    fn quality_flags(text: &str) -> SegmentFlags {
+        let mut words = HashMap::new();
+        let tokens: Vec<usize> = text
+            .split_whitespace()
+            .map(|word| {
+                let next = words.len();
+                *words.entry(word).or_insert(next)
+            })
+            .collect();
+        let result = DecodingResult {
+            text: text.to_string(),
+            compression_ratio: compression_ratio(text),
+            ..Default::default()
         };
-        let log_probs = log_softmax(logits, 2);
 
-        let beam_log_probs = beams.iter().enumerate().map(|(i, beam)| {
-            let batch = i;
-            let token_index = beam.seq.len() - 1;
+        result.evaluate_flags(&tokens, &DecodeOptions::recommended())
+    }
+
+    #[test]
+    fn looping_text_is_flagged() {
+        let flags = quality_flags(&" the".repeat(30));
+
+        assert!(flags.compression_ratio);
+        assert!(flags.repetition);
+        assert!(!flags.log_prob);
+
+        let flags = quality_flags(&" and then we went home".repeat(6));
+        assert!(flags.compression_ratio);
+        assert!(flags.repetition);
+    }
+
+    #[test]
+    fn prose_is_not_flagged() {
+        let text = " The quick brown fox jumps over the lazy dog, while the farmer's wife \
+                    watches from the kitchen window and wonders why it bothers.";
+
+        assert_eq!(quality_flags(text), SegmentFlags::default());
+    }
+
+    #[test]
+    fn short_text_is_not_flagged_for_its_compression_ratio() {
+        // a few repeated words do not compress, but the zlib overhead is no signal either
+        let flags = quality_flags(" no no no no no");
 
-            log_probs
-                .clone()
-                .slice([batch..batch + 1, token_index..token_index + 1])
-                .flatten::<1>(0, 2)
+        assert!(!flags.compression_ratio);
+        assert!(flags.repetition);
+        assert!(!quality_flags(" yes").any());
+    }
+
+    #[test]
+    fn a_long_prompt_fits_a_tiny_decoder_context_with_either_policy() {
+        let (whisper, bpe) = tiny_model(16);
+        let mels = random_mels(300);
+        let prompt: Vec<usize> = (0..40).collect();
+
+        for beam_size in [1, 2] {
+            for (context_policy, n_tokens) in [
+                // the prompt gives way to the 8 tokens a chunk may generate, leaving
+                // <|startofprev|> and 3 prompt tokens
+                (ContextPolicy::TruncatePrompt, 16 - 4),
+                // the prompt keeps half the context, <|startofprev|> and 7 tokens
+                (ContextPolicy::Stop, 16 - 8),
+            ] {
+                let options = DecodeOptions {
+                    beam_size,
+                    context_policy,
+                    repetition: None,
+                    ..deterministic_options()
+                };
+                let result = mels_to_text(
+                    &whisper,
+                    &bpe,
+                    Language::English,
+                    mels.clone(),
+                    50,
+                    &prompt,
+                    &options,
+                )
+                .unwrap();
+
+                // the random model never ends the text, so decoding runs until the context is full
+                assert_eq!(
+                    result.tokens.len(),
+                    n_tokens,
+                    "{context_policy:?} with {beam_size} beams"
+                );
+            }
+        }
+
+        // a sequence beyond the context is an error rather than a panic
+        let tokens =
+            Tensor::<TestBackend, 2, burn::tensor::Int>::zeros([1, 17], &Default::default());
+        let encoder_output = whisper.forward_encoder(random_mels(300)).unwrap();
+        assert!(matches!(
+            whisper.forward_decoder(tokens, encoder_output),
+            Err(WhisperError::SequenceTooLong { len: 17, max: 16 })
+        ));
+    }
+
+    /// `seconds` of a tone at each of `frequencies` in turn, with `gap` seconds of silence
+    /// before each switch.
+    fn alternating_tones(frequencies: &[f32], seconds: f32, gap: f32) -> Vec<f32> {
+        let n_tone = (seconds * SAMPLE_RATE as f32) as usize;
+        let n_gap = (gap * SAMPLE_RATE as f32) as usize;
+        let n_switches = frequencies.len() - 1;
+        frequencies
+            .iter()
+            .enumerate()
+            .flat_map(|(k, &frequency)| {
+                (0..n_tone).map(move |i| {
+                    if k < n_switches && i >= n_tone - n_gap {
+                        return 0.0;
+                    }
+                    let t = i as f32 / SAMPLE_RATE as f32;
+                    0.5 * (std::f32::consts::TAU * frequency * t).sin()
+                })
+            })
+            .collect()
+    }
+
+    fn turn_seconds(waveform: &[f32]) -> Vec<f32> {
+        speaker_turns::<TestBackend>(waveform, 80, &Default::default(), &TurnOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|turn| turn as f32 / SAMPLE_RATE as f32)
+            .collect()
+    }
+
+    #[test]
+    fn turns_are_found_where_alternating_tones_switch() {
+        let turns = turn_seconds(&alternating_tones(
+            &[300.0, 2000.0, 300.0, 2000.0],
+            4.0,
+            0.0,
+        ));
+
+        assert_eq!(turns.len(), 3, "turns at {turns:?}");
+        for (turn, switch) in turns.iter().zip([4.0, 8.0, 12.0]) {
+            assert!((turn - switch).abs() < 0.1, "turns at {turns:?}");
+        }
+    }
+
+    #[test]
+    fn turns_snap_to_a_nearby_silence() {
+        // a pause ends each tone 0.4 seconds before the next one starts
+        let turns = turn_seconds(&alternating_tones(&[300.0, 2000.0, 300.0], 4.0, 0.4));
+
+        assert_eq!(turns.len(), 2, "turns at {turns:?}");
+        for (turn, switch) in turns.iter().zip([4.0, 8.0]) {
+            assert!((switch - 0.4..switch).contains(turn), "turns at {turns:?}");
+        }
+    }
+
+    #[test]
+    fn a_steady_tone_has_no_turns() {
+        assert!(turn_seconds(&alternating_tones(&[300.0, 300.0, 300.0], 4.0, 0.0)).is_empty());
+    }
+
+    #[test]
+    fn batched_beams_get_the_logits_of_individual_passes() {
+        let (whisper, _) = tiny_model(64);
+        let device = Default::default();
+        let encoder_output = whisper.forward_encoder(random_mels(300)).unwrap();
+        let beams = [[261, 5, 17, 300], [261, 5, 40, 41], [261, 9, 9, 1000]];
+        let last_logits = |tokens: Tensor<TestBackend, 2, burn::tensor::Int>| {
+            let [n_beams, seq_len] = tokens.dims();
+            whisper
+                .forward_decoder(tokens, encoder_output.clone())
+                .unwrap()
+                .slice([0..n_beams, seq_len - 1..seq_len])
                 .into_data()
                 .to_vec::<f32>()
                 .unwrap()
-        });
+        };
 
-        beam_log_probs
-            .zip(beams)
-            .map(|(log_probs, beam)| {
-                log_probs
-                    .into_iter()
-                    .map(|log_prob| log_prob.elem::<f64>())
-                    .enumerate()
-                    .map(|(token_id, log_prob)| {
-                        (
-                            BeamSearchToken { token: token_id },
-                            beam.log_prob + log_prob,
-                        )
-                    })
-                    .collect()
-            })
+        let batched = last_logits(Tensor::from_ints(beams, &device));
+        for (beam, batched) in beams.iter().zip(batched.chunks(N_VOCAB)) {
+            let single = last_logits(Tensor::from_ints([*beam], &device));
+            for (batched, single) in batched.iter().zip(&single) {
+                assert!((batched - single).abs() < 1e-4, "{batched} != {single}");
+            }
+        }
+    }
+
+    fn boosted(filter: &impl LogitFilter, seq: &[usize]) -> Vec<usize> {
+        let logits = filtered(filter, seq);
+        (0..logits.len())
+            .filter(|&token| logits[token] > 0.0)
             .collect()
-    };
+    }
 
-    let beamsearch_is_finished = |toks: &[BeamSearchToken]| {
-        if let Some(btok) = toks.last() {
-            btok.token == end_token
-        } else {
-            false
+    #[test]
+    fn phrase_tokens_are_boosted_only_after_their_prefix() {
+        let bias = LogitBias {
+            phrases: vec![vec![1, 2, 3], vec![4, 5]],
+            boost: 2.0,
+        };
+
+        // the first tokens of the phrases are always boosted
+        assert_eq!(boosted(&bias, &[SOT]), [1, 4]);
+        assert_eq!(boosted(&bias, &[SOT, 1]), [1, 2, 4]);
+        assert_eq!(boosted(&bias, &[SOT, 1, 2]), [1, 3, 4]);
+        assert_eq!(boosted(&bias, &[SOT, 4]), [1, 4, 5]);
+        // a later word of a phrase is not favored on its own
+        assert_eq!(boosted(&bias, &[SOT, 7, 2]), [1, 4]);
+        assert_eq!(filtered(&bias, &[SOT, 1])[2], 2.0);
+    }
+
+    #[test]
+    fn a_token_continuing_several_matches_is_boosted_once() {
+        let bias = LogitBias {
+            phrases: vec![vec![1, 1, 2]],
+            boost: 2.0,
+        };
+
+        assert_eq!(filtered(&bias, &[SOT, 1])[1], 2.0);
+    }
+
+    #[test]
+    fn phrases_are_tokenized_after_a_space() {
+        let (_, bpe) = tiny_model(64);
+        let bias = LogitBias::from_phrases(&bpe, &["next track", "  ", "open settings "], 1.0);
+
+        assert_eq!(
+            bias.phrases,
+            [
+                bpe.encode_ordinary(" next track"),
+                bpe.encode_ordinary(" open settings")
+            ]
+        );
+    }
+
+    #[test]
+    fn logit_bias_is_added_at_every_step() {
+        let filters = TokenFilters {
+            logit_bias: HashMap::from([(3, 1.5), (8, -2.0)]),
+            ..token_filters()
+        };
+
+        for seq in [&[SOT][..], &[SOT, 3, 3]] {
+            let logits = filtered(&filters, seq);
+            assert_eq!((logits[3], logits[8], logits[4]), (1.5, -2.0, 0.0));
         }
-    };
+    }
 
-    let beam_size = 5;
-    let max_depth = 30;
-    let tokens: Vec<_> = beam::beam_search(
-        vec![initial_tokens],
-        beamsearch_next,
-        beamsearch_is_finished,
-        beam_size,
-        max_depth,
-    )
-    .into_iter()
-    .map(|btok| btok.token)
-    .collect();
+    #[test]
+    fn non_finite_logits_abort_or_are_clamped() {
+        let logits = vec![0.5, f64::NAN, f64::INFINITY, f64::NEG_INFINITY];
 
-    let text = bpe.decode(&tokens[..], false)?;
+        let mut aborted = logits.clone();
+        assert!(!NonFinitePolicy::Abort.check(&mut aborted));
+        assert!(aborted[1].is_nan());
 
-    Ok((text, tokens))
-}
+        let mut clamped = logits.clone();
+        assert!(NonFinitePolicy::Clamp.check(&mut clamped));
+        assert_eq!(clamped, vec![0.5, -MAX_LOGIT, MAX_LOGIT, -MAX_LOGIT]);
 
-//HELPERS
-fn find_chunk_overlap(
-    prev_tokens: &[usize],
-    curr_tokens: &[usize],
-    max_n_offsets: usize,
-    min_n_overlaps: usize,
-) -> Option<(usize, usize)> {
-    let mut max_overlap = 0;
-    let mut max_overlap_indices = (0, 0);
-    let n_offsets = prev_tokens.len().min(curr_tokens.len()).min(max_n_offsets);
+        // finite logits pass either policy untouched
+        for policy in [NonFinitePolicy::Abort, NonFinitePolicy::Clamp] {
+            let mut finite = vec![-3.0, 0.0, 2.5];
+            assert!(policy.check(&mut finite));
+            assert_eq!(finite, vec![-3.0, 0.0, 2.5]);
+        }
+    }
 
-    for offset in 0..n_offsets {
-        let prev_start_index = prev_tokens.len() - 1 - offset;
-        let mut overlap_iter = prev_tokens
+    /// Makes the embedding of `token`, which the decoder also uses to score it, NaN.
+    struct NanEmbedding {
+        token: usize,
+    }
+
+    impl<B: Backend> ModuleMapper<B> for NanEmbedding {
+        fn map_float<const D: usize>(
+            &mut self,
+            _id: ParamId,
+            tensor: Tensor<B, D>,
+        ) -> Tensor<B, D> {
+            if tensor.dims()[0] != N_VOCAB {
+                return tensor;
+            }
+            let shape = tensor.shape();
+            let row = shape.num_elements() / N_VOCAB;
+            let mut values = tensor.to_data().to_vec::<f32>().unwrap();
+            values[self.token * row..(self.token + 1) * row].fill(f32::NAN);
+
+            Tensor::from_data(TensorData::new(values, shape), &tensor.device())
+        }
+    }
+
+    #[test]
+    fn nan_logits_abort_the_chunk_or_are_never_chosen() {
+        let nan_token = 120;
+        let (whisper, bpe) = tiny_model(64);
+        let whisper = whisper.map(&mut NanEmbedding { token: nan_token });
+        let mels = random_mels(300);
+
+        for beam_size in [1, 2] {
+            let decode = |non_finite_logits| {
+                let options = DecodeOptions {
+                    beam_size,
+                    non_finite_logits,
+                    ..deterministic_options()
+                };
+                mels_to_text(
+                    &whisper,
+                    &bpe,
+                    Language::English,
+                    mels.clone(),
+                    50,
+                    &[],
+                    &options,
+                )
+            };
+
+            // the first generated token already sees the NaN logit
+            assert!(matches!(
+                decode(NonFinitePolicy::Abort),
+                Err(WhisperError::NumericalError { chunk: 0, step: 0 })
+            ));
+            let result = decode(NonFinitePolicy::Clamp).unwrap();
+            assert!(!result.tokens.is_empty());
+            assert!(!result.tokens.contains(&nan_token));
+        }
+    }
+
+    fn origins(chunk: usize, tokens: &[(usize, f64)]) -> Vec<TokenOrigin> {
+        tokens
             .iter()
-            .skip(prev_start_index)
-            .zip(curr_tokens.iter())
-            .enumerate()
-            .filter(|(_, (&old, &new))| old == new);
+            .map(|&(token, log_prob)| TokenOrigin {
+                token,
+                chunk,
+                chunk_start_s: chunk as f64,
+                chunk_end_s: chunk as f64 + 2.0,
+                log_prob: Some(log_prob),
+            })
+            .collect()
+    }
 
-        let n_overlap = overlap_iter.clone().count();
-        if n_overlap > max_overlap {
-            max_overlap = n_overlap;
+    fn spliced(prev: &[TokenOrigin], curr: &[TokenOrigin]) -> Vec<(usize, usize)> {
+        let tokens = |origins: &[TokenOrigin]| -> Vec<usize> {
+            origins.iter().map(|origin| origin.token).collect()
+        };
+        let strategy = MergeStrategy::Confidence { min_agreement: 0.5 };
+        let (_, bpe) = tiny_model(64);
+        let merge_point = strategy.merge_point(&bpe, &tokens(prev), &tokens(curr), END);
+        assert!(merge_point.is_some());
 
-            let curr_overlap_index = overlap_iter.next().unwrap().0;
-            let prev_overlap_index = prev_start_index + curr_overlap_index;
-            max_overlap_indices = (prev_overlap_index, curr_overlap_index)
+        let mut merged = prev.to_vec();
+        splice_tokens(&mut merged, curr, merge_point, true, END);
+        merged
+            .iter()
+            .map(|origin| (origin.token, origin.chunk))
+            .collect()
+    }
+
+    #[test]
+    fn each_aligned_token_comes_from_the_more_confident_chunk() {
+        // the chunks align from the 3, agreeing on all but the 4 the newer chunk heard as 9
+        let prev = origins(
+            0,
+            &[
+                (1, -0.1),
+                (2, -0.1),
+                (3, -0.5),
+                (4, -0.2),
+                (5, -0.1),
+                (6, -3.0),
+            ],
+        );
+        let curr = origins(1, &[(3, -0.5), (9, -2.0), (5, -0.4), (6, -0.1), (7, -0.1)]);
+
+        assert_eq!(
+            spliced(&prev, &curr),
+            // ties go to the newer chunk, as do tokens it is more sure of
+            vec![(1, 0), (2, 0), (3, 1), (4, 0), (5, 0), (6, 1), (7, 1)]
+        );
+
+        let curr = origins(1, &[(3, -0.5), (9, -0.01), (5, -0.4), (6, -0.1), (7, -0.1)]);
+        assert_eq!(
+            spliced(&prev, &curr),
+            vec![(1, 0), (2, 0), (3, 1), (9, 1), (5, 0), (6, 1), (7, 1)]
+        );
+    }
+
+    struct SavedCheckpoints(Vec<Checkpoint>);
+
+    impl CheckpointSink for SavedCheckpoints {
+        fn save(&mut self, checkpoint: &Checkpoint) -> Result<()> {
+            self.0.push(checkpoint.clone());
+            Ok(())
         }
     }
 
-    if max_overlap >= min_n_overlaps {
-        Some(max_overlap_indices)
-    } else {
-        None
+    #[test]
+    fn prompt_holds_the_merged_text_with_the_overlaps_once() {
+        let (whisper, bpe) = tiny_model(448);
+        let end_token = special_token(&bpe, SpecialToken::EndofText).unwrap();
+        // no quality check can drop the prompt
+        let options = DecodeOptions {
+            compression_ratio_threshold: None,
+            log_prob_threshold: None,
+            max_ngram_repeats: None,
+            condition_on_previous_text: true,
+            ..tiny_window_options()
+        };
+        let mut saved = SavedCheckpoints(Vec::new());
+
+        waveform_to_text_checkpointed(
+            &whisper,
+            &bpe,
+            Language::English,
+            &sine(5.0),
+            SAMPLE_RATE,
+            &options,
+            None,
+            &mut saved,
+        )
+        .unwrap();
+
+        assert!(saved.0.len() > 2);
+        for checkpoint in &saved.0 {
+            let merged: Vec<usize> = checkpoint
+                .tokens
+                .iter()
+                .chain(&checkpoint.state.window)
+                .copied()
+                .filter(|&tok| tok < end_token)
+                .collect();
+            assert_eq!(checkpoint.state.prompt_tokens, merged);
+        }
+    }
+
+    #[test]
+    fn text_is_never_replaced_by_the_end_of_the_previous_chunk() {
+        let prev = origins(0, &[(1, -0.1), (2, -0.1), (3, -0.1), (END, -0.01)]);
+        let curr = origins(1, &[(1, -0.1), (2, -0.1), (3, -0.1), (4, -3.0), (5, -0.1)]);
+
+        assert_eq!(
+            spliced(&prev, &curr),
+            vec![(1, 1), (2, 1), (3, 1), (4, 1), (5, 1)]
+        );
+
+        // tokens restored from old checkpoints have no probability and give way
+        let mut prev = origins(0, &[(1, -0.1), (2, -0.1), (3, -0.1), (4, -0.1)]);
+        prev[3].log_prob = None;
+        let curr = origins(1, &[(1, -0.1), (2, -0.1), (3, -0.1), (8, -5.0)]);
+        assert_eq!(spliced(&prev, &curr), vec![(1, 1), (2, 1), (3, 1), (8, 1)]);
+    }
+
+    #[test]
+    fn seams_agreeing_on_too_little_are_decoded_again() {
+        let prev = [1, 2, 3, 4, 5, 6];
+        let curr = [3, 9, 5, 6, 7];
+        let merge_point = Some((2, 0));
+        assert_eq!(overlap_agreement(&prev, &curr, (2, 0)), 0.75);
+
+        let confidence = |min_agreement| MergeStrategy::Confidence { min_agreement };
+        assert!(!confidence(0.75).needs_reseam(&prev, &curr, merge_point));
+        assert!(confidence(0.8).needs_reseam(&prev, &curr, merge_point));
+        // chunks that do not align at all always are
+        assert!(confidence(0.0).needs_reseam(&prev, &curr, None));
+        assert!(!MergeStrategy::TokenOverlap.needs_reseam(&prev, &curr, None));
     }
 }
@@ -0,0 +1,745 @@
+//! Runs the whole transcription pipeline with a tiny randomly initialized model and the
+//! miniature tokenizer in `tests/fixtures`, whose byte-level vocabulary is followed by whisper's
+//! special tokens. The text is meaningless; what matters is that decoding terminates and only
+//! yields tokens the model has.
+
+use burn::backend::ndarray::NdArray;
+use burn::module::{Module, ModuleVisitor, ParamId};
+use burn::tensor::{Int, Tensor, TensorData};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use whisper_stream::audio::{PreprocessOptions, TurnOptions, VadOptions};
+use whisper_stream::cache::EncoderCache;
+use whisper_stream::checkpoint::{Checkpoint, CheckpointSink};
+use whisper_stream::error::WhisperError;
+use whisper_stream::eval::{align, ErrorCounts};
+use whisper_stream::model::load::{
+    load_decoder, load_encoder, load_named_model, random_whisper, save_decoder, save_encoder,
+};
+use whisper_stream::model::save::{save_whisper_bundle, verify_bundle};
+use whisper_stream::model::{AudioEncoderConfig, TextDecoderConfig, Whisper, WhisperConfig};
+use whisper_stream::postprocess::PostprocessOptions;
+use whisper_stream::token::{Gpt2Tokenizer, Language, SpecialToken, VocabKind};
+use whisper_stream::transcribe::{
+    transcribe, waveform_to_text_checkpointed, waveform_to_text_streaming, waveform_to_transcript,
+    CancellationToken, ContextPolicy, DecodeEvent, DecodeOptions, LanguageMode, LogitFilter,
+    MergeStrategy, NonFinitePolicy, Task, TranscribeOptions, Transcript,
+};
+
+type Backend = NdArray;
+
+const SAMPLE_RATE: usize = 16000;
+/// 256 byte tokens, 4 merges and the 1608 special tokens of the multilingual layout.
+const N_VOCAB: usize = 1868;
+
+/// The model is built once and cloned for each test, since transcribing never changes it.
+fn tiny_model() -> (Whisper<Backend>, Gpt2Tokenizer) {
+    static WHISPER: OnceLock<Mutex<Whisper<Backend>>> = OnceLock::new();
+    // 200 encoder positions hold 4 seconds of audio
+    let whisper = WHISPER.get_or_init(|| Mutex::new(tiny_model_with_audio_ctx(200).0));
+
+    (whisper.lock().unwrap().clone(), tokenizer())
+}
+
+fn tiny_model_with_audio_ctx(n_audio_ctx: usize) -> (Whisper<Backend>, Gpt2Tokenizer) {
+    let config = WhisperConfig::new(
+        AudioEncoderConfig::new(8, n_audio_ctx, 64, 2, 2),
+        TextDecoderConfig::new(N_VOCAB, 64, 64, 2, 2),
+    );
+    let whisper = random_whisper::<Backend>(&config, 0, &Default::default());
+
+    (whisper, tokenizer())
+}
+
+fn tokenizer() -> Gpt2Tokenizer {
+    Gpt2Tokenizer::from_bytes(
+        include_bytes!("fixtures/tokenizer.json"),
+        VocabKind::from_n_vocab(N_VOCAB),
+    )
+    .unwrap()
+}
+
+fn sine(seconds: f32, frequency: f32) -> Vec<f32> {
+    (0..(seconds * SAMPLE_RATE as f32) as usize)
+        .map(|i| 0.5 * (std::f32::consts::TAU * frequency * i as f32 / SAMPLE_RATE as f32).sin())
+        .collect()
+}
+
+/// The recommended options with overlapping windows that, with their padding, fit the 4
+/// seconds of the tiny encoder.
+fn options() -> TranscribeOptions {
+    TranscribeOptions::recommended()
+        .language(Language::English)
+        .beam_size(3)
+        .temperatures(vec![0.0, 0.5])
+        .seed(0)
+        .vad(None)
+        .chunk_length(2.5)
+        .chunk_overlap(1.0)
+        .end_padding(50)
+}
+
+/// The options above with a single beam at a single temperature, for the tests of what
+/// happens around the search.
+fn greedy_options() -> TranscribeOptions {
+    options().beam_size(1).temperatures(vec![0.0])
+}
+
+fn assert_valid(transcript: &Transcript, bpe: &Gpt2Tokenizer) {
+    assert!(transcript.tokens.iter().all(|&token| token < N_VOCAB));
+    assert_eq!(transcript.tokens.len(), transcript.token_origins.len());
+    assert!(!transcript.segments.is_empty());
+    for segment in &transcript.segments {
+        assert!(segment.start <= segment.end);
+        assert!(segment.result.tokens.iter().all(|&token| token < N_VOCAB));
+    }
+    bpe.decode(&transcript.tokens, true).unwrap();
+}
+
+#[test]
+fn fixture_has_whisper_special_tokens() {
+    let (_, bpe) = tiny_model();
+
+    assert_eq!(bpe.kind(), VocabKind::Multilingual);
+    assert!(!bpe.is_special(259));
+    assert!(bpe.is_special(260));
+    assert!(bpe.is_special(N_VOCAB - 1));
+}
+
+#[test]
+fn sine_wave_is_transcribed_with_each_merge_strategy() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(4.0, 440.0);
+
+    for strategy in [
+        MergeStrategy::TokenOverlap,
+        MergeStrategy::WordBoundary,
+        MergeStrategy::Confidence { min_agreement: 0.5 },
+    ] {
+        let options = greedy_options().merge_strategy(strategy).reseam(true);
+        let transcript = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+
+        assert_valid(&transcript, &bpe);
+    }
+}
+
+#[test]
+fn sine_wave_is_transcribed_with_timestamps() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(2.0, 220.0);
+
+    let options = options().timestamps(true);
+    let transcript = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+
+    assert_valid(&transcript, &bpe);
+}
+
+#[test]
+fn quantized_model_transcribes_like_the_full_precision_one() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(3.0, 330.0);
+    let options = options();
+
+    let full = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+    let quantized = whisper.clone().quantize();
+    let quantized = transcribe(&quantized, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+
+    assert_valid(&quantized, &bpe);
+    let errors = ErrorCounts::from_ops(&align(&full.tokens, &quantized.tokens));
+    assert!(errors.rate() < 0.2, "token error rate {}", errors.rate());
+}
+
+/// Bytes held by the float and int tensors of a module.
+struct ParamBytes(usize);
+
+impl ModuleVisitor<Backend> for ParamBytes {
+    fn visit_float<const D: usize>(&mut self, _id: ParamId, tensor: &Tensor<Backend, D>) {
+        self.0 += tensor.shape().num_elements() * std::mem::size_of::<f32>();
+    }
+
+    fn visit_int<const D: usize>(&mut self, _id: ParamId, tensor: &Tensor<Backend, D, Int>) {
+        self.0 += tensor.shape().num_elements() * std::mem::size_of::<i64>();
+    }
+}
+
+fn param_bytes(whisper: &Whisper<Backend>) -> usize {
+    let mut bytes = ParamBytes(0);
+    whisper.visit(&mut bytes);
+    bytes.0
+}
+
+#[test]
+fn quantized_decoder_holds_a_byte_per_projection_weight() {
+    let (whisper, _) = tiny_model();
+    let full = param_bytes(&whisper);
+    let quantized = param_bytes(&whisper.quantize());
+
+    // each of the 2 decoder blocks has 8 projections of 64 x 64 weights and 2 of 64 x 256, 65536 in all
+    let projection_weights = 2 * (8 * 64 * 64 + 2 * 64 * 256);
+    // the quantized ones take a byte each and their scales 4 bytes per output channel
+    let scales = 2 * (8 * 64 + 64 + 256) * 4;
+    assert_eq!(full - quantized, projection_weights * 3 - scales);
+}
+
+#[test]
+fn streamed_text_is_the_text_of_the_transcript() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(4.0, 440.0);
+    let options = greedy_options().decode;
+
+    let mut streamed = Vec::new();
+    waveform_to_text_streaming(
+        &whisper,
+        &bpe,
+        Language::English,
+        &waveform,
+        SAMPLE_RATE,
+        &options,
+        &mut streamed,
+    )
+    .unwrap();
+    let transcript = waveform_to_transcript(
+        &whisper,
+        &bpe,
+        Language::English,
+        &waveform,
+        SAMPLE_RATE,
+        &options,
+    )
+    .unwrap();
+
+    assert!(transcript.segments.len() > 1);
+    assert!(!transcript.text.is_empty());
+    assert_eq!(String::from_utf8(streamed).unwrap(), transcript.text);
+}
+
+/// Keeps the checkpoints in memory, failing to save any after the first `capacity`.
+struct InterruptedSink {
+    saved: Vec<Checkpoint>,
+    capacity: usize,
+}
+
+impl CheckpointSink for InterruptedSink {
+    fn save(&mut self, checkpoint: &Checkpoint) -> whisper_stream::error::Result<()> {
+        if self.saved.len() == self.capacity {
+            return Err(WhisperError::Checkpoint("the disk is full".into()));
+        }
+        self.saved.push(checkpoint.clone());
+        Ok(())
+    }
+}
+
+#[test]
+fn a_resumed_transcription_matches_an_uninterrupted_one() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(5.0, 440.0);
+    let options = greedy_options().decode;
+    let run = |resume, sink: &mut InterruptedSink| {
+        waveform_to_text_checkpointed(
+            &whisper,
+            &bpe,
+            Language::English,
+            &waveform,
+            SAMPLE_RATE,
+            &options,
+            resume,
+            sink,
+        )
+    };
+
+    let mut uninterrupted = InterruptedSink {
+        saved: Vec::new(),
+        capacity: usize::MAX,
+    };
+    let expected = run(None, &mut uninterrupted).unwrap();
+    assert!(uninterrupted.saved.len() > 2);
+
+    let mut interrupted = InterruptedSink {
+        saved: Vec::new(),
+        capacity: 2,
+    };
+    assert!(matches!(
+        run(None, &mut interrupted),
+        Err(WhisperError::Checkpoint(_))
+    ));
+    let checkpoint = interrupted.saved.pop().unwrap();
+    assert_eq!(checkpoint.n_chunks(), 2);
+
+    let mut resumed_sink = InterruptedSink {
+        saved: Vec::new(),
+        capacity: usize::MAX,
+    };
+    let resumed = run(Some(checkpoint.clone()), &mut resumed_sink).unwrap();
+    assert_eq!(resumed.tokens, expected.tokens);
+    assert_eq!(resumed.text, expected.text);
+
+    // windows cut elsewhere would not continue where the checkpoint stopped
+    let rechunked = DecodeOptions {
+        chunk_overlap_s: 0.5,
+        ..options.clone()
+    };
+    let result = waveform_to_text_checkpointed(
+        &whisper,
+        &bpe,
+        Language::English,
+        &waveform,
+        SAMPLE_RATE,
+        &rechunked,
+        Some(checkpoint),
+        &mut resumed_sink,
+    );
+    assert!(matches!(result, Err(WhisperError::Checkpoint(_))));
+}
+
+#[test]
+fn greedy_decoding_is_reproducible() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(2.0, 440.0);
+    let options = greedy_options();
+
+    let first = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+    let second = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+
+    assert_eq!(first.tokens, second.tokens);
+}
+
+#[test]
+fn sampling_is_reproduced_from_the_seed_of_the_transcript() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(2.0, 440.0);
+    let mut options = options().temperatures(vec![1.0]);
+    options.decode.seed = None;
+
+    let first = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+    let options = options.seed(first.metadata.seed);
+    let second = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+
+    assert_eq!(second.metadata.seed, first.metadata.seed);
+    assert_eq!(first.tokens, second.tokens);
+}
+
+/// Counts the decoding steps it sees, without changing the logits.
+#[derive(Debug, Default)]
+struct CountingFilter(AtomicUsize);
+
+impl LogitFilter for CountingFilter {
+    fn apply(&self, _seq: &[usize], _logits: &mut [f64]) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn every_option_reaches_the_decoder_and_the_transcript() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(2.0, 440.0);
+    let cache_root = std::env::temp_dir().join(format!("whisper-options-{}", std::process::id()));
+    let weights = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tokenizer.json");
+    let cache = Arc::new(EncoderCache::open(&cache_root, "tiny", &weights, 1 << 20).unwrap());
+    let (events, received) = mpsc::channel();
+    let filter = Arc::new(CountingFilter::default());
+    let preprocess = PreprocessOptions {
+        remove_dc: true,
+        ..Default::default()
+    };
+    let postprocess = PostprocessOptions {
+        normalize_spacing: true,
+        ..Default::default()
+    };
+    let vad = VadOptions {
+        energy_threshold_db: -80.0,
+        ..Default::default()
+    };
+    let turn_detection = TurnOptions {
+        threshold: 10.0,
+        ..Default::default()
+    };
+
+    let options = TranscribeOptions::new()
+        .language_mode(LanguageMode::DetectPerChunk {
+            min_switch_prob: 0.9,
+        })
+        .language(Language::English)
+        .task(Task::Translate)
+        .beam_size(2)
+        .nbest(2)
+        .patience(1.5)
+        .temperatures(vec![0.0, 0.4])
+        .prompt(" the")
+        .timestamps(true)
+        .word_timestamps(false)
+        .compression_ratio_threshold(Some(3.0))
+        .log_prob_threshold(None)
+        .no_speech_threshold(Some(0.9))
+        .max_ngram_repeats(Some(6))
+        .context_policy(ContextPolicy::Stop)
+        .non_finite_logits(NonFinitePolicy::Clamp)
+        .merge_strategy(MergeStrategy::TokenOverlap)
+        .reseam(true)
+        .condition_on_previous_text(false)
+        .seed(7)
+        .suppress_non_speech(false)
+        .preprocess(preprocess.clone())
+        .postprocess(postprocess.clone())
+        .vad(Some(vad.clone()))
+        .turn_detection(Some(turn_detection.clone()))
+        .chunk_length(2.5)
+        .chunk_overlap(1.0)
+        .end_padding(50)
+        .encoder_attention_window(Some(100))
+        .encoder_cache(cache)
+        .events(events)
+        .cancel(CancellationToken::new())
+        .logit_bias(HashMap::from([(5, -1.0)]))
+        .logit_filter(filter.clone());
+
+    // a fixed language replaces the detection per chunk
+    assert_eq!(options.language, LanguageMode::Fixed(Language::English));
+    let decode = &options.decode;
+    assert_eq!(decode.detect_language_per_chunk, None);
+    assert_eq!(decode.task, Task::Translate);
+    assert_eq!(decode.beam_size, 2);
+    assert_eq!(decode.nbest, 2);
+    assert_eq!(decode.patience, 1.5);
+    assert_eq!(decode.temperatures, [0.0, 0.4]);
+    assert_eq!(decode.prompt.as_deref(), Some(" the"));
+    assert!(decode.timestamps);
+    assert!(!decode.word_timestamps);
+    assert_eq!(decode.compression_ratio_threshold, Some(3.0));
+    assert_eq!(decode.log_prob_threshold, None);
+    assert_eq!(decode.no_speech_threshold, Some(0.9));
+    assert_eq!(decode.max_ngram_repeats, Some(6));
+    assert_eq!(decode.context_policy, ContextPolicy::Stop);
+    assert_eq!(decode.non_finite_logits, NonFinitePolicy::Clamp);
+    assert_eq!(decode.merge_strategy, MergeStrategy::TokenOverlap);
+    assert!(decode.reseam);
+    assert!(!decode.condition_on_previous_text);
+    assert_eq!(decode.seed, Some(7));
+    assert!(!decode.suppress_non_speech);
+    assert_eq!(
+        format!("{:?}", decode.preprocess),
+        format!("{preprocess:?}")
+    );
+    assert_eq!(
+        format!("{:?}", decode.postprocess),
+        format!("{postprocess:?}")
+    );
+    assert_eq!(format!("{:?}", decode.vad), format!("{:?}", Some(vad)));
+    assert_eq!(
+        format!("{:?}", decode.turn_detection),
+        format!("{:?}", Some(turn_detection))
+    );
+    assert_eq!(decode.chunk_length_s, 2.5);
+    assert_eq!(decode.chunk_overlap_s, 1.0);
+    assert_eq!(decode.end_padding_frames, 50);
+    assert_eq!(decode.encoder_attention_window.0, Some(100));
+    assert!(decode.encoder_cache.is_some());
+    assert!(decode.events.is_some());
+    assert!(decode.cancel.is_some());
+    assert_eq!(decode.logit_bias, HashMap::from([(5, -1.0)]));
+    assert_eq!(decode.logit_filters.len(), 1);
+
+    let transcript = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+    drop(options);
+    std::fs::remove_dir_all(&cache_root).unwrap();
+
+    assert_valid(&transcript, &bpe);
+    assert_eq!(transcript.language, "en");
+    assert_eq!(transcript.metadata.task, Task::Translate);
+    assert_eq!(transcript.metadata.beam_size, 2);
+    assert_eq!(transcript.metadata.temperatures, [0.0, 0.4]);
+    assert_eq!(transcript.metadata.seed, 7);
+    assert!(filter.0.load(Ordering::Relaxed) > 0);
+    let finished = received
+        .iter()
+        .filter(|event| matches!(event, DecodeEvent::ChunkFinished { .. }))
+        .count();
+    assert_eq!(finished, transcript.segments.len());
+}
+
+#[test]
+#[allow(deprecated)]
+fn deprecated_waveform_to_text_transcribes_with_the_default_options() {
+    // the default windows need whisper's full 30 seconds of encoder context
+    let (whisper, bpe) = tiny_model_with_audio_ctx(1500);
+    let waveform = sine(1.0, 440.0);
+    let waveform_to_text = || -> Result<(String, Vec<usize>), WhisperError> {
+        whisper_stream::transcribe::waveform_to_text(
+            &whisper,
+            &bpe,
+            Language::English,
+            waveform.clone(),
+            SAMPLE_RATE,
+            false,
+        )
+    };
+
+    let (text, tokens) = waveform_to_text().unwrap();
+
+    assert!(tokens.iter().all(|&token| token < N_VOCAB));
+    assert_eq!(text, bpe.decode(&tokens, true).unwrap());
+    // the defaults never sample, so the result is the same every time
+    assert_eq!(waveform_to_text().unwrap(), (text, tokens));
+}
+
+/// Cancels the transcription when the second chunk starts decoding.
+#[derive(Debug)]
+struct CancelOnSecondChunk {
+    /// Last token of the prefix every chunk starts from.
+    prefix_end: usize,
+    chunks: AtomicUsize,
+    cancel: CancellationToken,
+}
+
+impl LogitFilter for CancelOnSecondChunk {
+    fn apply(&self, seq: &[usize], _logits: &mut [f64]) {
+        if seq.last() == Some(&self.prefix_end) && self.chunks.fetch_add(1, Ordering::Relaxed) == 1
+        {
+            self.cancel.cancel();
+        }
+    }
+}
+
+#[test]
+fn cancelling_returns_the_chunks_decoded_before() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(4.0, 440.0);
+    // a single beam at a single temperature starts each chunk once
+    let options = greedy_options();
+    let full = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+    assert!(full.segments.len() > 1);
+
+    let cancel = CancellationToken::new();
+    let options = options
+        .cancel(cancel.clone())
+        .logit_filter(Arc::new(CancelOnSecondChunk {
+            prefix_end: bpe.special_token(SpecialToken::NoTimeStamps).unwrap(),
+            chunks: AtomicUsize::new(0),
+            cancel,
+        }));
+    let Err(WhisperError::Cancelled(Some(partial))) =
+        transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options)
+    else {
+        panic!("transcription was not cancelled with a partial transcript");
+    };
+
+    assert_eq!(partial.segments.len(), 1);
+    assert_eq!(
+        partial.segments[0].result.tokens,
+        full.segments[0].result.tokens
+    );
+    assert_eq!(partial.text, full.segments[0].result.text);
+}
+
+/// Compares JSON values, allowing numbers to differ by rounding.
+fn assert_json_close(actual: &serde_json::Value, expected: &serde_json::Value, path: &str) {
+    use serde_json::Value;
+
+    match (actual, expected) {
+        (Value::Number(actual), Value::Number(expected)) => {
+            let (actual, expected) = (actual.as_f64().unwrap(), expected.as_f64().unwrap());
+            let tolerance = 1e-4 * expected.abs().max(1.0);
+            assert!(
+                (actual - expected).abs() <= tolerance,
+                "{path}: {actual} != {expected}"
+            );
+        }
+        (Value::Array(actual), Value::Array(expected)) => {
+            assert_eq!(actual.len(), expected.len(), "{path}");
+            for (i, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+                assert_json_close(actual, expected, &format!("{path}[{i}]"));
+            }
+        }
+        (Value::Object(actual), Value::Object(expected)) => {
+            let keys = |object: &serde_json::Map<String, Value>| object.keys().cloned().collect();
+            let actual_keys: Vec<String> = keys(actual);
+            assert_eq!(actual_keys, keys(expected), "{path}");
+            for (key, expected) in expected {
+                assert_json_close(&actual[key], expected, &format!("{path}.{key}"));
+            }
+        }
+        _ => assert_eq!(actual, expected, "{path}"),
+    }
+}
+
+#[test]
+fn verbose_json_matches_the_golden_file() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(5.0, 440.0);
+
+    let options = options().timestamps(true);
+    let transcript = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+    let actual: serde_json::Value =
+        serde_json::from_str(&transcript.to_verbose_json(&bpe)).unwrap();
+    let expected: serde_json::Value =
+        serde_json::from_str(include_str!("fixtures/verbose_json.json")).unwrap();
+
+    assert_json_close(&actual, &expected, "");
+}
+
+#[test]
+fn encoder_and_decoder_of_different_depths_are_saved_loaded_and_paired() {
+    let (_, bpe) = tiny_model();
+    let device = Default::default();
+    let encoder_config = AudioEncoderConfig::new(8, 200, 64, 2, 12);
+    let decoder_config = TextDecoderConfig::new(N_VOCAB, 64, 64, 2, 2);
+    let (encoder, _) = random_whisper::<Backend>(
+        &WhisperConfig::new(encoder_config.clone(), decoder_config.clone()),
+        1,
+        &device,
+    )
+    .into_parts();
+    let (_, decoder) = random_whisper::<Backend>(
+        &WhisperConfig::new(
+            AudioEncoderConfig::new(8, 200, 64, 2, 2),
+            decoder_config.clone(),
+        ),
+        2,
+        &device,
+    )
+    .into_parts();
+
+    let dir = std::env::temp_dir().join(format!("whisper-parts-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    save_encoder(encoder.clone(), &dir.join("encoder")).unwrap();
+    save_decoder(decoder.clone(), &dir.join("decoder")).unwrap();
+    let loaded = Whisper::from_parts(
+        load_encoder::<Backend>(&dir.join("encoder"), &encoder_config, &device).unwrap(),
+        load_decoder::<Backend>(&dir.join("decoder"), &decoder_config, &device).unwrap(),
+    )
+    .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let original = Whisper::from_parts(encoder, decoder).unwrap();
+    let waveform = sine(2.0, 440.0);
+    let expected = transcribe(&original, &bpe, &waveform, SAMPLE_RATE, &options()).unwrap();
+    let transcript = transcribe(&loaded, &bpe, &waveform, SAMPLE_RATE, &options()).unwrap();
+
+    assert_valid(&transcript, &bpe);
+    assert_eq!(transcript.tokens, expected.tokens);
+}
+
+#[test]
+fn parts_of_different_state_sizes_are_not_paired() {
+    let device = Default::default();
+    let (encoder, _) = random_whisper::<Backend>(
+        &WhisperConfig::new(
+            AudioEncoderConfig::new(8, 200, 64, 2, 2),
+            TextDecoderConfig::new(N_VOCAB, 64, 64, 2, 2),
+        ),
+        0,
+        &device,
+    )
+    .into_parts();
+    let (_, decoder) = random_whisper::<Backend>(
+        &WhisperConfig::new(
+            AudioEncoderConfig::new(8, 200, 32, 2, 2),
+            TextDecoderConfig::new(N_VOCAB, 64, 32, 2, 2),
+        ),
+        0,
+        &device,
+    )
+    .into_parts();
+
+    let error = Whisper::from_parts(encoder, decoder).err().unwrap();
+    assert!(matches!(
+        error,
+        WhisperError::StateSizeMismatch {
+            encoder: 64,
+            decoder: 32
+        }
+    ));
+}
+
+#[test]
+fn reseaming_decodes_a_bridge_across_seams_the_chunks_disagree_on() {
+    let (whisper, bpe) = tiny_model();
+    let waveform = sine(4.0, 440.0);
+    let decode_steps = |reseam: bool| {
+        // only chunks agreeing on every aligned token are merged without a bridge
+        let options = options()
+            .merge_strategy(MergeStrategy::Confidence { min_agreement: 1.0 })
+            .reseam(reseam);
+        let transcript = transcribe(&whisper, &bpe, &waveform, SAMPLE_RATE, &options).unwrap();
+        assert_valid(&transcript, &bpe);
+
+        let steps: Vec<usize> = transcript
+            .metadata
+            .metrics
+            .chunks
+            .iter()
+            .map(|chunk| chunk.decode_steps)
+            .collect();
+        (transcript.segments.len(), steps)
+    };
+
+    let (n_segments, plain) = decode_steps(false);
+    let (n_reseamed_segments, reseamed) = decode_steps(true);
+
+    // the bridges belong to the chunks after the seams, and the first chunk has none
+    assert_eq!(n_reseamed_segments, n_segments);
+    assert_eq!(reseamed[0], plain[0]);
+    assert!(reseamed[1..]
+        .iter()
+        .zip(&plain[1..])
+        .all(|(reseamed, plain)| reseamed > plain));
+}
+
+fn fixed_mels(n_frames: usize) -> Tensor<Backend, 3> {
+    let values: Vec<f32> = (0..8 * n_frames)
+        .map(|i| ((i * 37 % 101) as f32 / 50.0) - 1.0)
+        .collect();
+
+    Tensor::from_data(
+        TensorData::new(values, [1, 8, n_frames]),
+        &Default::default(),
+    )
+}
+
+#[test]
+fn saved_bundles_load_back_to_the_same_model() {
+    let (whisper, bpe) = tiny_model();
+    let config = WhisperConfig::new(
+        AudioEncoderConfig::new(8, 200, 64, 2, 2),
+        TextDecoderConfig::new(N_VOCAB, 64, 64, 2, 2),
+    );
+    let device = Default::default();
+    let dir = std::env::temp_dir().join(format!("whisper-bundle-{}", std::process::id()));
+    let mels = fixed_mels(400);
+    let tokens = Tensor::<Backend, 2, Int>::from_ints([[1, 2, 3, 300, 301]], &device);
+
+    for (name, whisper) in [
+        ("full", whisper.clone()),
+        ("int8", whisper.clone().quantize()),
+    ] {
+        save_whisper_bundle(&whisper, &config, &bpe, &dir, name).unwrap();
+        verify_bundle::<Backend>(&dir, name, &device).unwrap();
+        let (loaded_bpe, loaded_config, loaded) =
+            load_named_model::<Backend>(&dir, name, &device).unwrap();
+
+        assert_eq!(loaded.is_quantized(), whisper.is_quantized());
+        assert_eq!(loaded_config.to_string(), config.to_string());
+        assert_eq!(loaded_bpe.encode(" hello"), bpe.encode(" hello"));
+        let expected = whisper.forward(mels.clone(), tokens.clone()).unwrap();
+        let actual = loaded.forward(mels.clone(), tokens.clone()).unwrap();
+        assert_eq!(
+            actual.into_data().to_vec::<f32>().unwrap(),
+            expected.into_data().to_vec::<f32>().unwrap(),
+            "{name}"
+        );
+    }
+
+    // weights saved for fewer encoder positions than the config claims load but do not verify
+    let shorter = WhisperConfig::new(
+        AudioEncoderConfig::new(8, 100, 64, 2, 2),
+        TextDecoderConfig::new(N_VOCAB, 64, 64, 2, 2),
+    );
+    save_whisper_bundle(&whisper, &shorter, &bpe, &dir, "mismatched").unwrap();
+    assert!(matches!(
+        verify_bundle::<Backend>(&dir, "mismatched", &device),
+        Err(WhisperError::WeightsCorrupt { .. })
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}